@@ -0,0 +1,9 @@
+fn main() {
+    // no protoc needed unless the gRPC server is built
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/imagor.proto").expect("compiling proto/imagor.proto");
+    }
+}
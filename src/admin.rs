@@ -0,0 +1,227 @@
+use crate::config::DefaultsSettings;
+use crate::error::ApiError;
+use crate::imagorpath::hasher::{source_digest, suffix_result_storage_hasher};
+use crate::imagorpath::parse::parse_params;
+use crate::processor::fonts::{FontList, FontRegistry};
+use crate::result_meta::ResultMeta;
+use crate::state::AppStateDyn;
+use crate::storage::content_type::ContentType;
+use axum::extract::{Path, State};
+use axum::Json;
+use serde::Serialize;
+use tracing::warn;
+
+/// Where a single derivative lives in each tier
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CacheKeys {
+    pub result_key: String,
+    /// Responses are cached by request path, so this is only known when the
+    /// key was given as an imagor path
+    pub cache_key: Option<String>,
+}
+
+impl CacheKeys {
+    /// `key` is either a signed or unsafe imagor path, or a precomputed
+    /// result storage key
+    pub fn resolve(defaults: &DefaultsSettings, key: &str) -> Self {
+        let key = key.trim_start_matches('/');
+
+        match parse_params(key) {
+            Ok(params) if params.unsafe_ || params.hash.is_some() => {
+                let params = params.with_defaults(defaults).canonicalized(defaults);
+                CacheKeys {
+                    result_key: suffix_result_storage_hasher(&params),
+                    cache_key: Some(format!("GET:/{}", key)),
+                }
+            }
+            _ => CacheKeys {
+                result_key: key.to_string(),
+                cache_key: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EntryInfo {
+    pub size: usize,
+    pub content_type: ContentType,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub keys: CacheKeys,
+    pub cache: Option<EntryInfo>,
+    pub result_storage: Option<EntryInfo>,
+}
+
+async fn lookup(state: &AppStateDyn, key: &str) -> Result<CacheEntry, ApiError> {
+    let keys = CacheKeys::resolve(&state.defaults, key);
+
+    let cache = match &keys.cache_key {
+        Some(cache_key) => state
+            .cache
+            .get(cache_key)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get cache: {}", e)))?,
+        None => None,
+    }
+    .map(|data| EntryInfo {
+        size: data.len(),
+        content_type: ContentType::sniff(&data).unwrap_or_default(),
+    });
+
+    let result_storage = state
+        .storage
+        .stat(&keys.result_key)
+        .await
+        .ok()
+        .flatten()
+        .map(|stat| EntryInfo {
+            size: stat.size as usize,
+            content_type: stat.content_type.unwrap_or_default(),
+        });
+
+    Ok(CacheEntry {
+        keys,
+        cache,
+        result_storage,
+    })
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn inspect_cache(
+    State(state): State<AppStateDyn>,
+    Path(key): Path<String>,
+) -> Result<Json<CacheEntry>, ApiError> {
+    let entry = lookup(&state, &key).await?;
+    if entry.cache.is_none() && entry.result_storage.is_none() {
+        return Err(ApiError::not_found(format!("Nothing cached for {}", key)));
+    }
+
+    Ok(Json(entry))
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn evict_cache(
+    State(state): State<AppStateDyn>,
+    Path(key): Path<String>,
+) -> Result<Json<CacheEntry>, ApiError> {
+    let entry = lookup(&state, &key).await?;
+    if entry.cache.is_none() && entry.result_storage.is_none() {
+        return Err(ApiError::not_found(format!("Nothing cached for {}", key)));
+    }
+
+    if let (Some(cache_key), Some(_)) = (&entry.keys.cache_key, &entry.cache) {
+        state
+            .cache
+            .delete(cache_key)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to evict cached response: {}", e)))?;
+    }
+    if entry.result_storage.is_some() {
+        state
+            .storage
+            .delete(&entry.keys.result_key)
+            .await
+            .map_err(|e| {
+                warn!("Failed to evict result [{}]: {}", entry.keys.result_key, e);
+                ApiError::internal(format!("Failed to evict result image: {}", e))
+            })?;
+    }
+
+    // what was evicted
+    Ok(Json(entry))
+}
+
+/// A stored result and how it was rendered
+#[derive(Serialize, Debug)]
+pub struct ResultInfo {
+    pub result_key: String,
+    pub size: u64,
+    pub content_type: Option<ContentType>,
+    /// When it was saved, as an HTTP date
+    pub modified: Option<String>,
+    /// Missing for results saved before it was kept, or in storage that
+    /// doesn't keep custom metadata
+    pub meta: Option<ResultMeta>,
+}
+
+/// The metadata saved with the result for `key`, to tell whether a stale
+/// rendition came from old parameters or an old source
+#[tracing::instrument(skip(state))]
+pub async fn meta_of_result(
+    State(state): State<AppStateDyn>,
+    Path(key): Path<String>,
+) -> Result<Json<ResultInfo>, ApiError> {
+    let keys = CacheKeys::resolve(&state.defaults, &key);
+    let stat = state
+        .storage
+        .stat(&keys.result_key)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to look up result image: {}", e)))?
+        .ok_or_else(|| ApiError::not_found(format!("No result stored for {}", key)))?;
+
+    Ok(Json(ResultInfo {
+        result_key: keys.result_key,
+        size: stat.size,
+        content_type: stat.content_type,
+        modified: stat.modified.map(httpdate::fmt_http_date),
+        meta: ResultMeta::from_custom(&stat.custom),
+    }))
+}
+
+#[derive(Serialize, Debug)]
+pub struct SourceEviction {
+    pub source: String,
+    pub evicted: usize,
+}
+
+/// Drop every cached response derived from `source`
+#[tracing::instrument(skip(state))]
+pub async fn evict_source(
+    State(state): State<AppStateDyn>,
+    Path(source): Path<String>,
+) -> Result<Json<SourceEviction>, ApiError> {
+    let evicted = state
+        .cache
+        .invalidate_tag(&source_digest(&source))
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to evict cached responses: {}", e)))?;
+
+    Ok(Json(SourceEviction { source, evicted }))
+}
+
+/// The font families `label()` can draw with, and the aliases for them
+#[tracing::instrument]
+pub async fn list_fonts() -> Json<FontList> {
+    Json(FontRegistry::global().list())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_imagor_path() {
+        let keys = CacheKeys::resolve(&DefaultsSettings::default(), "/unsafe/100x100/img.png");
+        assert_eq!(
+            keys.cache_key,
+            Some("GET:/unsafe/100x100/img.png".to_string())
+        );
+        assert_ne!(keys.result_key, "unsafe/100x100/img.png");
+    }
+
+    #[test]
+    fn test_resolve_precomputed_key() {
+        let keys = CacheKeys::resolve(&DefaultsSettings::default(), "img.0123456789abcdef.png");
+        assert_eq!(
+            keys,
+            CacheKeys {
+                result_key: "img.0123456789abcdef.png".to_string(),
+                cache_key: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use color_eyre::{eyre::eyre, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use url::Url;
+
+/// Hosts whose match is remembered before the cache is cleared, so a flood
+/// of made up hosts can't grow it without bound
+const MAX_CACHED_HOSTS: usize = 10_000;
+
+/// A source the HTTP loader refused to fetch, returned to the client as JSON
+#[derive(Debug, Error, Serialize)]
+#[error("Source is not allowed: {url}")]
+pub struct SourceNotAllowed {
+    pub error: &'static str,
+    #[serde(rename = "source")]
+    pub url: String,
+    pub host: Option<String>,
+}
+
+impl SourceNotAllowed {
+    fn new(url: &str, host: Option<String>) -> Self {
+        Self {
+            error: "source_not_allowed",
+            url: url.to_string(),
+            host,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Any,
+    Host(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// `regex:` entries are used as they are, anchored to the whole host.
+    /// Anything else is a host where `*` matches any run of characters,
+    /// e.g. `*.cdn.example.com` or `img-*.example.com`.
+    fn parse(source: &str) -> Result<Self> {
+        let source = source.trim();
+        if let Some(pattern) = source.strip_prefix("regex:") {
+            let regex = Regex::new(&format!("(?i)^(?:{})$", pattern))
+                .map_err(|e| eyre!("Invalid allowed source {:?}: {}", source, e))?;
+            return Ok(Pattern::Regex(regex));
+        }
+
+        let source = source.to_lowercase();
+        if source == "*" {
+            return Ok(Pattern::Any);
+        }
+        if !source.contains('*') {
+            return Ok(Pattern::Host(source));
+        }
+
+        let glob = source
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(".*");
+        Ok(Pattern::Regex(Regex::new(&format!("^{}$", glob))?))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Host(pattern) => pattern == host,
+            Pattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+/// The hosts the HTTP loader may fetch from. Empty allows any host.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    patterns: Arc<Vec<Pattern>>,
+    matches: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl AllowList {
+    pub fn new(sources: &[String]) -> Result<Self> {
+        let patterns = sources
+            .iter()
+            .map(|source| Pattern::parse(source))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            patterns: Arc::new(patterns),
+            matches: Arc::default(),
+        })
+    }
+
+    /// Whether the HTTP loader may fetch `url`
+    pub fn check(&self, url: &str) -> Result<(), SourceNotAllowed> {
+        if self.patterns.is_empty() {
+            return Ok(());
+        }
+
+        let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_lowercase))
+        else {
+            return Err(SourceNotAllowed::new(url, None));
+        };
+
+        if self.is_allowed_host(&host) {
+            Ok(())
+        } else {
+            Err(SourceNotAllowed::new(url, Some(host)))
+        }
+    }
+
+    fn is_allowed_host(&self, host: &str) -> bool {
+        let mut matches = self.matches.lock().expect("allow list lock poisoned");
+        if let Some(allowed) = matches.get(host) {
+            return *allowed;
+        }
+
+        let allowed = self.patterns.iter().any(|pattern| pattern.matches(host));
+        if matches.len() >= MAX_CACHED_HOSTS {
+            matches.clear();
+        }
+        matches.insert(host.to_string(), allowed);
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow_list(sources: &[&str]) -> AllowList {
+        AllowList::new(&sources.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_globs() {
+        let allow_list = allow_list(&["*.cdn.example.com", "img-*.example.org"]);
+
+        assert!(allow_list.check("https://eu.cdn.example.com/a.png").is_ok());
+        assert!(allow_list
+            .check("https://a.b.cdn.example.com/a.png")
+            .is_ok());
+        assert!(allow_list.check("https://img-01.example.org/a.png").is_ok());
+        assert!(allow_list.check("https://cdn.example.com/a.png").is_err());
+        assert!(allow_list
+            .check("https://eu.cdn.example.com.evil/a.png")
+            .is_err());
+        assert!(allow_list
+            .check("https://static.example.org/a.png")
+            .is_err());
+    }
+
+    #[test]
+    fn test_regex() {
+        let allow_list = allow_list(&[r"regex:img[0-9]+\.example\.com"]);
+
+        assert!(allow_list.check("https://img1.example.com/a.png").is_ok());
+        assert!(allow_list.check("https://IMG42.example.com/a.png").is_ok());
+        assert!(allow_list.check("https://img.example.com/a.png").is_err());
+        assert!(allow_list
+            .check("https://img1.example.com.evil/a.png")
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(AllowList::new(&["regex:img[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_rejection() {
+        let allow_list = allow_list(&["example.com"]);
+
+        let err = allow_list.check("https://evil.com/a.png").unwrap_err();
+        assert_eq!(err.host.as_deref(), Some("evil.com"));
+        assert_eq!(
+            serde_json::to_value(&err).unwrap()["error"],
+            "source_not_allowed"
+        );
+        assert!(allow_list.check("not a url").unwrap_err().host.is_none());
+    }
+
+    #[test]
+    fn test_matches_are_cached() {
+        let allow_list = allow_list(&["*.example.com"]);
+        allow_list.check("https://a.example.com/1.png").unwrap();
+        allow_list.check("https://a.example.com/2.png").unwrap();
+        let _ = allow_list.check("https://evil.com/a.png");
+
+        let matches = allow_list.matches.lock().unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches.get("evil.com"), Some(&false));
+    }
+}
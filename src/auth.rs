@@ -0,0 +1,211 @@
+use crate::config::{AuthSettings, RateLimitBudget};
+use crate::imagorpath::hasher::source_digest;
+use axum::async_trait;
+use axum::http::{header, HeaderMap};
+use color_eyre::Result;
+use redis::{AsyncCommands, Client};
+use secrecy::ExposeSecret;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The key a request authenticated with, added to its extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    /// Used in metrics and logs in place of the key itself
+    pub name: String,
+    /// Overrides the default rate limit budget
+    pub rate_limit: Option<RateLimitBudget>,
+}
+
+impl ApiKey {
+    /// Parse a key stored as a Redis hash with a `name` and optionally
+    /// `requests_per_second` and `burst`
+    fn from_fields(fields: &HashMap<String, String>) -> Option<Self> {
+        let name = fields.get("name")?.clone();
+        let requests_per_second = fields
+            .get("requests_per_second")
+            .and_then(|v| v.parse().ok());
+        let burst = fields.get("burst").and_then(|v| v.parse().ok());
+        let rate_limit = match (requests_per_second, burst) {
+            (None, None) => None,
+            (requests_per_second, burst) => {
+                let default = RateLimitBudget::default();
+                Some(RateLimitBudget {
+                    requests_per_second: requests_per_second.unwrap_or(default.requests_per_second),
+                    burst: burst.unwrap_or(default.burst),
+                })
+            }
+        };
+        Some(ApiKey { name, rate_limit })
+    }
+}
+
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    async fn lookup(&self, key: &str) -> Result<Option<ApiKey>>;
+}
+
+/// Keys from the config file. They're held by digest, so a lookup doesn't
+/// compare against the keys themselves.
+#[derive(Debug, Default)]
+pub struct ConfigKeyStore {
+    keys: HashMap<String, ApiKey>,
+}
+
+impl ConfigKeyStore {
+    pub fn new(settings: &AuthSettings) -> Self {
+        Self {
+            keys: settings
+                .keys
+                .iter()
+                .map(|key| {
+                    (
+                        source_digest(key.key.expose_secret()),
+                        ApiKey {
+                            name: key.name.clone(),
+                            rate_limit: key.rate_limit,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyStore for ConfigKeyStore {
+    async fn lookup(&self, key: &str) -> Result<Option<ApiKey>> {
+        Ok(self.keys.get(&source_digest(key)).cloned())
+    }
+}
+
+/// Keys kept in Redis as hashes under `apikey:<sha1 of the key>`, so they
+/// can be issued and revoked without a reload
+#[derive(Debug, Clone)]
+pub struct RedisKeyStore {
+    client: Client,
+}
+
+impl RedisKeyStore {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        let client = Client::open(redis_url)?;
+        Ok(RedisKeyStore { client })
+    }
+
+    async fn get_connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(Into::into)
+    }
+}
+
+fn key_key(key: &str) -> String {
+    format!("apikey:{}", source_digest(key))
+}
+
+#[async_trait]
+impl KeyStore for RedisKeyStore {
+    #[tracing::instrument(skip_all)]
+    async fn lookup(&self, key: &str) -> Result<Option<ApiKey>> {
+        let mut conn = self.get_connection().await?;
+        let fields: HashMap<String, String> = conn.hgetall(key_key(key)).await?;
+        Ok(ApiKey::from_fields(&fields))
+    }
+}
+
+/// API key authentication for the processing endpoints. Keys are sent as
+/// `Authorization: Bearer <key>` or in an `X-Api-Key` header, and looked up
+/// in the config first, then Redis.
+#[derive(Clone)]
+pub struct Auth {
+    stores: Vec<Arc<dyn KeyStore>>,
+}
+
+impl Auth {
+    pub fn new(stores: Vec<Arc<dyn KeyStore>>) -> Self {
+        Self { stores }
+    }
+
+    pub fn from_settings(settings: &AuthSettings) -> Result<Self> {
+        let mut stores: Vec<Arc<dyn KeyStore>> = vec![Arc::new(ConfigKeyStore::new(settings))];
+        if let Some(redis_uri) = &settings.redis_uri {
+            stores.push(Arc::new(RedisKeyStore::new(redis_uri)?));
+        }
+        Ok(Self::new(stores))
+    }
+
+    /// The key sent with a request, if any
+    pub fn credentials(headers: &HeaderMap) -> Option<&str> {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        bearer
+            .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+    }
+
+    /// `None` for a key no store knows
+    pub async fn authenticate(&self, key: &str) -> Result<Option<ApiKey>> {
+        for store in &self.stores {
+            if let Some(api_key) = store.lookup(key).await? {
+                return Ok(Some(api_key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ApiKeySettings;
+
+    #[test]
+    fn test_credentials() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Auth::credentials(&headers), None);
+
+        headers.insert("x-api-key", "abc".parse().unwrap());
+        assert_eq!(Auth::credentials(&headers), Some("abc"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer xyz".parse().unwrap());
+        assert_eq!(Auth::credentials(&headers), Some("xyz"));
+
+        headers.insert(header::AUTHORIZATION, "Basic dXNlcjpwYXNz".parse().unwrap());
+        assert_eq!(Auth::credentials(&headers), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_config_keys() {
+        let settings = AuthSettings {
+            enabled: true,
+            keys: vec![ApiKeySettings {
+                name: "mobile".to_string(),
+                key: "s3cret".to_string().into(),
+                rate_limit: None,
+            }],
+            ..Default::default()
+        };
+        let auth = Auth::from_settings(&settings).unwrap();
+
+        let api_key = auth.authenticate("s3cret").await.unwrap().unwrap();
+        assert_eq!(api_key.name, "mobile");
+        assert!(auth.authenticate("guess").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_key_from_fields() {
+        let fields = HashMap::from([
+            ("name".to_string(), "partner".to_string()),
+            ("burst".to_string(), "500".to_string()),
+        ]);
+        let api_key = ApiKey::from_fields(&fields).unwrap();
+        assert_eq!(api_key.name, "partner");
+        assert_eq!(api_key.rate_limit.unwrap().burst, 500);
+
+        assert!(ApiKey::from_fields(&HashMap::new()).is_none());
+    }
+}
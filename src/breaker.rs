@@ -0,0 +1,221 @@
+use crate::config::CircuitBreakerSettings;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// A request that wasn't sent because its host is failing
+#[derive(Debug, Error)]
+#[error("{host} is failing, not fetching from it for now")]
+pub struct CircuitOpen {
+    pub host: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One probe request is let through to see if the host has recovered,
+    /// and another after the deadline if it never reports back
+    HalfOpen {
+        probe_deadline: Instant,
+    },
+}
+
+impl State {
+    fn gauge(&self) -> f64 {
+        match self {
+            State::Closed { .. } => 0.0,
+            State::HalfOpen { .. } => 1.0,
+            State::Open { .. } => 2.0,
+        }
+    }
+}
+
+/// Per-host circuit breakers for origin fetches. After `failure_threshold`
+/// failures in a row a host's breaker opens and requests to it fail
+/// straight away. Once `open_secs` have passed a single probe is let
+/// through: if it succeeds the breaker closes, otherwise it opens again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakers {
+    enabled: bool,
+    failure_threshold: u32,
+    open_for: Duration,
+    hosts: Arc<Mutex<HashMap<String, State>>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            failure_threshold: settings.failure_threshold.max(1),
+            open_for: Duration::from_secs(settings.open_secs),
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether a request to `host` may be sent. Every request that's allowed
+    /// has to be followed by a call to [`CircuitBreakers::record`].
+    pub fn allow(&self, host: &str) -> Result<(), CircuitOpen> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let mut hosts = self.hosts.lock().expect("circuit breaker lock poisoned");
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until }
+            | State::HalfOpen {
+                probe_deadline: until,
+            } if until <= now => {
+                self.set(
+                    host,
+                    state,
+                    State::HalfOpen {
+                        probe_deadline: now + self.open_for,
+                    },
+                );
+                Ok(())
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                metrics::counter!("origin_circuit_rejections_total", "host" => host.to_string())
+                    .increment(1);
+                Err(CircuitOpen {
+                    host: host.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Record the outcome of a request to `host`. Failures are transport
+    /// errors and 5xx responses.
+    pub fn record(&self, host: &str, ok: bool) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut hosts = self.hosts.lock().expect("circuit breaker lock poisoned");
+        if ok {
+            if let Some(state) = hosts.get_mut(host) {
+                if !matches!(state, State::Closed { .. }) {
+                    info!("{} recovered, closing its circuit breaker", host);
+                }
+                self.set(host, state, State::Closed { failures: 0 });
+            }
+            return;
+        }
+
+        let state = hosts
+            .entry(host.to_string())
+            .or_insert(State::Closed { failures: 0 });
+        let next = match *state {
+            State::Closed { failures } if failures + 1 < self.failure_threshold => State::Closed {
+                failures: failures + 1,
+            },
+            _ => {
+                warn!(
+                    "{} is failing, opening its circuit breaker for {:?}",
+                    host, self.open_for
+                );
+                metrics::counter!("origin_circuit_opened_total", "host" => host.to_string())
+                    .increment(1);
+                State::Open {
+                    until: Instant::now() + self.open_for,
+                }
+            }
+        };
+        self.set(host, state, next);
+    }
+
+    fn set(&self, host: &str, state: &mut State, next: State) {
+        if state.gauge() != next.gauge() {
+            metrics::gauge!("origin_circuit_state", "host" => host.to_string()).set(next.gauge());
+        }
+        *state = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(failure_threshold: u32, open_secs: u64) -> CircuitBreakers {
+        CircuitBreakers::new(&CircuitBreakerSettings {
+            enabled: true,
+            failure_threshold,
+            open_secs,
+        })
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breakers = breakers(3, 30);
+        let host = "origin.example.com";
+
+        breakers.record(host, false);
+        breakers.record(host, false);
+        breakers.record(host, true);
+        breakers.record(host, false);
+        breakers.record(host, false);
+        assert!(breakers.allow(host).is_ok());
+
+        breakers.record(host, false);
+        assert!(breakers.allow(host).is_err());
+        assert!(breakers.allow("other.example.com").is_ok());
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breakers = breakers(1, 0);
+        let host = "origin.example.com";
+        breakers.record(host, false);
+
+        // the probe fails and the breaker opens again
+        assert!(breakers.allow(host).is_ok());
+        breakers.record(host, false);
+
+        // the probe succeeds and the breaker closes
+        assert!(breakers.allow(host).is_ok());
+        breakers.record(host, true);
+        assert_eq!(
+            breakers.hosts.lock().unwrap()[host],
+            State::Closed { failures: 0 }
+        );
+    }
+
+    #[test]
+    fn test_only_one_probe_at_a_time() {
+        let breakers = breakers(1, 30);
+        let host = "origin.example.com";
+        breakers.hosts.lock().unwrap().insert(
+            host.to_string(),
+            State::Open {
+                until: Instant::now(),
+            },
+        );
+
+        assert!(breakers.allow(host).is_ok());
+        assert!(breakers.allow(host).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breakers = CircuitBreakers::new(&CircuitBreakerSettings {
+            enabled: false,
+            failure_threshold: 1,
+            open_secs: 30,
+        });
+        breakers.record("origin.example.com", false);
+        assert!(breakers.allow("origin.example.com").is_ok());
+    }
+}
@@ -5,6 +5,16 @@ use std::time::Duration;
 #[async_trait]
 pub trait ImageCache: Send + Sync {
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Values in the same order as `keys`
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>>;
     async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()>;
     async fn delete(&self, key: &str) -> Result<()>;
+    async fn delete_many(&self, keys: &[String]) -> Result<()>;
+
+    /// Associate `key` with `tag` so it can be dropped by `invalidate_tag`
+    async fn tag(&self, tag: &str, key: &str) -> Result<()>;
+    /// Delete every key tagged with `tag`, returning how many there were
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize>;
+
+    async fn ping(&self) -> Result<()>;
 }
@@ -0,0 +1,250 @@
+use crate::config::RedisSettings;
+use color_eyre::{eyre::eyre, Result};
+use deadpool::managed::{Metrics, Object, Pool, RecycleResult};
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType};
+use redis::{Client, Cmd, IntoConnectionInfo, Pipeline, RedisError, RedisFuture, TlsMode, Value};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+
+/// Where the Redis servers are, from the scheme of the configured URI
+#[derive(Debug, Clone, PartialEq)]
+enum Topology {
+    /// `redis://host:6379/0`, or `rediss://` for TLS
+    Standalone(String),
+    /// `redis+cluster://host1:6379,host2:6379`, the nodes to discover the
+    /// cluster from
+    Cluster(Vec<String>),
+    /// `redis+sentinel://host1:26379,host2:26379/mymaster/0`. Credentials in
+    /// the URI are for the master, the sentinels are connected to without.
+    Sentinel {
+        sentinels: Vec<String>,
+        service: String,
+        master: String,
+        tls: bool,
+    },
+}
+
+impl Topology {
+    fn parse(uri: &str) -> Result<Self> {
+        let (scheme, rest) = uri
+            .split_once("://")
+            .ok_or_else(|| eyre!("Invalid Redis URI {:?}", uri))?;
+        let (base, kind) = scheme.split_once('+').unwrap_or((scheme, ""));
+        if base != "redis" && base != "rediss" {
+            return Err(eyre!("Unsupported Redis URI scheme {:?}", scheme));
+        }
+
+        let (auth, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (format!("{}@", auth), rest),
+            None => (String::new(), rest),
+        };
+        let (hosts, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let hosts: Vec<_> = hosts
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+
+        match kind {
+            "" => Ok(Topology::Standalone(uri.to_string())),
+            "cluster" => Ok(Topology::Cluster(
+                hosts
+                    .iter()
+                    .map(|host| format!("{}://{}{}", base, auth, host))
+                    .collect(),
+            )),
+            "sentinel" => {
+                let (service, db) = path.split_once('/').unwrap_or((path, "0"));
+                if service.is_empty() {
+                    return Err(eyre!("Redis sentinel URI {:?} has no service name", uri));
+                }
+                let Some(first) = hosts.first() else {
+                    return Err(eyre!("Redis sentinel URI {:?} has no hosts", uri));
+                };
+                Ok(Topology::Sentinel {
+                    sentinels: hosts
+                        .iter()
+                        .map(|host| format!("{}://{}", base, host))
+                        .collect(),
+                    service: service.to_string(),
+                    // only the credentials and database are used
+                    master: format!("{}://{}{}/{}", base, auth, first, db),
+                    tls: base == "rediss",
+                })
+            }
+            _ => Err(eyre!("Unsupported Redis URI scheme {:?}", scheme)),
+        }
+    }
+}
+
+/// Opens multiplexed connections to a standalone server or to the master a
+/// sentinel points at, asking the sentinels again for every new connection
+/// so a failover is followed
+pub enum Manager {
+    Client(Client),
+    Sentinel(Mutex<SentinelClient>),
+}
+
+impl deadpool::managed::Manager for Manager {
+    type Type = MultiplexedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<MultiplexedConnection, RedisError> {
+        match self {
+            Manager::Client(client) => client.get_multiplexed_async_connection().await,
+            Manager::Sentinel(sentinel) => sentinel.lock().await.get_async_connection().await,
+        }
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut MultiplexedConnection,
+        _: &Metrics,
+    ) -> RecycleResult<RedisError> {
+        redis::cmd("PING").query_async::<()>(conn).await?;
+        Ok(())
+    }
+}
+
+/// A pool of connections, or for a cluster a single connection that routes
+/// each command to the node holding its keys
+#[derive(Clone)]
+pub enum RedisConnections {
+    Pool(Pool<Manager>),
+    Cluster {
+        client: Arc<ClusterClient>,
+        connection: Arc<OnceCell<ClusterConnection>>,
+    },
+}
+
+impl RedisConnections {
+    pub fn new(settings: &RedisSettings) -> Result<Self> {
+        let manager = match Topology::parse(&settings.uri)? {
+            Topology::Standalone(uri) => Manager::Client(Client::open(uri)?),
+            Topology::Cluster(nodes) => {
+                return Ok(RedisConnections::Cluster {
+                    client: Arc::new(ClusterClient::new(nodes)?),
+                    connection: Default::default(),
+                })
+            }
+            Topology::Sentinel {
+                sentinels,
+                service,
+                master,
+                tls,
+            } => {
+                let node_connection_info = SentinelNodeConnectionInfo {
+                    tls_mode: tls.then_some(TlsMode::Secure),
+                    redis_connection_info: Some(master.into_connection_info()?.redis),
+                };
+                Manager::Sentinel(Mutex::new(SentinelClient::build(
+                    sentinels,
+                    service,
+                    Some(node_connection_info),
+                    SentinelServerType::Master,
+                )?))
+            }
+        };
+
+        let pool = Pool::builder(manager)
+            .max_size(settings.pool_size.max(1))
+            .build()?;
+        Ok(RedisConnections::Pool(pool))
+    }
+
+    pub async fn get(&self) -> Result<Connection> {
+        match self {
+            RedisConnections::Pool(pool) => Ok(Connection::Pooled(pool.get().await?)),
+            RedisConnections::Cluster { client, connection } => {
+                let connection = connection
+                    .get_or_try_init(|| client.get_async_connection())
+                    .await?;
+                Ok(Connection::Cluster(connection.clone()))
+            }
+        }
+    }
+}
+
+pub enum Connection {
+    Pooled(Object<Manager>),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Connection::Pooled(conn) => conn.req_packed_command(cmd),
+            Connection::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Connection::Pooled(conn) => conn.req_packed_commands(cmd, offset, count),
+            Connection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Pooled(conn) => conn.get_db(),
+            Connection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standalone() {
+        assert_eq!(
+            Topology::parse("rediss://:secret@redis:6379/1").unwrap(),
+            Topology::Standalone("rediss://:secret@redis:6379/1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cluster() {
+        assert_eq!(
+            Topology::parse("redis+cluster://:secret@node1:6379,node2:6379").unwrap(),
+            Topology::Cluster(vec![
+                "redis://:secret@node1:6379".to_string(),
+                "redis://:secret@node2:6379".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sentinel() {
+        assert_eq!(
+            Topology::parse("rediss+sentinel://:secret@s1:26379,s2:26379/mymaster/2").unwrap(),
+            Topology::Sentinel {
+                sentinels: vec![
+                    "rediss://s1:26379".to_string(),
+                    "rediss://s2:26379".to_string()
+                ],
+                service: "mymaster".to_string(),
+                master: "rediss://:secret@s1:26379/2".to_string(),
+                tls: true,
+            }
+        );
+        assert!(Topology::parse("redis+sentinel://s1:26379").is_err());
+    }
+
+    #[test]
+    fn test_unsupported_scheme() {
+        assert!(Topology::parse("memcached://cache:11211").is_err());
+        assert!(Topology::parse("redis+proxy://cache:6379").is_err());
+        assert!(Topology::parse("redis").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use super::cache::ImageCache;
+use axum::async_trait;
+use color_eyre::Result;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > Instant::now())
+    }
+}
+
+/// In-process cache, for single instance deployments and tests
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    tags: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ImageCache for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut entries = self.entries.lock().expect("memory cache lock poisoned");
+        match entries.get(key) {
+            Some(entry) if entry.is_live() => Ok(Some(entry.value.clone())),
+            Some(_) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("memory cache lock poisoned")
+            .insert(
+                key.to_string(),
+                Entry {
+                    value: value.to_vec(),
+                    expires_at: ttl.map(|ttl| Instant::now() + ttl),
+                },
+            );
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.entries
+            .lock()
+            .expect("memory cache lock poisoned")
+            .remove(key);
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<()> {
+        let mut entries = self.entries.lock().expect("memory cache lock poisoned");
+        for key in keys {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn tag(&self, tag: &str, key: &str) -> Result<()> {
+        self.tags
+            .lock()
+            .expect("memory cache lock poisoned")
+            .entry(tag.to_string())
+            .or_default()
+            .insert(key.to_string());
+        Ok(())
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize> {
+        let keys: Vec<String> = self
+            .tags
+            .lock()
+            .expect("memory cache lock poisoned")
+            .remove(tag)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+
+        self.delete_many(&keys).await?;
+        Ok(keys.len())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ttl_expiry() {
+        let cache = MemoryCache::new();
+        cache.set("a", b"1", Some(Duration::ZERO)).await.unwrap();
+        cache.set("b", b"2", None).await.unwrap();
+
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.get("b").await.unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_operations() {
+        let cache = MemoryCache::new();
+        cache.set("a", b"1", None).await.unwrap();
+        cache.set("b", b"2", None).await.unwrap();
+
+        let keys = vec!["a".to_string(), "missing".to_string(), "b".to_string()];
+        assert_eq!(
+            cache.mget(&keys).await.unwrap(),
+            vec![Some(b"1".to_vec()), None, Some(b"2".to_vec())]
+        );
+
+        cache.delete_many(&keys).await.unwrap();
+        assert_eq!(cache.mget(&keys).await.unwrap(), vec![None, None, None]);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_tag() {
+        let cache = MemoryCache::new();
+        for key in ["small", "large", "other"] {
+            cache.set(key, b"img", None).await.unwrap();
+        }
+        cache.tag("source", "small").await.unwrap();
+        cache.tag("source", "large").await.unwrap();
+
+        assert_eq!(cache.invalidate_tag("source").await.unwrap(), 2);
+        assert_eq!(cache.get("small").await.unwrap(), None);
+        assert_eq!(cache.get("other").await.unwrap(), Some(b"img".to_vec()));
+        assert_eq!(cache.invalidate_tag("source").await.unwrap(), 0);
+    }
+}
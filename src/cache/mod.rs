@@ -1,3 +1,5 @@
 pub mod cache;
-
+pub mod connection;
+pub mod memory;
 pub mod redis;
+pub mod s3;
@@ -1,26 +1,35 @@
 use super::cache::ImageCache;
+use super::connection::{Connection, RedisConnections};
+use crate::config::RedisSettings;
 use axum::async_trait;
 use color_eyre::Result;
 use redis::AsyncCommands;
-use redis::Client;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct RedisCache {
-    client: Client,
+    connections: RedisConnections,
+    key_prefix: String,
 }
 
 impl RedisCache {
-    pub fn new(redis_url: &str) -> Result<Self> {
-        let client = Client::open(redis_url)?;
-        Ok(RedisCache { client })
+    pub fn new(settings: &RedisSettings) -> Result<Self> {
+        Ok(RedisCache {
+            connections: RedisConnections::new(settings)?,
+            key_prefix: settings.key_prefix.clone(),
+        })
     }
 
-    async fn get_connection(&self) -> Result<redis::aio::MultiplexedConnection> {
-        self.client
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(Into::into)
+    async fn get_connection(&self) -> Result<Connection> {
+        self.connections.get().await
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
+    fn tag_key(&self, tag: &str) -> String {
+        format!("{}tag:{}", self.key_prefix, tag)
     }
 }
 
@@ -28,22 +37,70 @@ impl RedisCache {
 impl ImageCache for RedisCache {
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         let mut conn = self.get_connection().await?;
-        let data: Option<Vec<u8>> = conn.get(key).await?;
+        let data: Option<Vec<u8>> = conn.get(self.key(key)).await?;
         Ok(data)
     }
 
     async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
         let mut conn = self.get_connection().await?;
         let res = match ttl {
-            Some(duration) => conn.set_ex(key, value, duration.as_secs()).await,
-            None => conn.set(key, value).await,
+            Some(duration) => conn.set_ex(self.key(key), value, duration.as_secs()).await,
+            None => conn.set(self.key(key), value).await,
         };
 
         res.map_err(Into::into)
     }
 
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.get_connection().await?;
+        // MGET with a single key would come back as a plain value
+        let keys: Vec<_> = keys.iter().map(|key| self.key(key)).collect();
+        let data: Vec<Option<Vec<u8>>> =
+            redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+        Ok(data)
+    }
+
     async fn delete(&self, key: &str) -> Result<()> {
         let mut conn = self.get_connection().await?;
-        conn.del(key).await.map_err(Into::into)
+        conn.del(self.key(key)).await.map_err(Into::into)
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.get_connection().await?;
+        let keys: Vec<_> = keys.iter().map(|key| self.key(key)).collect();
+        conn.del(keys).await.map_err(Into::into)
+    }
+
+    async fn tag(&self, tag: &str, key: &str) -> Result<()> {
+        let mut conn = self.get_connection().await?;
+        conn.sadd(self.tag_key(tag), key).await.map_err(Into::into)
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize> {
+        let mut conn = self.get_connection().await?;
+        let keys: Vec<String> = conn.smembers(self.tag_key(tag)).await?;
+
+        // a single DEL is atomic on one server and split by slot on a cluster
+        let mut doomed: Vec<_> = keys.iter().map(|key| self.key(key)).collect();
+        doomed.push(self.tag_key(tag));
+        conn.del::<_, ()>(doomed).await?;
+
+        Ok(keys.len())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let mut conn = self.get_connection().await?;
+        redis::cmd("PING")
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(Into::into)
     }
 }
@@ -0,0 +1,332 @@
+use super::cache::ImageCache;
+use crate::config::{CacheExpiration, S3CacheSettings};
+use crate::imagorpath::hasher::source_digest;
+use crate::storage::s3::{client, delete_objects, sdk_error};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    BucketLifecycleConfiguration, ExpirationStatus, LifecycleExpiration, LifecycleRule,
+    LifecycleRuleAndOperator, LifecycleRuleFilter, Tag,
+};
+use aws_sdk_s3::Client;
+use axum::async_trait;
+use color_eyre::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Object tag holding the TTL bucket an entry expires with
+const TTL_TAG: &str = "imagor-ttl";
+/// Metadata holding when an entry expires, in seconds since the epoch. It's
+/// checked on every read, since lifecycle rules only run about once a day.
+const EXPIRES_AT: &str = "expires-at";
+/// Lifecycle rules installed by the cache are recognised by this id prefix
+const RULE_PREFIX: &str = "imagor-ttl-";
+
+/// TTLs are rounded up to one of these, so the bucket needs only a handful
+/// of lifecycle rules. Entries that live longer, or have no TTL, are kept
+/// until they're deleted.
+const TTL_BUCKETS: &[(&str, u64)] = &[("1d", 1), ("7d", 7), ("30d", 30), ("365d", 365)];
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The tag value and lifetime in days for an entry that lives for `ttl`
+fn ttl_bucket(ttl: Duration) -> Option<(&'static str, u64)> {
+    TTL_BUCKETS
+        .iter()
+        .copied()
+        .find(|(_, days)| ttl <= DAY * *days as u32)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Response cache backed by an S3-compatible bucket. Entries are tagged
+/// with a TTL bucket that lifecycle rules, or the janitor where there are
+/// none, delete them by.
+#[derive(Clone)]
+pub struct S3Cache {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Cache {
+    pub async fn new(settings: &S3CacheSettings) -> Self {
+        Self {
+            client: client(&settings.s3).await,
+            bucket: settings.s3.bucket.clone(),
+            prefix: settings.prefix.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Install the lifecycle rules, or start the janitor
+    pub async fn start(&self, settings: &S3CacheSettings) -> Result<()> {
+        match settings.expiration {
+            CacheExpiration::Lifecycle => self.install_lifecycle_rules().await,
+            CacheExpiration::Janitor => {
+                let cache = self.clone();
+                let interval = Duration::from_secs(settings.janitor_interval_secs.max(60));
+                tokio::spawn(async move {
+                    let mut ticker = tokio::time::interval(interval);
+                    loop {
+                        ticker.tick().await;
+                        match cache.sweep().await {
+                            Ok(0) => {}
+                            Ok(n) => info!("Deleted {} expired cache objects", n),
+                            Err(e) => warn!("Cache janitor failed: {}", e),
+                        }
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        format!("{}/objects/{}", self.prefix, source_digest(key))
+    }
+
+    fn tag_prefix(&self, tag: &str) -> String {
+        format!("{}/tags/{}/", self.prefix, source_digest(tag))
+    }
+
+    /// Replaces rules this cache installed before and keeps any others
+    async fn install_lifecycle_rules(&self) -> Result<()> {
+        let existing = match self
+            .client
+            .get_bucket_lifecycle_configuration()
+            .bucket(&self.bucket)
+            .send()
+            .await
+        {
+            Ok(output) => output.rules().to_vec(),
+            // a bucket without rules answers NoSuchLifecycleConfiguration
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 404) => Vec::new(),
+            Err(e) => return Err(sdk_error(e)),
+        };
+
+        let mut rules: Vec<LifecycleRule> = existing
+            .into_iter()
+            .filter(|rule| !rule.id().is_some_and(|id| id.starts_with(RULE_PREFIX)))
+            .collect();
+        for (bucket, days) in TTL_BUCKETS {
+            let filter = LifecycleRuleAndOperator::builder()
+                .prefix(format!("{}/", self.prefix))
+                .tags(Tag::builder().key(TTL_TAG).value(*bucket).build()?)
+                .build();
+            rules.push(
+                LifecycleRule::builder()
+                    .id(format!("{}{}", RULE_PREFIX, bucket))
+                    .filter(LifecycleRuleFilter::builder().and(filter).build())
+                    .expiration(LifecycleExpiration::builder().days(*days as i32).build())
+                    .status(ExpirationStatus::Enabled)
+                    .build()?,
+            );
+        }
+
+        self.client
+            .put_bucket_lifecycle_configuration()
+            .bucket(&self.bucket)
+            .lifecycle_configuration(
+                BucketLifecycleConfiguration::builder()
+                    .set_rules(Some(rules))
+                    .build()?,
+            )
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        info!("Installed cache lifecycle rules on {}", self.bucket);
+        Ok(())
+    }
+
+    /// Delete the objects whose TTL bucket has run out, as lifecycle rules
+    /// would. Returns how many were deleted.
+    #[tracing::instrument(skip(self))]
+    pub async fn sweep(&self) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(format!("{}/", self.prefix))
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(sdk_error)?.contents() {
+                let (Some(key), Some(modified)) = (object.key(), object.last_modified()) else {
+                    continue;
+                };
+                let age = SystemTime::try_from(*modified)
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .unwrap_or_default();
+                // nothing expires sooner than the shortest bucket
+                if age < DAY * TTL_BUCKETS[0].1 as u32 {
+                    continue;
+                }
+
+                let tagging = self
+                    .client
+                    .get_object_tagging()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .map_err(sdk_error)?;
+                let days = tagging
+                    .tag_set()
+                    .iter()
+                    .find(|tag| tag.key() == TTL_TAG)
+                    .and_then(|tag| TTL_BUCKETS.iter().find(|(b, _)| *b == tag.value()))
+                    .map(|(_, days)| *days);
+                if days.is_some_and(|days| age >= DAY * days as u32) {
+                    expired.push(key.to_string());
+                }
+            }
+        }
+
+        let count = expired.len();
+        self.delete_objects(expired).await?;
+        Ok(count)
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let output = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(sdk_error(e)),
+        };
+
+        let expires_at = output
+            .metadata()
+            .and_then(|metadata| metadata.get(EXPIRES_AT))
+            .and_then(|v| v.parse::<u64>().ok());
+        if expires_at.is_some_and(|at| at <= unix_secs(SystemTime::now())) {
+            return Ok(None);
+        }
+
+        Ok(Some(output.body.collect().await?.into_bytes().to_vec()))
+    }
+
+    async fn put_object(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(value.to_vec()));
+        if let Some(ttl) = ttl {
+            request = request.metadata(EXPIRES_AT, unix_secs(SystemTime::now() + ttl).to_string());
+            if let Some((bucket, _)) = ttl_bucket(ttl) {
+                request = request.tagging(format!("{}={}", TTL_TAG, bucket));
+            }
+        }
+        request.send().await.map_err(sdk_error)?;
+        Ok(())
+    }
+
+    async fn delete_objects(&self, keys: Vec<String>) -> Result<()> {
+        delete_objects(&self.client, &self.bucket, &keys).await
+    }
+}
+
+#[async_trait]
+impl ImageCache for S3Cache {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get_object(&self.object_key(key)).await
+    }
+
+    async fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    #[tracing::instrument(skip(self, value))]
+    async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        self.put_object(&self.object_key(key), value, ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.delete_objects(vec![self.object_key(key)]).await
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<()> {
+        self.delete_objects(keys.iter().map(|key| self.object_key(key)).collect())
+            .await
+    }
+
+    /// Kept as an object under the tag holding the key. Markers expire with
+    /// the longest TTL bucket, so ones left behind by expired entries go too.
+    async fn tag(&self, tag: &str, key: &str) -> Result<()> {
+        let (_, days) = TTL_BUCKETS[TTL_BUCKETS.len() - 1];
+        let marker = format!("{}{}", self.tag_prefix(tag), source_digest(key));
+        self.put_object(&marker, key.as_bytes(), Some(DAY * days as u32))
+            .await
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize> {
+        let mut markers = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(self.tag_prefix(tag))
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(sdk_error)?.contents() {
+                markers.extend(object.key().map(str::to_string));
+            }
+        }
+
+        let mut doomed = Vec::with_capacity(markers.len() * 2);
+        for marker in &markers {
+            if let Some(key) = self.get_object(marker).await? {
+                doomed.push(self.object_key(&String::from_utf8_lossy(&key)));
+            }
+        }
+        let count = doomed.len();
+        doomed.extend(markers);
+        self.delete_objects(doomed).await?;
+
+        Ok(count)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_bucket() {
+        assert_eq!(ttl_bucket(Duration::from_secs(60)), Some(("1d", 1)));
+        assert_eq!(ttl_bucket(DAY), Some(("1d", 1)));
+        assert_eq!(ttl_bucket(DAY + Duration::from_secs(1)), Some(("7d", 7)));
+        assert_eq!(ttl_bucket(DAY * 365), Some(("365d", 365)));
+        assert_eq!(ttl_bucket(DAY * 366), None);
+    }
+}
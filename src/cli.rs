@@ -0,0 +1,251 @@
+use clap::{Parser, ValueEnum};
+use config::Value;
+
+/// Command line flags. Anything set here takes precedence over config files
+/// and environment variables.
+#[derive(Parser, Debug, Default)]
+#[command(name = "imagor-rs", version, about)]
+pub struct Cli {
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub host: Option<String>,
+    /// HMAC secret used to sign URLs
+    #[arg(long)]
+    pub secret: Option<String>,
+    /// Accept unsigned `/unsafe/...` paths
+    #[arg(long = "unsafe", num_args = 0..=1, default_missing_value = "true")]
+    pub allow_unsafe: Option<bool>,
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Hosts the HTTP loader may fetch from, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub allowed_sources: Vec<String>,
+
+    #[arg(long, value_enum)]
+    pub storage: Option<StorageKind>,
+    #[arg(long)]
+    pub storage_path_prefix: Option<String>,
+    #[arg(long)]
+    pub s3_bucket: Option<String>,
+    #[arg(long)]
+    pub s3_region: Option<String>,
+    #[arg(long)]
+    pub s3_endpoint: Option<String>,
+    #[arg(long)]
+    pub s3_access_key: Option<String>,
+    #[arg(long)]
+    pub s3_secret_key: Option<String>,
+    #[arg(long)]
+    pub gcs_bucket: Option<String>,
+    #[arg(long)]
+    pub gcs_credentials: Option<String>,
+    #[arg(long)]
+    pub file_base_dir: Option<String>,
+
+    /// Set any configuration key, e.g. `--set processor.max_width=4000`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub set: Vec<(String, String)>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    pub dump_config: bool,
+    /// Run as a render worker for `processor.isolation`, taking jobs on
+    /// stdin
+    #[arg(long, hide = true)]
+    pub render_worker: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+    S3,
+    Gcs,
+    Filesystem,
+}
+
+impl StorageKind {
+    fn client(&self) -> &'static str {
+        match self {
+            StorageKind::S3 => "S3",
+            StorageKind::Gcs => "GCS",
+            StorageKind::Filesystem => "Filesystem",
+        }
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.trim().to_string(), v.to_string()))
+        .filter(|(k, _)| !k.is_empty())
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))
+}
+
+impl Cli {
+    /// Storage backend chosen explicitly or implied by a backend specific flag
+    fn storage_kind(&self) -> Option<StorageKind> {
+        self.storage.or(if self.s3_bucket.is_some() {
+            Some(StorageKind::S3)
+        } else if self.gcs_bucket.is_some() {
+            Some(StorageKind::Gcs)
+        } else if self.file_base_dir.is_some() {
+            Some(StorageKind::Filesystem)
+        } else {
+            None
+        })
+    }
+
+    /// Settings keys and values for every flag that was given
+    pub fn overrides(&self) -> Vec<(String, Value)> {
+        let origin = String::from("command line");
+        let mut overrides: Vec<(String, Value)> = Vec::new();
+        let mut set = |key: &str, value: Value| overrides.push((key.to_string(), value));
+
+        if let Some(port) = self.port {
+            set("application.port", Value::new(Some(&origin), port as u64));
+        }
+        for (key, value) in [
+            ("application.host", &self.host),
+            ("application.hmac_secret", &self.secret),
+            ("application.log_level", &self.log_level),
+            ("storage.path_prefix", &self.storage_path_prefix),
+        ] {
+            if let Some(value) = value {
+                set(key, Value::new(Some(&origin), value.clone()));
+            }
+        }
+        if let Some(allow_unsafe) = self.allow_unsafe {
+            set(
+                "application.allow_unsafe",
+                Value::new(Some(&origin), allow_unsafe),
+            );
+        }
+        if !self.allowed_sources.is_empty() {
+            set(
+                "loader.allowed_sources",
+                Value::new(Some(&origin), self.allowed_sources.clone()),
+            );
+        }
+
+        if let Some(kind) = self.storage_kind() {
+            let fields = match kind {
+                StorageKind::S3 => vec![
+                    ("bucket", &self.s3_bucket),
+                    ("region", &self.s3_region),
+                    ("endpoint", &self.s3_endpoint),
+                    ("access_key", &self.s3_access_key),
+                    ("secret_key", &self.s3_secret_key),
+                ],
+                StorageKind::Gcs => vec![
+                    ("bucket", &self.gcs_bucket),
+                    ("credentials", &self.gcs_credentials),
+                ],
+                StorageKind::Filesystem => vec![("base_dir", &self.file_base_dir)],
+            };
+            let fields: Vec<_> = fields
+                .into_iter()
+                .filter_map(|(field, value)| value.as_ref().map(|v| (field, v)))
+                .collect();
+
+            let client = format!("storage.client.{}", kind.client());
+            if fields.is_empty() {
+                // select the backend, leaving its fields to other sources
+                set(
+                    &client,
+                    Value::new(Some(&origin), config::Map::<String, Value>::new()),
+                );
+            }
+            for (field, value) in fields {
+                set(
+                    &format!("{}.{}", client, field),
+                    Value::new(Some(&origin), value.clone()),
+                );
+            }
+        }
+
+        for (key, value) in &self.set {
+            set(key, Value::new(Some(&origin), value.clone()));
+        }
+
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Settings, StorageClient};
+
+    fn settings(args: &[&str]) -> Settings {
+        let cli =
+            Cli::try_parse_from(std::iter::once("imagor-rs").chain(args.iter().copied())).unwrap();
+
+        cli.overrides()
+            .into_iter()
+            .fold(config::Config::builder(), |builder, (key, value)| {
+                builder.set_override(key, value).unwrap()
+            })
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cli_overrides() {
+        let settings = settings(&[
+            "--port",
+            "9000",
+            "--storage",
+            "s3",
+            "--s3-bucket",
+            "imgs",
+            "--s3-region",
+            "eu-west-1",
+            "--s3-access-key",
+            "key",
+            "--s3-secret-key",
+            "secret",
+            "--unsafe",
+            "--allowed-sources",
+            "example.com,*.example.org",
+            "--set",
+            "processor.max_width=4000",
+        ]);
+
+        assert_eq!(settings.application.port, 9000);
+        assert!(settings.application.allow_unsafe);
+        assert_eq!(
+            settings.loader.allowed_sources,
+            vec!["example.com", "*.example.org"]
+        );
+        assert_eq!(settings.processor.max_width, 4000);
+        let StorageClient::S3(s3) = settings.storage.client else {
+            panic!("expected S3 storage");
+        };
+        assert_eq!(s3.bucket, "imgs");
+        assert_eq!(s3.region, "eu-west-1");
+    }
+
+    #[test]
+    fn test_cli_unsafe_false() {
+        let settings = settings(&["--unsafe=false"]);
+        assert!(!settings.application.allow_unsafe);
+    }
+
+    #[test]
+    fn test_cli_storage_without_fields() {
+        let settings = settings(&["--storage", "filesystem"]);
+        let StorageClient::Filesystem(fs) = settings.storage.client else {
+            panic!("expected filesystem storage");
+        };
+        assert_eq!(fs.base_dir, "uploads");
+    }
+
+    #[test]
+    fn test_cli_rejects_bad_set() {
+        assert!(Cli::try_parse_from(["imagor-rs", "--set", "novalue"]).is_err());
+    }
+}
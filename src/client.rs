@@ -0,0 +1,148 @@
+use crate::imagorpath::filter::Filter;
+use crate::imagorpath::generate::{to_signed_string, to_unsafe_string, Signer};
+use crate::imagorpath::params::{Fit, HAlign, Params, VAlign};
+use crate::imagorpath::type_utils::F32;
+
+/// Fluent builder for imagor URLs, for programs embedding the crate.
+#[derive(Debug, Default)]
+pub struct UrlBuilder {
+    base_url: Option<String>,
+    params: Params,
+}
+
+impl UrlBuilder {
+    pub fn new(image: impl Into<String>) -> Self {
+        UrlBuilder {
+            base_url: None,
+            params: Params {
+                image: Some(image.into()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Prefix generated paths with the address of the imagor server.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn resize(mut self, width: i32, height: i32) -> Self {
+        self.params.width = Some(width);
+        self.params.height = Some(height);
+        self
+    }
+
+    pub fn fit_in(mut self) -> Self {
+        self.params.fit = Some(Fit::FitIn);
+        self
+    }
+
+    pub fn stretch(mut self) -> Self {
+        self.params.fit = Some(Fit::Stretch);
+        self
+    }
+
+    pub fn crop(mut self, left: f32, top: f32, right: f32, bottom: f32) -> Self {
+        self.params.crop_left = Some(F32(left));
+        self.params.crop_top = Some(F32(top));
+        self.params.crop_right = Some(F32(right));
+        self.params.crop_bottom = Some(F32(bottom));
+        self
+    }
+
+    pub fn h_flip(mut self) -> Self {
+        self.params.h_flip = true;
+        self
+    }
+
+    pub fn v_flip(mut self) -> Self {
+        self.params.v_flip = true;
+        self
+    }
+
+    pub fn h_align(mut self, h_align: HAlign) -> Self {
+        self.params.h_align = Some(h_align);
+        self
+    }
+
+    pub fn v_align(mut self, v_align: VAlign) -> Self {
+        self.params.v_align = Some(v_align);
+        self
+    }
+
+    pub fn smart(mut self) -> Self {
+        self.params.smart = true;
+        self
+    }
+
+    pub fn meta(mut self) -> Self {
+        self.params.meta = true;
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.params.filters.push(filter);
+        self
+    }
+
+    pub fn filters(mut self, filters: impl IntoIterator<Item = Filter>) -> Self {
+        self.params.filters.extend(filters);
+        self
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn into_params(self) -> Params {
+        self.params
+    }
+
+    pub fn to_unsafe_url(&self) -> String {
+        self.with_base_url(to_unsafe_string(&self.params))
+    }
+
+    pub fn sign<S: Signer>(&self, signer: S) -> String {
+        self.with_base_url(to_signed_string(&self.params, signer))
+    }
+
+    fn with_base_url(&self, path: String) -> String {
+        match &self.base_url {
+            Some(base_url) => format!("{}/{}", base_url.trim_end_matches('/'), path),
+            None => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::signer::HmacSigner;
+    use secrecy::SecretString;
+
+    #[test]
+    fn test_url_builder_sign() {
+        let signer = HmacSigner::new(SecretString::from("mysecret".to_string()));
+        let url =
+            UrlBuilder::new("raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png")
+                .resize(500, 500)
+                .v_align(VAlign::Top)
+                .sign(signer);
+
+        assert_eq!(
+            url,
+            "cST4Ko5_FqwT3BDn-Wf4gO3RFSk=/500x500/top/raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png"
+        );
+    }
+
+    #[test]
+    fn test_url_builder_base_url() {
+        let url = UrlBuilder::new("img.png")
+            .base_url("http://localhost:8080/")
+            .resize(10, 20)
+            .to_unsafe_url();
+
+        assert_eq!(url, "http://localhost:8080/unsafe/10x20/img.png");
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::{DimensionMode, DimensionSettings};
+use crate::imagorpath::params::Params;
+use color_eyre::{eyre::eyre, Result};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Ratios this close count as the same, so `1920x1080` is 16:9
+const RATIO_TOLERANCE: f64 = 0.01;
+
+#[derive(Debug, Error, PartialEq)]
+#[error("{width}x{height} isn't an allowed size")]
+pub struct SizeNotAllowed {
+    pub width: i32,
+    pub height: i32,
+}
+
+/// One side of an allowed size
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Any,
+    Exact(i32),
+    Range(i32, i32),
+}
+
+impl Side {
+    fn parse(side: &str) -> Option<Self> {
+        let side = side.trim();
+        if side == "*" {
+            return Some(Side::Any);
+        }
+        match side.split_once('-') {
+            Some((min, max)) => {
+                let (min, max) = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+                (min <= max).then_some(Side::Range(min, max))
+            }
+            None => side.parse().ok().map(Side::Exact),
+        }
+    }
+
+    fn matches(&self, value: i32) -> bool {
+        self.nearest(value) == value
+    }
+
+    fn nearest(&self, value: i32) -> i32 {
+        match *self {
+            Side::Any => value,
+            Side::Exact(exact) => exact,
+            Side::Range(min, max) => value.clamp(min, max),
+        }
+    }
+}
+
+/// The output sizes and aspect ratios a deployment serves, so arbitrary
+/// widths and heights can't be used to fill result storage and the cache
+#[derive(Debug, Clone, Default)]
+pub struct AllowedDimensions {
+    /// Empty allows any size
+    sizes: Arc<Vec<(Side, Side)>>,
+    /// Width over height, empty allows any ratio
+    ratios: Arc<Vec<f64>>,
+    mode: DimensionMode,
+}
+
+impl AllowedDimensions {
+    pub fn new(settings: &DimensionSettings) -> Result<Self> {
+        let sizes = settings
+            .sizes
+            .iter()
+            .map(|size| {
+                size.split_once('x')
+                    .and_then(|(w, h)| Some((Side::parse(w)?, Side::parse(h)?)))
+                    .ok_or_else(|| eyre!("Invalid allowed size {:?}", size))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ratios = settings
+            .aspect_ratios
+            .iter()
+            .map(|ratio| {
+                let side = |side: &str| side.trim().parse::<f64>().ok();
+                ratio
+                    .split_once(':')
+                    .and_then(|(w, h)| Some((side(w)?, side(h)?)))
+                    .filter(|(w, h)| *w > 0.0 && *h > 0.0)
+                    .map(|(w, h)| w / h)
+                    .ok_or_else(|| eyre!("Invalid allowed aspect ratio {:?}", ratio))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(AllowedDimensions {
+            sizes: Arc::new(sizes),
+            ratios: Arc::new(ratios),
+            mode: settings.mode,
+        })
+    }
+
+    /// `params` when their size is allowed. Otherwise, they're refused or
+    /// snapped to the nearest allowed size, the size first and then the
+    /// height to the nearest ratio.
+    pub fn apply(&self, params: Params) -> Result<Params, SizeNotAllowed> {
+        let (width, height) = (params.width.unwrap_or(0), params.height.unwrap_or(0));
+        let (w, h) = self.nearest_size(width, height);
+        let (w, h) = self.nearest_ratio(w, h);
+        if (w, h) == (width, height) {
+            return Ok(params);
+        }
+
+        match self.mode {
+            DimensionMode::Reject => Err(SizeNotAllowed { width, height }),
+            DimensionMode::Snap => {
+                metrics::counter!("dimensions_snapped_total").increment(1);
+                Ok(Params {
+                    width: Some(w),
+                    height: Some(h),
+                    path: None,
+                    ..params
+                })
+            }
+        }
+    }
+
+    fn nearest_size(&self, width: i32, height: i32) -> (i32, i32) {
+        if self.sizes.iter().any(|(w, h)| w.matches(width) && h.matches(height)) {
+            return (width, height);
+        }
+
+        self.sizes
+            .iter()
+            .map(|(w, h)| (w.nearest(width), h.nearest(height)))
+            .min_by_key(|(w, h)| (w - width).abs() + (h - height).abs())
+            .unwrap_or((width, height))
+    }
+
+    /// Only sizes with both sides set have a ratio, `0` keeps the source's
+    fn nearest_ratio(&self, width: i32, height: i32) -> (i32, i32) {
+        if width <= 0 || height <= 0 || self.ratios.is_empty() {
+            return (width, height);
+        }
+
+        let ratio = width as f64 / height as f64;
+        let close = |allowed: &f64| (allowed - ratio).abs() / allowed;
+        if self.ratios.iter().any(|allowed| close(allowed) <= RATIO_TOLERANCE) {
+            return (width, height);
+        }
+
+        let nearest = self
+            .ratios
+            .iter()
+            .min_by(|a, b| close(a).total_cmp(&close(b)))
+            .copied()
+            .unwrap_or(ratio);
+        (width, ((width as f64 / nearest).round() as i32).max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(sizes: &[&str], ratios: &[&str], mode: DimensionMode) -> AllowedDimensions {
+        AllowedDimensions::new(&DimensionSettings {
+            sizes: sizes.iter().map(|s| s.to_string()).collect(),
+            aspect_ratios: ratios.iter().map(|s| s.to_string()).collect(),
+            mode,
+        })
+        .unwrap()
+    }
+
+    fn sized(width: i32, height: i32) -> Params {
+        Params {
+            width: Some(width),
+            height: Some(height),
+            ..Default::default()
+        }
+    }
+
+    fn size(params: Params) -> (i32, i32) {
+        (params.width.unwrap(), params.height.unwrap())
+    }
+
+    #[test]
+    fn test_reject() {
+        let allowed = allowed(&["300x200", "100-1600x0"], &[], DimensionMode::Reject);
+
+        assert!(allowed.apply(sized(300, 200)).is_ok());
+        assert!(allowed.apply(sized(800, 0)).is_ok());
+        assert_eq!(
+            allowed.apply(sized(301, 200)),
+            Err(SizeNotAllowed {
+                width: 301,
+                height: 200
+            })
+        );
+        assert!(allowed.apply(sized(2000, 0)).is_err());
+        assert!(allowed.apply(Params::default()).is_err());
+    }
+
+    #[test]
+    fn test_snap() {
+        let allowed = allowed(&["300x200", "100-1600x0"], &[], DimensionMode::Snap);
+
+        assert_eq!(size(allowed.apply(sized(310, 190)).unwrap()), (300, 200));
+        assert_eq!(size(allowed.apply(sized(2000, 0)).unwrap()), (1600, 0));
+        assert_eq!(size(allowed.apply(sized(50, 0)).unwrap()), (100, 0));
+    }
+
+    #[test]
+    fn test_aspect_ratios() {
+        let reject = allowed(&[], &["16:9", "1:1"], DimensionMode::Reject);
+        assert!(reject.apply(sized(1920, 1080)).is_ok());
+        assert!(reject.apply(sized(500, 500)).is_ok());
+        assert!(reject.apply(sized(500, 0)).is_ok());
+        assert!(reject.apply(sized(500, 300)).is_err());
+
+        let snap = allowed(&[], &["16:9", "1:1"], DimensionMode::Snap);
+        assert_eq!(size(snap.apply(sized(1600, 1000)).unwrap()), (1600, 900));
+        assert_eq!(size(snap.apply(sized(500, 480)).unwrap()), (500, 500));
+    }
+
+    #[test]
+    fn test_any_size_when_unset() {
+        let allowed = AllowedDimensions::default();
+        assert_eq!(size(allowed.apply(sized(123, 456)).unwrap()), (123, 456));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let settings = |sizes: &[&str], ratios: &[&str]| DimensionSettings {
+            sizes: sizes.iter().map(|s| s.to_string()).collect(),
+            aspect_ratios: ratios.iter().map(|s| s.to_string()).collect(),
+            mode: DimensionMode::Reject,
+        };
+        assert!(AllowedDimensions::new(&settings(&["300"], &[])).is_err());
+        assert!(AllowedDimensions::new(&settings(&["300-100x0"], &[])).is_err());
+        assert!(AllowedDimensions::new(&settings(&[], &["16/9"])).is_err());
+        assert!(AllowedDimensions::new(&settings(&[], &["0:1"])).is_err());
+    }
+}
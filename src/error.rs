@@ -0,0 +1,275 @@
+use crate::allowlist::SourceNotAllowed;
+use crate::imagorpath::error::ParseError;
+use axum::extract::multipart::{MultipartError, MultipartRejection};
+use axum::extract::rejection::JsonRejection;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use thiserror::Error;
+
+tokio::task_local! {
+    /// The ID of the request being handled, set by
+    /// [`request_id`](crate::middleware::request_id)
+    pub static REQUEST_ID: String;
+}
+
+/// Why a request failed. Answered as an RFC 7807 `application/problem+json`
+/// document, with the request's ID so it can be found in the logs.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// An imagor path that doesn't parse
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    /// Parsed, but not something that can be served, like a path without an
+    /// image or an unknown preset
+    #[error("{detail}")]
+    Request { status: StatusCode, detail: String },
+    /// Missing credentials, a bad signature or no permission
+    #[error("{detail}")]
+    Auth { status: StatusCode, detail: String },
+    /// A source on a host the loader may not fetch from
+    #[error(transparent)]
+    SourceNotAllowed(#[from] SourceNotAllowed),
+    /// The source or an asset couldn't be fetched
+    #[error("{detail}")]
+    Upstream { status: StatusCode, detail: String },
+    /// The source was fetched but couldn't be processed
+    #[error("{detail}")]
+    Processing { status: StatusCode, detail: String },
+    #[error("Too many requests")]
+    RateLimited { retry_after: Duration },
+    /// Anything else that went wrong on this side
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn bad_request(detail: impl Into<String>) -> Self {
+        ApiError::Request {
+            status: StatusCode::BAD_REQUEST,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        ApiError::Request {
+            status: StatusCode::NOT_FOUND,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn unauthorized(detail: impl Into<String>) -> Self {
+        ApiError::Auth {
+            status: StatusCode::UNAUTHORIZED,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn forbidden(detail: impl Into<String>) -> Self {
+        ApiError::Auth {
+            status: StatusCode::FORBIDDEN,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn internal(detail: impl Into<String>) -> Self {
+        ApiError::Internal(detail.into())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Parse(_) | ApiError::SourceNotAllowed(_) => StatusCode::BAD_REQUEST,
+            ApiError::Request { status, .. }
+            | ApiError::Auth { status, .. }
+            | ApiError::Upstream { status, .. }
+            | ApiError::Processing { status, .. } => *status,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The problem type, the same for every error of a kind
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Parse(_) => "parse-error",
+            ApiError::Request { .. } => "invalid-request",
+            ApiError::Auth { .. } => "unauthorized",
+            ApiError::SourceNotAllowed(_) => "source-not-allowed",
+            ApiError::Upstream { .. } => "upstream-error",
+            ApiError::Processing { .. } => "processing-error",
+            ApiError::RateLimited { .. } => "rate-limited",
+            ApiError::Internal(_) => "internal-error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::Parse(_) => "Invalid image path",
+            ApiError::Request { .. } => "Invalid request",
+            ApiError::Auth { .. } => "Not authorized",
+            ApiError::SourceNotAllowed(_) => "Source not allowed",
+            ApiError::Upstream { .. } => "Source unavailable",
+            ApiError::Processing { .. } => "Image processing failed",
+            ApiError::RateLimited { .. } => "Too many requests",
+            ApiError::Internal(_) => "Internal server error",
+        }
+    }
+
+    /// Members beyond the standard ones, for errors that say more
+    fn extensions(&self) -> Option<Value> {
+        match self {
+            ApiError::Parse(e) => serde_json::to_value(e).ok(),
+            ApiError::SourceNotAllowed(e) => Some(json!({ "source": e.url, "host": e.host })),
+            _ => None,
+        }
+    }
+}
+
+impl From<axum::http::Error> for ApiError {
+    fn from(e: axum::http::Error) -> Self {
+        ApiError::Internal(format!("Failed to build response: {}", e))
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> Self {
+        ApiError::Request {
+            status: e.status(),
+            detail: e.body_text(),
+        }
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(e: MultipartRejection) -> Self {
+        ApiError::Request {
+            status: e.status(),
+            detail: e.body_text(),
+        }
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(e: MultipartError) -> Self {
+        ApiError::Request {
+            status: e.status(),
+            detail: e.body_text(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    extensions: Option<Value>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let problem = Problem {
+            kind: format!("urn:imagor-rs:problem:{}", self.kind()),
+            title: self.title(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            request_id: REQUEST_ID.try_with(String::clone).ok(),
+            extensions: self.extensions(),
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        match self {
+            ApiError::RateLimited { retry_after } => {
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+            }
+            _ if status == StatusCode::UNAUTHORIZED => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    async fn problem(error: ApiError) -> (Response, Value) {
+        let response = REQUEST_ID
+            .scope("abc123".to_string(), async { error.into_response() })
+            .await;
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (
+            Response::from_parts(parts, Default::default()),
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_problem_json() {
+        let (response, body) = problem(ApiError::Processing {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            detail: "Image is too large".to_string(),
+        })
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(
+            body,
+            json!({
+                "type": "urn:imagor-rs:problem:processing-error",
+                "title": "Image processing failed",
+                "status": 422,
+                "detail": "Image is too large",
+                "request_id": "abc123",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_problem_extensions() {
+        let parse = ParseError {
+            offset: 15,
+            segment: "filters:nope()".to_string(),
+            expected: "unknown filter".to_string(),
+        };
+        let (response, body) = problem(parse.into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body["type"], "urn:imagor-rs:problem:parse-error");
+        assert_eq!(body["offset"], 15);
+        assert_eq!(body["segment"], "filters:nope()");
+
+        let (response, _) = problem(ApiError::RateLimited {
+            retry_after: Duration::from_millis(1500),
+        })
+        .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        let (response, _) = problem(ApiError::unauthorized("An API key is required")).await;
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+}
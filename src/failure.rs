@@ -0,0 +1,233 @@
+use crate::breaker::CircuitOpen;
+use crate::config::ErrorStatusSettings;
+use crate::processor::image::ProcessError;
+use crate::storage::retry::StatusError;
+use crate::storage::storage::{Missing, SourceTooLarge};
+use axum::http::StatusCode;
+use color_eyre::Report;
+use infer::MatcherType;
+use std::io::ErrorKind;
+
+/// Why a source couldn't be served, each kind answered with its own
+/// configurable status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFailure {
+    /// Nothing at the source URL or storage key
+    NotFound,
+    /// The origin didn't answer in time
+    Timeout,
+    /// The origin couldn't be reached or answered with an error
+    Unreachable,
+    /// The origin's circuit breaker is open
+    CircuitOpen,
+    /// Over the source size limit
+    TooLarge,
+    /// Something other than an image
+    UnsupportedMedia,
+    /// An image that couldn't be decoded
+    Undecodable,
+}
+
+impl SourceFailure {
+    /// Classifies a loader or storage error by its causes. Errors that say
+    /// nothing more are taken for a missing source.
+    pub fn of_fetch(report: &Report) -> Self {
+        if report.downcast_ref::<Missing>().is_some() {
+            return SourceFailure::NotFound;
+        }
+
+        for cause in report.chain() {
+            if cause.is::<SourceTooLarge>() {
+                return SourceFailure::TooLarge;
+            }
+            if cause.is::<CircuitOpen>() {
+                return SourceFailure::CircuitOpen;
+            }
+            if let Some(e) = cause.downcast_ref::<StatusError>() {
+                return SourceFailure::of_status(e.status);
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return match e.status() {
+                    Some(status) => SourceFailure::of_status(status.as_u16()),
+                    None if e.is_timeout() => SourceFailure::Timeout,
+                    None => SourceFailure::Unreachable,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<google_cloud_storage::http::Error>() {
+                return match e {
+                    google_cloud_storage::http::Error::Response(response) => {
+                        SourceFailure::of_status(response.code)
+                    }
+                    google_cloud_storage::http::Error::HttpClient(e) if e.is_timeout() => {
+                        SourceFailure::Timeout
+                    }
+                    _ => SourceFailure::Unreachable,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return match e.kind() {
+                    ErrorKind::NotFound => SourceFailure::NotFound,
+                    ErrorKind::TimedOut => SourceFailure::Timeout,
+                    _ => SourceFailure::Unreachable,
+                };
+            }
+        }
+        SourceFailure::NotFound
+    }
+
+    /// Classifies an origin's error response
+    pub fn of_status(status: u16) -> Self {
+        match status {
+            404 | 410 => SourceFailure::NotFound,
+            408 | 504 => SourceFailure::Timeout,
+            _ => SourceFailure::Unreachable,
+        }
+    }
+
+    /// Processing errors that come down to the source
+    pub fn of_process(e: &ProcessError) -> Option<Self> {
+        match e {
+            ProcessError::ImageLoadError | ProcessError::Undecodable(_) => {
+                Some(SourceFailure::Undecodable)
+            }
+            ProcessError::VideoNotSupported => Some(SourceFailure::UnsupportedMedia),
+            _ => None,
+        }
+    }
+
+    pub fn status(self, settings: &ErrorStatusSettings) -> StatusCode {
+        let (configured, default) = match self {
+            SourceFailure::NotFound => (settings.not_found, StatusCode::NOT_FOUND),
+            SourceFailure::Timeout => (settings.timeout, StatusCode::GATEWAY_TIMEOUT),
+            SourceFailure::Unreachable => (settings.unreachable, StatusCode::BAD_GATEWAY),
+            SourceFailure::CircuitOpen => (settings.circuit_open, StatusCode::BAD_GATEWAY),
+            SourceFailure::TooLarge => (settings.too_large, StatusCode::PAYLOAD_TOO_LARGE),
+            SourceFailure::UnsupportedMedia => (
+                settings.unsupported_media,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            SourceFailure::Undecodable => (settings.undecodable, StatusCode::UNPROCESSABLE_ENTITY),
+        };
+        StatusCode::from_u16(configured).unwrap_or(default)
+    }
+}
+
+/// Whether fetched bytes can be an image: sniffed as one, or sniffed as
+/// nothing and not served as something else. SVG is only told apart by the
+/// origin's `Content-Type`, or as XML.
+pub fn is_image(data: &[u8], content_type: Option<&str>) -> bool {
+    if let Some(kind) = infer::get(data) {
+        return matches!(kind.matcher_type(), MatcherType::Image | MatcherType::Video)
+            || matches!(kind.mime_type(), "application/pdf" | "text/xml");
+    }
+
+    let Some(content_type) = content_type else {
+        return true;
+    };
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.is_empty()
+        || content_type.starts_with("image/")
+        || content_type.starts_with("video/")
+        || matches!(
+            content_type.as_str(),
+            "application/pdf"
+                | "application/octet-stream"
+                | "binary/octet-stream"
+                | "application/xml"
+                | "text/xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    #[test]
+    fn test_of_fetch() {
+        let report = |status| {
+            Report::new(StatusError {
+                url: "https://example.com/a.png".to_string(),
+                status,
+            })
+        };
+        assert_eq!(
+            SourceFailure::of_fetch(&report(404)),
+            SourceFailure::NotFound
+        );
+        assert_eq!(
+            SourceFailure::of_fetch(&report(503)),
+            SourceFailure::Unreachable
+        );
+        assert_eq!(
+            SourceFailure::of_fetch(&report(504)),
+            SourceFailure::Timeout
+        );
+
+        let missing = std::io::Error::new(ErrorKind::NotFound, "no such file");
+        assert_eq!(
+            SourceFailure::of_fetch(&Report::new(missing)),
+            SourceFailure::NotFound
+        );
+        let timed_out = std::io::Error::new(ErrorKind::TimedOut, "timed out");
+        assert_eq!(
+            SourceFailure::of_fetch(&Report::new(timed_out).wrap_err("fetching")),
+            SourceFailure::Timeout
+        );
+        assert_eq!(
+            SourceFailure::of_fetch(&eyre!("no such key").wrap_err(Missing)),
+            SourceFailure::NotFound
+        );
+        assert_eq!(
+            SourceFailure::of_fetch(&Report::new(CircuitOpen {
+                host: "example.com".to_string()
+            })),
+            SourceFailure::CircuitOpen
+        );
+        assert_eq!(
+            SourceFailure::of_fetch(&Report::new(SourceTooLarge { limit: 10 })),
+            SourceFailure::TooLarge
+        );
+    }
+
+    #[test]
+    fn test_configured_status() {
+        let settings = ErrorStatusSettings::default();
+        assert_eq!(
+            SourceFailure::Timeout.status(&settings),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            SourceFailure::Undecodable.status(&settings),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let settings = ErrorStatusSettings {
+            not_found: 410,
+            unreachable: 1000,
+            ..Default::default()
+        };
+        assert_eq!(SourceFailure::NotFound.status(&settings), StatusCode::GONE);
+        // not a status, the default is kept
+        assert_eq!(
+            SourceFailure::Unreachable.status(&settings),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn test_is_image() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert!(is_image(png, Some("text/html")));
+        assert!(is_image(b"%PDF-1.7", None));
+        assert!(is_image(b"<?xml version=\"1.0\"?><svg/>", None));
+        assert!(is_image(b"<svg/>", Some("image/svg+xml")));
+        assert!(is_image(b"???", None));
+        assert!(is_image(b"???", Some("application/octet-stream")));
+
+        assert!(!is_image(b"<!DOCTYPE html><html></html>", None));
+        assert!(!is_image(b"{\"error\": true}", Some("application/json")));
+        assert!(!is_image(b"PK\x03\x04\x14\0\0\0", None));
+    }
+}
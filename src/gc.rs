@@ -0,0 +1,235 @@
+use crate::config::GcSettings;
+use crate::imagorpath::hasher::is_result_key;
+use crate::storage::storage::ImageStorage;
+use color_eyre::Result;
+use futures::TryStreamExt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::{interval_at, Instant};
+use tracing::{info, warn};
+
+/// Results stat'ed at once while scanning
+const STAT_CONCURRENCY: usize = 16;
+
+/// What a collection deleted, or would have in a dry run
+#[derive(Debug, Default, PartialEq)]
+pub struct Collected {
+    pub deleted: usize,
+    pub reclaimed_bytes: u64,
+}
+
+struct Entry {
+    key: String,
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Collect garbage from result storage every `interval_secs`. Results under
+/// `trash_prefix` are left to the purge retention window, and keys that
+/// aren't results, i.e. source images, are never touched. Must be called
+/// within a tokio runtime.
+pub fn spawn(settings: &GcSettings, storage: Arc<dyn ImageStorage>, trash_prefix: String) {
+    if !settings.enabled || (settings.max_age_secs == 0 && settings.max_total_bytes == 0) {
+        return;
+    }
+
+    let settings = settings.clone();
+    let period = Duration::from_secs(settings.interval_secs.max(60));
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            match collect(storage.as_ref(), &settings, &trash_prefix).await {
+                Ok(collected) if settings.dry_run => info!(
+                    "Garbage collection would delete {} results, {} bytes",
+                    collected.deleted, collected.reclaimed_bytes
+                ),
+                Ok(collected) => {
+                    metrics::counter!("result_storage_gc_deleted_total")
+                        .increment(collected.deleted as u64);
+                    metrics::counter!("result_storage_gc_reclaimed_bytes_total")
+                        .increment(collected.reclaimed_bytes);
+                    info!(
+                        "Garbage collection deleted {} results, {} bytes",
+                        collected.deleted, collected.reclaimed_bytes
+                    );
+                }
+                Err(e) => warn!("Garbage collection failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Delete results not used for `max_age_secs`, then the least recently used
+/// until the rest fit in `max_total_bytes`. Results are aged by when they
+/// were last read where the backend keeps track, else by when they were
+/// written.
+#[tracing::instrument(skip(storage))]
+pub async fn collect(
+    storage: &dyn ImageStorage,
+    settings: &GcSettings,
+    trash_prefix: &str,
+) -> Result<Collected> {
+    let mut entries: Vec<Entry> = storage
+        .list("")
+        .try_filter(|key| {
+            futures::future::ready(!key.starts_with(trash_prefix) && is_result_key(key))
+        })
+        .map_ok(|key| async move {
+            // gone since it was listed
+            let Some(stat) = storage.stat(&key).await? else {
+                return Ok(None);
+            };
+            let last_used = stat
+                .accessed
+                .into_iter()
+                .chain(stat.modified)
+                .max()
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            Ok(Some(Entry {
+                key,
+                size: stat.size,
+                last_used,
+            }))
+        })
+        .try_buffer_unordered(STAT_CONCURRENCY)
+        .try_filter_map(|entry| futures::future::ready(Ok(entry)))
+        .try_collect()
+        .await?;
+
+    // oldest first
+    entries.sort_by_key(|entry| entry.last_used);
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(settings.max_age_secs);
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+    let mut collected = Collected::default();
+    for entry in entries {
+        let age = now.duration_since(entry.last_used).unwrap_or_default();
+        let expired = settings.max_age_secs > 0 && age > max_age;
+        let over_budget = settings.max_total_bytes > 0 && total > settings.max_total_bytes;
+        if !expired && !over_budget {
+            break;
+        }
+
+        if settings.dry_run {
+            info!("Garbage collection would delete [{}]", entry.key);
+        } else if let Err(e) = storage.delete(&entry.key).await {
+            warn!("Failed to delete result [{}]: {}", entry.key, e);
+            continue;
+        }
+        total -= entry.size;
+        collected.deleted += 1;
+        collected.reclaimed_bytes += entry.size;
+    }
+
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathutil::normalize::SafeCharsType;
+    use crate::storage::file::FileStorage;
+    use crate::storage::storage::Blob;
+    use std::fs::{File, FileTimes};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    const A: &str = "a.0123456789abcdef0123.png";
+    const D: &str = "trash/d.0123456789abcdef0123.png";
+
+    /// Results of 100 bytes each, last used 1, 2 and 3 hours ago, one in the
+    /// trash from 4 hours ago and a source from 5 hours ago
+    async fn storage(name: &str) -> FileStorage {
+        let dir =
+            std::env::temp_dir().join(format!("imagor-rs-gc-{}-{}", name, std::process::id()));
+        let storage = FileStorage::new(dir, String::new(), SafeCharsType::Default);
+        for (key, hours) in [
+            (A, 1),
+            ("b.0123456789abcdef0123.png", 2),
+            ("c.0123456789abcdef0123", 3),
+            (D, 4),
+            ("source.png", 5),
+        ] {
+            storage.put(key, &Blob::new(vec![0; 100])).await.unwrap();
+            let time = SystemTime::now() - HOUR * hours;
+            File::options()
+                .write(true)
+                .open(storage.get_full_path(key))
+                .unwrap()
+                .set_times(FileTimes::new().set_accessed(time).set_modified(time))
+                .unwrap();
+        }
+        storage
+    }
+
+    async fn keys(storage: &FileStorage) -> Vec<String> {
+        let mut keys: Vec<String> = storage.list("").try_collect().await.unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn test_max_age() {
+        let storage = storage("age").await;
+        let settings = GcSettings {
+            max_age_secs: 90 * 60,
+            ..Default::default()
+        };
+
+        let collected = collect(&storage, &settings, "trash/").await.unwrap();
+        assert_eq!(
+            collected,
+            Collected {
+                deleted: 2,
+                reclaimed_bytes: 200
+            }
+        );
+        assert_eq!(keys(&storage).await, [A, "source.png", D]);
+        std::fs::remove_dir_all(&storage.base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_size_budget() {
+        let storage = storage("budget").await;
+        let settings = GcSettings {
+            max_total_bytes: 150,
+            ..Default::default()
+        };
+
+        let collected = collect(&storage, &settings, "trash/").await.unwrap();
+        assert_eq!(collected.deleted, 2);
+        assert_eq!(keys(&storage).await, [A, "source.png", D]);
+        std::fs::remove_dir_all(&storage.base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let storage = storage("dry").await;
+        let settings = GcSettings {
+            max_age_secs: 1,
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let collected = collect(&storage, &settings, "trash/").await.unwrap();
+        assert_eq!(collected.reclaimed_bytes, 300);
+        assert_eq!(keys(&storage).await.len(), 5);
+        std::fs::remove_dir_all(&storage.base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sources_are_kept() {
+        let storage = storage("sources").await;
+        let settings = GcSettings {
+            max_age_secs: 1,
+            ..Default::default()
+        };
+
+        let collected = collect(&storage, &settings, "trash/").await.unwrap();
+        assert_eq!(collected.deleted, 3);
+        assert_eq!(keys(&storage).await, ["source.png", D]);
+        std::fs::remove_dir_all(&storage.base_dir).unwrap();
+    }
+}
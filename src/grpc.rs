@@ -0,0 +1,290 @@
+use crate::auth::Auth;
+use crate::imagorpath::generate::to_signed_string;
+use crate::imagorpath::parse::parse_params;
+use crate::startup::render;
+use crate::state::AppStateDyn;
+use axum::body::{to_bytes, Body};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use serde::Serialize;
+use std::io::Cursor;
+use tokio::net::TcpListener;
+use tonic::metadata::MetadataMap;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+pub mod proto {
+    tonic::include_proto!("imagor.v1");
+}
+
+use proto::imagor_server::{Imagor, ImagorServer};
+use proto::{
+    MetaRequest, MetaResponse, ProcessRequest, ProcessResponse, SignUrlRequest, SignUrlResponse,
+};
+
+/// The HTTP image endpoints over gRPC, sharing the HTTP server's state
+#[derive(Clone)]
+pub struct ImagorService {
+    state: AppStateDyn,
+}
+
+impl ImagorService {
+    pub fn new(state: AppStateDyn) -> Self {
+        Self { state }
+    }
+
+    /// Checks the call's API key like `require_api_key` does, sent as
+    /// `authorization: Bearer <key>` or `x-api-key` metadata
+    async fn authorize(&self, metadata: &MetadataMap) -> Result<(), Status> {
+        let Some(auth) = &self.state.auth else {
+            return Ok(());
+        };
+        let headers = metadata.clone().into_headers();
+        let key = Auth::credentials(&headers)
+            .ok_or_else(|| Status::unauthenticated("An API key is required"))?;
+
+        match auth.authenticate(key).await {
+            Ok(Some(api_key)) => {
+                metrics::counter!("grpc_api_key_requests_total", "api_key" => api_key.name)
+                    .increment(1);
+                Ok(())
+            }
+            Ok(None) => Err(Status::unauthenticated("Invalid API key")),
+            Err(e) => Err(Status::unavailable(format!(
+                "Failed to check API key: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Renders `path` as `GET /{path}` would. The data is empty when the
+    /// result is to be fetched from the returned URL instead.
+    async fn render(&self, path: &str, accept: &str) -> Result<ProcessResponse, Status> {
+        let params = parse_params(path.trim_start_matches('/'))
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut headers = HeaderMap::new();
+        if let Ok(accept) = HeaderValue::from_str(accept) {
+            headers.insert(header::ACCEPT, accept);
+        }
+
+        let response = render(&self.state, &headers, params)
+            .await
+            .map_err(|e| to_status(e.status(), e.to_string()))?;
+        // owned, the body isn't `Sync` so nothing may borrow the response
+        // across the await below
+        let (location, content_type) = {
+            let headers = response.headers();
+            (
+                header_string(headers, header::LOCATION),
+                header_string(headers, header::CONTENT_TYPE),
+            )
+        };
+        if response.status() == StatusCode::FOUND {
+            return Ok(ProcessResponse {
+                redirect_url: location,
+                ..Default::default()
+            });
+        }
+
+        let data = body_bytes(response.into_body()).await?;
+        Ok(ProcessResponse {
+            data,
+            content_type,
+            redirect_url: String::new(),
+        })
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[tonic::async_trait]
+impl Imagor for ImagorService {
+    #[tracing::instrument(skip_all)]
+    async fn process(
+        &self,
+        request: Request<ProcessRequest>,
+    ) -> Result<Response<ProcessResponse>, Status> {
+        self.authorize(request.metadata()).await?;
+        let request = request.into_inner();
+        info!("gRPC process: {}", request.path);
+
+        let response = self.render(&request.path, &request.accept).await?;
+        Ok(Response::new(response))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_meta(
+        &self,
+        request: Request<MetaRequest>,
+    ) -> Result<Response<MetaResponse>, Status> {
+        self.authorize(request.metadata()).await?;
+        let request = request.into_inner();
+        info!("gRPC meta: {}", request.path);
+
+        let response = self.render(&request.path, "").await?;
+        let data = if response.redirect_url.is_empty() {
+            response.data
+        } else {
+            fetch_redirect(&response.redirect_url).await?
+        };
+        let meta = Meta::new(&data, response.content_type);
+        let json = serde_json::to_string(&meta)
+            .map_err(|e| Status::internal(format!("Failed to encode metadata: {}", e)))?;
+
+        Ok(Response::new(MetaResponse { json }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn sign_url(
+        &self,
+        request: Request<SignUrlRequest>,
+    ) -> Result<Response<SignUrlResponse>, Status> {
+        // an admin call, so refused outright without keys to check
+        if self.state.auth.is_none() {
+            return Err(Status::permission_denied("Signing needs `auth.enabled`"));
+        }
+        self.authorize(request.metadata()).await?;
+        let request = request.into_inner();
+        let params = parse_params(request.path.trim_start_matches('/'))
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if params.image.is_none() {
+            return Err(Status::invalid_argument("Image parameter is missing"));
+        }
+
+        let path = to_signed_string(&params, self.state.signer.clone());
+        Ok(Response::new(SignUrlResponse { path }))
+    }
+}
+
+/// What `GetMeta` reports about a processed image
+#[derive(Serialize, Debug, PartialEq)]
+struct Meta {
+    content_type: String,
+    bytes: usize,
+    /// `None` for data outputs like `blurhash()`
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl Meta {
+    fn new(data: &[u8], content_type: String) -> Self {
+        let dimensions = image::ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+
+        Meta {
+            content_type,
+            bytes: data.len(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+        }
+    }
+}
+
+async fn body_bytes(body: Body) -> Result<Vec<u8>, Status> {
+    to_bytes(body, usize::MAX)
+        .await
+        .map(|bytes| bytes.to_vec())
+        .map_err(|e| Status::internal(format!("Failed to read image: {}", e)))
+}
+
+async fn fetch_redirect(url: &str) -> Result<Vec<u8>, Status> {
+    let response = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Status::unavailable(format!("Failed to fetch result image: {}", e)))?;
+    let data = response
+        .bytes()
+        .await
+        .map_err(|e| Status::unavailable(format!("Failed to fetch result image: {}", e)))?;
+
+    Ok(data.to_vec())
+}
+
+/// The gRPC status for an HTTP error from the image endpoints
+fn to_status(status: StatusCode, message: String) -> Status {
+    match status {
+        StatusCode::BAD_REQUEST
+        | StatusCode::UNSUPPORTED_MEDIA_TYPE
+        | StatusCode::UNPROCESSABLE_ENTITY => Status::invalid_argument(message),
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => {
+            Status::deadline_exceeded(message)
+        }
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => {
+            Status::resource_exhausted(message)
+        }
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Serves gRPC on `host:port` until the process exits
+pub async fn spawn(host: &str, port: u16, state: AppStateDyn) -> Result<()> {
+    let address = format!("{}:{}", host, port);
+    let listener = TcpListener::bind(&address)
+        .await
+        .wrap_err("Failed to bind the gRPC port")?;
+    info!("gRPC server started at {}", listener.local_addr()?);
+    let incoming = TcpIncoming::from_listener(listener, true, None)
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to listen for gRPC: {}", e))?;
+
+    let service = ImagorServer::new(ImagorService::new(state));
+    tokio::spawn(async move {
+        if let Err(e) = Server::builder()
+            .add_service(service)
+            .serve_with_incoming(incoming)
+            .await
+        {
+            warn!("gRPC server failed: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn test_to_status() {
+        let code = |status| to_status(status, String::new()).code();
+        assert_eq!(code(StatusCode::BAD_REQUEST), Code::InvalidArgument);
+        assert_eq!(code(StatusCode::FORBIDDEN), Code::PermissionDenied);
+        assert_eq!(code(StatusCode::NOT_FOUND), Code::NotFound);
+        assert_eq!(code(StatusCode::REQUEST_TIMEOUT), Code::DeadlineExceeded);
+        assert_eq!(code(StatusCode::PAYLOAD_TOO_LARGE), Code::ResourceExhausted);
+        assert_eq!(code(StatusCode::BAD_GATEWAY), Code::Unavailable);
+        assert_eq!(code(StatusCode::INTERNAL_SERVER_ERROR), Code::Internal);
+    }
+
+    #[test]
+    fn test_meta() {
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let meta = Meta::new(&png, "image/png".to_string());
+        assert_eq!(meta.width, Some(3));
+        assert_eq!(meta.height, Some(2));
+        assert_eq!(meta.bytes, png.len());
+
+        let meta = Meta::new(b"LEHV6nWB2yk8", "text/plain".to_string());
+        assert_eq!(meta.width, None);
+    }
+}
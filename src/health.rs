@@ -0,0 +1,136 @@
+use crate::state::AppStateDyn;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use color_eyre::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// Serving, but an optional dependency is failing
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    required: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let failing = checks.values().filter(|c| c.status != Status::Ok);
+        let status = failing.fold(Status::Ok, |status, check| {
+            if check.required {
+                Status::Unavailable
+            } else if status == Status::Ok {
+                Status::Degraded
+            } else {
+                status
+            }
+        });
+
+        Readiness { status, checks }
+    }
+}
+
+async fn check(required: bool, f: impl Future<Output = Result<()>>) -> Check {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, f).await {
+        Ok(result) => result,
+        Err(_) => Err(color_eyre::eyre::eyre!(
+            "timed out after {:?}",
+            CHECK_TIMEOUT
+        )),
+    };
+
+    Check {
+        status: if result.is_ok() {
+            Status::Ok
+        } else {
+            Status::Unavailable
+        },
+        latency_ms: start.elapsed().as_millis(),
+        error: result.err().map(|e| e.to_string()),
+        required,
+    }
+}
+
+/// Whether the process is running at all. Never touches dependencies, so a
+/// slow backend can't get the pod restarted.
+#[tracing::instrument]
+pub async fn live() -> &'static str {
+    "OK"
+}
+
+/// Whether requests can be served: storage, the response cache and libvips
+/// are required, processing stats are optional
+#[tracing::instrument(skip(state))]
+pub async fn ready(State(state): State<AppStateDyn>) -> (StatusCode, Json<Readiness>) {
+    let processor = state.processor.clone();
+    let vips = async move { tokio::task::spawn_blocking(move || processor.health()).await? };
+
+    let (storage, cache, vips) = tokio::join!(
+        check(true, state.storage.ping()),
+        check(true, state.cache.ping()),
+        check(true, vips),
+    );
+    let mut checks = BTreeMap::from([("storage", storage), ("cache", cache), ("vips", vips)]);
+    if let Some(stats) = &state.stats {
+        checks.insert("stats", check(false, stats.ping()).await);
+    }
+
+    let readiness = Readiness::new(checks);
+    let status = match readiness.status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Ok | Status::Degraded => StatusCode::OK,
+    };
+
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    async fn checks(results: Vec<(&'static str, bool, bool)>) -> Readiness {
+        let mut checks = BTreeMap::new();
+        for (name, required, ok) in results {
+            let result = if ok { Ok(()) } else { Err(eyre!("down")) };
+            checks.insert(name, check(required, async { result }).await);
+        }
+        Readiness::new(checks)
+    }
+
+    #[tokio::test]
+    async fn test_readiness_status() {
+        let all_ok = checks(vec![("storage", true, true), ("stats", false, true)]).await;
+        assert_eq!(all_ok.status, Status::Ok);
+
+        let degraded = checks(vec![("storage", true, true), ("stats", false, false)]).await;
+        assert_eq!(degraded.status, Status::Degraded);
+        assert_eq!(degraded.checks["stats"].error.as_deref(), Some("down"));
+
+        let unavailable = checks(vec![("storage", true, false), ("stats", false, false)]).await;
+        assert_eq!(unavailable.status, Status::Unavailable);
+    }
+}
@@ -17,6 +17,7 @@ macro_rules! define_colors {
                 }
             }
 
+            #[allow(clippy::should_implement_trait)]
             pub fn from_str(s: &str) -> Option<Self> {
                 match s {
                     $(
@@ -30,7 +31,7 @@ macro_rules! define_colors {
         impl std::fmt::Display for NamedColor {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 match self {
-                    $(Self::$name => write!(f, "{}", stringify!($name))),*
+                    $(Self::$name => write!(f, "{}", stringify!($name).to_lowercase())),*
                 }
             }
         }
@@ -48,7 +49,8 @@ pub enum Color {
 }
 
 impl Color {
-    pub fn to_rgb(&self, img: &VipsImage) -> Option<(u8, u8, u8)> {
+    /// The color itself, for every kind that doesn't depend on the image
+    pub fn fixed_rgb(&self) -> Option<(u8, u8, u8)> {
         match self {
             Color::Named(named) => {
                 let Color::Rgb(r, g, b) = named.to_rgb() else {
@@ -70,18 +72,37 @@ impl Color {
                 }
                 None
             }
+            _ => None,
+        }
+    }
+
+    pub fn to_rgb(&self, img: &VipsImage) -> Option<(u8, u8, u8)> {
+        match self {
             Color::Auto => {
-                let point = ops::getpoint(img, 0, 0).ok().map(|p| {
-                    if p.len() >= 3 {
-                        (p[0] as u8, p[1] as u8, p[2] as u8)
-                    } else {
-                        (0, 0, 0)
-                    }
-                });
+                // the average of the corners, which is the border color of
+                // a letterboxed frame or a product on a plain background
+                let (w, h) = (img.get_width(), img.get_page_height());
+                let corners = [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)];
+                let points = corners
+                    .iter()
+                    .map(|(x, y)| ops::getpoint(img, *x, *y).ok())
+                    .collect::<Option<Vec<_>>>()?;
 
-                point
+                let mut sum = [0.0; 3];
+                for point in &points {
+                    // greyscale, with or without alpha
+                    let rgb = match point.as_slice() {
+                        [r, g, b, ..] => [*r, *g, *b],
+                        [v, ..] => [*v; 3],
+                        [] => return None,
+                    };
+                    sum.iter_mut().zip(rgb).for_each(|(s, c)| *s += c);
+                }
+                let [r, g, b] =
+                    sum.map(|s| (s / points.len() as f64).round().clamp(0.0, 255.0) as u8);
+                Some((r, g, b))
             }
-            _ => None,
+            _ => self.fixed_rgb(),
         }
     }
 }
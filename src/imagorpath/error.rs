@@ -0,0 +1,84 @@
+use crate::error::ApiError;
+use axum::response::{IntoResponse, Response};
+use nom::{
+    error::{VerboseError, VerboseErrorKind},
+    Offset,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+/// An imagor path that failed to parse
+#[derive(Serialize, Error, Debug, Clone, PartialEq, Eq)]
+#[error("{expected} at offset {offset} in `{segment}`")]
+pub struct ParseError {
+    /// Byte offset into the path where parsing stopped
+    pub offset: usize,
+    /// The `/`-separated segment containing the offset
+    pub segment: String,
+    /// Human-readable description of what the parser was looking for
+    pub expected: String,
+}
+
+impl ParseError {
+    /// `at` must be a subslice of `input`
+    pub fn new(input: &str, at: &str, expected: impl Into<String>) -> Self {
+        let offset = input.offset(at).min(input.len());
+        let start = input[..offset].rfind('/').map_or(0, |i| i + 1);
+        let end = input[offset..]
+            .find('/')
+            .map_or(input.len(), |i| offset + i);
+
+        Self {
+            offset,
+            segment: input[start..end].to_string(),
+            expected: expected.into(),
+        }
+    }
+
+    /// Reports the innermost failure, described by the closest enclosing context
+    pub fn from_verbose(input: &str, e: VerboseError<&str>) -> Self {
+        let Some((at, kind)) = e.errors.first() else {
+            return Self::new(input, input, "expected a valid path");
+        };
+
+        let expected = e
+            .errors
+            .iter()
+            .find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(context) => Some(describe_context(context)),
+                _ => None,
+            })
+            .unwrap_or_else(|| match kind {
+                VerboseErrorKind::Char(c) => format!("expected '{}'", c),
+                VerboseErrorKind::Nom(kind) => format!("expected {}", kind.description()),
+                VerboseErrorKind::Context(context) => describe_context(context),
+            });
+
+        Self::new(input, at, expected)
+    }
+}
+
+fn describe_context(context: &str) -> String {
+    match context {
+        "parse_path" => "expected a valid path",
+        "parse_unsafe" => "expected `unsafe`",
+        "parse_meta" => "expected `meta`",
+        "parse_trim" => "expected trim[:top-left|bottom-right][:tolerance]",
+        "parse_crop" => "expected AxB:CxD crop",
+        "parse_fit" => "expected `fit-in` or `stretch`",
+        "parse_dimensions" => "expected WxH dimensions",
+        "parse_padding" => "expected GxH:IxJ padding",
+        "parse_alignment" => "expected alignment",
+        "parse_smart" => "expected `smart`",
+        "parse_filters" => "expected filters:NAME(ARGS)",
+        "parse_image" => "expected image path",
+        other => other,
+    }
+    .to_string()
+}
+
+impl IntoResponse for ParseError {
+    fn into_response(self) -> Response {
+        ApiError::Parse(self).into_response()
+    }
+}
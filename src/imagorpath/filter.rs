@@ -1,111 +1,431 @@
-use crate::imagorpath::{color::Color, type_utils::F32};
+use crate::imagorpath::{color::Color, registry::FilterRegistry, type_utils::F32};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Filter {
+    /// `[a, b, c, d, tx, ty]`, moving each pixel to
+    /// `(a x + b y + tx, c x + d y + ty)`
+    Affine([F32; 6]),
+    /// Stretch the levels so the darkest and lightest tones reach black and white
+    AutoContrast,
     BackgroundColor(Color),
-    Blur(F32),
+    /// Bits per sample to save with: 8, 10, 12 or 16
+    Bitdepth(u8),
+    /// Gaussian blur of the whole image, or only a region of it
+    Blur(F32, Option<Region>),
+    /// Return a BlurHash of the result instead of the image
+    Blurhash(HashOutput),
+    /// A frame this many pixels wide around the image
+    Border(u32, Color),
     Brightness(i32),
     Contrast(i32),
+    /// Tone curves, applied in order
+    Curves(Vec<Curve>),
+    /// A filter added through the `FilterRegistry`
+    Custom(CustomFilter),
+    /// Noise reduction, 0 to 10
+    Denoise(F32),
+    /// Shadows to the first color, highlights to the second
+    Duotone(Color, Color),
+    /// Spread the lightness evenly across its histogram
+    Equalize,
     Fill(Color),
     Focal(FocalParams),
     Format(ImageType),
+    /// `format(auto)`, resolved per request from the `Accept` header
+    AutoFormat,
+    /// Sample this many evenly spaced frames (or pages), shown for delay ms each
+    Frames(usize, u32),
+    /// Above 1 brightens the midtones, below 1 darkens them
+    Gamma(F32),
     Grayscale,
     Hue(F32),
+    /// Interpolation to resize with instead of thumbnailing's own
+    Kernel(ResizeKernel),
     Label(LabelParams),
     MaxBytes(usize),
     MaxFrames(usize),
     Modulate(F32, F32, F32),
+    NoUpscale,
     Orient(i32),
     Padding(Color, PaddingParams),
+    /// Return the dominant color and an n-color palette as JSON instead of the image
+    Palette(usize),
     Page(usize),
+    /// Corners of a quad, clockwise from the top left, to straighten out
+    /// into a rectangle
+    Perspective([(F32, F32); 4]),
+    /// Mosaic the image in blocks of this many pixels
+    Pixelate(u32),
+    /// Mosaic only a region, e.g. to redact a face or a license plate
+    PixelateRegion(Region, u32),
+    /// Video frame to extract, counting from 0
+    Frame(usize),
+    /// Video timestamp in seconds to extract a frame from
+    Seek(F32),
     Dpi(u32),
+    /// Device pixel ratio, scales the requested width and height
+    Dpr(F32),
     Proportion(F32),
     Quality(u8),
+    /// Serve the source bytes untouched, ignoring every other operation
+    Raw,
     Rgb(F32, F32, F32),
     Rotate(i32),
     RoundCorner(RoundedCornerParams),
     Saturation(F32),
+    Sepia,
+    /// A drop shadow offset by x and y pixels, softened by a blur sigma
+    Shadow(i32, i32, F32, Color),
     Sharpen(F32),
     StripExif,
     StripIcc,
     StripMetadata,
+    /// Enlarge this many times with the super-resolution model
+    Superres(u32),
+    /// Return a ThumbHash of the result instead of the image
+    Thumbhash(HashOutput),
+    /// Wash over with a color, strength from 0 to 100
+    Tint(Color, F32),
     Upscale,
+    /// Unsharp mask: radius in pixels, amount as a multiple and threshold
+    /// in levels from 0 to 255
+    Usm(F32, F32, F32),
+    /// A name and value for `label()` text to fill in as `{name}`
+    Var(String, String),
+    /// Saturation boost, -100 to 100, weighted towards muted colors
+    Vibrance(i32),
     Watermark(WatermarkParams),
 }
 
 impl std::fmt::Display for Filter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Filter::Affine(matrix) => {
+                let matrix: Vec<String> = matrix.iter().map(|v| v.0.to_string()).collect();
+                write!(f, "affine({})", matrix.join(","))
+            }
+            Filter::AutoContrast => write!(f, "autocontrast()"),
             Filter::BackgroundColor(color) => write!(f, "background_color({})", color),
-            Filter::Blur(amount) => write!(f, "blur({})", amount.0),
+            Filter::Bitdepth(depth) => write!(f, "bitdepth({})", depth),
+            Filter::Blur(amount, None) => write!(f, "blur({})", amount.0),
+            Filter::Blur(amount, Some(region)) => write!(f, "blur({},{})", amount.0, region),
+            Filter::Blurhash(output) => write!(f, "blurhash({})", output),
+            Filter::Border(width, color) => write!(f, "border({},{})", width, color),
             Filter::Brightness(value) => write!(f, "brightness({})", value),
             Filter::Contrast(value) => write!(f, "contrast({})", value),
+            Filter::Curves(curves) => {
+                let curves: Vec<String> = curves.iter().map(Curve::to_string).collect();
+                write!(f, "curves({})", curves.join(";"))
+            }
+            Filter::Custom(custom) => write!(f, "{}({})", custom.name, custom.args),
+            Filter::Denoise(strength) => write!(f, "denoise({})", strength.0),
+            Filter::Duotone(dark, light) => write!(f, "duotone({},{})", dark, light),
+            Filter::Equalize => write!(f, "equalize()"),
             Filter::Fill(color) => write!(f, "fill({})", color),
             Filter::Focal(value) => write!(f, "focal({})", value),
-            Filter::Format(format) => write!(f, "format({:?})", format),
+            Filter::Format(format) => write!(f, "format({})", format),
+            Filter::AutoFormat => write!(f, "format(auto)"),
+            Filter::Frames(n, delay) => write!(f, "frames({},{})", n, delay),
+            Filter::Gamma(value) => write!(f, "gamma({})", value.0),
             Filter::Grayscale => write!(f, "grayscale()"),
             Filter::Hue(value) => write!(f, "hue({})", value),
-            Filter::Label(params) => write!(f, "label({:?})", params),
+            Filter::Kernel(kernel) => write!(f, "kernel({})", kernel),
+            Filter::Label(params) => write!(f, "label({})", params),
             Filter::MaxBytes(value) => write!(f, "max_bytes({})", value),
             Filter::MaxFrames(value) => write!(f, "max_frames({})", value),
-            Filter::Modulate(b, s, h) => write!(f, "modulate({}, {}, {})", b, s, h),
+            Filter::Modulate(b, s, h) => write!(f, "modulate({},{},{})", b, s, h),
+            Filter::NoUpscale => write!(f, "no_upscale()"),
             Filter::Orient(value) => write!(f, "orient({})", value),
             Filter::Padding(color, params) => write!(f, "padding({},{})", color, params),
+            Filter::Palette(n) => write!(f, "palette({})", n),
             Filter::Page(value) => write!(f, "page({})", value),
+            Filter::Perspective(points) => {
+                let points: Vec<String> = points
+                    .iter()
+                    .map(|(x, y)| format!("{},{}", x.0, y.0))
+                    .collect();
+                write!(f, "perspective({})", points.join(","))
+            }
+            Filter::Pixelate(size) => write!(f, "pixelate({})", size),
+            Filter::PixelateRegion(region, size) => {
+                write!(f, "pixelate_region({},{})", region, size)
+            }
+            Filter::Frame(value) => write!(f, "frame({})", value),
+            Filter::Seek(value) => write!(f, "seek({})", value.0),
             Filter::Dpi(value) => write!(f, "dpi({})", value),
+            Filter::Dpr(value) => write!(f, "dpr({})", value.0),
             Filter::Proportion(value) => write!(f, "proportion({})", value.0),
             Filter::Quality(value) => write!(f, "quality({})", value),
+            Filter::Raw => write!(f, "raw()"),
             Filter::Rgb(r, g, b) => write!(f, "rgb({},{},{})", r, g, b),
             Filter::Rotate(value) => write!(f, "rotate({})", value),
-            Filter::RoundCorner(params) => write!(f, "round_corner({:?})", params),
+            Filter::RoundCorner(params) => write!(f, "round_corner({})", params),
             Filter::Saturation(value) => write!(f, "saturation({})", value),
+            Filter::Sepia => write!(f, "sepia()"),
+            Filter::Superres(scale) => write!(f, "superres({})", scale),
+            Filter::Shadow(x, y, sigma, color) => {
+                write!(f, "shadow({},{},{},{})", x, y, sigma.0, color)
+            }
             Filter::Sharpen(value) => write!(f, "sharpen({})", value.0),
             Filter::StripExif => write!(f, "strip_exif()"),
             Filter::StripIcc => write!(f, "strip_icc()"),
             Filter::StripMetadata => write!(f, "strip_metadata()"),
+            Filter::Thumbhash(output) => write!(f, "thumbhash({})", output),
+            Filter::Tint(color, strength) => write!(f, "tint({},{})", color, strength),
             Filter::Upscale => write!(f, "upscale()"),
-            Filter::Watermark(params) => write!(f, "watermark({:?})", params),
+            Filter::Vibrance(amount) => write!(f, "vibrance({})", amount),
+            Filter::Usm(radius, amount, threshold) => {
+                write!(f, "usm({},{},{})", radius, amount, threshold)
+            }
+            Filter::Var(name, value) => write!(f, "var({},{})", name, value),
+            Filter::Watermark(params) => write!(f, "watermark({})", params),
         }
     }
 }
 
+/// Every name returned by `Filter::name`
+pub const FILTER_NAMES: &[&str] = &[
+    "affine",
+    "autocontrast",
+    "background_color",
+    "bitdepth",
+    "blur",
+    "blurhash",
+    "border",
+    "brightness",
+    "contrast",
+    "curves",
+    "denoise",
+    "duotone",
+    "equalize",
+    "fill",
+    "focal",
+    "format",
+    "frames",
+    "gamma",
+    "grayscale",
+    "hue",
+    "kernel",
+    "label",
+    "max_bytes",
+    "max_frames",
+    "modulate",
+    "no_upscale",
+    "orient",
+    "padding",
+    "palette",
+    "page",
+    "perspective",
+    "pixelate",
+    "pixelate_region",
+    "frame",
+    "seek",
+    "dpi",
+    "dpr",
+    "proportion",
+    "quality",
+    "raw",
+    "rgb",
+    "rotate",
+    "round_corner",
+    "saturation",
+    "sepia",
+    "shadow",
+    "sharpen",
+    "strip_exif",
+    "strip_icc",
+    "strip_metadata",
+    "superres",
+    "thumbhash",
+    "tint",
+    "upscale",
+    "usm",
+    "var",
+    "vibrance",
+    "watermark",
+];
+
 impl Filter {
     pub fn name(&self) -> String {
         let name = match self {
+            Filter::Affine(_) => "affine",
+            Filter::AutoContrast => "autocontrast",
             Filter::BackgroundColor(_) => "background_color",
-            Filter::Blur(_) => "blur",
+            Filter::Bitdepth(_) => "bitdepth",
+            Filter::Blur(_, _) => "blur",
+            Filter::Blurhash(_) => "blurhash",
+            Filter::Border(_, _) => "border",
             Filter::Brightness(_) => "brightness",
             Filter::Contrast(_) => "contrast",
+            Filter::Curves(_) => "curves",
+            Filter::Custom(custom) => return custom.name.clone(),
+            Filter::Denoise(_) => "denoise",
+            Filter::Duotone(_, _) => "duotone",
+            Filter::Equalize => "equalize",
             Filter::Fill(_) => "fill",
             Filter::Focal(_) => "focal",
-            Filter::Format(_) => "format",
+            Filter::Format(_) | Filter::AutoFormat => "format",
+            Filter::Frames(_, _) => "frames",
+            Filter::Gamma(_) => "gamma",
             Filter::Grayscale => "grayscale",
             Filter::Hue(_) => "hue",
+            Filter::Kernel(_) => "kernel",
             Filter::Label(_) => "label",
             Filter::MaxBytes(_) => "max_bytes",
             Filter::MaxFrames(_) => "max_frames",
             Filter::Modulate(_, _, _) => "modulate",
+            Filter::NoUpscale => "no_upscale",
             Filter::Orient(_) => "orient",
             Filter::Padding(_, _) => "padding",
+            Filter::Palette(_) => "palette",
             Filter::Page(_) => "page",
+            Filter::Perspective(_) => "perspective",
+            Filter::Pixelate(_) => "pixelate",
+            Filter::PixelateRegion(_, _) => "pixelate_region",
+            Filter::Frame(_) => "frame",
+            Filter::Seek(_) => "seek",
             Filter::Dpi(_) => "dpi",
+            Filter::Dpr(_) => "dpr",
             Filter::Proportion(_) => "proportion",
             Filter::Quality(_) => "quality",
+            Filter::Raw => "raw",
             Filter::Rgb(_, _, _) => "rgb",
             Filter::Rotate(_) => "rotate",
             Filter::RoundCorner(_) => "round_corner",
             Filter::Saturation(_) => "saturation",
+            Filter::Sepia => "sepia",
+            Filter::Superres(_) => "superres",
+            Filter::Shadow(_, _, _, _) => "shadow",
             Filter::Sharpen(_) => "sharpen",
             Filter::StripExif => "strip_exif",
             Filter::StripIcc => "strip_icc",
             Filter::StripMetadata => "strip_metadata",
+            Filter::Thumbhash(_) => "thumbhash",
+            Filter::Tint(_, _) => "tint",
             Filter::Upscale => "upscale",
+            Filter::Usm(_, _, _) => "usm",
+            Filter::Var(_, _) => "var",
+            Filter::Vibrance(_) => "vibrance",
             Filter::Watermark(_) => "watermark",
         };
 
-        return name.to_string();
+        name.to_string()
+    }
+}
+
+/// Matches filters by name the way the path parser reads them, ignoring case
+/// and underscores, so `max_frames`, `maxFrames` and `max_frames()` are equal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterMatcher(String);
+
+impl FilterMatcher {
+    pub fn new(name: &str) -> Self {
+        Self(normalize_filter_name(name))
+    }
+
+    /// Whether any filter has this name, built-in or registered
+    pub fn is_known(&self) -> bool {
+        self.is_built_in() || FilterRegistry::global().contains(&self.0)
+    }
+
+    pub fn is_built_in(&self) -> bool {
+        FILTER_NAMES
+            .iter()
+            .any(|name| normalize_filter_name(name) == self.0)
+    }
+
+    pub fn matches(&self, filter: &Filter) -> bool {
+        self.0 == normalize_filter_name(&filter.name())
+    }
+}
+
+pub(crate) fn normalize_filter_name(name: &str) -> String {
+    name.trim()
+        .trim_end_matches("()")
+        .to_lowercase()
+        .replace('_', "")
+}
+
+/// How `blurhash()` and `thumbhash()` return the hash
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashOutput {
+    #[default]
+    Text,
+    Json,
+}
+
+impl HashOutput {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            HashOutput::Text => "text/plain; charset=utf-8",
+            HashOutput::Json => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HashOutput::Text => ".txt",
+            HashOutput::Json => ".json",
+        }
+    }
+}
+
+impl std::fmt::Display for HashOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashOutput::Text => Ok(()),
+            HashOutput::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// How pixels are interpolated when resizing: `nearest` keeps pixel art
+/// crisp, `lanczos3` is thumbnailing's own and suits photos
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeKernel {
+    Nearest,
+    Linear,
+    Cubic,
+    Mitchell,
+    Lanczos2,
+    Lanczos3,
+}
+
+impl ResizeKernel {
+    pub const ALL: [ResizeKernel; 6] = [
+        ResizeKernel::Nearest,
+        ResizeKernel::Linear,
+        ResizeKernel::Cubic,
+        ResizeKernel::Mitchell,
+        ResizeKernel::Lanczos2,
+        ResizeKernel::Lanczos3,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResizeKernel::Nearest => "nearest",
+            ResizeKernel::Linear => "linear",
+            ResizeKernel::Cubic => "cubic",
+            ResizeKernel::Mitchell => "mitchell",
+            ResizeKernel::Lanczos2 => "lanczos2",
+            ResizeKernel::Lanczos3 => "lanczos3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kernel| kernel.name().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+impl std::fmt::Display for ResizeKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
     }
 }
 
@@ -128,12 +448,37 @@ pub enum ImageType {
 
 impl ImageType {
     pub fn to_content_type(&self) -> String {
-        return format!("image/{}", self.to_string().to_lowercase());
+        format!("image/{}", self.to_string().to_lowercase())
     }
 
     pub fn is_animation_supported(&self) -> bool {
         matches!(self, ImageType::GIF | ImageType::WEBP)
     }
+
+    /// The best format a client accepts: AVIF, then WebP, otherwise JPEG
+    pub fn negotiate(accept: &str) -> ImageType {
+        let accepted = |mime: &str| {
+            accept.split(',').any(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let matches = parts.next().is_some_and(|m| m.eq_ignore_ascii_case(mime));
+                // `q=0` means explicitly not acceptable
+                let refused = parts.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                matches && !refused
+            })
+        };
+
+        if accepted("image/avif") {
+            ImageType::AVIF
+        } else if accepted("image/webp") {
+            ImageType::WEBP
+        } else {
+            ImageType::JPEG
+        }
+    }
 }
 
 impl std::fmt::Display for ImageType {
@@ -198,6 +543,19 @@ pub struct WatermarkParams {
     pub h_ratio: Option<F32>,
 }
 
+impl fmt::Display for WatermarkParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.image, self.x, self.y, self.alpha)?;
+        if let Some(w_ratio) = self.w_ratio {
+            write!(f, ",{}", w_ratio)?;
+            if let Some(h_ratio) = self.h_ratio {
+                write!(f, ",{}", h_ratio)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum WatermarkPosition {
     Pixels(i32),
@@ -210,6 +568,21 @@ pub enum WatermarkPosition {
     Repeat,
 }
 
+impl fmt::Display for WatermarkPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatermarkPosition::Pixels(px) => write!(f, "{}", px),
+            WatermarkPosition::Percentage(pct) => write!(f, "{}p", pct),
+            WatermarkPosition::Left => write!(f, "left"),
+            WatermarkPosition::Right => write!(f, "right"),
+            WatermarkPosition::Center => write!(f, "center"),
+            WatermarkPosition::Top => write!(f, "top"),
+            WatermarkPosition::Bottom => write!(f, "bottom"),
+            WatermarkPosition::Repeat => write!(f, "repeat"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct RoundedCornerParams {
     pub rx: u32,
@@ -217,6 +590,120 @@ pub struct RoundedCornerParams {
     pub color: Option<Color>,
 }
 
+impl fmt::Display for RoundedCornerParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.rx)?;
+        if let Some(ry) = self.ry {
+            write!(f, ",{}", ry)?;
+        }
+        if let Some(color) = &self.color {
+            write!(f, ",{}", color)?;
+        }
+        Ok(())
+    }
+}
+
+/// A rectangle of the image as it is when filters run, by its left, top,
+/// right and bottom edges. In pixels, or fractions of the image's size when
+/// none of them is above 1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub left: F32,
+    pub top: F32,
+    pub right: F32,
+    pub bottom: F32,
+}
+
+impl Region {
+    /// Left, top, width and height in pixels, clipped to the image, or
+    /// `None` when nothing of it is left
+    pub fn area(&self, width: i32, height: i32) -> Option<(i32, i32, i32, i32)> {
+        let edges = [self.left.0, self.top.0, self.right.0, self.bottom.0];
+        let fractions = edges.iter().all(|edge| *edge <= 1.0);
+        let [left, top, right, bottom] = [
+            (edges[0], width),
+            (edges[1], height),
+            (edges[2], width),
+            (edges[3], height),
+        ]
+        .map(|(edge, size)| {
+            let edge = if fractions { edge * size as f32 } else { edge };
+            (edge.round() as i32).clamp(0, size)
+        });
+        (right > left && bottom > top).then_some((left, top, right - left, bottom - top))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.left.0, self.top.0, self.right.0, self.bottom.0
+        )
+    }
+}
+
+/// A `curves()` curve through `(input, output)` levels, for one channel or
+/// all of them. Levels 0 and 255 map to themselves unless a point says
+/// otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Curve {
+    pub channel: Option<Channel>,
+    pub points: Vec<(u8, u8)>,
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(channel) = self.channel {
+            write!(f, "{}=", channel)?;
+        }
+        let points: Vec<String> = self
+            .points
+            .iter()
+            .map(|(input, output)| format!("{}:{}", input, output))
+            .collect();
+        write!(f, "{}", points.join(","))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    R,
+    G,
+    B,
+}
+
+impl Channel {
+    /// Its band in an RGB image
+    pub fn band(&self) -> usize {
+        match self {
+            Channel::R => 0,
+            Channel::G => 1,
+            Channel::B => 2,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Channel::R => write!(f, "r"),
+            Channel::G => write!(f, "g"),
+            Channel::B => write!(f, "b"),
+        }
+    }
+}
+
+/// A registered filter's name and its arguments as its parse function
+/// returned them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CustomFilter {
+    pub name: String,
+    pub args: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct LabelParams {
     pub text: String,
@@ -226,6 +713,41 @@ pub struct LabelParams {
     pub color: Color,
     pub alpha: Option<u8>,
     pub font: Option<String>,
+    /// Space kept between the text and the image edges it's aligned to
+    pub padding: Option<u32>,
+    /// Width in pixels to wrap lines at, the image's inside its padding
+    /// when unset
+    pub wrap: Option<u32>,
+}
+
+impl fmt::Display for LabelParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.text, self.x, self.y, self.size, self.color
+        )?;
+        // optional trailing args, with empty ones standing in for those
+        // skipped before a later one
+        let options = [
+            self.alpha.map(|alpha| alpha.to_string()),
+            self.font.clone(),
+            self.padding.map(|padding| padding.to_string()),
+            self.wrap.map(|wrap| wrap.to_string()),
+        ];
+        let count = options
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1);
+        for (i, option) in options[..count].iter().enumerate() {
+            // a font is never read as an alpha, so one doesn't need a gap
+            if i == 0 && option.is_none() && self.font.is_some() {
+                continue;
+            }
+            write!(f, ",{}", option.as_deref().unwrap_or_default())?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -239,6 +761,20 @@ pub enum LabelPosition {
     Bottom,
 }
 
+impl fmt::Display for LabelPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelPosition::Pixels(px) => write!(f, "{}", px),
+            LabelPosition::Percentage(pct) => write!(f, "{}p", pct),
+            LabelPosition::Left => write!(f, "left"),
+            LabelPosition::Right => write!(f, "right"),
+            LabelPosition::Center => write!(f, "center"),
+            LabelPosition::Top => write!(f, "top"),
+            LabelPosition::Bottom => write!(f, "bottom"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum FocalParams {
     Region {
@@ -1,4 +1,4 @@
-use super::params::{Fit, HAlign, Params, TrimBy, VAlign};
+use super::params::{Fit, Params, TrimBy};
 use super::type_utils::F32;
 use core::fmt;
 use url::form_urlencoded;
@@ -18,8 +18,8 @@ pub fn generate_path(p: &Params) -> String {
         generate_fit(p),
         generate_size_and_flip(p),
         generate_padding(p),
-        generate_valign(p),
         generate_halign(p),
+        generate_valign(p),
         generate_smart(p),
         generate_filters(p),
         generate_image(p),
@@ -40,7 +40,7 @@ fn generate_meta(p: &Params) -> Option<String> {
 }
 
 fn generate_trim(p: &Params) -> Option<String> {
-    if p.trim || (p.trim_by == TrimBy::TopLeft || p.trim_by == TrimBy::BottomRight) {
+    if p.trim {
         let trims = vec![
             Some("trim".to_string()),
             if p.trim_by == TrimBy::BottomRight {
@@ -80,8 +80,12 @@ fn generate_crop(p: &Params) -> Option<String> {
 
 fn generate_fit(p: &Params) -> Option<String> {
     match p.fit {
-        Some(Fit::FitIn) => Some("fit-in".to_string()),
-        Some(Fit::Stretch) => Some("fill".to_string()),
+        Some(Fit::FitIn) => Some(format!(
+            "{}{}fit-in",
+            if p.adaptive_fit_in { "adaptive-" } else { "" },
+            if p.full_fit_in { "full-" } else { "" },
+        )),
+        Some(Fit::Stretch) => Some("stretch".to_string()),
         _ => None,
     }
 }
@@ -94,21 +98,15 @@ fn generate_size_and_flip(p: &Params) -> Option<String> {
         || p.padding_left.is_some()
         || p.padding_top.is_some()
     {
-        let width = p.width.unwrap_or(0);
-        let height = p.height.unwrap_or(0);
-        let h_flip = p.h_flip ^ (width < 0);
-        let v_flip = p.v_flip ^ (height < 0);
+        let h_flip = p.h_flip ^ p.width.is_some_and(|w| w < 0);
+        let v_flip = p.v_flip ^ p.height.is_some_and(|h| h < 0);
 
         let h_flip_str = if h_flip { "-" } else { "" };
         let v_flip_str = if v_flip { "-" } else { "" };
+        let width = p.width.map(|w| w.abs().to_string()).unwrap_or_default();
+        let height = p.height.map(|h| h.abs().to_string()).unwrap_or_default();
 
-        Some(format!(
-            "{}{}{}{}",
-            h_flip_str,
-            width.abs(),
-            v_flip_str,
-            height.abs()
-        ))
+        Some(format!("{}{}x{}{}", h_flip_str, width, v_flip_str, height))
     } else {
         None
     }
@@ -136,25 +134,11 @@ fn generate_padding(p: &Params) -> Option<String> {
 }
 
 fn generate_halign(p: &Params) -> Option<String> {
-    if let Some(h_align) = &p.h_align {
-        match h_align {
-            HAlign::Left | HAlign::Right => Some(h_align.to_string()),
-            _ => None,
-        }
-    } else {
-        None
-    }
+    p.h_align.map(|h_align| h_align.to_string())
 }
 
 fn generate_valign(p: &Params) -> Option<String> {
-    if let Some(v_align) = &p.v_align {
-        match v_align {
-            VAlign::Top | VAlign::Bottom => Some(v_align.to_string()),
-            _ => None,
-        }
-    } else {
-        None
-    }
+    p.v_align.map(|v_align| v_align.to_string())
 }
 
 fn generate_smart(p: &Params) -> Option<String> {
@@ -180,7 +164,7 @@ fn generate_filters(p: &Params) -> Option<String> {
 
 fn generate_image(p: &Params) -> Option<String> {
     p.image.as_ref().map(|image| {
-        if image.contains('?')
+        if image.contains(['?', '%', '+', ' '])
             || image.starts_with("trim/")
             || image.starts_with("meta/")
             || image.starts_with("fit-in/")
@@ -192,9 +176,7 @@ fn generate_image(p: &Params) -> Option<String> {
             || image.starts_with("center/")
             || image.starts_with("smart/")
         {
-            form_urlencoded::Serializer::new(String::new())
-                .append_pair("", image)
-                .finish()
+            form_urlencoded::byte_serialize(image.as_bytes()).collect()
         } else {
             image.to_string()
         }
@@ -214,3 +196,401 @@ pub fn to_signed_string<S: Signer>(p: &Params, signer: S) -> String {
     let img_path = generate_path(p);
     format!("{}/{}", signer.sign(&img_path), img_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::color::{Color, NamedColor};
+    use crate::imagorpath::filter::{
+        Channel, Curve, Filter, FilterMatcher, FocalParams, HashOutput, ImageType, LabelParams,
+        LabelPosition, Region, ResizeKernel, RoundedCornerParams, WatermarkParams,
+        WatermarkPosition, FILTER_NAMES,
+    };
+    use crate::imagorpath::params::{HAlign, VAlign};
+    use crate::imagorpath::parse::parse_path;
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn arb_f32() -> impl Strategy<Value = F32> {
+        (-400i32..400).prop_map(|n| F32(n as f32 / 4.0))
+    }
+
+    fn arb_color() -> impl Strategy<Value = Color> {
+        prop_oneof![
+            prop::sample::select(vec![
+                NamedColor::Red,
+                NamedColor::Cyan,
+                NamedColor::Yellow,
+                NamedColor::AliceBlue,
+            ])
+            .prop_map(Color::Named),
+            "[0-9a-f]{6}".prop_map(Color::Hex),
+            (any::<u8>(), any::<u8>(), any::<u8>()).prop_map(|(r, g, b)| Color::Rgb(r, g, b)),
+            Just(Color::Auto),
+            Just(Color::Blur),
+            Just(Color::None),
+        ]
+    }
+
+    fn arb_image_type() -> impl Strategy<Value = ImageType> {
+        prop::sample::select(vec![
+            ImageType::GIF,
+            ImageType::JPEG,
+            ImageType::PNG,
+            ImageType::MAGICK,
+            ImageType::PDF,
+            ImageType::SVG,
+            ImageType::TIFF,
+            ImageType::WEBP,
+            ImageType::HEIF,
+            ImageType::BMP,
+            ImageType::AVIF,
+            ImageType::JP2K,
+        ])
+    }
+
+    fn arb_label_position() -> impl Strategy<Value = LabelPosition> {
+        prop_oneof![
+            (-500i32..500).prop_map(LabelPosition::Pixels),
+            (0i32..400).prop_map(|n| LabelPosition::Percentage(F32(n as f32 / 4.0))),
+            Just(LabelPosition::Left),
+            Just(LabelPosition::Right),
+            Just(LabelPosition::Center),
+            Just(LabelPosition::Top),
+            Just(LabelPosition::Bottom),
+        ]
+    }
+
+    fn arb_watermark_position() -> impl Strategy<Value = WatermarkPosition> {
+        prop_oneof![
+            (-500i32..500).prop_map(WatermarkPosition::Pixels),
+            (0i32..400).prop_map(|n| WatermarkPosition::Percentage(F32(n as f32 / 4.0))),
+            Just(WatermarkPosition::Left),
+            Just(WatermarkPosition::Right),
+            Just(WatermarkPosition::Center),
+            Just(WatermarkPosition::Top),
+            Just(WatermarkPosition::Bottom),
+            Just(WatermarkPosition::Repeat),
+        ]
+    }
+
+    fn arb_filter() -> impl Strategy<Value = Filter> {
+        prop_oneof![
+            arb_color().prop_map(Filter::BackgroundColor),
+            (
+                arb_f32(),
+                proptest::option::of((arb_f32(), arb_f32(), arb_f32(), arb_f32()))
+            )
+                .prop_map(|(sigma, region)| {
+                    let region = region.map(|(left, top, right, bottom)| Region {
+                        left,
+                        top,
+                        right,
+                        bottom,
+                    });
+                    Filter::Blur(sigma, region)
+                }),
+            (-100i32..100).prop_map(Filter::Brightness),
+            (-100i32..100).prop_map(Filter::Contrast),
+            prop::collection::vec(
+                (
+                    proptest::option::of(prop::sample::select(vec![
+                        Channel::R,
+                        Channel::G,
+                        Channel::B
+                    ])),
+                    prop::collection::vec(any::<(u8, u8)>(), 1..4),
+                )
+                    .prop_map(|(channel, points)| Curve { channel, points }),
+                1..3
+            )
+            .prop_map(Filter::Curves),
+            (1i32..40).prop_map(|n| Filter::Gamma(F32(n as f32 / 4.0))),
+            arb_color().prop_map(Filter::Fill),
+            (arb_f32(), arb_f32()).prop_map(|(x, y)| Filter::Focal(FocalParams::Point(x, y))),
+            (arb_f32(), arb_f32(), arb_f32(), arb_f32()).prop_map(|(l, t, r, b)| {
+                Filter::Focal(FocalParams::Region {
+                    top_left: (l, t),
+                    bottom_right: (r, b),
+                })
+            }),
+            arb_image_type().prop_map(Filter::Format),
+            Just(Filter::AutoFormat),
+            Just(Filter::Grayscale),
+            arb_f32().prop_map(Filter::Hue),
+            (
+                "[a-zA-Z0-9 ]{1,12}",
+                arb_label_position(),
+                arb_label_position(),
+                1u32..200,
+                arb_color(),
+                proptest::option::of(any::<u8>()),
+                proptest::option::of("[a-z]{1,8}"),
+                proptest::option::of(0u32..100),
+                proptest::option::of(1u32..2000),
+            )
+                .prop_map(|(text, x, y, size, color, alpha, font, padding, wrap)| {
+                    Filter::Label(LabelParams {
+                        text,
+                        x,
+                        y,
+                        size,
+                        color,
+                        alpha,
+                        font,
+                        padding,
+                        wrap,
+                    })
+                }),
+            any::<u32>().prop_map(|v| Filter::MaxBytes(v as usize)),
+            (0usize..1000).prop_map(Filter::MaxFrames),
+            (1usize..100, 0u32..10_000).prop_map(|(n, delay)| Filter::Frames(n, delay)),
+            (arb_f32(), arb_f32(), arb_f32()).prop_map(|(b, s, h)| Filter::Modulate(b, s, h)),
+            (arb_f32(), arb_f32(), arb_f32()).prop_map(|(r, a, t)| Filter::Usm(r, a, t)),
+            ("[a-z_]{1,8}", "[a-zA-Z0-9 ]{0,12}")
+                .prop_map(|(name, value)| Filter::Var(name, value)),
+            arb_f32().prop_map(Filter::Denoise),
+            (arb_color(), arb_color()).prop_map(|(dark, light)| Filter::Duotone(dark, light)),
+            Just(Filter::Sepia),
+            (2u32..=8).prop_map(Filter::Superres),
+            prop::array::uniform6(arb_f32()).prop_map(Filter::Affine),
+            prop::array::uniform4((arb_f32(), arb_f32())).prop_map(Filter::Perspective),
+            ((0u32..100), arb_color()).prop_map(|(width, color)| Filter::Border(width, color)),
+            (
+                (-50i32..50),
+                (-50i32..50),
+                (0i32..400).prop_map(|n| F32(n as f32 / 4.0)),
+                arb_color()
+            )
+                .prop_map(|(x, y, sigma, color)| Filter::Shadow(x, y, sigma, color)),
+            (-100i32..100).prop_map(Filter::Vibrance),
+            Just(Filter::AutoContrast),
+            Just(Filter::Equalize),
+            (arb_color(), arb_f32()).prop_map(|(color, strength)| Filter::Tint(color, strength)),
+            prop::sample::select(vec![0, 90, 180, 270]).prop_map(Filter::Orient),
+            (1usize..=16).prop_map(Filter::Palette),
+            (1usize..100).prop_map(Filter::Page),
+            (0u32..64).prop_map(Filter::Pixelate),
+            (arb_f32(), arb_f32(), arb_f32(), arb_f32(), 0u32..64).prop_map(
+                |(left, top, right, bottom, size)| {
+                    Filter::PixelateRegion(
+                        Region {
+                            left,
+                            top,
+                            right,
+                            bottom,
+                        },
+                        size,
+                    )
+                }
+            ),
+            (0usize..10_000).prop_map(Filter::Frame),
+            (0u32..3600).prop_map(|s| Filter::Seek(F32(s as f32 / 2.0))),
+            (0u32..1200).prop_map(Filter::Dpi),
+            (1i32..=20).prop_map(|n| Filter::Dpr(F32(n as f32 / 4.0))),
+            arb_f32().prop_map(Filter::Proportion),
+            (0u8..=100).prop_map(Filter::Quality),
+            prop::sample::select(vec![8u8, 10, 12, 16]).prop_map(Filter::Bitdepth),
+            prop::sample::select(ResizeKernel::ALL.to_vec()).prop_map(Filter::Kernel),
+            (arb_f32(), arb_f32(), arb_f32()).prop_map(|(r, g, b)| Filter::Rgb(r, g, b)),
+            prop::sample::select(vec![0, 90, 180, 270]).prop_map(Filter::Rotate),
+            (
+                0u32..100,
+                proptest::option::of(0u32..100),
+                proptest::option::of(arb_color())
+            )
+                .prop_filter(
+                    "numeric colors are ambiguous without ry",
+                    |(_, ry, color)| {
+                        ry.is_some() || !matches!(color, Some(Color::Rgb(..) | Color::Hex(_)))
+                    }
+                )
+                .prop_map(|(rx, ry, color)| {
+                    Filter::RoundCorner(RoundedCornerParams { rx, ry, color })
+                }),
+            arb_f32().prop_map(Filter::Saturation),
+            arb_f32().prop_map(Filter::Sharpen),
+            Just(Filter::StripExif),
+            Just(Filter::StripIcc),
+            Just(Filter::StripMetadata),
+            Just(Filter::Raw),
+            prop::sample::select(vec![HashOutput::Text, HashOutput::Json])
+                .prop_map(Filter::Blurhash),
+            prop::sample::select(vec![HashOutput::Text, HashOutput::Json])
+                .prop_map(Filter::Thumbhash),
+            Just(Filter::Upscale),
+            Just(Filter::NoUpscale),
+            (
+                prop::sample::select(vec!["wm.png", "example.com/wm.png"]),
+                arb_watermark_position(),
+                arb_watermark_position(),
+                0u8..=100,
+                proptest::option::of((arb_f32(), proptest::option::of(arb_f32()))),
+            )
+                .prop_map(|(image, x, y, alpha, ratios)| {
+                    Filter::Watermark(WatermarkParams {
+                        image: image.to_string(),
+                        x,
+                        y,
+                        alpha,
+                        w_ratio: ratios.map(|(w, _)| w),
+                        h_ratio: ratios.and_then(|(_, h)| h),
+                    })
+                }),
+        ]
+    }
+
+    prop_compose! {
+        fn arb_params()(
+            meta in any::<bool>(),
+            trim in proptest::option::of((
+                prop::sample::select(vec![TrimBy::TopLeft, TrimBy::BottomRight]),
+                proptest::option::of((0i32..255).prop_map(|t| F32(t as f32))),
+            )),
+            crop in proptest::option::of((arb_f32(), arb_f32(), arb_f32(), arb_f32())),
+            fit in proptest::option::of(prop::sample::select(vec![
+                (Fit::FitIn, false, false),
+                (Fit::FitIn, true, false),
+                (Fit::FitIn, false, true),
+                (Fit::FitIn, true, true),
+                (Fit::Stretch, false, false),
+            ])),
+            width in proptest::option::of(0i32..5000),
+            height in proptest::option::of(0i32..5000),
+            h_flip in any::<bool>(),
+            v_flip in any::<bool>(),
+            h_align in proptest::option::of(
+                prop::sample::select(vec![HAlign::Left, HAlign::Right, HAlign::Center]),
+            ),
+            v_align in proptest::option::of(
+                prop::sample::select(vec![VAlign::Top, VAlign::Bottom, VAlign::Middle]),
+            ),
+            padding in proptest::option::of((0i32..100, 0i32..100, 0i32..100, 0i32..100)),
+            smart in any::<bool>(),
+            filters in prop::collection::vec(arb_filter(), 0..4),
+            image in prop::sample::select(vec![
+                "img",
+                "foobar.jpg",
+                "example.com/foobar.png",
+                "raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png",
+            ]),
+        ) -> Params {
+            Params {
+                meta,
+                trim: trim.is_some(),
+                trim_by: trim.map(|(by, _)| by).unwrap_or_default(),
+                trim_tolerance: trim.and_then(|(_, tolerance)| tolerance),
+                crop_left: crop.map(|c| c.0),
+                crop_top: crop.map(|c| c.1),
+                crop_right: crop.map(|c| c.2),
+                crop_bottom: crop.map(|c| c.3),
+                fit: fit.map(|f| f.0),
+                adaptive_fit_in: fit.is_some_and(|f| f.1),
+                full_fit_in: fit.is_some_and(|f| f.2),
+                width,
+                height,
+                padding_left: padding.map(|p| p.0),
+                padding_top: padding.map(|p| p.1),
+                padding_right: padding.map(|p| p.2),
+                padding_bottom: padding.map(|p| p.3),
+                h_flip,
+                v_flip,
+                h_align,
+                v_align,
+                smart,
+                filters,
+                image: Some(image.to_string()),
+                ..Default::default()
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_generate_round_trip(p in arb_params()) {
+            let path = generate_path(&p);
+            let (rest, parsed) = parse_path(&path).unwrap();
+
+            prop_assert_eq!(rest, "");
+            prop_assert_eq!(Params { path: None, ..parsed }, p);
+        }
+
+        #[test]
+        fn test_parse_generate_unsafe_round_trip(p in arb_params()) {
+            let path = to_unsafe_string(&p);
+            let (_, parsed) = parse_path(&path).unwrap();
+
+            prop_assert_eq!(Params { path: None, ..parsed }, Params { unsafe_: true, ..p });
+        }
+
+        #[test]
+        fn test_filter_name_is_known(f in arb_filter()) {
+            prop_assert!(FILTER_NAMES.contains(&f.name().as_str()));
+            prop_assert!(FilterMatcher::new(&f.name()).matches(&f));
+        }
+    }
+
+    #[test]
+    fn test_generate_parse_query_image_round_trip() {
+        let p = Params {
+            width: Some(100),
+            height: Some(100),
+            image: Some("https://example.com/a b+c.jpg?w=1&h=2".to_string()),
+            ..Default::default()
+        };
+
+        let path = generate_path(&p);
+        let (rest, parsed) = parse_path(&path).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(parsed.image, p.image);
+    }
+
+    #[test]
+    fn test_parse_generate_padding_round_trip() {
+        for path in [
+            "10x11:12x13/fit-in/300x200/10x20:30x40/img.jpg",
+            "fit-in/-300x200/5x0:0x15/filters:fill(white)/img.jpg",
+            "300x200/15x5/img.jpg",
+        ] {
+            let (rest, parsed) = parse_path(path).unwrap();
+
+            assert_eq!(rest, "");
+            assert!(parsed.padding_left.is_some(), "no padding in {}", path);
+            assert_eq!(generate_path(&parsed), path);
+        }
+    }
+
+    #[test]
+    fn test_generate_path() {
+        let p = Params {
+            trim: true,
+            trim_by: TrimBy::BottomRight,
+            trim_tolerance: Some(F32(100.0)),
+            crop_left: Some(F32(10.0)),
+            crop_top: Some(F32(11.0)),
+            crop_right: Some(F32(12.0)),
+            crop_bottom: Some(F32(13.0)),
+            fit: Some(Fit::FitIn),
+            width: Some(300),
+            height: Some(200),
+            h_flip: true,
+            v_flip: true,
+            h_align: Some(HAlign::Left),
+            v_align: Some(VAlign::Top),
+            smart: true,
+            filters: vec![
+                Filter::Grayscale,
+                Filter::Fill(Color::Named(NamedColor::Cyan)),
+                Filter::Format(ImageType::WEBP),
+            ],
+            image: Some("s.glbimg.com/es/ge/f/original/2011/03/29/orlandosilva_60.jpg".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            generate_path(&p),
+            "trim:bottom-right:100/10x11:12x13/fit-in/-300x-200/left/top/smart/filters:grayscale():fill(cyan):format(webp)/s.glbimg.com/es/ge/f/original/2011/03/29/orlandosilva_60.jpg"
+        );
+    }
+}
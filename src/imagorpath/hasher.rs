@@ -25,7 +25,23 @@ pub enum AuthError {
 fn hex_digest_path(path: &str) -> String {
     let digest = Sha1::digest(path.as_bytes());
     let hash = hex::encode(digest);
-    format!("{}/{}/{}", &hash[..2], &hash[2..4], &hash[4..])
+    format!("{}/{}", shard_dirs(&hash), &hash[4..])
+}
+
+/// The two levels of directories `hex_digest_path` spreads paths over
+fn shard_dirs(hash: &str) -> String {
+    format!("{}/{}", &hash[..2], &hash[2..4])
+}
+
+/// Directories to keep `key` in so no one directory holds too many, laid
+/// out as `digest_storage_hasher` paths are, e.g. `a9/4a`
+pub fn digest_shard(key: &str) -> String {
+    shard_dirs(&source_digest(key))
+}
+
+/// Flat digest of a source image URI, e.g. for cache tags and stats keys
+pub fn source_digest(image: &str) -> String {
+    hex::encode(Sha1::digest(image.as_bytes()))
 }
 
 pub fn digest_storage_hasher(image: &str) -> String {
@@ -43,21 +59,20 @@ pub fn suffix_result_storage_hasher(p: &params::Params) -> String {
     let hash = format!(".{}", hex::encode(&digest[..10]));
 
     let image = p.image.as_ref().unwrap();
-    let image = if image.starts_with("https://") {
-        &image[8..].to_string()
-    } else if image.starts_with("http://") {
-        &image[7..].to_string()
-    } else {
-        image
-    };
+    let image = image
+        .strip_prefix("https://")
+        .or_else(|| image.strip_prefix("http://"))
+        .unwrap_or(image);
 
     let dot_idx = image.rfind('.');
     let slash_idx = image.rfind('/');
 
     if let Some(dot_idx) = dot_idx {
-        if slash_idx.map_or(true, |idx| idx < dot_idx) {
+        if slash_idx.is_none_or(|idx| idx < dot_idx) {
             let ext = if p.meta {
                 ".json".to_string()
+            } else if let Some(output) = p.data_output() {
+                output.extension().to_string()
             } else {
                 p.filters
                     .iter()
@@ -73,6 +88,26 @@ pub fn suffix_result_storage_hasher(p: &params::Params) -> String {
     format!("{}{}", image, hash)
 }
 
+/// Whether `key` is shaped like a `suffix_result_storage_hasher` key, i.e.
+/// its name ends in the `.<hash>` suffix, maybe followed by an extension.
+/// Results share storage with sources, so this keeps anything that deletes
+/// results in bulk away from the sources.
+pub fn is_result_key(key: &str) -> bool {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let parts: Vec<&str> = name.split('.').skip(1).collect();
+    let is_hash = |part: &str| {
+        part.len() == 20
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    match parts.as_slice() {
+        [.., last] if is_hash(last) => true,
+        [.., hash, _] => is_hash(hash),
+        _ => false,
+    }
+}
+
 pub fn size_suffix_result_storage_hasher(p: &params::Params) -> String {
     let path = p.path.clone().unwrap_or_else(|| generate_path(p));
     let digest = Sha1::digest(path.as_bytes());
@@ -94,9 +129,11 @@ pub fn size_suffix_result_storage_hasher(p: &params::Params) -> String {
     let slash_idx = image.rfind('/');
 
     if let Some(dot_idx) = dot_idx {
-        if slash_idx.map_or(true, |idx| idx < dot_idx) {
+        if slash_idx.is_none_or(|idx| idx < dot_idx) {
             let ext = if p.meta {
                 ".json".to_string()
+            } else if let Some(output) = p.data_output() {
+                output.extension().to_string()
             } else {
                 p.filters
                     .iter()
@@ -217,7 +254,7 @@ mod tests {
         let p_without_path = Params { path: None, ..p };
         assert_eq!(
             digest_result_storage_hasher(&p_without_path),
-            "e7/d1/edae4e3a014626605954c8ce09a6d8526ed1"
+            "d5/c2/804e5d81c475bee50f731db17ee613f43262"
         );
     }
 
@@ -245,7 +282,7 @@ mod tests {
         let p_without_path = Params { path: None, ..p };
         assert_eq!(
             suffix_result_storage_hasher(&p_without_path),
-            "foobar.e7d1edae4e3a01462660",
+            "foobar.d5c2804e5d81c475bee5",
         );
     }
 
@@ -304,10 +341,23 @@ mod tests {
         let p_without_path = Params { path: None, ..p };
         assert_eq!(
             suffix_result_storage_hasher(&p_without_path),
-            "foobar.b9bb1f64c760cf298fb6.jpg",
+            "foobar.45d8ebb31bd4ed80c26e.jpg",
         );
     }
 
+    #[test]
+    fn test_is_result_key() {
+        let (_, p) = parse_path("166x169/top/dir/foobar.jpg").unwrap();
+        assert!(is_result_key(&suffix_result_storage_hasher(&p)));
+        let (_, p) = parse_path("166x169/top/foobar").unwrap();
+        assert!(is_result_key(&suffix_result_storage_hasher(&p)));
+
+        assert!(!is_result_key("dir/foobar.jpg"));
+        assert!(!is_result_key("foobar"));
+        assert!(!is_result_key("45d8ebb31bd4ed80c26e.jpg"));
+        assert!(!is_result_key("foobar.45d8ebb31bd4ed80c26e.tar.gz"));
+    }
+
     #[test]
     fn test_suffix_result_storage_hasher_with_format() {
         let p = Params {
@@ -321,11 +371,11 @@ mod tests {
         println!("{}", generate_path(&p));
         assert_eq!(
             suffix_result_storage_hasher(&p),
-            "example.com/foobar.98c5e02e0ba162bce51d.webp",
+            "example.com/foobar.8aade9060badfcb289f9.webp",
         );
         assert_eq!(
             size_suffix_result_storage_hasher(&p),
-            "example.com/foobar.98c5e02e0ba162bce51d_17x19.webp",
+            "example.com/foobar.8aade9060badfcb289f9_17x19.webp",
         );
     }
 
@@ -342,11 +392,11 @@ mod tests {
         println!("{}", generate_path(&p));
         assert_eq!(
             suffix_result_storage_hasher(&p),
-            "example.com/foobar.b56fe819cae010721433.json",
+            "example.com/foobar.d72ff6ef20ba41fa570c.json",
         );
         assert_eq!(
             size_suffix_result_storage_hasher(&p),
-            "example.com/foobar.b56fe819cae010721433_17x19.json"
+            "example.com/foobar.d72ff6ef20ba41fa570c_17x19.json"
         );
     }
 
@@ -364,11 +414,11 @@ mod tests {
         println!("{}", generate_path(&p));
         assert_eq!(
             suffix_result_storage_hasher(&p),
-            "example.com/foobar.551f72136cd4ce0aaf36.json",
+            "example.com/foobar.c80ab0faf85b35a140a8.json",
         );
         assert_eq!(
             size_suffix_result_storage_hasher(&p),
-            "example.com/foobar.551f72136cd4ce0aaf36_17x19.json"
+            "example.com/foobar.c80ab0faf85b35a140a8_17x19.json"
         );
     }
 }
@@ -1,8 +1,10 @@
 pub mod color;
+pub mod error;
 pub mod filter;
 pub mod generate;
 pub mod hasher;
-pub mod normalize;
 pub mod params;
 pub mod parse;
+pub mod registry;
+pub mod signer;
 pub mod type_utils;
@@ -1,5 +1,6 @@
-use super::filter::Filter;
+use super::filter::{Filter, HashOutput, ImageType};
 use super::type_utils::F32;
+use crate::config::DefaultsSettings;
 use core::fmt;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
@@ -78,7 +79,8 @@ pub enum TrimBy {
     BottomRight,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
 pub struct Params {
     #[serde(skip)]
     pub params: bool,
@@ -104,6 +106,10 @@ pub struct Params {
     pub crop_bottom: Option<F32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fit: Option<Fit>,
+    /// Swap the fit-in box to match the image orientation
+    pub adaptive_fit_in: bool,
+    /// Fit the smaller side of the image in the box instead of the larger one
+    pub full_fit_in: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub width: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -126,6 +132,159 @@ pub struct Params {
     pub filters: Vec<Filter>,
 }
 
+impl Params {
+    /// True when the path asks for the image and nothing else, so the
+    /// source bytes can be served untouched
+    pub fn is_identity(&self) -> bool {
+        self.keeps_pixels()
+            && self.width.unwrap_or(0) == 0
+            && self.height.unwrap_or(0) == 0
+            && self.filters.is_empty()
+    }
+
+    /// True when processing a `width` by `height` source in `format` would
+    /// only re-encode it: the size asked for is the source's, and the only
+    /// filter asks for the format it's already in
+    pub fn is_noop_for(&self, width: i32, height: i32, format: ImageType) -> bool {
+        let same =
+            |asked: Option<i32>, side: i32| matches!(asked, None | Some(0)) || asked == Some(side);
+        self.keeps_pixels()
+            && same(self.width, width)
+            && same(self.height, height)
+            && self
+                .filters
+                .iter()
+                .all(|filter| *filter == Filter::Format(format))
+    }
+
+    /// No crop, trim, padding or flip, and the image rather than its metadata
+    fn keeps_pixels(&self) -> bool {
+        !self.meta
+            && !self.trim
+            && self.crop_left.is_none()
+            && self.crop_top.is_none()
+            && self.crop_right.is_none()
+            && self.crop_bottom.is_none()
+            && self.padding_left.is_none()
+            && self.padding_top.is_none()
+            && self.padding_right.is_none()
+            && self.padding_bottom.is_none()
+            && !self.h_flip
+            && !self.v_flip
+    }
+
+    /// How the result is returned when the path asks for data about the
+    /// image, a hash or a palette, instead of the image itself
+    pub fn data_output(&self) -> Option<HashOutput> {
+        self.filters.iter().rev().find_map(|filter| match filter {
+            Filter::Blurhash(output) | Filter::Thumbhash(output) => Some(*output),
+            Filter::Palette(_) => Some(HashOutput::Json),
+            _ => None,
+        })
+    }
+
+    /// Fill in anything the path left unset from the configured defaults.
+    /// Explicit values in the path always win.
+    pub fn with_defaults(mut self, defaults: &DefaultsSettings) -> Self {
+        if self.fit.is_none() {
+            self.fit = defaults.fit;
+        }
+        self.image = self.image.map(|image| defaults.url_decoding.apply(&image));
+
+        let has = |pred: fn(&Filter) -> bool| self.filters.iter().any(pred);
+        let mut extra = Vec::new();
+
+        if let Some(quality) = defaults.quality {
+            if !has(|f| matches!(f, Filter::Quality(_))) {
+                extra.push(Filter::Quality(quality));
+            }
+        }
+        let has_format = has(|f| matches!(f, Filter::Format(_) | Filter::AutoFormat));
+        if defaults.auto_format && !has_format {
+            extra.push(Filter::AutoFormat);
+        } else if let Some(format) = defaults.format.filter(|_| !has_format) {
+            extra.push(Filter::Format(format));
+        }
+        if defaults.strip_metadata && !has(|f| matches!(f, Filter::StripMetadata)) {
+            extra.push(Filter::StripMetadata);
+        }
+        if let Some(upscale) = defaults.upscale {
+            if !has(|f| matches!(f, Filter::Upscale | Filter::NoUpscale)) {
+                extra.push(if upscale {
+                    Filter::Upscale
+                } else {
+                    Filter::NoUpscale
+                });
+            }
+        }
+
+        self.filters.extend(extra);
+        self
+    }
+
+    /// Move filters whose position doesn't affect the output after the rest,
+    /// in a fixed order, when `canonical_filter_order` is set. The path is
+    /// dropped so the result key is derived from the reordered filters.
+    pub fn canonicalized(mut self, defaults: &DefaultsSettings) -> Self {
+        if !defaults.canonical_filter_order {
+            return self;
+        }
+
+        // stable, so repeats of the same filter keep their last-wins order
+        let (mut commutative, ordered): (Vec<_>, Vec<_>) = self
+            .filters
+            .into_iter()
+            .partition(|f| commutative_rank(f).is_some());
+        commutative.sort_by_key(commutative_rank);
+
+        self.filters = ordered.into_iter().chain(commutative).collect();
+        self.path = None;
+        self
+    }
+
+    /// The last `dpr()` filter, if any
+    pub fn device_pixel_ratio(&self) -> Option<f32> {
+        self.filters.iter().rev().find_map(|f| match f {
+            Filter::Dpr(dpr) => Some(dpr.0),
+            _ => None,
+        })
+    }
+
+    /// Width and height multiplied by the device pixel ratio, `None` when
+    /// there is nothing to scale
+    pub fn scaled_by_dpr(&self) -> Option<Self> {
+        let dpr = self.device_pixel_ratio().filter(|dpr| *dpr != 1.0)?;
+        let scale = |v: Option<i32>| v.map(|v| (v as f32 * dpr).round() as i32);
+
+        Some(Params {
+            width: scale(self.width),
+            height: scale(self.height),
+            ..self.clone()
+        })
+    }
+
+    pub fn has_auto_format(&self) -> bool {
+        self.filters.iter().any(|f| matches!(f, Filter::AutoFormat))
+    }
+
+    /// Resolve `format(auto)` for a client's `Accept` header. The path is
+    /// dropped so the result key is derived from the negotiated format.
+    pub fn with_negotiated_format(mut self, accept: &str) -> Self {
+        if !self.has_auto_format() {
+            return self;
+        }
+
+        let format = ImageType::negotiate(accept);
+        for filter in self.filters.iter_mut() {
+            if matches!(filter, Filter::AutoFormat) {
+                *filter = Filter::Format(format);
+            }
+        }
+        self.path = None;
+        self
+    }
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum FilterParseError {
     #[error("Unknown filter: {0}")]
@@ -169,3 +328,218 @@ pub enum UtilityFilter {
     Preview,
     Raw,
 }
+
+/// Position in the canonical order for filters that only set load or export
+/// options, `None` for filters applied to the pixels in sequence
+fn commutative_rank(filter: &Filter) -> Option<u8> {
+    match filter {
+        Filter::Page(_) => Some(0),
+        Filter::Frame(_) => Some(1),
+        Filter::Seek(_) => Some(2),
+        Filter::Dpi(_) => Some(3),
+        Filter::Dpr(_) => Some(4),
+        // alternatives for the same setting share a rank
+        Filter::Upscale | Filter::NoUpscale => Some(5),
+        Filter::MaxBytes(_) => Some(6),
+        Filter::Quality(_) => Some(7),
+        Filter::Format(_) | Filter::AutoFormat => Some(8),
+        Filter::StripExif => Some(9),
+        Filter::StripIcc => Some(10),
+        Filter::StripMetadata => Some(11),
+        Filter::Bitdepth(_) => Some(12),
+        Filter::Kernel(_) => Some(13),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_path;
+    use crate::pathutil::decode::UrlDecoding;
+
+    #[test]
+    fn test_with_defaults_fills_unset_values() {
+        let defaults = DefaultsSettings {
+            quality: Some(80),
+            format: Some(ImageType::WEBP),
+            auto_format: false,
+            fit: Some(Fit::FitIn),
+            strip_metadata: true,
+            upscale: Some(false),
+            canonical_filter_order: false,
+            url_decoding: UrlDecoding::Once,
+        };
+
+        let (_, params) = parse_path("300x200/filters:quality(50):upscale()/img.jpg").unwrap();
+        let params = params.with_defaults(&defaults);
+
+        assert_eq!(params.fit, Some(Fit::FitIn));
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Quality(50),
+                Filter::Upscale,
+                Filter::Format(ImageType::WEBP),
+                Filter::StripMetadata,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_defaults_decodes_double_encoded_image() {
+        let defaults = DefaultsSettings {
+            url_decoding: UrlDecoding::Auto,
+            ..Default::default()
+        };
+        let path = "unsafe/300x200/https%253A%252F%252Fexample.com%252Fa%2520b.jpg";
+
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.image.as_deref(),
+            Some("https%3A%2F%2Fexample.com%2Fa%20b.jpg")
+        );
+        let params = params.with_defaults(&defaults);
+        assert_eq!(params.image.as_deref(), Some("https://example.com/a b.jpg"));
+    }
+
+    #[test]
+    fn test_is_identity() {
+        let (_, params) = parse_path("unsafe/img.jpg").unwrap();
+        assert!(params.is_identity());
+
+        let (_, params) = parse_path("unsafe/fit-in/smart/img.jpg").unwrap();
+        assert!(params.is_identity());
+
+        for path in [
+            "unsafe/300x200/img.jpg",
+            "unsafe/-x/img.jpg",
+            "unsafe/10x10:20x20/img.jpg",
+            "unsafe/meta/img.jpg",
+            "unsafe/filters:quality(90)/img.jpg",
+        ] {
+            let (_, params) = parse_path(path).unwrap();
+            assert!(!params.is_identity(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_is_noop_for() {
+        for path in [
+            "unsafe/img.png",
+            "unsafe/300x200/img.png",
+            "unsafe/fit-in/300x0/img.png",
+            "unsafe/0x200/smart/img.png",
+            "unsafe/300x200/filters:format(png)/img.png",
+        ] {
+            let (_, params) = parse_path(path).unwrap();
+            assert!(params.is_noop_for(300, 200, ImageType::PNG), "{}", path);
+        }
+
+        for path in [
+            "unsafe/300x201/img.png",
+            "unsafe/200x0/img.png",
+            "unsafe/-300x200/img.png",
+            "unsafe/10x10:20x20/300x200/img.png",
+            "unsafe/300x200/filters:format(webp)/img.png",
+            "unsafe/300x200/filters:quality(80)/img.png",
+            "unsafe/300x200/filters:grayscale()/img.png",
+            "unsafe/meta/300x200/img.png",
+        ] {
+            let (_, params) = parse_path(path).unwrap();
+            assert!(!params.is_noop_for(300, 200, ImageType::PNG), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_with_empty_defaults_is_noop() {
+        let (_, params) = parse_path("stretch/300x200/filters:grayscale()/img.jpg").unwrap();
+        let (_, expected) = parse_path("stretch/300x200/filters:grayscale()/img.jpg").unwrap();
+
+        assert_eq!(params.with_defaults(&DefaultsSettings::default()), expected);
+    }
+
+    #[test]
+    fn test_auto_format_negotiation() {
+        let defaults = DefaultsSettings {
+            format: Some(ImageType::PNG),
+            auto_format: true,
+            ..Default::default()
+        };
+
+        let (_, params) = parse_path("unsafe/300x200/img.jpg").unwrap();
+        let params = params.with_defaults(&defaults);
+        assert_eq!(params.filters, vec![Filter::AutoFormat]);
+
+        let params = params.with_negotiated_format("image/avif,image/webp,*/*");
+        assert_eq!(params.filters, vec![Filter::Format(ImageType::AVIF)]);
+        assert_eq!(params.path, None);
+
+        // an explicit format in the path isn't negotiated
+        let (_, params) = parse_path("unsafe/filters:format(gif)/img.jpg").unwrap();
+        let params = params
+            .with_defaults(&defaults)
+            .with_negotiated_format("image/webp");
+        assert_eq!(params.filters, vec![Filter::Format(ImageType::GIF)]);
+    }
+
+    #[test]
+    fn test_negotiate_accept() {
+        assert_eq!(
+            ImageType::negotiate("image/avif,image/webp,*/*;q=0.8"),
+            ImageType::AVIF
+        );
+        assert_eq!(
+            ImageType::negotiate("image/avif;q=0, image/webp"),
+            ImageType::WEBP
+        );
+        assert_eq!(ImageType::negotiate("*/*"), ImageType::JPEG);
+        assert_eq!(ImageType::negotiate(""), ImageType::JPEG);
+    }
+
+    #[test]
+    fn test_canonical_filter_order() {
+        let defaults = DefaultsSettings {
+            canonical_filter_order: true,
+            ..Default::default()
+        };
+        let canonical = |path: &str| parse_path(path).unwrap().1.canonicalized(&defaults);
+
+        let a =
+            canonical("unsafe/filters:quality(80):blur(2):strip_metadata():grayscale()/img.jpg");
+        let b =
+            canonical("unsafe/filters:strip_metadata():blur(2):grayscale():quality(80)/img.jpg");
+        assert_eq!(a, b);
+        assert_eq!(a.path, None);
+
+        // pixel filters keep their order
+        let c = canonical("unsafe/filters:grayscale():blur(2):quality(80)/img.jpg");
+        assert_ne!(a, c);
+
+        // the last of conflicting settings still wins
+        let d = canonical("unsafe/filters:upscale():blur(2):no_upscale()/img.jpg");
+        assert_eq!(d.filters.last(), Some(&Filter::NoUpscale));
+
+        let strict = parse_path("unsafe/filters:quality(80):blur(2)/img.jpg")
+            .unwrap()
+            .1
+            .canonicalized(&DefaultsSettings::default());
+        assert!(strict.path.is_some());
+    }
+
+    #[test]
+    fn test_scaled_by_dpr() {
+        let (_, params) = parse_path("unsafe/300x0/filters:dpr(1.5)/img.jpg").unwrap();
+        let scaled = params.scaled_by_dpr().unwrap();
+        assert_eq!(scaled.width, Some(450));
+        assert_eq!(scaled.height, Some(0));
+
+        let (_, params) = parse_path("unsafe/-300x200/filters:dpr(2)/img.jpg").unwrap();
+        let scaled = params.scaled_by_dpr().unwrap();
+        assert_eq!((scaled.width, scaled.height), (Some(600), Some(400)));
+        assert!(scaled.h_flip);
+
+        let (_, params) = parse_path("unsafe/300x200/filters:dpr(1)/img.jpg").unwrap();
+        assert!(params.scaled_by_dpr().is_none());
+    }
+}
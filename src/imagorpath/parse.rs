@@ -1,58 +1,61 @@
 use super::color::{Color, NamedColor};
+use super::error::ParseError;
 use super::filter::{
-    Filter, FocalParams, ImageType, LabelParams, LabelPosition, RoundedCornerParams,
-    WatermarkParams, WatermarkPosition,
+    Channel, Curve, Filter, FocalParams, HashOutput, ImageType, LabelParams, LabelPosition, Region,
+    ResizeKernel, RoundedCornerParams, WatermarkParams, WatermarkPosition,
 };
 use super::params::{Fit, HAlign, Params, TrimBy, VAlign};
+use super::registry::FilterRegistry;
 use super::type_utils::F32;
-use axum::{
-    async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
-};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
 use color_eyre::Result;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, tag_no_case, take_while1, take_while_m_n},
-    character::complete::{alphanumeric1, char, digit1},
-    combinator::{map, opt, recognize, value},
+    bytes::complete::{tag, tag_no_case, take_while, take_while1, take_while_m_n},
+    character::complete::{char, digit1},
+    combinator::{cut, map, map_res, opt, peek, recognize, value},
     error::{context, ErrorKind, VerboseError, VerboseErrorKind},
-    multi::{many1, separated_list0, separated_list1},
+    multi::{separated_list0, separated_list1},
     sequence::{pair, preceded, separated_pair, terminated, tuple},
     AsChar, IResult,
 };
+use percent_encoding::percent_decode_str;
 use tracing::info;
 
-#[derive(Debug)]
-pub struct CyberpunkPath {
-    pub path: String,
-}
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
 
 #[async_trait]
 impl<S> FromRequestParts<S> for Params
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = ParseError;
 
     #[tracing::instrument(skip(parts, _state))]
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         // Access the URI and perform your custom parsing logic
+        // keep the query string, it belongs to the source image URL
         let uri = &parts.uri;
-        let path = uri.path().trim_start_matches("/params");
+        let path = uri
+            .path_and_query()
+            .map_or(uri.path(), |pq| pq.as_str())
+            .trim_start_matches("/params");
 
         info!("Parsing path: {}", path);
 
         // TODO: check auth of imagorpath
 
-        let (_, params) = parse_path(path).map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("Failed to parse params: {}", e),
-            )
-        })?;
+        parse_params(path)
+    }
+}
 
-        Ok(params)
+/// Parse a complete imagor path, rejecting anything left unconsumed
+pub fn parse_params(input: &str) -> Result<Params, ParseError> {
+    match parse_path(input) {
+        Ok(("", params)) => Ok(params),
+        Ok((rest, _)) => Err(ParseError::new(input, rest, "expected end of path")),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(ParseError::from_verbose(input, e)),
+        Err(nom::Err::Incomplete(_)) => Err(ParseError::new(input, "", "expected more input")),
     }
 }
 
@@ -66,13 +69,38 @@ impl TryFrom<&str> for Params {
 }
 
 fn parse_unsafe(input: &str) -> IResult<&str, bool, VerboseError<&str>> {
-    value(false, tag("unsafe/"))(input)
+    value(true, tag("unsafe/"))(input)
+}
+
+/// A thumbor signature: the base64 of an HMAC-SHA1, 27 characters and `=`
+fn parse_hash(input: &str) -> IResult<&str, String, VerboseError<&str>> {
+    map(
+        terminated(
+            recognize(pair(
+                take_while_m_n(27, 27, |c: char| {
+                    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+                }),
+                char('='),
+            )),
+            char('/'),
+        ),
+        str::to_string,
+    )(input)
+}
+
+/// `unsafe/`, or the path's signature
+fn parse_signature(input: &str) -> IResult<&str, (bool, Option<String>), VerboseError<&str>> {
+    alt((
+        map(parse_unsafe, |unsafe_| (unsafe_, None)),
+        map(parse_hash, |hash| (false, Some(hash))),
+    ))(input)
 }
 
 fn parse_meta(input: &str) -> IResult<&str, bool, VerboseError<&str>> {
     value(true, tag("meta/"))(input)
 }
 
+#[allow(clippy::type_complexity)]
 fn parse_trim(
     input: &str,
 ) -> IResult<&str, (bool, Option<TrimBy>, Option<F32>), VerboseError<&str>> {
@@ -86,10 +114,7 @@ fn parse_trim(
                     value(TrimBy::BottomRight, tag("bottom-right")),
                 )),
             )),
-            opt(preceded(
-                char(':'),
-                map(digit1, |s: &str| F32(s.parse().unwrap())),
-            )),
+            opt(preceded(char(':'), parse_f32)),
         ))),
         char('/'),
     )(input)
@@ -119,49 +144,67 @@ fn parse_f32(input: &str) -> IResult<&str, F32, VerboseError<&str>> {
     )(input)
 }
 
+fn parse_dimension(input: &str) -> IResult<&str, (bool, Option<i32>), VerboseError<&str>> {
+    pair(
+        map(opt(char('-')), |flip| flip.is_some()),
+        opt(map_res(digit1, |s: &str| s.parse::<i32>())),
+    )(input)
+}
+
+#[allow(clippy::type_complexity)]
 fn parse_dimensions(
     input: &str,
 ) -> IResult<&str, (Option<i32>, Option<i32>, bool, bool), VerboseError<&str>> {
     terminated(
-        tuple((
-            map(opt(recognize(pair(opt(char('-')), digit1))), |d| {
-                d.map(|s: &str| s.parse::<i32>().unwrap())
-            }),
-            preceded(
-                char('x'),
-                map(opt(recognize(pair(opt(char('-')), digit1))), |d| {
-                    d.map(|s: &str| s.parse::<i32>().unwrap())
-                }),
-            ),
-        )),
+        separated_pair(parse_dimension, char('x'), parse_dimension),
         char('/'),
     )(input)
-    .map(|(next_input, (width, height))| {
-        (
-            next_input,
-            (
-                width.map(|w| w.abs()),
-                height.map(|h| h.abs()),
-                width.map_or(false, |w| w < 0),
-                height.map_or(false, |h| h < 0),
-            ),
-        )
+    .map(|(next_input, ((h_flip, width), (v_flip, height)))| {
+        (next_input, (width, height, h_flip, v_flip))
+    })
+}
+
+fn parse_padding(input: &str) -> IResult<&str, (i32, i32, i32, i32), VerboseError<&str>> {
+    let side = || map(nom::character::complete::u32, |v| v as i32);
+
+    terminated(
+        pair(
+            separated_pair(side(), char('x'), side()),
+            opt(preceded(
+                char(':'),
+                separated_pair(side(), char('x'), side()),
+            )),
+        ),
+        char('/'),
+    )(input)
+    .map(|(next_input, ((left, top), right_bottom))| {
+        let (right, bottom) = right_bottom.unwrap_or((left, top));
+        (next_input, (left, top, right, bottom))
     })
 }
 
-fn parse_fit(input: &str) -> IResult<&str, Option<Fit>, VerboseError<&str>> {
+/// Fit mode plus the thumbor `adaptive-` and `full-` fit-in modifiers
+type FitDetails = (Fit, bool, bool);
+
+fn parse_fit(input: &str) -> IResult<&str, Option<FitDetails>, VerboseError<&str>> {
     let (input, fit) = opt(alt((
-        value(Fit::FitIn, tag("fit-in/")),
-        value(Fit::Stretch, tag("stretch/")),
+        map(
+            terminated(
+                pair(opt(tag("adaptive-")), opt(tag("full-"))),
+                tag("fit-in/"),
+            ),
+            |(adaptive, full)| (Fit::FitIn, adaptive.is_some(), full.is_some()),
+        ),
+        value((Fit::Stretch, false, false), tag("stretch/")),
     )))(input)?;
 
     // Check if both fit-in and stretch are present
     let (input, both_present) = opt(pair(tag("fit-in/"), tag("stretch/")))(input)?;
 
     match (fit, both_present) {
-        (Some(_), Some(_)) => Ok((input, Some(Fit::FitIn))), // Default to FitIn if both are present
+        (Some((_, adaptive, full)), Some(_)) => Ok((input, Some((Fit::FitIn, adaptive, full)))), // Default to FitIn if both are present
         (Some(f), None) => Ok((input, Some(f))),
-        (None, Some(_)) => Ok((input, Some(Fit::FitIn))), // Default to FitIn if both are present
+        (None, Some(_)) => Ok((input, Some((Fit::FitIn, false, false)))), // Default to FitIn if both are present
         (None, None) => Ok((input, None)),
     }
 }
@@ -183,6 +226,7 @@ fn parse_alignment(
                 value(VAlign::Top, tag("top")),
                 value(VAlign::Bottom, tag("bottom")),
                 value(VAlign::Middle, tag("middle")),
+                value(VAlign::Middle, tag("center")),
             )),
             char('/'),
         )),
@@ -198,6 +242,7 @@ fn parse_color(input: &str) -> IResult<&str, Color, VerboseError<&str>> {
         map(tag_no_case("auto"), |_| Color::Auto),
         map(tag_no_case("blur"), |_| Color::Blur),
         map(tag_no_case("none"), |_| Color::None),
+        map(tag_no_case("transparent"), |_| Color::None),
         map(
             tuple((
                 nom::character::complete::u8,
@@ -209,7 +254,10 @@ fn parse_color(input: &str) -> IResult<&str, Color, VerboseError<&str>> {
             |(r, _, g, _, b)| Color::Rgb(r, g, b),
         ),
         map(
-            preceded(char('#'), take_while_m_n(6, 6, |c: char| c.is_hex_digit())),
+            preceded(
+                opt(char('#')),
+                take_while_m_n(6, 6, |c: char| c.is_hex_digit()),
+            ),
             |hex: &str| Color::Hex(hex.to_string()),
         ),
         map(
@@ -254,7 +302,7 @@ fn take_until_unbalanced(input: &str) -> IResult<&str, &str, VerboseError<&str>>
     // Skip the first opening parenthesis
     if let Some((_, '(')) = chars.next() {
         let start_idx = 1;
-        while let Some((idx, ch)) = chars.next() {
+        for (idx, ch) in chars {
             match ch {
                 '(' => depth += 1,
                 ')' => {
@@ -278,18 +326,49 @@ fn take_until_unbalanced(input: &str) -> IResult<&str, &str, VerboseError<&str>>
 }
 
 fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
+    // once it looks like NAME(ARGS) a bad filter is a hard error, rather than
+    // letting the image parser swallow the rest of the path
+    pair(
+        take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        take_until_unbalanced,
+    )(input)?;
+
+    cut(parse_filter_call)(input)
+}
+
+fn parse_filter_call(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
     let (input, name) = take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)?;
     let (input, args) = take_until_unbalanced(input)?;
 
-    let (remaining_input, filter) = match name.to_lowercase().as_str() {
+    // accept both the documented snake_case names and the legacy concatenated form
+    let (remaining_input, filter) = match name.to_lowercase().replace('_', "").as_str() {
+        "affine" => {
+            let (_, affine) = map(parse_affine_params, Filter::Affine)(args)?;
+            (input, affine)
+        }
+        "autocontrast" => (input, Filter::AutoContrast),
         "backgroundcolor" => {
             let (_, color) = parse_color(args)?;
             (input, Filter::BackgroundColor(color))
         }
+        "bitdepth" => {
+            let (_, bitdepth) = map(parse_bitdepth, Filter::Bitdepth)(args)?;
+            (input, bitdepth)
+        }
         "blur" => {
-            let (_, blur) = map(parse_f32, Filter::Blur)(args)?;
+            let (_, blur) = map(
+                pair(parse_f32, opt(preceded(char(','), parse_region))),
+                |(sigma, region)| Filter::Blur(sigma, region),
+            )(args)?;
             (input, blur)
         }
+        "border" => {
+            let (_, border) = map(
+                separated_pair(parse_border_width, char(','), parse_color),
+                |(width, color)| Filter::Border(width, color),
+            )(args)?;
+            (input, border)
+        }
         "brightness" => {
             let (_, brightness) = map(nom::character::complete::i32, Filter::Brightness)(args)?;
             (input, brightness)
@@ -298,6 +377,14 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             let (_, contrast) = map(nom::character::complete::i32, Filter::Contrast)(args)?;
             (input, contrast)
         }
+        "blurhash" => {
+            let (_, output) = parse_hash_output(args)?;
+            (input, Filter::Blurhash(output))
+        }
+        "thumbhash" => {
+            let (_, output) = parse_hash_output(args)?;
+            (input, Filter::Thumbhash(output))
+        }
         "fill" => {
             let (_, color) = parse_color(args)?;
             (input, Filter::Fill(color))
@@ -306,6 +393,7 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             let (_, focal_point) = parse_focal_point(args)?;
             (input, Filter::Focal(focal_point))
         }
+        "format" if args.eq_ignore_ascii_case("auto") => (input, Filter::AutoFormat),
         "format" => {
             let image_type = match args.to_uppercase().as_str() {
                 "GIF" => ImageType::GIF,
@@ -328,15 +416,50 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             };
             (input, Filter::Format(image_type))
         }
+        "frames" => {
+            let (_, frames) =
+                map(parse_frames_params, |(n, delay)| Filter::Frames(n, delay))(args)?;
+            (input, frames)
+        }
+        "gamma" => {
+            let (_, gamma) = map(parse_gamma, Filter::Gamma)(args)?;
+            (input, gamma)
+        }
+        "equalize" => (input, Filter::Equalize),
         "grayscale" => (input, Filter::Grayscale),
         "hue" => {
             let (_, hue) = map(parse_f32, Filter::Hue)(args)?;
             (input, hue)
         }
+        "kernel" => match ResizeKernel::from_name(args) {
+            Some(kernel) => (input, Filter::Kernel(kernel)),
+            None => {
+                return Err(nom::Err::Error(VerboseError {
+                    errors: vec![(
+                        args,
+                        VerboseErrorKind::Context(
+                            "Kernel must be nearest, linear, cubic, mitchell, lanczos2 or lanczos3",
+                        ),
+                    )],
+                }))
+            }
+        },
         "label" => {
             let (_, label) = map(parse_label_params, Filter::Label)(args)?;
             (input, label)
         }
+        "curves" => {
+            let (_, curves) = map(separated_list1(char(';'), parse_curve), Filter::Curves)(args)?;
+            (input, curves)
+        }
+        "denoise" => {
+            let (_, denoise) = map(parse_f32, Filter::Denoise)(args)?;
+            (input, denoise)
+        }
+        "duotone" => {
+            let (_, (dark, light)) = separated_pair(parse_color, char(','), parse_color)(args)?;
+            (input, Filter::Duotone(dark, light))
+        }
         "maxbytes" => {
             let (_, max_bytes) = map(nom::character::complete::u64, |v| {
                 Filter::MaxBytes(v as usize)
@@ -358,14 +481,46 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             let (_, orient) = map(nom::character::complete::i32, Filter::Orient)(args)?;
             (input, orient)
         }
+        "palette" => {
+            let (_, palette) = map(parse_palette_size, Filter::Palette)(args)?;
+            (input, palette)
+        }
         "page" => {
             let (_, page) = map(nom::character::complete::u64, |v| Filter::Page(v as usize))(args)?;
             (input, page)
         }
+        "perspective" => {
+            let (_, perspective) = map(parse_perspective_params, Filter::Perspective)(args)?;
+            (input, perspective)
+        }
+        "pixelate" => {
+            let (_, pixelate) = map(nom::character::complete::u32, Filter::Pixelate)(args)?;
+            (input, pixelate)
+        }
+        "pixelateregion" => {
+            let (_, pixelate) = map(
+                separated_pair(parse_region, char(','), nom::character::complete::u32),
+                |(region, size)| Filter::PixelateRegion(region, size),
+            )(args)?;
+            (input, pixelate)
+        }
+        "frame" => {
+            let (_, frame) =
+                map(nom::character::complete::u64, |v| Filter::Frame(v as usize))(args)?;
+            (input, frame)
+        }
+        "seek" => {
+            let (_, seek) = map(parse_seek, Filter::Seek)(args)?;
+            (input, seek)
+        }
         "dpi" => {
             let (_, dpi) = map(nom::character::complete::u32, Filter::Dpi)(args)?;
             (input, dpi)
         }
+        "dpr" => {
+            let (_, dpr) = map(parse_dpr, Filter::Dpr)(args)?;
+            (input, dpr)
+        }
         "proportion" => {
             let (_, proportion) = map(parse_f32, Filter::Proportion)(args)?;
             (input, proportion)
@@ -374,10 +529,12 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             let (_, quality) = map(nom::character::complete::u8, Filter::Quality)(args)?;
             (input, quality)
         }
+        "raw" => (input, Filter::Raw),
         "rgb" => {
             let (_, rgb) = map(parse_rgb, |(r, g, b)| Filter::Rgb(r, g, b))(args)?;
             (input, rgb)
         }
+        "noupscale" => (input, Filter::NoUpscale),
         "rotate" => {
             let (_, rotate) = map(nom::character::complete::i32, Filter::Rotate)(args)?;
             (input, rotate)
@@ -394,19 +551,62 @@ fn parse_filter(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
             let (_, sharpen) = map(parse_f32, Filter::Sharpen)(args)?;
             (input, sharpen)
         }
+        "sepia" => (input, Filter::Sepia),
+        "shadow" => {
+            let (_, shadow) = map(
+                tuple((
+                    terminated(parse_shadow_offset, char(',')),
+                    terminated(parse_shadow_offset, char(',')),
+                    terminated(parse_shadow_sigma, char(',')),
+                    parse_color,
+                )),
+                |(x, y, sigma, color)| Filter::Shadow(x, y, sigma, color),
+            )(args)?;
+            (input, shadow)
+        }
         "stripexif" => (input, Filter::StripExif),
         "stripicc" => (input, Filter::StripIcc),
         "stripmetadata" => (input, Filter::StripMetadata),
+        "superres" => {
+            let (_, superres) = map(parse_superres_scale, Filter::Superres)(args)?;
+            (input, superres)
+        }
+        "tint" => {
+            let (_, (color, strength)) = separated_pair(parse_color, char(','), parse_f32)(args)?;
+            (input, Filter::Tint(color, strength))
+        }
         "upscale" => (input, Filter::Upscale),
+        "var" => {
+            let (_, var) = parse_var(args)?;
+            (input, var)
+        }
+        "vibrance" => {
+            let (_, vibrance) = map(nom::character::complete::i32, Filter::Vibrance)(args)?;
+            (input, vibrance)
+        }
+        "usm" => {
+            let (_, usm) = map(parse_usm_params, |(radius, amount, threshold)| {
+                Filter::Usm(radius, amount, threshold)
+            })(args)?;
+            (input, usm)
+        }
         "watermark" => {
             let (_, watermark) = map(parse_watermark_params, Filter::Watermark)(args)?;
             (input, watermark)
         }
-        _ => {
-            return Err(nom::Err::Error(VerboseError {
-                errors: vec![(input, VerboseErrorKind::Context("Unknown filter"))],
-            }))
-        }
+        _ => match FilterRegistry::global().parse(name, args) {
+            Some(Ok(custom)) => (input, Filter::Custom(custom)),
+            Some(Err(message)) => {
+                return Err(nom::Err::Error(VerboseError {
+                    errors: vec![(args, VerboseErrorKind::Context(message))],
+                }))
+            }
+            None => {
+                return Err(nom::Err::Error(VerboseError {
+                    errors: vec![(name, VerboseErrorKind::Context("unknown filter"))],
+                }))
+            }
+        },
     };
 
     Ok((remaining_input, filter))
@@ -419,6 +619,84 @@ fn parse_filters(input: &str) -> IResult<&str, Vec<Filter>, VerboseError<&str>>
     )(input)
 }
 
+/// `n[,delay]`, delay in milliseconds
+fn parse_frames_params(input: &str) -> IResult<&str, (usize, u32), VerboseError<&str>> {
+    let (input, n) = map(nom::character::complete::u64, |v| v as usize)(input)?;
+    let (input, delay) = opt(preceded(char(','), nom::character::complete::u32))(input)?;
+    Ok((input, (n, delay.unwrap_or(DEFAULT_FRAME_DELAY_MS))))
+}
+
+/// Higher ratios would only multiply the output past any useful density
+const MAX_DPR: f32 = 5.0;
+
+fn parse_dpr(input: &str) -> IResult<&str, F32, VerboseError<&str>> {
+    let (rest, dpr) = parse_f32(input)?;
+    if dpr.0 <= 0.0 || dpr.0 > MAX_DPR {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Device pixel ratio must be above 0 and at most 5"),
+            )],
+        }));
+    }
+    Ok((rest, dpr))
+}
+
+fn parse_bitdepth(input: &str) -> IResult<&str, u8, VerboseError<&str>> {
+    let (rest, depth) = nom::character::complete::u8(input)?;
+    if !matches!(depth, 8 | 10 | 12 | 16) {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Bit depth must be 8, 10, 12 or 16"),
+            )],
+        }));
+    }
+    Ok((rest, depth))
+}
+
+/// More colors than a theme can use, and k-means gets slow past this
+const MAX_PALETTE_SIZE: u64 = 16;
+
+fn parse_palette_size(input: &str) -> IResult<&str, usize, VerboseError<&str>> {
+    let (rest, n) = nom::character::complete::u64(input)?;
+    if n == 0 || n > MAX_PALETTE_SIZE {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Palette size must be between 1 and 16"),
+            )],
+        }));
+    }
+    Ok((rest, n as usize))
+}
+
+fn parse_hash_output(input: &str) -> IResult<&str, HashOutput, VerboseError<&str>> {
+    match input {
+        "" => Ok((input, HashOutput::Text)),
+        _ if input.eq_ignore_ascii_case("json") => Ok(("", HashOutput::Json)),
+        _ => Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Hash output must be empty or json"),
+            )],
+        })),
+    }
+}
+
+fn parse_seek(input: &str) -> IResult<&str, F32, VerboseError<&str>> {
+    let (rest, seconds) = parse_f32(input)?;
+    if seconds.0 < 0.0 {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Seek time can't be negative"),
+            )],
+        }));
+    }
+    Ok((rest, seconds))
+}
+
 fn parse_modulate_params(input: &str) -> IResult<&str, (F32, F32, F32), VerboseError<&str>> {
     let (input, modulate) = separated_list1(char(','), parse_f32)(input)?;
     if modulate.len() != 3 {
@@ -433,6 +711,157 @@ fn parse_modulate_params(input: &str) -> IResult<&str, (F32, F32, F32), VerboseE
     }
 }
 
+/// `left,top,right,bottom`
+fn parse_region(input: &str) -> IResult<&str, Region, VerboseError<&str>> {
+    map(
+        tuple((
+            terminated(parse_f32, char(',')),
+            terminated(parse_f32, char(',')),
+            terminated(parse_f32, char(',')),
+            parse_f32,
+        )),
+        |(left, top, right, bottom)| Region {
+            left,
+            top,
+            right,
+            bottom,
+        },
+    )(input)
+}
+
+fn parse_superres_scale(input: &str) -> IResult<&str, u32, VerboseError<&str>> {
+    let (rest, scale) = nom::character::complete::u32(input)?;
+    if !(2..=8).contains(&scale) {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Superres scale must be 2 to 8"),
+            )],
+        }));
+    }
+    Ok((rest, scale))
+}
+
+/// Wider borders and farther shadows only grow the canvas, the processor
+/// still checks what they'd come to against its limits
+const MAX_FRAME: u32 = 1000;
+
+/// Blurs spread about three sigmas, so this keeps shadows within the frame
+const MAX_SHADOW_SIGMA: f32 = 100.0;
+
+fn parse_border_width(input: &str) -> IResult<&str, u32, VerboseError<&str>> {
+    let (rest, width) = nom::character::complete::u32(input)?;
+    if width > MAX_FRAME {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Border width must be at most 1000"),
+            )],
+        }));
+    }
+    Ok((rest, width))
+}
+
+fn parse_shadow_offset(input: &str) -> IResult<&str, i32, VerboseError<&str>> {
+    let (rest, offset) = nom::character::complete::i32(input)?;
+    if offset.unsigned_abs() > MAX_FRAME {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Shadow offsets must be -1000 to 1000"),
+            )],
+        }));
+    }
+    Ok((rest, offset))
+}
+
+fn parse_shadow_sigma(input: &str) -> IResult<&str, F32, VerboseError<&str>> {
+    let (rest, sigma) = parse_f32(input)?;
+    if !(0.0..=MAX_SHADOW_SIGMA).contains(&sigma.0) {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Shadow sigma must be 0 to 100"),
+            )],
+        }));
+    }
+    Ok((rest, sigma))
+}
+
+fn parse_gamma(input: &str) -> IResult<&str, F32, VerboseError<&str>> {
+    let (rest, gamma) = parse_f32(input)?;
+    if gamma.0 <= 0.0 {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(input, VerboseErrorKind::Context("Gamma must be above 0"))],
+        }));
+    }
+    Ok((rest, gamma))
+}
+
+/// `[r=|g=|b=]input:output,...`
+fn parse_curve(input: &str) -> IResult<&str, Curve, VerboseError<&str>> {
+    let channel = alt((
+        value(Channel::R, tag_no_case("r")),
+        value(Channel::G, tag_no_case("g")),
+        value(Channel::B, tag_no_case("b")),
+    ));
+    let point = separated_pair(
+        nom::character::complete::u8,
+        char(':'),
+        nom::character::complete::u8,
+    );
+    map(
+        pair(
+            opt(terminated(channel, char('='))),
+            separated_list1(char(','), point),
+        ),
+        |(channel, points)| Curve { channel, points },
+    )(input)
+}
+
+fn parse_usm_params(input: &str) -> IResult<&str, (F32, F32, F32), VerboseError<&str>> {
+    let (input, usm) = separated_list1(char(','), parse_f32)(input)?;
+    if usm.len() != 3 {
+        Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Unsharp mask requires radius, amount and threshold"),
+            )],
+        }))
+    } else {
+        Ok((input, (usm[0], usm[1], usm[2])))
+    }
+}
+
+fn parse_affine_params(input: &str) -> IResult<&str, [F32; 6], VerboseError<&str>> {
+    let (input, values) = separated_list1(char(','), parse_f32)(input)?;
+    match <[F32; 6]>::try_from(values) {
+        Ok(matrix) => Ok((input, matrix)),
+        Err(_) => Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Affine requires a, b, c, d, tx and ty"),
+            )],
+        })),
+    }
+}
+
+fn parse_perspective_params(input: &str) -> IResult<&str, [(F32, F32); 4], VerboseError<&str>> {
+    let (input, values) = separated_list1(char(','), parse_f32)(input)?;
+    if values.len() != 8 {
+        return Err(nom::Err::Error(VerboseError {
+            errors: vec![(
+                input,
+                VerboseErrorKind::Context("Perspective requires four x,y corners"),
+            )],
+        }));
+    }
+    Ok((
+        input,
+        std::array::from_fn(|i| (values[2 * i], values[2 * i + 1])),
+    ))
+}
+
 fn parse_rgb(input: &str) -> IResult<&str, (F32, F32, F32), VerboseError<&str>> {
     let (input, rgb) = separated_list1(char(','), parse_f32)(input)?;
     if rgb.len() != 3 {
@@ -445,14 +874,20 @@ fn parse_rgb(input: &str) -> IResult<&str, (F32, F32, F32), VerboseError<&str>>
 }
 
 fn parse_label_params(input: &str) -> IResult<&str, LabelParams, VerboseError<&str>> {
-    let (input, (text, x, y, size, color, alpha, font)) = tuple((
+    let (input, (text, x, y, size, color, alpha, font, padding, wrap)) = tuple((
         take_while1(|c| c != ','),
         preceded(char(','), parse_label_position),
         preceded(char(','), parse_label_position),
         preceded(char(','), nom::character::complete::u32),
         preceded(char(','), parse_color),
-        opt(preceded(char(','), nom::character::complete::u8)),
-        opt(preceded(char(','), take_while1(|c| c != ','))),
+        // an empty arg skips an optional one to get to those after it
+        opt(alt((
+            map(preceded(char(','), nom::character::complete::u8), Some),
+            value(None, terminated(char(','), peek(char(',')))),
+        ))),
+        opt(preceded(char(','), take_while(|c| c != ','))),
+        opt(preceded(char(','), opt(nom::character::complete::u32))),
+        opt(preceded(char(','), nom::character::complete::u32)),
     ))(input)?;
 
     Ok((
@@ -463,12 +898,25 @@ fn parse_label_params(input: &str) -> IResult<&str, LabelParams, VerboseError<&s
             y,
             size,
             color,
-            alpha,
-            font: font.map(|s| s.to_string()),
+            alpha: alpha.flatten(),
+            font: font.filter(|s| !s.is_empty()).map(|s| s.to_string()),
+            padding: padding.flatten(),
+            wrap,
         },
     ))
 }
 
+fn parse_var(input: &str) -> IResult<&str, Filter, VerboseError<&str>> {
+    map(
+        separated_pair(
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            char(','),
+            nom::combinator::rest,
+        ),
+        |(name, value): (&str, &str)| Filter::Var(name.to_string(), value.to_string()),
+    )(input)
+}
+
 fn parse_label_position(input: &str) -> IResult<&str, LabelPosition, VerboseError<&str>> {
     alt((
         value(LabelPosition::Left, tag("left")),
@@ -476,8 +924,8 @@ fn parse_label_position(input: &str) -> IResult<&str, LabelPosition, VerboseErro
         value(LabelPosition::Center, tag("center")),
         value(LabelPosition::Top, tag("top")),
         value(LabelPosition::Bottom, tag("bottom")),
+        map(terminated(parse_f32, char('p')), LabelPosition::Percentage),
         map(nom::character::complete::i32, LabelPosition::Pixels),
-        map(parse_f32, LabelPosition::Percentage),
     ))(input)
 }
 
@@ -506,7 +954,7 @@ fn parse_watermark_params(input: &str) -> IResult<&str, WatermarkParams, Verbose
     Ok((
         input,
         WatermarkParams {
-            image: image.to_string(),
+            image: query_unescape(image),
             x,
             y,
             alpha,
@@ -524,21 +972,29 @@ fn parse_watermark_position(input: &str) -> IResult<&str, WatermarkPosition, Ver
         value(WatermarkPosition::Top, tag("top")),
         value(WatermarkPosition::Bottom, tag("bottom")),
         value(WatermarkPosition::Repeat, tag("repeat")),
+        map(
+            terminated(parse_f32, char('p')),
+            WatermarkPosition::Percentage,
+        ),
         map(nom::character::complete::i32, WatermarkPosition::Pixels),
-        map(parse_f32, WatermarkPosition::Percentage),
     ))(input)
 }
 
+fn is_image_char(c: char) -> bool {
+    c.is_alphanumeric() || "-._~:/?@!$&'*+,;=%".contains(c)
+}
+
+/// Query-unescape an image URI or label text, same as imagor. Anything
+/// that isn't valid percent-encoded UTF-8 is passed through untouched.
+pub(crate) fn query_unescape(input: &str) -> String {
+    percent_decode_str(&input.replace('+', " "))
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .unwrap_or_else(|_| input.to_string())
+}
+
 fn parse_image(input: &str) -> IResult<&str, String, VerboseError<&str>> {
-    recognize(many1(alt((
-        alphanumeric1,
-        tag("."),
-        tag("-"),
-        tag("_"),
-        tag("/"),
-        tag(":"),
-    ))))(input)
-    .map(|(next_input, result)| (next_input, result.to_string()))
+    map(take_while1(is_image_char), query_unescape)(input)
 }
 
 #[tracing::instrument]
@@ -548,12 +1004,13 @@ pub fn parse_path(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
         map(
             tuple((
                 opt(char('/')),
-                context("parse_unsafe", opt(parse_unsafe)),
+                context("parse_signature", opt(parse_signature)),
                 context("parse_meta", opt(parse_meta)),
                 context("parse_trim", opt(parse_trim)),
                 context("parse_crop", opt(parse_crop)),
                 context("parse_fit", opt(parse_fit)),
                 context("parse_dimensions", opt(parse_dimensions)),
+                context("parse_padding", opt(parse_padding)),
                 context("parse_alignment", opt(parse_alignment)),
                 context("parse_smart", opt(parse_smart)),
                 context("parse_filters", opt(parse_filters)),
@@ -561,19 +1018,21 @@ pub fn parse_path(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
             )),
             |(
                 _,
-                unsafe_,
+                signature,
                 meta,
                 trim_details,
                 crop,
                 fit,
                 dimensions,
+                padding,
                 alignment,
                 smart,
                 filters,
                 image,
             )| {
                 Params {
-                    unsafe_: unsafe_.unwrap_or_default(),
+                    unsafe_: signature.as_ref().is_some_and(|(unsafe_, _)| *unsafe_),
+                    hash: signature.and_then(|(_, hash)| hash),
                     path: Some(input.to_string()),
                     image,
                     trim: trim_details.as_ref().map(|t| t.0).unwrap_or_default(),
@@ -585,6 +1044,10 @@ pub fn parse_path(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
                     crop_bottom: crop.map(|(_, _, _, bottom)| bottom),
                     width: dimensions.and_then(|(width, _, _, _)| width),
                     height: dimensions.and_then(|(_, height, _, _)| height),
+                    padding_left: padding.map(|(left, _, _, _)| left),
+                    padding_top: padding.map(|(_, top, _, _)| top),
+                    padding_right: padding.map(|(_, _, right, _)| right),
+                    padding_bottom: padding.map(|(_, _, _, bottom)| bottom),
                     meta: meta.unwrap_or_default(),
                     h_flip: dimensions
                         .map(|(_, _, h_flip, _)| h_flip)
@@ -599,7 +1062,9 @@ pub fn parse_path(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
                         .as_ref()
                         .and_then(|(_, v_align)| v_align.to_owned()),
                     smart: smart.unwrap_or_default(),
-                    fit: fit.unwrap_or_default(),
+                    fit: fit.flatten().map(|(fit, _, _)| fit),
+                    adaptive_fit_in: fit.flatten().is_some_and(|(_, adaptive, _)| adaptive),
+                    full_fit_in: fit.flatten().is_some_and(|(_, _, full)| full),
                     filters: filters.unwrap_or_default(),
                     ..Default::default()
                 }
@@ -612,6 +1077,8 @@ pub fn parse_path(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
 mod tests {
 
     use super::*;
+    use crate::imagorpath::filter::CustomFilter;
+    use crate::imagorpath::hasher::suffix_result_storage_hasher;
     use crate::imagorpath::params::{Fit, HAlign, TrimBy, VAlign};
     use nom::error::convert_error;
     use pretty_assertions::assert_eq;
@@ -667,6 +1134,7 @@ mod tests {
         let expected_params = Params {
             path: Some("unsafe/30x40:100x150/filters:fill(cyan)/raw.githubusercontent.com/cshum/imagor/master/testdata/dancing-banana.gif".to_string()),
             image: Some("raw.githubusercontent.com/cshum/imagor/master/testdata/dancing-banana.gif".to_string()),
+            unsafe_: true,
             trim: false,
             trim_by: TrimBy::TopLeft,
             crop_left: Some(F32(30.0)),
@@ -696,6 +1164,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_signed_path() {
+        let params = parse_params("/cST4Ko5_FqwT3BDn-Wf4gO3RFSk=/500x500/top/img.png").unwrap();
+        assert_eq!(params.hash.as_deref(), Some("cST4Ko5_FqwT3BDn-Wf4gO3RFSk="));
+        assert!(!params.unsafe_);
+        assert_eq!(params.width, Some(500));
+        assert_eq!(params.image.as_deref(), Some("img.png"));
+
+        let params = parse_params("unsafe/500x500/img.png").unwrap();
+        assert_eq!(params.hash, None);
+        assert!(params.unsafe_);
+
+        // a folder that only looks like one is part of the image
+        let params = parse_params("some-folder/img.png").unwrap();
+        assert_eq!(params.hash, None);
+        assert_eq!(params.image.as_deref(), Some("some-folder/img.png"));
+    }
+
     #[test]
     fn test_parse_generate_url_image() {
         let uri = "meta/trim:bottom-right:100/10x11:12x13/fit-in/-300x-200/left/top/smart/filters:grayscale()/s.glbimg.com/es/ge/f/original/2011/03/29/orlandosilva_60.jpg";
@@ -787,12 +1273,547 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_parse_params_unknown_filter() {
+        let err =
+            parse_params("unsafe/fit-in/100x100/filters:grayscale():nope(1)/img.jpg").unwrap_err();
+
+        assert_eq!(
+            err,
+            ParseError {
+                offset: 42,
+                segment: "filters:grayscale():nope(1)".to_string(),
+                expected: "unknown filter".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_params_registered_filter() {
+        FilterRegistry::global()
+            .register(
+                "test_tint",
+                |args| match args {
+                    "" => Err("test_tint needs a color"),
+                    _ => Ok(args.to_lowercase()),
+                },
+                |img, _| Ok(img.clone()),
+            )
+            .unwrap();
+
+        let params = parse_params("unsafe/filters:testTint(FF0000):grayscale()/img.jpg").unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Custom(CustomFilter {
+                    name: "test_tint".to_string(),
+                    args: "ff0000".to_string(),
+                }),
+                Filter::Grayscale,
+            ]
+        );
+        assert_eq!(params.filters[0].to_string(), "test_tint(ff0000)");
+
+        let err = parse_params("unsafe/filters:test_tint()/img.jpg").unwrap_err();
+        assert_eq!(err.expected, "test_tint needs a color");
+    }
+
+    #[test]
+    fn test_parse_params_bad_filter_argument() {
+        let err = parse_params("unsafe/filters:blur(abc)/img.jpg").unwrap_err();
+
+        assert_eq!(err.offset, 20);
+        assert_eq!(err.segment, "filters:blur(abc)");
+        assert_eq!(err.expected, "expected filters:NAME(ARGS)");
+    }
+
+    #[test]
+    fn test_parse_params_trailing_input() {
+        let err = parse_params("unsafe/100x100/img(1).jpg").unwrap_err();
+
+        assert_eq!(err.offset, 18);
+        assert_eq!(err.segment, "img(1).jpg");
+        assert_eq!(err.expected, "expected end of path");
+    }
+
+    #[test]
+    fn test_parse_params_ok() {
+        let params = parse_params("unsafe/100x100/img.jpg").unwrap();
+        assert_eq!(params.image, Some("img.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_parse_error_json_schema() {
+        let err = parse_params("unsafe/filters:nope()/img.jpg").unwrap_err();
+        let body = serde_json::to_value(&err).unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "offset": 15,
+                "segment": "filters:nope()",
+                "expected": "unknown filter",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_thumbor_fit_in_aliases() {
+        let (_, p) = parse_path("adaptive-fit-in/300x200/img.jpg").unwrap();
+        assert_eq!(
+            (p.fit, p.adaptive_fit_in, p.full_fit_in),
+            (Some(Fit::FitIn), true, false)
+        );
+
+        let (_, p) = parse_path("full-fit-in/300x200/img.jpg").unwrap();
+        assert_eq!(
+            (p.fit, p.adaptive_fit_in, p.full_fit_in),
+            (Some(Fit::FitIn), false, true)
+        );
+
+        let (_, p) = parse_path("adaptive-full-fit-in/300x200/img.jpg").unwrap();
+        assert_eq!(
+            (p.fit, p.adaptive_fit_in, p.full_fit_in),
+            (Some(Fit::FitIn), true, true)
+        );
+        assert_eq!(p.image, Some("img.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_parse_thumbor_legacy_filters() {
+        let (_, p) =
+            parse_path("300x200/left/center/filters:fill(transparent):no_upscale()/img.jpg")
+                .unwrap();
+
+        assert_eq!(p.h_align, Some(HAlign::Left));
+        assert_eq!(p.v_align, Some(VAlign::Middle));
+        assert_eq!(
+            p.filters,
+            vec![Filter::Fill(Color::None), Filter::NoUpscale]
+        );
+    }
+
+    #[test]
+    fn test_parse_path_with_padding() {
+        let (rest, result) = parse_path("fit-in/300x200/10x20:30x40/img.jpg").unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(result.width, Some(300));
+        assert_eq!(result.height, Some(200));
+        assert_eq!(
+            (
+                result.padding_left,
+                result.padding_top,
+                result.padding_right,
+                result.padding_bottom
+            ),
+            (Some(10), Some(20), Some(30), Some(40))
+        );
+        assert_eq!(result.image, Some("img.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_parse_path_with_symmetric_padding() {
+        let (_, result) = parse_path("300x200/15x5/img.jpg").unwrap();
+
+        assert_eq!(
+            (
+                result.padding_left,
+                result.padding_top,
+                result.padding_right,
+                result.padding_bottom
+            ),
+            (Some(15), Some(5), Some(15), Some(5))
+        );
+    }
+
+    #[test]
+    fn test_parse_path_with_encoded_image() {
+        let input =
+            "unsafe/fit-in/100x100/https%3A%2F%2Fexample.com%2Fimg%20name.jpg%3Fw%3D1%26h%3D2";
+        let (rest, result) = parse_path(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(
+            result.image,
+            Some("https://example.com/img name.jpg?w=1&h=2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_path_with_query_string_image() {
+        let input = "unsafe/100x100/filters:grayscale()/https://example.com/img.jpg?w=1&h=2";
+        let (rest, result) = parse_path(input).unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(
+            result.image,
+            Some("https://example.com/img.jpg?w=1&h=2".to_string())
+        );
+        assert_eq!(result.filters, vec![Filter::Grayscale]);
+    }
+
+    #[test]
+    fn test_parse_watermark_with_encoded_image() {
+        let (_, result) =
+            parse_filters("filters:watermark(example.com%2Fa%2Cb.png,0,0,0)").unwrap();
+
+        assert_eq!(
+            result,
+            vec![Filter::Watermark(WatermarkParams {
+                image: "example.com/a,b.png".to_string(),
+                x: WatermarkPosition::Pixels(0),
+                y: WatermarkPosition::Pixels(0),
+                alpha: 0,
+                w_ratio: None,
+                h_ratio: None,
+            })]
+        );
+    }
+
+    #[test]
+    fn test_parse_dpr_filter() {
+        let (_, params) = parse_path("unsafe/300x200/filters:dpr(1.5)/img.jpg").unwrap();
+        assert_eq!(params.filters, vec![Filter::Dpr(F32(1.5))]);
+
+        assert!(parse_params("unsafe/300x200/filters:dpr(0)/img.jpg").is_err());
+        assert!(parse_params("unsafe/300x200/filters:dpr(8)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_bitdepth_filter() {
+        let (_, params) = parse_path("unsafe/filters:bitdepth(16):format(png)/img.tif").unwrap();
+        assert_eq!(
+            params.filters,
+            vec![Filter::Bitdepth(16), Filter::Format(ImageType::PNG)]
+        );
+
+        assert!(parse_params("unsafe/filters:bitdepth(9)/img.tif").is_err());
+        assert!(parse_params("unsafe/filters:bitdepth(256)/img.tif").is_err());
+    }
+
+    #[test]
+    fn test_parse_kernel_filter() {
+        let (_, params) = parse_path("unsafe/64x64/filters:kernel(Nearest)/sprite.png").unwrap();
+        assert_eq!(params.filters, vec![Filter::Kernel(ResizeKernel::Nearest)]);
+        assert_eq!(params.filters[0].to_string(), "kernel(nearest)");
+
+        assert!(parse_params("unsafe/64x64/filters:kernel(bilinear)/sprite.png").is_err());
+    }
+
+    #[test]
+    fn test_parse_usm_and_denoise_filters() {
+        let (_, params) = parse_path("unsafe/filters:usm(1.5,0.8,4):denoise(2)/scan.tif").unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Usm(F32(1.5), F32(0.8), F32(4.0)),
+                Filter::Denoise(F32(2.0))
+            ]
+        );
+
+        assert!(parse_params("unsafe/filters:usm(1.5,0.8)/scan.tif").is_err());
+    }
+
+    #[test]
+    fn test_parse_toning_filters() {
+        let (_, params) =
+            parse_path("unsafe/filters:sepia():tint(ff8800,40):duotone(20,20,60,white)/img.jpg")
+                .unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Sepia,
+                Filter::Tint(Color::Hex("ff8800".to_string()), F32(40.0)),
+                Filter::Duotone(Color::Rgb(20, 20, 60), Color::Named(NamedColor::White)),
+            ]
+        );
+
+        assert!(parse_params("unsafe/filters:tint(red)/img.jpg").is_err());
+        assert!(parse_params("unsafe/filters:duotone(black)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_tone_filters() {
+        let path = "unsafe/filters:gamma(2.2):curves(64:48,192:210;r=0:10,255:245)/img.jpg";
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Gamma(F32(2.2)),
+                Filter::Curves(vec![
+                    Curve {
+                        channel: None,
+                        points: vec![(64, 48), (192, 210)],
+                    },
+                    Curve {
+                        channel: Some(Channel::R),
+                        points: vec![(0, 10), (255, 245)],
+                    },
+                ]),
+            ]
+        );
+        assert_eq!(
+            params.filters[1].to_string(),
+            "curves(64:48,192:210;r=0:10,255:245)"
+        );
+
+        assert!(parse_params("unsafe/filters:gamma(0)/img.jpg").is_err());
+        assert!(parse_params("unsafe/filters:curves(x=1:2)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_level_filters() {
+        let path = "unsafe/filters:vibrance(40):auto_contrast():equalize()/img.jpg";
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.filters,
+            vec![Filter::Vibrance(40), Filter::AutoContrast, Filter::Equalize]
+        );
+        assert_eq!(params.filters[1].to_string(), "autocontrast()");
+    }
+
+    #[test]
+    fn test_parse_superres() {
+        let (_, params) = parse_path("unsafe/filters:superres(4)/img.jpg").unwrap();
+        assert_eq!(params.filters, vec![Filter::Superres(4)]);
+        assert_eq!(params.filters[0].to_string(), "superres(4)");
+
+        assert!(parse_params("unsafe/filters:superres(1)/img.jpg").is_err());
+        assert!(parse_params("unsafe/filters:superres(16)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_label_templates() {
+        let label = |path: &str| match parse_params(path).unwrap().filters.as_slice() {
+            [.., Filter::Label(label)] => label.clone(),
+            filters => panic!("no label in {:?}", filters),
+        };
+
+        let legacy = label("unsafe/filters:label(hi,10,10,20,red,arial)/img.jpg");
+        assert_eq!(
+            (legacy.alpha, legacy.font.as_deref()),
+            (None, Some("arial"))
+        );
+        assert_eq!(legacy.to_string(), "hi,10,10,20,red,arial");
+
+        let padded =
+            label("unsafe/filters:label({width}x{height},right,bottom,20,fff,,,12,300)/img.jpg");
+        assert_eq!(padded.text, "{width}x{height}");
+        assert_eq!((padded.alpha, padded.font.as_deref()), (None, None));
+        assert_eq!((padded.padding, padded.wrap), (Some(12), Some(300)));
+        assert_eq!(
+            padded.to_string(),
+            "{width}x{height},right,bottom,20,fff,,,12,300"
+        );
+
+        let (_, params) = parse_path(
+            "filters:var(owner,Jo%2C Co):label(© {owner},left,top,20,red,128,,8)/img.jpg",
+        )
+        .unwrap();
+        assert_eq!(
+            params.filters[0],
+            Filter::Var("owner".to_string(), "Jo%2C Co".to_string())
+        );
+        assert_eq!(params.filters[0].to_string(), "var(owner,Jo%2C Co)");
+        let Filter::Label(label) = &params.filters[1] else {
+            panic!("expected a label");
+        };
+        assert_eq!(
+            (label.alpha, label.padding, label.wrap),
+            (Some(128), Some(8), None)
+        );
+        assert_eq!(label.to_string(), "© {owner},left,top,20,red,128,,8");
+
+        assert!(parse_params("unsafe/filters:var(a b,c)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_transform_filters() {
+        let path =
+            "unsafe/filters:affine(1,0.2,0,1,-10,0):perspective(12,8,590,20,600,410,0,400)/img.jpg";
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Affine([F32(1.0), F32(0.2), F32(0.0), F32(1.0), F32(-10.0), F32(0.0)]),
+                Filter::Perspective([
+                    (F32(12.0), F32(8.0)),
+                    (F32(590.0), F32(20.0)),
+                    (F32(600.0), F32(410.0)),
+                    (F32(0.0), F32(400.0)),
+                ]),
+            ]
+        );
+        assert_eq!(params.filters[0].to_string(), "affine(1,0.2,0,1,-10,0)");
+        assert_eq!(
+            params.filters[1].to_string(),
+            "perspective(12,8,590,20,600,410,0,400)"
+        );
+
+        assert!(parse_params("unsafe/filters:affine(1,0,0,1)/img.jpg").is_err());
+        assert!(parse_params("unsafe/filters:perspective(0,0,1,0,1,1)/img.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_frame_filters() {
+        let path = "unsafe/filters:border(12,white):shadow(-4,8,6.5,333333)/img.jpg";
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Border(12, Color::Named(NamedColor::White)),
+                Filter::Shadow(-4, 8, F32(6.5), Color::Hex("333333".to_string())),
+            ]
+        );
+        assert_eq!(params.filters[1].to_string(), "shadow(-4,8,6.5,333333)");
+        assert!(parse_params("unsafe/filters:shadow(4,4,black)/img.jpg").is_err());
+
+        for filter in [
+            "border(1001,white)",
+            "border(4294967295,white)",
+            "shadow(-1001,0,2,black)",
+            "shadow(0,2147483647,2,black)",
+            "shadow(4,4,100.5,black)",
+            "shadow(4,4,-1,black)",
+        ] {
+            let path = format!("unsafe/filters:{}/img.jpg", filter);
+            assert!(parse_params(&path).is_err(), "{}", filter);
+        }
+        assert!(parse_params(
+            "unsafe/filters:border(1000,white):shadow(-1000,1000,100,black)/img.jpg"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_blur_region() {
+        let (_, params) =
+            parse_path("unsafe/filters:blur(3):blur(8,10,20,110,60)/img.jpg").unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Blur(F32(3.0), None),
+                Filter::Blur(
+                    F32(8.0),
+                    Some(Region {
+                        left: F32(10.0),
+                        top: F32(20.0),
+                        right: F32(110.0),
+                        bottom: F32(60.0),
+                    })
+                ),
+            ]
+        );
+        assert_eq!(params.filters[1].to_string(), "blur(8,10,20,110,60)");
+    }
+
+    #[test]
+    fn test_parse_pixelate_filters() {
+        let path = "unsafe/filters:pixelate(12):pixelate_region(0.1,0.2,0.5,0.6,8)/img.jpg";
+        let (_, params) = parse_path(path).unwrap();
+        assert_eq!(
+            params.filters,
+            vec![
+                Filter::Pixelate(12),
+                Filter::PixelateRegion(
+                    Region {
+                        left: F32(0.1),
+                        top: F32(0.2),
+                        right: F32(0.5),
+                        bottom: F32(0.6),
+                    },
+                    8
+                ),
+            ]
+        );
+        assert_eq!(
+            params.filters[1].to_string(),
+            "pixelate_region(0.1,0.2,0.5,0.6,8)"
+        );
+        assert!(parse_params("unsafe/filters:pixelate_region(10,10,50,8)/img.jpg").is_err());
+
+        let region = Region {
+            left: F32(0.25),
+            top: F32(0.0),
+            right: F32(0.75),
+            bottom: F32(2.0),
+        };
+        // 2 is above 1, so these are all pixels
+        assert_eq!(region.area(100, 50), Some((0, 0, 1, 2)));
+        let region = Region {
+            bottom: F32(0.5),
+            ..region
+        };
+        assert_eq!(region.area(100, 50), Some((25, 0, 50, 25)));
+        let region = Region {
+            left: F32(200.0),
+            top: F32(0.0),
+            right: F32(300.0),
+            bottom: F32(10.0),
+        };
+        assert_eq!(region.area(100, 50), None);
+    }
+
+    #[test]
+    fn test_parse_placeholder_filters() {
+        let (_, params) = parse_path("unsafe/300x200/filters:blurhash()/img.jpg").unwrap();
+        assert_eq!(params.filters, vec![Filter::Blurhash(HashOutput::Text)]);
+        assert_eq!(params.data_output(), Some(HashOutput::Text));
+
+        let (_, params) = parse_path("unsafe/300x200/filters:thumbhash(json)/img.jpg").unwrap();
+        assert_eq!(params.filters, vec![Filter::Thumbhash(HashOutput::Json)]);
+        assert!(suffix_result_storage_hasher(&params).ends_with(".json"));
+
+        assert!(parse_params("unsafe/filters:blurhash(xml)/img.jpg").is_err());
+
+        let (_, params) = parse_path("unsafe/filters:palette(5)/img.png").unwrap();
+        assert_eq!(params.filters, vec![Filter::Palette(5)]);
+        assert_eq!(params.data_output(), Some(HashOutput::Json));
+        assert!(parse_params("unsafe/filters:palette(0)/img.png").is_err());
+        assert!(parse_params("unsafe/filters:palette(17)/img.png").is_err());
+    }
+
+    #[test]
+    fn test_parse_video_filters() {
+        let (_, params) = parse_path("unsafe/300x0/filters:seek(2.5)/clip.mp4").unwrap();
+        assert_eq!(params.filters, vec![Filter::Seek(F32(2.5))]);
+
+        let (_, params) = parse_path("unsafe/300x0/filters:frame(48)/clip.webm").unwrap();
+        assert_eq!(params.filters, vec![Filter::Frame(48)]);
+
+        assert!(parse_params("unsafe/300x0/filters:seek(-1)/clip.mp4").is_err());
+    }
+
+    #[test]
+    fn test_parse_frames_filter() {
+        let (_, params) = parse_path("unsafe/filters:frames(8,120)/doc.pdf").unwrap();
+        assert_eq!(params.filters, vec![Filter::Frames(8, 120)]);
+
+        let (_, params) = parse_path("unsafe/filters:frames(4)/anim.gif").unwrap();
+        assert_eq!(params.filters, vec![Filter::Frames(4, 100)]);
+    }
+
+    #[test]
+    fn test_parse_raw_filter() {
+        let (_, params) = parse_path("unsafe/300x200/filters:raw()/img.jpg").unwrap();
+        assert_eq!(params.filters, vec![Filter::Raw]);
+        assert_eq!(params.filters[0].to_string(), "raw()");
+    }
+
+    #[test]
+    fn test_parse_image_invalid_escape_passes_through() {
+        let (_, result) = parse_path("unsafe/img%zz.jpg").unwrap();
+        assert_eq!(result.image, Some("img%zz.jpg".to_string()));
+    }
+
     #[test]
     fn test_parse_path_from_example() {
         let input = "unsafe/fit-in/-180x180/filters:hue(290):saturation(100):fill(yellow)/https://raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png";
         let expected = Params {
             path: Some("unsafe/fit-in/-180x180/filters:hue(290):saturation(100):fill(yellow)/https://raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png".to_string()),
             image: Some("https://raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png".to_string()),
+            unsafe_: true,
             width: Some(180),
             height: Some(180),
             h_flip: true,
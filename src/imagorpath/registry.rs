@@ -0,0 +1,175 @@
+use super::filter::{normalize_filter_name, CustomFilter, FilterMatcher};
+use crate::processor::image::Image;
+use color_eyre::{eyre::eyre, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use thiserror::Error;
+
+/// Checks a custom filter's arguments, returning them as they should be
+/// kept in the path and result key, e.g. with defaults filled in
+pub type ParseFn = dyn Fn(&str) -> Result<String, &'static str> + Send + Sync;
+/// Applies a custom filter to the image, given its parsed arguments
+pub type ApplyFn = dyn Fn(&Image, &str) -> Result<Image> + Send + Sync;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("`{0}` is a built-in filter")]
+    BuiltIn(String),
+    #[error("a filter named `{0}` is already registered")]
+    Duplicate(String),
+    #[error("`{0}` isn't a valid filter name, use letters, digits and underscores")]
+    InvalidName(String),
+}
+
+struct Registered {
+    name: String,
+    parse: Box<ParseFn>,
+    apply: Box<ApplyFn>,
+}
+
+/// Filters added by library users, next to the built-ins. They're parsed
+/// from paths like any other filter and applied by the libvips processor;
+/// the pure backend skips them.
+///
+/// ```no_run
+/// use imagor_rs::imagorpath::registry::FilterRegistry;
+/// use imagor_rs::processor::image::Image;
+///
+/// FilterRegistry::global()
+///     .register(
+///         "invert",
+///         |args| if args.is_empty() { Ok(String::new()) } else { Err("invert takes no arguments") },
+///         |img, _| Ok(Image::new(libvips::ops::invert(img)?)),
+///     )
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct FilterRegistry {
+    filters: RwLock<HashMap<String, Arc<Registered>>>,
+}
+
+impl FilterRegistry {
+    /// The registry paths are parsed and processed with
+    pub fn global() -> &'static FilterRegistry {
+        static GLOBAL: OnceLock<FilterRegistry> = OnceLock::new();
+        GLOBAL.get_or_init(FilterRegistry::default)
+    }
+
+    /// Names are matched like the built-ins', ignoring case and underscores
+    pub fn register<P, A>(&self, name: &str, parse: P, apply: A) -> Result<(), RegisterError>
+    where
+        P: Fn(&str) -> Result<String, &'static str> + Send + Sync + 'static,
+        A: Fn(&Image, &str) -> Result<Image> + Send + Sync + 'static,
+    {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(RegisterError::InvalidName(name.to_string()));
+        }
+        if FilterMatcher::new(name).is_built_in() {
+            return Err(RegisterError::BuiltIn(name.to_string()));
+        }
+
+        let mut filters = self.filters.write().unwrap_or_else(|e| e.into_inner());
+        let key = normalize_filter_name(name);
+        if filters.contains_key(&key) {
+            return Err(RegisterError::Duplicate(name.to_string()));
+        }
+        filters.insert(
+            key,
+            Arc::new(Registered {
+                name: name.to_string(),
+                parse: Box::new(parse),
+                apply: Box::new(apply),
+            }),
+        );
+
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<Registered>> {
+        self.filters
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&normalize_filter_name(name))
+            .cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The filter for `name(args)`, `None` when none is registered by that
+    /// name
+    pub fn parse(&self, name: &str, args: &str) -> Option<Result<CustomFilter, &'static str>> {
+        let registered = self.get(name)?;
+        Some((registered.parse)(args).map(|args| CustomFilter {
+            name: registered.name.clone(),
+            args,
+        }))
+    }
+
+    pub fn apply(&self, image: &Image, filter: &CustomFilter) -> Result<Image> {
+        let registered = self
+            .get(&filter.name)
+            .ok_or_else(|| eyre!("Filter `{}` isn't registered", filter.name))?;
+        (registered.apply)(image, &filter.args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> FilterRegistry {
+        let registry = FilterRegistry::default();
+        registry
+            .register(
+                "posterize",
+                |args| match args {
+                    "" => Ok("4".to_string()),
+                    _ if args.parse::<u8>().is_ok() => Ok(args.to_string()),
+                    _ => Err("posterize takes a number of levels"),
+                },
+                |img, _| Ok(img.clone()),
+            )
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_register() {
+        let registry = registry();
+        assert!(registry.contains("posterize"));
+        assert!(registry.contains("Poster_ize"));
+
+        let noop = |img: &Image, _: &str| Ok(img.clone());
+        assert_eq!(
+            registry.register("posterize", |a| Ok(a.to_string()), noop),
+            Err(RegisterError::Duplicate("posterize".to_string()))
+        );
+        assert_eq!(
+            registry.register("round_corner", |a| Ok(a.to_string()), noop),
+            Err(RegisterError::BuiltIn("round_corner".to_string()))
+        );
+        assert_eq!(
+            registry.register("bad name", |a| Ok(a.to_string()), noop),
+            Err(RegisterError::InvalidName("bad name".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let registry = registry();
+        assert_eq!(
+            registry.parse("posterize", ""),
+            Some(Ok(CustomFilter {
+                name: "posterize".to_string(),
+                args: "4".to_string(),
+            }))
+        );
+        assert_eq!(
+            registry.parse("posterize", "x"),
+            Some(Err("posterize takes a number of levels"))
+        );
+        assert_eq!(registry.parse("solarize", ""), None);
+    }
+}
@@ -0,0 +1,73 @@
+use super::generate::Signer;
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, SecretString};
+use sha1::Sha1;
+
+/// Thumbor compatible URL signer: HMAC-SHA1 of the path, base64 URL encoded.
+#[derive(Clone)]
+pub struct HmacSigner {
+    secret: SecretString,
+}
+
+impl HmacSigner {
+    pub fn new(secret: SecretString) -> Self {
+        HmacSigner { secret }
+    }
+
+    /// An empty secret signs nothing worth checking
+    pub fn has_secret(&self) -> bool {
+        !self.secret.expose_secret().is_empty()
+    }
+
+    /// Whether `hash` is the signature of `path`, compared in constant time.
+    /// Nothing verifies without a secret.
+    pub fn verify(&self, path: &str, hash: &str) -> bool {
+        let Ok(hash) = URL_SAFE.decode(hash) else {
+            return false;
+        };
+        self.has_secret() && self.mac(path).verify_slice(&hash).is_ok()
+    }
+
+    fn mac(&self, path: &str) -> Hmac<Sha1> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(path.as_bytes());
+        mac
+    }
+}
+
+impl Signer for HmacSigner {
+    fn sign(&self, path: &str) -> String {
+        URL_SAFE.encode(self.mac(path).finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_signer_matches_thumbor() {
+        let signer = HmacSigner::new(SecretString::from("mysecret".to_string()));
+        assert_eq!(
+            signer.sign(
+                "500x500/top/raw.githubusercontent.com/cshum/imagor/master/testdata/gopher.png"
+            ),
+            "cST4Ko5_FqwT3BDn-Wf4gO3RFSk="
+        );
+    }
+
+    #[test]
+    fn test_hmac_signer_verify() {
+        let signer = HmacSigner::new(SecretString::from("mysecret".to_string()));
+        let path = "500x500/top/img.png";
+        assert!(signer.verify(path, &signer.sign(path)));
+        assert!(!signer.verify("500x501/top/img.png", &signer.sign(path)));
+        assert!(!signer.verify(path, "not base64!"));
+
+        let unsigned = HmacSigner::new(SecretString::from(String::new()));
+        assert!(!unsigned.verify(path, &unsigned.sign(path)));
+    }
+}
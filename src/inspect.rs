@@ -0,0 +1,242 @@
+use crate::config::DefaultsSettings;
+use crate::error::ApiError;
+use crate::imagorpath::generate::{generate_path, to_signed_string, to_unsafe_string};
+use crate::imagorpath::hasher::{
+    digest_result_storage_hasher, size_suffix_result_storage_hasher, suffix_result_storage_hasher,
+};
+use crate::imagorpath::params::Params;
+use crate::imagorpath::parse::parse_params;
+use crate::imagorpath::signer::HmacSigner;
+use crate::state::AppStateDyn;
+use axum::extract::State;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Debug)]
+pub struct InspectRequest {
+    /// A full URL with scheme and host, or an imagor path
+    pub url: String,
+}
+
+/// Result storage key under each hasher strategy
+#[derive(Serialize, Debug)]
+pub struct ResultKeys {
+    pub suffix: String,
+    pub size_suffix: String,
+    pub digest: String,
+}
+
+impl ResultKeys {
+    pub fn new(params: &Params) -> Self {
+        ResultKeys {
+            suffix: suffix_result_storage_hasher(params),
+            size_suffix: size_suffix_result_storage_hasher(params),
+            digest: digest_result_storage_hasher(params),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub requested: Value,
+    pub effective: Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Inspection {
+    pub params: Params,
+    /// The path regenerated from `params`, without the signature
+    pub canonical_path: String,
+    pub result_keys: ResultKeys,
+    /// Fields the deployment defaults changed, keyed by param name
+    pub defaults_diff: BTreeMap<String, Change>,
+}
+
+/// The params of an imagor path, with the paths, URLs and result storage
+/// keys generated from them
+#[derive(Serialize, Debug)]
+pub struct Described {
+    #[serde(flatten)]
+    pub params: Params,
+    /// Regenerated with the deployment defaults applied, as it's processed
+    pub canonical_path: String,
+    pub unsafe_url: String,
+    /// Only for requests with an API key, when a secret is configured
+    pub signed_url: Option<String>,
+    pub result_keys: ResultKeys,
+}
+
+/// The imagor path of `url`, dropping the scheme, host and `/params` prefix
+pub fn imagor_path(url: &str) -> &str {
+    let path = match url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    {
+        Some(rest) => rest.find('/').map_or("", |idx| &rest[idx..]),
+        None => url,
+    };
+
+    path.trim_start_matches('/')
+        .trim_start_matches("params/")
+        .trim_start_matches('/')
+}
+
+fn diff(requested: &Params, effective: &Params) -> BTreeMap<String, Change> {
+    let as_map = |params: &Params| match serde_json::to_value(params) {
+        Ok(Value::Object(map)) => map,
+        _ => Default::default(),
+    };
+    let requested = as_map(requested);
+    let mut effective = as_map(effective);
+
+    requested
+        .into_iter()
+        .filter_map(|(field, requested)| {
+            let effective = effective.remove(&field).unwrap_or(Value::Null);
+            (requested != effective).then_some((
+                field,
+                Change {
+                    requested,
+                    effective,
+                },
+            ))
+        })
+        .collect()
+}
+
+pub fn inspect(defaults: &DefaultsSettings, url: &str) -> Result<Inspection, ApiError> {
+    let path = imagor_path(url);
+    let parse = || parse_params(path);
+
+    let requested = parse()?;
+    let params = parse()?.with_defaults(defaults).canonicalized(defaults);
+    if params.image.is_none() {
+        return Err(ApiError::bad_request("Image parameter is missing"));
+    }
+
+    Ok(Inspection {
+        canonical_path: generate_path(&params),
+        result_keys: ResultKeys::new(&params),
+        defaults_diff: diff(&requested, &params),
+        params,
+    })
+}
+
+/// URLs are generated from `params` as requested, served from `host`
+pub fn describe(
+    params: Params,
+    defaults: &DefaultsSettings,
+    signer: Option<&HmacSigner>,
+    host: &str,
+) -> Described {
+    let effective = params
+        .clone()
+        .with_defaults(defaults)
+        .canonicalized(defaults);
+
+    Described {
+        canonical_path: generate_path(&effective),
+        unsafe_url: format!("http://{}/{}", host, to_unsafe_string(&params)),
+        signed_url: signer.map(|signer| {
+            format!(
+                "http://{}/{}",
+                host,
+                to_signed_string(&params, signer.clone())
+            )
+        }),
+        result_keys: ResultKeys::new(&effective),
+        params,
+    }
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn inspect_params(
+    State(state): State<AppStateDyn>,
+    Json(req): Json<InspectRequest>,
+) -> Result<Json<Inspection>, ApiError> {
+    inspect(&state.defaults, &req.url).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::filter::ImageType;
+
+    #[test]
+    fn test_imagor_path() {
+        assert_eq!(
+            imagor_path("https://imagor.example.com/unsafe/100x100/img.png"),
+            "unsafe/100x100/img.png"
+        );
+        assert_eq!(
+            imagor_path("http://localhost:8080/params/unsafe/img.png"),
+            "unsafe/img.png"
+        );
+        assert_eq!(
+            imagor_path("/unsafe/https://example.com/img.png"),
+            "unsafe/https://example.com/img.png"
+        );
+    }
+
+    #[test]
+    fn test_inspect_diff_against_defaults() {
+        let defaults = DefaultsSettings {
+            quality: Some(80),
+            format: Some(ImageType::WEBP),
+            ..Default::default()
+        };
+        let inspection = inspect(
+            &defaults,
+            "https://imagor.example.com/unsafe/100x100/filters:quality(50)/img.png",
+        )
+        .unwrap();
+
+        assert_eq!(
+            inspection.canonical_path,
+            "100x100/filters:quality(50):format(webp)/img.png"
+        );
+        assert_eq!(
+            inspection.result_keys.suffix.rsplit('.').next(),
+            Some("webp")
+        );
+        assert_eq!(inspection.defaults_diff.len(), 1);
+        assert!(inspection.defaults_diff.contains_key("filters"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let defaults = DefaultsSettings {
+            format: Some(ImageType::WEBP),
+            ..Default::default()
+        };
+        let params = parse_params("unsafe/fit-in/100x100/img.png").unwrap();
+        let signer = HmacSigner::new("mysecret".to_string().into());
+
+        let described = describe(params.clone(), &defaults, Some(&signer), "localhost:8080");
+        assert_eq!(
+            described.canonical_path,
+            "fit-in/100x100/filters:format(webp)/img.png"
+        );
+        assert_eq!(
+            described.unsafe_url,
+            "http://localhost:8080/unsafe/fit-in/100x100/img.png"
+        );
+        let signed = described.signed_url.as_deref().unwrap();
+        assert!(signed.ends_with("/fit-in/100x100/img.png"));
+        assert!(!signed.contains("/unsafe/"));
+        assert_eq!(
+            described.result_keys.suffix.rsplit('.').next(),
+            Some("webp")
+        );
+
+        // params stay at the top level, as before
+        let json = serde_json::to_value(&described).unwrap();
+        assert_eq!(json["width"], 100);
+        assert_eq!(json["image"], "img.png");
+
+        let unsigned = describe(params, &defaults, None, "localhost:8080");
+        assert!(unsigned.signed_url.is_none());
+    }
+}
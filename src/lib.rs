@@ -1,10 +1,45 @@
+#![allow(clippy::module_inception)]
+
+pub mod admin;
+pub mod allowlist;
+pub mod auth;
+pub mod breaker;
 pub mod cache;
+pub mod cli;
+pub mod client;
 pub mod config;
+pub mod dimensions;
+pub mod error;
+pub mod failure;
+pub mod gc;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
 pub mod imagorpath;
+pub mod inspect;
+pub mod memory;
 pub mod metrics;
 pub mod middleware;
+pub mod mirror;
+pub mod origin_auth;
+pub mod pathutil;
+pub mod prefetch;
+pub mod presets;
 pub mod processor;
+pub mod quarantine;
+pub mod ratelimit;
+pub mod reload;
+pub mod result_meta;
+pub mod script;
 pub mod startup;
 pub mod state;
+pub mod stats;
 pub mod storage;
 pub mod telemetry;
+pub mod tenant;
+pub mod tls;
+#[cfg(feature = "worker")]
+pub mod worker;
+pub mod writebehind;
+
+pub use processor::embed::{process, IntoParams};
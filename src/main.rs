@@ -1,16 +1,42 @@
+use clap::Parser;
 use color_eyre::Result;
-use imagor_rs::config::get_configuration;
+use imagor_rs::cli::Cli;
+use imagor_rs::config::{get_configuration, set_overrides};
+use imagor_rs::processor::isolated::run_worker;
 use imagor_rs::startup::Application;
 use imagor_rs::telemetry::{get_subscriber, init_subscriber};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
+    let cli = Cli::parse();
+    if cli.render_worker {
+        return run_worker();
+    }
+
     let parse_dotenv = dotenvy::dotenv();
     if let Err(e) = parse_dotenv {
         tracing::warn!("failed to parse .env file: {}", e);
     }
 
+    set_overrides(cli.overrides());
+    if cli.check_config || cli.dump_config {
+        let configuration = match get_configuration() {
+            Ok(configuration) => configuration,
+            Err(e) => {
+                eprintln!("invalid configuration: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if cli.dump_config {
+            println!("{}", serde_json::to_string_pretty(&configuration)?);
+        } else {
+            println!("configuration ok");
+        }
+        return Ok(());
+    }
+
     let configuration = get_configuration()
         .inspect_err(|e| tracing::error!("Failed to load configuration: {}", e))
         .expect("Failed to read configuration");
@@ -0,0 +1,156 @@
+use crate::config::LimitsSettings;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Reservations are counted in whole MiB, so a budget of terabytes still
+/// fits in the semaphore's permits
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MemoryError {
+    #[error("decoding needs {needed} bytes, the budget is {budget}")]
+    OverBudget { needed: u64, budget: u64 },
+    #[error("no memory freed up for {needed} bytes within {wait:?}")]
+    Busy { needed: u64, wait: Duration },
+}
+
+/// Keeps the decoded size of the images being processed within budget,
+/// per request and across all of them, so a burst of huge sources can't
+/// run the server out of memory
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget {
+    /// The smaller of the per-request and total budgets, 0 for no limit
+    max_request_bytes: u64,
+    /// Permits in MiB, `None` when the total isn't limited
+    total: Option<Arc<Semaphore>>,
+    wait: Duration,
+}
+
+/// Memory held for a request, given back when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: u64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        metrics::gauge!("memory_reserved_bytes").decrement(self.bytes as f64);
+    }
+}
+
+fn mib(bytes: u64) -> u32 {
+    bytes
+        .div_ceil(MIB)
+        .min(Semaphore::MAX_PERMITS as u64)
+        .min(u32::MAX as u64) as u32
+}
+
+impl MemoryBudget {
+    pub fn new(settings: &LimitsSettings) -> Self {
+        let max_request_bytes = match (settings.max_request_memory, settings.max_total_memory) {
+            (0, total) | (total, 0) => total,
+            (request, total) => request.min(total),
+        };
+        let total = (settings.max_total_memory > 0)
+            .then(|| Arc::new(Semaphore::new(mib(settings.max_total_memory) as usize)));
+
+        MemoryBudget {
+            max_request_bytes,
+            total,
+            wait: Duration::from_secs(settings.memory_wait_secs),
+        }
+    }
+
+    /// Whether there's anything to check, so sizes needn't be estimated
+    pub fn is_limited(&self) -> bool {
+        self.max_request_bytes > 0 || self.total.is_some()
+    }
+
+    /// Holds `bytes` of the total budget, waiting for other requests to
+    /// give theirs back. Refused outright when a single request may never
+    /// need that much.
+    pub async fn reserve(&self, bytes: u64) -> Result<Reservation, MemoryError> {
+        if self.max_request_bytes > 0 && bytes > self.max_request_bytes {
+            metrics::counter!("memory_budget_rejections_total", "reason" => "over_budget")
+                .increment(1);
+            return Err(MemoryError::OverBudget {
+                needed: bytes,
+                budget: self.max_request_bytes,
+            });
+        }
+
+        let permit = match &self.total {
+            Some(total) => {
+                let acquire = total.clone().acquire_many_owned(mib(bytes));
+                match tokio::time::timeout(self.wait, acquire).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        metrics::counter!("memory_budget_rejections_total", "reason" => "busy")
+                            .increment(1);
+                        return Err(MemoryError::Busy {
+                            needed: bytes,
+                            wait: self.wait,
+                        });
+                    }
+                }
+            }
+            None => None,
+        };
+
+        metrics::gauge!("memory_reserved_bytes").increment(bytes as f64);
+        Ok(Reservation {
+            bytes,
+            _permit: permit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(max_request_memory: u64, max_total_memory: u64) -> MemoryBudget {
+        MemoryBudget::new(&LimitsSettings {
+            max_request_memory,
+            max_total_memory,
+            memory_wait_secs: 0,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_request_budget() {
+        let budget = budget(10 * MIB, 0);
+        assert!(budget.reserve(10 * MIB).await.is_ok());
+        assert_eq!(
+            budget.reserve(10 * MIB + 1).await.unwrap_err(),
+            MemoryError::OverBudget {
+                needed: 10 * MIB + 1,
+                budget: 10 * MIB
+            }
+        );
+        assert!(!MemoryBudget::default().is_limited());
+    }
+
+    #[tokio::test]
+    async fn test_total_budget() {
+        let budget = budget(0, 10 * MIB);
+
+        let first = budget.reserve(6 * MIB).await.unwrap();
+        assert!(matches!(
+            budget.reserve(6 * MIB).await,
+            Err(MemoryError::Busy { .. })
+        ));
+        // bigger than the whole budget, waiting wouldn't help
+        assert!(matches!(
+            budget.reserve(11 * MIB).await,
+            Err(MemoryError::OverBudget { .. })
+        ));
+
+        drop(first);
+        assert!(budget.reserve(6 * MIB).await.is_ok());
+    }
+}
@@ -1,24 +1,33 @@
+use crate::tenant::Tenant;
 use axum::{
     extract::{MatchedPath, Request},
     middleware::Next,
     response::IntoResponse,
 };
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
 use tokio::time::Instant;
 
+/// The recorder is global, so servers after the first, e.g. in tests, share
+/// it
 pub fn setup_metrics_recorder() -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
-
-    PrometheusBuilder::new()
-        .set_buckets_for_metric(
-            Matcher::Full("http_requests_duration_seconds".to_string()),
-            EXPONENTIAL_SECONDS,
-        )
-        .unwrap()
-        .install_recorder()
-        .unwrap()
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+    HANDLE
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Full("http_requests_duration_seconds".to_string()),
+                    EXPONENTIAL_SECONDS,
+                )
+                .unwrap()
+                .install_recorder()
+                .unwrap()
+        })
+        .clone()
 }
 
 pub async fn track_metrics(req: Request, next: Next) -> impl IntoResponse {
@@ -29,6 +38,10 @@ pub async fn track_metrics(req: Request, next: Next) -> impl IntoResponse {
         req.uri().path().to_owned()
     };
     let method = req.method().clone();
+    let tenant = req
+        .extensions()
+        .get::<Tenant>()
+        .map_or(String::new(), |tenant| tenant.name().to_string());
 
     let response = next.run(req).await;
 
@@ -39,6 +52,7 @@ pub async fn track_metrics(req: Request, next: Next) -> impl IntoResponse {
         ("method", method.to_string()),
         ("path", path),
         ("status", status),
+        ("tenant", tenant),
     ];
 
     metrics::counter!("http_requests_total", &labels).increment(1);
@@ -1,65 +1,497 @@
+use crate::auth::{ApiKey, Auth};
+use crate::config::CompressionSettings;
+use crate::error::{ApiError, REQUEST_ID};
+use crate::imagorpath::filter::ImageType;
+use crate::imagorpath::hasher::source_digest;
+use crate::imagorpath::params::Params;
+use crate::imagorpath::parse::parse_params;
+use crate::ratelimit::limiter::Decision;
 use crate::state::AppStateDyn;
-use axum::http::{header, Response, StatusCode};
+use crate::tenant::Tenant;
+use axum::http::{
+    header, Extensions, HeaderMap, HeaderValue, Method, Response, StatusCode, Uri, Version,
+};
 use axum::{
     body::{to_bytes, Body},
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
     middleware::Next,
     response::IntoResponse,
 };
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tracing::warn;
+
+/// Probes and scrapes are never rate limited
+const UNLIMITED_PATHS: [&str; 4] = ["/health", "/live", "/ready", "/metrics"];
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longer IDs from clients are replaced, they'd only bloat logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Sources can be served as they are, and an SVG or XML one could carry
+/// script that would run on this origin
+pub fn no_script(response: axum::http::response::Builder) -> axum::http::response::Builder {
+    response
+        .header(header::CONTENT_SECURITY_POLICY, "script-src 'none'")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+}
 
 #[tracing::instrument(skip(state, req, next))]
 pub async fn cache_middleware(
     State(state): State<AppStateDyn>,
     req: Request,
     next: Next,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let cache_key = format!("{}:{}", req.method(), req.uri().path());
-
-    let cache_response = state.cache.get(&cache_key).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to get cache: {}", e),
-        )
-    })?;
+) -> Result<impl IntoResponse, ApiError> {
+    // the query string belongs to the source image URL, and so to the key
+    let uri = req.uri();
+    let path = uri.path_and_query().map_or(uri.path(), |pq| pq.as_str());
+    let params = parse_params(path.trim_start_matches('/'))
+        .ok()
+        .map(|params| params.with_defaults(&state.defaults));
+
+    // HEAD is answered from the same entry as GET, the body is dropped later
+    let method = match *req.method() {
+        Method::HEAD => &Method::GET,
+        ref method => method,
+    };
+    // each negotiated format is a separate response
+    let auto_format = params.as_ref().is_some_and(|p| p.has_auto_format());
+    let cache_key = if auto_format {
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        format!("{}:{}:{}", method, path, ImageType::negotiate(accept))
+    } else {
+        format!("{}:{}", method, path)
+    };
+    // tenants' paths can look the same and still be different images
+    let cache_key = match req.extensions().get::<Tenant>() {
+        Some(tenant) => format!("tenant:{}:{}", tenant.name(), cache_key),
+        None => cache_key,
+    };
+
+    let cache_response = state
+        .cache
+        .get(&cache_key)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get cache: {}", e)))?;
     if let Some(buf) = cache_response {
-        // Return cached response if available
-        let content_type = infer::get(&buf)
-            .map(|mime| mime.to_string())
-            .unwrap_or("image/jpeg".to_string());
-        let res = Response::builder()
-            .header(header::CONTENT_TYPE, content_type)
-            .body(Body::from(buf))
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to build response: {}", e),
-                )
-            })?;
+        // Return cached response if available. Hashes and palettes can't be
+        // sniffed back, the params say what they are.
+        let content_type = match params.as_ref().and_then(Params::data_output) {
+            Some(output) if infer::get(&buf).is_none() => output.content_type().to_string(),
+            _ => infer::get(&buf)
+                .map(|mime| mime.to_string())
+                .unwrap_or("image/jpeg".to_string()),
+        };
+        // may be a source that was served as it is
+        let mut res = no_script(Response::builder()).header(header::CONTENT_TYPE, content_type);
+        if auto_format {
+            res = res.header(header::VARY, "Accept");
+        }
+        let res = res.body(Body::from(buf))?;
 
         return Ok(res);
     }
 
     // If not cached, proceed with the request
     let response = next.run(req).await;
-    if response.status() != StatusCode::OK {
+    let no_store = response
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("no-store"));
+    if response.status() != StatusCode::OK || no_store {
         return Ok(response);
     }
 
     // Cache the response
     let (parts, body) = response.into_parts();
-    let bytes = to_bytes(body, usize::MAX).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to read response body: {}", e),
-        )
-    })?;
+    let bytes = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?;
 
     // TODO: use hash key for this
-    let _ = state
-        .cache
-        .set(&cache_key, bytes.as_ref(), Some(Duration::from_secs(3_600))) // 1 hour
-        .await;
+    let ttl = state.reloader.live().cache_ttl;
+    let _ = state.cache.set(&cache_key, bytes.as_ref(), Some(ttl)).await;
+
+    // tag with the source so every variant can be invalidated at once
+    if let Some(image) = params.and_then(|params| params.image) {
+        let _ = state
+            .cache
+            .tag(&source_digest(&image), &cache_key)
+            .await
+            .inspect_err(|e| warn!("Failed to tag cached response [{}]: {}", cache_key, e));
+    }
 
     Ok(Response::from_parts(parts, Body::from(bytes)))
 }
+
+#[tracing::instrument(skip(state, req, next))]
+pub async fn rate_limit_middleware(
+    State(state): State<AppStateDyn>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(rate_limit) = &state.rate_limit else {
+        return Ok(next.run(req).await);
+    };
+    if UNLIMITED_PATHS.contains(&req.uri().path()) {
+        return Ok(next.run(req).await);
+    }
+
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let api_key = req.extensions().get::<ApiKey>();
+    let Some(client) = rate_limit.client(req.headers(), peer, api_key) else {
+        return Ok(next.run(req).await);
+    };
+
+    match rate_limit.check(&client).await {
+        Ok(Decision::Allowed) => Ok(next.run(req).await),
+        Ok(Decision::Limited { retry_after }) => {
+            metrics::counter!("rate_limited_requests_total", "client" => client.kind())
+                .increment(1);
+            Err(ApiError::RateLimited { retry_after })
+        }
+        // a rate limiter that's down shouldn't take the service down with it
+        Err(e) => {
+            warn!(
+                "Rate limiter unavailable, letting the request through: {}",
+                e
+            );
+            Ok(next.run(req).await)
+        }
+    }
+}
+
+/// Finds the tenant a request belongs to by its host or path prefix, and
+/// charges the request to the tenant's budget. A matched prefix is stripped
+/// so the request routes as any other, which means this has to run before
+/// routing.
+#[tracing::instrument(skip(state, req, next))]
+pub async fn tenant_middleware(
+    State(state): State<AppStateDyn>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response<Body>, ApiError> {
+    let host = req
+        .uri()
+        .host()
+        .or_else(|| req.headers().get(header::HOST)?.to_str().ok());
+    let Some((tenant, path)) = state.tenants.resolve(host, req.uri().path()) else {
+        return Ok(next.run(req).await);
+    };
+    let tenant = tenant.clone();
+
+    if path != req.uri().path() {
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse()
+                .map_err(|e| ApiError::bad_request(format!("Invalid path: {}", e)))?,
+        );
+        *req.uri_mut() = Uri::from_parts(parts)
+            .map_err(|e| ApiError::bad_request(format!("Invalid path: {}", e)))?;
+    }
+
+    if !UNLIMITED_PATHS.contains(&req.uri().path()) {
+        match state.tenants.check(&tenant).await {
+            Ok(Decision::Allowed) => {}
+            Ok(Decision::Limited { retry_after }) => {
+                metrics::counter!("rate_limited_requests_total", "client" => "tenant", "tenant" => tenant.name().to_string())
+                    .increment(1);
+                return Err(ApiError::RateLimited { retry_after });
+            }
+            Err(e) => warn!(
+                "Rate limiter unavailable, letting the request through: {}",
+                e
+            ),
+        }
+    }
+
+    req.extensions_mut().insert(tenant.clone());
+    let response = next.run(req).await;
+    metrics::counter!(
+        "tenant_requests_total",
+        "tenant" => tenant.name().to_string(),
+        "status" => response.status().as_u16().to_string()
+    )
+    .increment(1);
+
+    Ok(response)
+}
+
+/// Adds `Content-Length` and `Accept-Ranges` to successful image responses,
+/// and answers a single byte range with `206 Partial Content`. Requests for
+/// several ranges, or with `If-Range`, get the whole image.
+#[tracing::instrument(skip(req, next))]
+pub async fn range_middleware(req: Request, next: Next) -> Result<impl IntoResponse, ApiError> {
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .filter(|_| req.method() == Method::GET && !req.headers().contains_key(header::IF_RANGE))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(req).await;
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?;
+    let len = bytes.len();
+    parts
+        .headers
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let (content_range, body) = match range.and_then(|range| byte_range(&range, len)) {
+        Some(Ok(range)) => {
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            (Some(content_range), bytes.slice(range))
+        }
+        Some(Err(Unsatisfiable)) => {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            (Some(format!("bytes */{}", len)), Default::default())
+        }
+        None => (None, bytes),
+    };
+    if let Some(content_range) = content_range {
+        parts.headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).expect("content range is a valid header"),
+        );
+    }
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[derive(Debug, PartialEq)]
+struct Unsatisfiable;
+
+/// The bytes a `Range` header asks for out of `len`. `None` for headers to be
+/// ignored: other units, several ranges or ones that don't parse.
+fn byte_range(header: &str, len: usize) -> Option<Result<Range<usize>, Unsatisfiable>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            len.saturating_sub(suffix)..len
+        }
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            start..end.saturating_add(1).min(len)
+        }
+    };
+
+    if range.start >= len || range.is_empty() {
+        return Some(Err(Unsatisfiable));
+    }
+    Some(Ok(range))
+}
+
+/// Authenticates requests that carry an API key and rejects unknown keys.
+/// Requests without one are let through, [`require_api_key`] decides
+/// whether they need one.
+#[tracing::instrument(skip(state, req, next))]
+pub async fn auth_middleware(
+    State(state): State<AppStateDyn>,
+    mut req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(auth) = &state.auth else {
+        return Ok(next.run(req).await);
+    };
+    let Some(key) = Auth::credentials(req.headers()) else {
+        return Ok(next.run(req).await);
+    };
+
+    let Some(api_key) = auth.authenticate(key).await.map_err(|e| ApiError::Auth {
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        detail: format!("Failed to check API key: {}", e),
+    })?
+    else {
+        return Err(ApiError::unauthorized("Invalid API key"));
+    };
+
+    let name = api_key.name.clone();
+    req.extensions_mut().insert(api_key);
+    let response = next.run(req).await;
+    metrics::counter!(
+        "api_key_requests_total",
+        "api_key" => name,
+        "status" => response.status().as_u16().to_string()
+    )
+    .increment(1);
+
+    Ok(response)
+}
+
+/// Rejects requests that didn't authenticate with an API key, when keys are
+/// required
+pub async fn require_api_key(
+    State(state): State<AppStateDyn>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    if state.auth.is_some() && req.extensions().get::<ApiKey>().is_none() {
+        return ApiError::unauthorized("An API key is required").into_response();
+    }
+    next.run(req).await
+}
+
+/// Rejects admin requests that didn't authenticate with an API key. Unlike
+/// [`require_api_key`] this fails closed: without `auth.enabled` there's no
+/// key to check, so the admin endpoints are refused outright.
+pub async fn require_admin_key(
+    State(state): State<AppStateDyn>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    if state.auth.is_none() {
+        return ApiError::forbidden("Admin endpoints need `auth.enabled`").into_response();
+    }
+    if req.extensions().get::<ApiKey>().is_none() {
+        return ApiError::unauthorized("An API key is required").into_response();
+    }
+    next.run(req).await
+}
+
+/// Tags the request with an ID, the client's `X-Request-Id` or a random one.
+/// Error responses carry it and the response echoes it back, so a failure
+/// can be found in the logs.
+pub async fn request_id(req: Request, next: Next) -> Response<Body> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Brotli or gzip, whichever the client prefers, for responses of the
+/// configured content types
+pub fn compression_layer(settings: &CompressionSettings) -> CompressionLayer<impl Predicate> {
+    let content_types = Arc::new(settings.content_types.clone());
+    let predicate = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+        compressible(&content_types, headers)
+    };
+
+    CompressionLayer::new().compress_when(SizeAbove::new(settings.min_bytes).and(predicate))
+}
+
+fn compressible(content_types: &[String], headers: &HeaderMap) -> bool {
+    // a range of the body can't be compressed on its own
+    if headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase())
+    else {
+        return false;
+    };
+    // raster images are compressed already
+    if content_type.starts_with("image/") && content_type != "image/svg+xml" {
+        return false;
+    }
+
+    content_types.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        if allowed.ends_with('/') {
+            content_type.starts_with(&allowed)
+        } else {
+            content_type == allowed
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible() {
+        let types = CompressionSettings::default().content_types;
+        let headers = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            headers
+        };
+
+        assert!(compressible(&types, &headers("application/json")));
+        assert!(compressible(&types, &headers("image/svg+xml")));
+        assert!(compressible(&types, &headers("text/plain; charset=utf-8")));
+        assert!(!compressible(&types, &headers("image/png")));
+        assert!(!compressible(&types, &headers("image/webp")));
+        assert!(!compressible(&types, &headers("application/octet-stream")));
+        assert!(!compressible(&types, &HeaderMap::new()));
+
+        // raster images never are, even when configured
+        let everything = vec!["image/".to_string(), "application/json".to_string()];
+        assert!(!compressible(&everything, &headers("image/jpeg")));
+
+        let mut ranged = headers("image/svg+xml");
+        ranged.insert(header::CONTENT_RANGE, "bytes 0-99/1000".parse().unwrap());
+        assert!(!compressible(&types, &ranged));
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Some(Ok(0..100)));
+        assert_eq!(byte_range("bytes=900-", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=-5000", 1000), Some(Ok(0..1000)));
+        assert_eq!(byte_range("bytes=990-2000", 1000), Some(Ok(990..1000)));
+        assert_eq!(
+            byte_range("bytes=0-18446744073709551615", 1000),
+            Some(Ok(0..1000))
+        );
+
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(Err(Unsatisfiable)));
+        assert_eq!(byte_range("bytes=-0", 1000), Some(Err(Unsatisfiable)));
+        assert_eq!(byte_range("bytes=0-", 0), Some(Err(Unsatisfiable)));
+
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(byte_range("bytes=5-1", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+        assert_eq!(byte_range("bytes=a-b", 1000), None);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::breaker::CircuitBreakers;
+use crate::config::LoaderSettings;
+use crate::origin_auth::OriginAuth;
+use crate::storage::retry::{RetryPolicy, StatusError};
+use color_eyre::{eyre::eyre, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use url::Url;
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    skip_until: Option<Instant>,
+}
+
+/// Fallback origins per source host. When the primary fetch fails with a 5xx
+/// or a transport error, mirrors are tried in order. A mirror that keeps
+/// failing is skipped until its cooldown has passed.
+#[derive(Debug, Clone)]
+pub struct Mirrors {
+    client: reqwest::Client,
+    auth: OriginAuth,
+    retry: RetryPolicy,
+    breakers: CircuitBreakers,
+    mirrors: HashMap<String, Vec<String>>,
+    health: Arc<Mutex<HashMap<String, Health>>>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl Mirrors {
+    pub fn new(settings: &LoaderSettings) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()?;
+
+        Ok(Self {
+            client,
+            auth: OriginAuth::new(&settings.auth),
+            retry: RetryPolicy::new(&settings.retry),
+            breakers: CircuitBreakers::new(&settings.circuit_breaker),
+            mirrors: settings
+                .mirrors
+                .iter()
+                .map(|(host, mirrors)| (host.to_lowercase(), mirrors.clone()))
+                .collect(),
+            health: Arc::new(Mutex::new(HashMap::new())),
+            failure_threshold: settings.mirror_failure_threshold.max(1),
+            cooldown: Duration::from_secs(settings.mirror_cooldown_secs),
+        })
+    }
+
+    /// The URL itself followed by the same path on every healthy mirror
+    fn candidates(&self, url: &str) -> Vec<(Option<String>, String)> {
+        let mut candidates = vec![(None, url.to_string())];
+
+        let Ok(parsed) = Url::parse(url) else {
+            return candidates;
+        };
+        let Some(mirrors) = parsed
+            .host_str()
+            .and_then(|host| self.mirrors.get(&host.to_lowercase()))
+        else {
+            return candidates;
+        };
+
+        let path = &url[parsed[..url::Position::BeforePath].len()..];
+        let health = self.health.lock().expect("mirror health lock poisoned");
+        let now = Instant::now();
+        candidates.extend(
+            mirrors
+                .iter()
+                .filter(|mirror| {
+                    health
+                        .get(*mirror)
+                        .and_then(|h| h.skip_until)
+                        .is_none_or(|until| until <= now)
+                })
+                .map(|mirror| {
+                    (
+                        Some(mirror.clone()),
+                        format!("{}{}", mirror.trim_end_matches('/'), path),
+                    )
+                }),
+        );
+
+        candidates
+    }
+
+    fn record(&self, mirror: &str, ok: bool) {
+        let mut health = self.health.lock().expect("mirror health lock poisoned");
+        let entry = health.entry(mirror.to_string()).or_default();
+
+        if ok {
+            *entry = Health::default();
+            return;
+        }
+
+        entry.consecutive_failures += 1;
+        if entry.consecutive_failures >= self.failure_threshold {
+            entry.skip_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    async fn request(&self, url: &str) -> Result<reqwest::Request> {
+        let request = self.client.get(url).build()?;
+        self.auth.authorize(&self.client, request).await
+    }
+
+    /// GET `url`, falling back to its mirrors on 5xx responses and transport
+    /// errors. Other responses, including 4xx, are returned as they are.
+    /// Transient failures retry the lot; hosts whose circuit breaker is open
+    /// are skipped, failing with [`CircuitOpen`](crate::breaker::CircuitOpen)
+    /// when none are left.
+    #[tracing::instrument(skip(self))]
+    pub async fn fetch(&self, url: &str) -> Result<reqwest::Response> {
+        self.retry.run("fetch", || self.fetch_once(url)).await
+    }
+
+    async fn fetch_once(&self, url: &str) -> Result<reqwest::Response> {
+        let mut last_error = eyre!("no origin to fetch {} from", url);
+
+        for (mirror, candidate) in self.candidates(url) {
+            let host = Url::parse(&candidate)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_lowercase))
+                .unwrap_or_default();
+            if let Err(e) = self.breakers.allow(&host) {
+                last_error = e.into();
+                continue;
+            }
+
+            let request = self.request(&candidate).await?;
+            let result = self.client.execute(request).await;
+            let failed = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+
+            self.breakers.record(&host, !failed);
+            if let Some(mirror) = &mirror {
+                self.record(mirror, !failed);
+            }
+
+            match result {
+                Ok(response) if !failed => return Ok(response),
+                Ok(response) => {
+                    warn!("{} responded {}", candidate, response.status());
+                    last_error = StatusError {
+                        url: candidate,
+                        status: response.status().as_u16(),
+                    }
+                    .into();
+                }
+                Err(e) => {
+                    warn!("failed to fetch {}: {}", candidate, e);
+                    last_error = e.into();
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirrors(threshold: u32, cooldown_secs: u64) -> Mirrors {
+        Mirrors::new(&LoaderSettings {
+            mirrors: HashMap::from([(
+                "cdn.example.com".to_string(),
+                vec![
+                    "https://backup.example.com/".to_string(),
+                    "http://bucket.s3-website.example.com".to_string(),
+                ],
+            )]),
+            mirror_failure_threshold: threshold,
+            mirror_cooldown_secs: cooldown_secs,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn urls(candidates: Vec<(Option<String>, String)>) -> Vec<String> {
+        candidates.into_iter().map(|(_, url)| url).collect()
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mirrors = mirrors(3, 30);
+
+        assert_eq!(
+            urls(mirrors.candidates("https://CDN.example.com/a/b.png?v=1")),
+            vec![
+                "https://CDN.example.com/a/b.png?v=1",
+                "https://backup.example.com/a/b.png?v=1",
+                "http://bucket.s3-website.example.com/a/b.png?v=1",
+            ]
+        );
+        assert_eq!(
+            urls(mirrors.candidates("https://other.example.com/a.png")),
+            vec!["https://other.example.com/a.png"]
+        );
+    }
+
+    #[test]
+    fn test_failing_mirror_is_skipped() {
+        let mirrors = mirrors(2, 30);
+        let backup = "https://backup.example.com/";
+
+        mirrors.record(backup, false);
+        assert_eq!(mirrors.candidates("https://cdn.example.com/a.png").len(), 3);
+
+        mirrors.record(backup, false);
+        assert_eq!(
+            urls(mirrors.candidates("https://cdn.example.com/a.png")),
+            vec![
+                "https://cdn.example.com/a.png",
+                "http://bucket.s3-website.example.com/a.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mirror_retried_after_cooldown() {
+        let mirrors = mirrors(1, 0);
+        mirrors.record("https://backup.example.com/", false);
+        assert_eq!(mirrors.candidates("https://cdn.example.com/a.png").len(), 3);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::config::SourceAuth;
+use aws_credential_types::Credentials;
+use aws_sigv4::http_request::{
+    sign, PayloadChecksumKind, SignableBody, SignableRequest, SigningSettings,
+};
+use aws_sigv4::sign::v4;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+/// Tokens are refreshed this long before they expire
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+/// Lifetime assumed when the token endpoint doesn't say
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+impl CachedToken {
+    fn is_fresh(&self, now: Instant) -> bool {
+        now + TOKEN_REFRESH_MARGIN < self.expires_at
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+enum Authenticator {
+    Sigv4 {
+        region: String,
+        service: String,
+        credentials: Credentials,
+    },
+    Oauth2 {
+        token_url: String,
+        client_id: String,
+        client_secret: SecretString,
+        scope: Option<String>,
+        token: Mutex<Option<CachedToken>>,
+    },
+}
+
+impl From<&SourceAuth> for Authenticator {
+    fn from(auth: &SourceAuth) -> Self {
+        match auth {
+            SourceAuth::AwsSigv4 {
+                region,
+                service,
+                access_key,
+                secret_key,
+            } => Authenticator::Sigv4 {
+                region: region.clone(),
+                service: service.clone(),
+                credentials: Credentials::new(
+                    access_key.expose_secret(),
+                    secret_key.expose_secret(),
+                    None,
+                    None,
+                    "imagor-rs",
+                ),
+            },
+            SourceAuth::Oauth2 {
+                token_url,
+                client_id,
+                client_secret,
+                scope,
+            } => Authenticator::Oauth2 {
+                token_url: token_url.clone(),
+                client_id: client_id.clone(),
+                client_secret: client_secret.clone(),
+                scope: scope.clone(),
+                token: Mutex::new(None),
+            },
+        }
+    }
+}
+
+/// Attaches credentials to requests for private origins, by source host
+#[derive(Clone, Default)]
+pub struct OriginAuth {
+    hosts: HashMap<String, Arc<Authenticator>>,
+}
+
+impl fmt::Debug for OriginAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OriginAuth")
+            .field("hosts", &self.hosts.keys())
+            .finish()
+    }
+}
+
+impl OriginAuth {
+    pub fn new(settings: &HashMap<String, SourceAuth>) -> Self {
+        Self {
+            hosts: settings
+                .iter()
+                .map(|(host, auth)| (host.to_lowercase(), Arc::new(auth.into())))
+                .collect(),
+        }
+    }
+
+    /// Sign or add a bearer token to `request` if its host needs it
+    pub async fn authorize(
+        &self,
+        client: &reqwest::Client,
+        mut request: reqwest::Request,
+    ) -> Result<reqwest::Request> {
+        let Some(auth) = request
+            .url()
+            .host_str()
+            .and_then(|host| self.hosts.get(&host.to_lowercase()))
+        else {
+            return Ok(request);
+        };
+
+        match auth.as_ref() {
+            Authenticator::Sigv4 {
+                region,
+                service,
+                credentials,
+            } => sign_sigv4(
+                &mut request,
+                region,
+                service,
+                credentials,
+                SystemTime::now(),
+            )?,
+            Authenticator::Oauth2 {
+                token_url,
+                client_id,
+                client_secret,
+                scope,
+                token,
+            } => {
+                let mut cached = token.lock().await;
+                let access_token = match cached.as_ref() {
+                    Some(cached) if cached.is_fresh(Instant::now()) => cached.access_token.clone(),
+                    _ => {
+                        let fresh = fetch_token(
+                            client,
+                            token_url,
+                            client_id,
+                            client_secret,
+                            scope.as_deref(),
+                        )
+                        .await?;
+                        let access_token = fresh.access_token.clone();
+                        *cached = Some(fresh);
+                        access_token
+                    }
+                };
+
+                request.headers_mut().insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("Bearer {}", access_token))?,
+                );
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+fn sign_sigv4(
+    request: &mut reqwest::Request,
+    region: &str,
+    service: &str,
+    credentials: &Credentials,
+    time: SystemTime,
+) -> Result<()> {
+    let mut settings = SigningSettings::default();
+    // S3 refuses requests without a payload hash
+    settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+
+    let identity = credentials.clone().into();
+    let params = v4::SigningParams::builder()
+        .identity(&identity)
+        .region(region)
+        .name(service)
+        .time(time)
+        .settings(settings)
+        .build()?
+        .into();
+
+    let headers = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+    let signable = SignableRequest::new(
+        request.method().as_str(),
+        request.url().as_str(),
+        headers,
+        SignableBody::UnsignedPayload,
+    )?;
+    let (instructions, _) = sign(signable, &params)?.into_parts();
+
+    let signed: Vec<(HeaderName, HeaderValue)> = instructions
+        .headers()
+        .map(|(name, value)| Ok((HeaderName::try_from(name)?, HeaderValue::from_str(value)?)))
+        .collect::<Result<_>>()?;
+    request.headers_mut().extend(signed);
+
+    Ok(())
+}
+
+async fn fetch_token(
+    client: &reqwest::Client,
+    token_url: &str,
+    client_id: &str,
+    client_secret: &SecretString,
+    scope: Option<&str>,
+) -> Result<CachedToken> {
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", client_id),
+        ("client_secret", client_secret.expose_secret()),
+    ];
+    if let Some(scope) = scope {
+        form.push(("scope", scope));
+    }
+
+    let response = client.post(token_url).form(&form).send().await?;
+    if !response.status().is_success() {
+        return Err(eyre!(
+            "token endpoint {} responded {}",
+            token_url,
+            response.status()
+        ));
+    }
+
+    let token: TokenResponse = response.json().await?;
+    let lifetime = token
+        .expires_in
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+
+    Ok(CachedToken {
+        access_token: token.access_token,
+        expires_at: Instant::now() + lifetime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_sign_sigv4() {
+        let client = reqwest::Client::new();
+        let mut request = client
+            .get("https://private-bucket.s3.amazonaws.com/images/cat.jpg")
+            .build()
+            .unwrap();
+        let credentials = Credentials::new("AKIDEXAMPLE", "secret", None, None, "test");
+        // 2015-08-30T12:36:00Z
+        let time = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+
+        sign_sigv4(&mut request, "us-east-1", "s3", &credentials, time).unwrap();
+
+        let header = |name: &str| request.headers().get(name).unwrap().to_str().unwrap();
+        assert!(header("authorization").starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/s3/aws4_request"
+        ));
+        assert_eq!(header("x-amz-date"), "20150830T123600Z");
+        assert_eq!(header("x-amz-content-sha256"), "UNSIGNED-PAYLOAD");
+    }
+
+    #[test]
+    fn test_cached_token_refreshes_early() {
+        let now = Instant::now();
+        let token = |secs| CachedToken {
+            access_token: "token".to_string(),
+            expires_at: now + Duration::from_secs(secs),
+        };
+
+        assert!(token(3600).is_fresh(now));
+        assert!(!token(10).is_fresh(now));
+    }
+
+    #[tokio::test]
+    async fn test_other_hosts_are_untouched() {
+        let auth = OriginAuth::new(&HashMap::from([(
+            "private.example.com".to_string(),
+            SourceAuth::AwsSigv4 {
+                region: "us-east-1".to_string(),
+                service: "s3".to_string(),
+                access_key: SecretString::from("AKIDEXAMPLE".to_string()),
+                secret_key: SecretString::from("secret".to_string()),
+            },
+        )]));
+        let client = reqwest::Client::new();
+
+        let public = client.get("https://example.com/a.jpg").build().unwrap();
+        let public = auth.authorize(&client, public).await.unwrap();
+        assert!(public.headers().get(AUTHORIZATION).is_none());
+
+        let private = client
+            .get("https://PRIVATE.example.com/a.jpg")
+            .build()
+            .unwrap();
+        let private = auth.authorize(&client, private).await.unwrap();
+        assert!(private.headers().get(AUTHORIZATION).is_some());
+    }
+}
@@ -0,0 +1,97 @@
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// How many times the image segment of a path is percent-decoded. The path
+/// parser always decodes once; the other modes handle callers that
+/// double-encode source URLs, which otherwise look up `%2F` literally.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UrlDecoding {
+    #[default]
+    Once,
+    /// Decode a second time whenever the result still has percent escapes
+    Auto,
+    Twice,
+}
+
+impl UrlDecoding {
+    /// `image` as the parser decoded it, decoded again if this mode asks
+    /// for it and the second pass is safe
+    pub fn apply(&self, image: &str) -> String {
+        let decode_again = match self {
+            UrlDecoding::Once => false,
+            UrlDecoding::Auto => has_escapes(image),
+            UrlDecoding::Twice => true,
+        };
+        if !decode_again {
+            return image.to_string();
+        }
+
+        // no `+` to space this time, a `+` here was already a `%2B`
+        match percent_decode_str(image).decode_utf8() {
+            Ok(decoded) if is_safe(&decoded) => {
+                if decoded != image {
+                    debug!("Decoded double-encoded image {} as {}", image, decoded);
+                }
+                decoded.into_owned()
+            }
+            _ => {
+                warn!("Not decoding image {} twice, the result is unsafe", image);
+                image.to_string()
+            }
+        }
+    }
+}
+
+/// Whether `s` has any `%XX` escape left
+fn has_escapes(s: &str) -> bool {
+    s.as_bytes()
+        .windows(3)
+        .any(|w| w[0] == b'%' && w[1].is_ascii_hexdigit() && w[2].is_ascii_hexdigit())
+}
+
+/// A second decode must not smuggle in what a single one would have shown:
+/// control characters, or `..` segments that escape the storage root
+fn is_safe(decoded: &str) -> bool {
+    !decoded.is_empty()
+        && !decoded.chars().any(char::is_control)
+        && !decoded.split(['/', '\\']).any(|segment| segment == "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_once_keeps_escapes() {
+        assert_eq!(
+            UrlDecoding::Once.apply("bucket/a%2Fb.jpg"),
+            "bucket/a%2Fb.jpg"
+        );
+    }
+
+    #[test]
+    fn test_double_encoded() {
+        // `https%253A%252F%252Fexample.com%252Fa%2520b.jpg` after the first decode
+        let once = "https%3A%2F%2Fexample.com%2Fa%20b.jpg";
+
+        for mode in [UrlDecoding::Auto, UrlDecoding::Twice] {
+            assert_eq!(mode.apply(once), "https://example.com/a b.jpg");
+        }
+        assert_eq!(UrlDecoding::Auto.apply("a+b.jpg"), "a+b.jpg");
+        assert_eq!(UrlDecoding::Auto.apply("100%.jpg"), "100%.jpg");
+    }
+
+    #[test]
+    fn test_unsafe_second_decode_is_skipped() {
+        for once in [
+            "a/%2E%2E/secret.jpg",
+            "a%2F..%2Fsecret.jpg",
+            "a%00.jpg",
+            "%FF.jpg",
+        ] {
+            assert_eq!(UrlDecoding::Twice.apply(once), once);
+        }
+    }
+}
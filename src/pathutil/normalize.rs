@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const UPPER_HEX: &str = "0123456789ABCDEF";
+
+trait SafeChars {
+    fn should_escape(&self, c: u8) -> bool;
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum SafeCharsType {
+    #[default]
+    Default,
+    Custom(HashSet<u8>),
+    Noop,
+}
+
+fn is_default_safe(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'/' || c == b'-' || c == b'_' || c == b'.' || c == b'~'
+}
+
+impl SafeChars for SafeCharsType {
+    fn should_escape(&self, c: u8) -> bool {
+        match self {
+            SafeCharsType::Default => !is_default_safe(c),
+            SafeCharsType::Custom(safe_chars) => !(is_default_safe(c) || safe_chars.contains(&c)),
+            SafeCharsType::Noop => false,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SafeCharsType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(s.into())
+    }
+}
+
+impl Serialize for SafeCharsType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            SafeCharsType::Default => serializer.serialize_str(""),
+            SafeCharsType::Noop => serializer.serialize_str("--"),
+            SafeCharsType::Custom(safe_chars) => {
+                let mut chars: Vec<u8> = safe_chars.iter().copied().collect();
+                chars.sort_unstable();
+                serializer.serialize_str(&String::from_utf8_lossy(&chars))
+            }
+        }
+    }
+}
+
+impl From<&str> for SafeCharsType {
+    fn from(s: &str) -> Self {
+        if s == "--" {
+            SafeCharsType::Noop
+        } else if s.is_empty() {
+            SafeCharsType::Default
+        } else {
+            SafeCharsType::Custom(s.bytes().collect())
+        }
+    }
+}
+
+impl From<String> for SafeCharsType {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+fn escape<F>(s: &str, should_escape: F) -> String
+where
+    F: Fn(u8) -> bool,
+{
+    let mut result = String::with_capacity(s.len());
+    for &c in s.as_bytes() {
+        if should_escape(c) {
+            if c == b' ' {
+                result.push('+');
+            } else {
+                result.push('%');
+                result.push(UPPER_HEX.as_bytes()[(c >> 4) as usize] as char);
+                result.push(UPPER_HEX.as_bytes()[(c & 15) as usize] as char);
+            }
+        } else {
+            result.push(c as char);
+        }
+    }
+    result
+}
+
+pub fn normalize(key: &str, safe_chars: &SafeCharsType) -> String {
+    let cleaned = key.replace("\r\n", "").replace(
+        [
+            '\r', '\n', '\u{000B}', '\u{000C}', '\u{0085}', '\u{2028}', '\u{2029}',
+        ],
+        "",
+    );
+
+    let cleaned = cleaned.trim_matches('/');
+    let path = Path::new(&cleaned).to_str().unwrap_or(cleaned);
+
+    escape(path, |c| safe_chars.should_escape(c))
+}
+
+/// The key a stored name was normalized from, so names found by listing
+/// can be handed back to the storage that stored them
+pub fn denormalize(name: &str, safe_chars: &SafeCharsType) -> String {
+    match safe_chars {
+        SafeCharsType::Noop => name.to_string(),
+        _ => percent_decode_str(&name.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_default_keeps_safe_chars() {
+        let key = "abcXYZ019/-_.~";
+        assert_eq!(normalize(key, &SafeCharsType::Default), key);
+    }
+
+    #[test]
+    fn test_normalize_default_escapes_every_unsafe_ascii_byte() {
+        for c in 0x20u8..0x7f {
+            let key = (c as char).to_string();
+            let expected = if is_default_safe(c) {
+                key.clone()
+            } else if c == b' ' {
+                "+".to_string()
+            } else {
+                format!("%{:02X}", c)
+            };
+            // leading and trailing slashes are trimmed before escaping
+            let expected = if c == b'/' { String::new() } else { expected };
+            assert_eq!(
+                normalize(&key, &SafeCharsType::Default),
+                expected,
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_escapes_non_ascii_bytes() {
+        assert_eq!(
+            normalize("café.jpg", &SafeCharsType::Default),
+            "caf%C3%A9.jpg"
+        );
+    }
+
+    #[test]
+    fn test_normalize_strips_line_breaks_and_outer_slashes() {
+        assert_eq!(
+            normalize("/a\r\nb\nc\u{2028}d/", &SafeCharsType::Default),
+            "abcd"
+        );
+    }
+
+    #[test]
+    fn test_normalize_custom_safe_chars() {
+        let safe_chars = SafeCharsType::from("!$");
+        assert_eq!(normalize("a!b$c&d e", &safe_chars), "a!b$c%26d+e");
+    }
+
+    #[test]
+    fn test_normalize_noop() {
+        let safe_chars = SafeCharsType::from("--");
+        assert_eq!(normalize("a b&c%d", &safe_chars), "a b&c%d");
+    }
+
+    #[test]
+    fn test_safe_chars_from_empty_string_is_default() {
+        assert!(matches!(SafeCharsType::from(""), SafeCharsType::Default));
+    }
+
+    #[test]
+    fn test_denormalize_round_trips() {
+        for key in ["a/b c.jpg", "café+1.jpg", "100%.png", "plain/key.png"] {
+            let normalized = normalize(key, &SafeCharsType::Default);
+            assert_eq!(denormalize(&normalized, &SafeCharsType::Default), key);
+        }
+        assert_eq!(denormalize("a+b%20", &SafeCharsType::Noop), "a+b%20");
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::PrefetchSettings;
+use crate::imagorpath::filter::Filter;
+use crate::imagorpath::hasher::suffix_result_storage_hasher;
+use crate::imagorpath::params::Params;
+use crate::processor::assets::Assets;
+use crate::processor::processor::ImageProcessor;
+use crate::result_meta::ResultMeta;
+use crate::storage::storage::{Blob, ImageStorage};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// Renders the pages following a `page(n)` request into result storage in
+/// the background, so a document viewer paging through sequentially hits
+/// stored results instead of waiting on the processor.
+#[derive(Clone)]
+pub struct Prefetcher {
+    pages: usize,
+    /// Prefetches only run on spare capacity, they never wait for a permit
+    permits: Arc<Semaphore>,
+}
+
+impl Prefetcher {
+    /// `None` when prefetching is disabled
+    pub fn new(settings: &PrefetchSettings) -> Option<Self> {
+        if !settings.enabled || settings.pages == 0 || settings.max_in_flight == 0 {
+            return None;
+        }
+
+        Some(Prefetcher {
+            pages: settings.pages,
+            permits: Arc::new(Semaphore::new(settings.max_in_flight)),
+        })
+    }
+
+    pub fn prefetch(
+        &self,
+        storage: Arc<dyn ImageStorage>,
+        processor: Arc<dyn ImageProcessor>,
+        params: &Params,
+        source: Arc<Blob>,
+        assets: Arc<Assets>,
+    ) {
+        let Some(page) = requested_page(params) else {
+            return;
+        };
+
+        for next in page + 1..=page + self.pages {
+            let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+                debug!("Prefetch queue is full, skipping pages from {}", next);
+                return;
+            };
+
+            let params = with_page(params, next);
+            let key = suffix_result_storage_hasher(&params);
+            let (storage, processor) = (storage.clone(), processor.clone());
+            let (source, assets) = (source.clone(), assets.clone());
+
+            tokio::spawn(async move {
+                let _permit = permit;
+                if storage.get(&key).await.is_ok() {
+                    return;
+                }
+
+                // rendered ahead of any request for it
+                let meta = ResultMeta {
+                    path: None,
+                    ..ResultMeta::new(&params)
+                };
+                let start = Instant::now();
+                let rendered = tokio::task::spawn_blocking(move || {
+                    processor.process(&source, &params, &assets)
+                })
+                .await;
+                match rendered {
+                    Ok(Ok(mut blob)) => {
+                        blob.metadata.custom = meta.with_duration(start.elapsed()).to_custom();
+                        let _ = storage.put(&key, &blob).await.inspect_err(|e| {
+                            warn!("Failed to save prefetched page [{}]: {}", key, e)
+                        });
+                    }
+                    // most likely past the last page
+                    Ok(Err(e)) => debug!("Failed to prefetch page {}: {}", next, e),
+                    Err(e) => warn!("Prefetch task failed: {}", e),
+                }
+            });
+        }
+    }
+}
+
+fn requested_page(params: &Params) -> Option<usize> {
+    params.filters.iter().find_map(|filter| match filter {
+        Filter::Page(page) => Some((*page).max(1)),
+        _ => None,
+    })
+}
+
+/// `params` for another page. The path is rewritten the same way so the
+/// result key matches the one a request for that page would get.
+pub fn with_page(params: &Params, page: usize) -> Params {
+    let mut params = params.clone();
+    let Some(current) = requested_page(&params) else {
+        return params;
+    };
+
+    for filter in params.filters.iter_mut() {
+        if let Filter::Page(_) = filter {
+            *filter = Filter::Page(page);
+        }
+    }
+    params.path = params
+        .path
+        .map(|path| path.replacen(&format!("page({})", current), &format!("page({})", page), 1));
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_params;
+
+    #[test]
+    fn test_with_page_matches_request_key() {
+        let params =
+            parse_params("unsafe/300x0/filters:page(2):quality(80)/docs/report.pdf").unwrap();
+        let next =
+            parse_params("unsafe/300x0/filters:page(3):quality(80)/docs/report.pdf").unwrap();
+
+        let prefetched = with_page(&params, 3);
+        assert_eq!(prefetched, next);
+        assert_eq!(
+            suffix_result_storage_hasher(&prefetched),
+            suffix_result_storage_hasher(&next)
+        );
+    }
+
+    #[test]
+    fn test_disabled_prefetcher() {
+        assert!(Prefetcher::new(&PrefetchSettings::default()).is_none());
+        assert!(Prefetcher::new(&PrefetchSettings {
+            enabled: true,
+            max_in_flight: 0,
+            ..Default::default()
+        })
+        .is_none());
+    }
+}
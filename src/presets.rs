@@ -0,0 +1,129 @@
+use crate::error::ApiError;
+use crate::imagorpath::params::Params;
+use crate::imagorpath::parse::{parse_params, query_unescape};
+use color_eyre::{eyre::eyre, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Named transformations from the config, requested as
+/// `/preset/<name>/<image>`. They need no signature, since clients can only
+/// pick from what the deployment offers.
+#[derive(Clone, Debug, Default)]
+pub struct Presets {
+    params: Arc<HashMap<String, Params>>,
+}
+
+impl Presets {
+    /// Each preset is an imagor path without the image, e.g.
+    /// `fit-in/200x200/filters:quality(80)`
+    pub fn new(presets: &HashMap<String, String>) -> Result<Self> {
+        let mut parsed = HashMap::new();
+        for (name, path) in presets {
+            let path = path.trim_matches('/');
+            let params = parse_params(&format!("{}/preset.jpg", path))
+                .map_err(|e| eyre!("Invalid preset `{}`: {}", name, e))?;
+            if params.image.as_deref() != Some("preset.jpg") {
+                return Err(eyre!("Preset `{}` mustn't include an image", name));
+            }
+            if params.unsafe_ || params.hash.is_some() {
+                return Err(eyre!(
+                    "Preset `{}` mustn't include a signature or `unsafe`",
+                    name
+                ));
+            }
+            // the preset's path, not the request's, so it's keyed like the
+            // equivalent imagor path
+            parsed.insert(
+                name.clone(),
+                Params {
+                    path: None,
+                    image: None,
+                    ..params
+                },
+            );
+        }
+
+        Ok(Presets {
+            params: Arc::new(parsed),
+        })
+    }
+
+    /// The params for `<name>/<image>`. The image is taken as is, so a
+    /// client can't slip its own filters in after the preset's.
+    pub fn params(&self, path: &str) -> Result<Params, ApiError> {
+        let (name, image) = path.split_once('/').unwrap_or((path, ""));
+        let preset = self
+            .params
+            .get(name)
+            .ok_or_else(|| ApiError::not_found(format!("No preset named `{}`", name)))?;
+        if image.is_empty() {
+            return Err(ApiError::bad_request("Image parameter is missing"));
+        }
+
+        Ok(Params {
+            image: Some(query_unescape(image)),
+            ..preset.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::filter::Filter;
+    use axum::http::StatusCode;
+
+    fn presets(presets: &[(&str, &str)]) -> Result<Presets> {
+        let presets = presets
+            .iter()
+            .map(|(name, path)| (name.to_string(), path.to_string()))
+            .collect();
+        Presets::new(&presets)
+    }
+
+    #[test]
+    fn test_preset_params() {
+        let presets = presets(&[("thumbnail", "fit-in/200x200/filters:quality(80)/")]).unwrap();
+
+        let params = presets.params("thumbnail/photos/cat.jpg").unwrap();
+        assert_eq!(params.width, Some(200));
+        assert_eq!(params.height, Some(200));
+        assert_eq!(params.filters, vec![Filter::Quality(80)]);
+        assert_eq!(params.image.as_deref(), Some("photos/cat.jpg"));
+        assert!(!params.unsafe_);
+
+        assert_eq!(
+            presets.params("hero/cat.jpg").unwrap_err().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            presets.params("thumbnail").unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_preset_filters_cannot_be_injected() {
+        let presets = presets(&[("thumbnail", "fit-in/200x200/filters:quality(80)")]).unwrap();
+
+        let params = presets
+            .params("thumbnail/filters:quality(100):strip_icc()/cat%20one.jpg")
+            .unwrap();
+        assert_eq!(params.filters, vec![Filter::Quality(80)]);
+        assert_eq!(
+            params.image.as_deref(),
+            Some("filters:quality(100):strip_icc()/cat one.jpg")
+        );
+
+        let params = presets.params("thumbnail/unsafe/0x0/cat.jpg").unwrap();
+        assert_eq!((params.width, params.height), (Some(200), Some(200)));
+        assert!(!params.unsafe_);
+    }
+
+    #[test]
+    fn test_invalid_presets() {
+        assert!(presets(&[("bad", "filters:nope(1)")]).is_err());
+        assert!(presets(&[("bad", "unsafe/200x200")]).is_err());
+        assert!(presets(&[("bad", "200x200/cat.jpg")]).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use crate::imagorpath::{filter::Filter, params::Params};
+use crate::storage::storage::Blob;
+
+/// External inputs a filter chain depends on, resolved on the async runtime
+/// before the image is handed to the blocking processor.
+#[derive(Debug, Default)]
+pub struct Assets {
+    images: HashMap<String, Blob>,
+}
+
+impl Assets {
+    /// Image URIs referenced by filters, deduplicated and in filter order.
+    pub fn required_images(params: &Params) -> Vec<String> {
+        params
+            .filters
+            .iter()
+            .filter_map(|filter| match filter {
+                Filter::Watermark(watermark) => Some(watermark.image.clone()),
+                _ => None,
+            })
+            .fold(Vec::new(), |mut acc, image| {
+                if !acc.contains(&image) {
+                    acc.push(image);
+                }
+                acc
+            })
+    }
+
+    pub fn insert_image(&mut self, uri: String, blob: Blob) {
+        self.images.insert(uri, blob);
+    }
+
+    pub fn image(&self, uri: &str) -> Option<&Blob> {
+        self.images.get(uri)
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = (&str, &Blob)> {
+        self.images.iter().map(|(uri, blob)| (uri.as_str(), blob))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_path;
+
+    #[test]
+    fn test_required_images_are_deduplicated() {
+        let (_, params) = parse_path(
+            "filters:watermark(a.png,0,0,0):grayscale():watermark(b.png,0,0,0):watermark(a.png,10,10,0)/img.jpg",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Assets::required_images(&params),
+            vec!["a.png".to_string(), "b.png".to_string()]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use super::image::ProcessError;
+use libvips::{ops::BandFormat, VipsImage};
+
+/// Decoded bytes allowed per pixel of `max_resolution`, enough for 16-bit RGBA
+const BYTES_PER_PIXEL: u64 = 8;
+
+/// What a load would decode, read from the image header alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeSize {
+    pub width: u64,
+    pub page_height: u64,
+    pub pages: u64,
+    pub bands: u64,
+    pub bytes_per_band: u64,
+}
+
+impl DecodeSize {
+    /// `image` must be opened lazily so only the header has been read.
+    /// `all_pages` is whether the real load will decode every page.
+    pub fn from_header(image: &VipsImage, all_pages: bool) -> Self {
+        let bytes_per_band = match image.get_format() {
+            Ok(BandFormat::Uchar | BandFormat::Char) => 1,
+            Ok(BandFormat::Ushort | BandFormat::Short) => 2,
+            Ok(BandFormat::Uint | BandFormat::Int | BandFormat::Float) => 4,
+            Ok(BandFormat::Complex | BandFormat::Double) => 8,
+            Ok(BandFormat::Dpcomplex) => 16,
+            _ => 1,
+        };
+        let pages = if all_pages { image.get_n_pages() } else { 1 };
+
+        DecodeSize {
+            width: image.get_width().max(0) as u64,
+            page_height: image.get_page_height().max(0) as u64,
+            pages: pages.max(1) as u64,
+            bands: image.get_bands().max(0) as u64,
+            bytes_per_band,
+        }
+    }
+
+    pub fn frame_pixels(&self) -> u64 {
+        self.width.saturating_mul(self.page_height)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.frame_pixels()
+            .saturating_mul(self.pages)
+            .saturating_mul(self.bands)
+            .saturating_mul(self.bytes_per_band)
+    }
+}
+
+/// Reject images wider or taller than the configured maximum
+pub fn check_dimensions(
+    width: u64,
+    height: u64,
+    max_width: i32,
+    max_height: i32,
+) -> Result<(), ProcessError> {
+    if width > max_width.max(0) as u64 || height > max_height.max(0) as u64 {
+        return Err(ProcessError::DecodeBudgetExceeded(format!(
+            "{}x{} exceeds the maximum of {}x{}",
+            width, height, max_width, max_height
+        )));
+    }
+    Ok(())
+}
+
+/// Per-request cap on decoded pixel bytes, so a small file that expands into
+/// thousands of frames is rejected before any of them are decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeBudget {
+    max_frame_pixels: u64,
+    max_bytes: u64,
+}
+
+impl DecodeBudget {
+    /// `None` when `max_resolution` is unset
+    pub fn from_max_resolution(max_resolution: i32) -> Option<Self> {
+        if max_resolution <= 0 {
+            return None;
+        }
+
+        let max_frame_pixels = max_resolution as u64;
+        Some(DecodeBudget {
+            max_frame_pixels,
+            max_bytes: max_frame_pixels * BYTES_PER_PIXEL,
+        })
+    }
+
+    pub fn check(&self, size: &DecodeSize) -> Result<(), ProcessError> {
+        if size.frame_pixels() > self.max_frame_pixels {
+            return Err(ProcessError::DecodeBudgetExceeded(format!(
+                "{}x{} exceeds the maximum resolution of {} pixels",
+                size.width, size.page_height, self.max_frame_pixels
+            )));
+        }
+        if size.bytes() > self.max_bytes {
+            return Err(ProcessError::DecodeBudgetExceeded(format!(
+                "decoding {} frames of {}x{} needs {} bytes, the budget is {}",
+                size.pages,
+                size.width,
+                size.page_height,
+                size.bytes(),
+                self.max_bytes
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u64, page_height: u64, pages: u64) -> DecodeSize {
+        DecodeSize {
+            width,
+            page_height,
+            pages,
+            bands: 4,
+            bytes_per_band: 1,
+        }
+    }
+
+    #[test]
+    fn test_check_dimensions() {
+        assert!(check_dimensions(4000, 3000, 4000, 4000).is_ok());
+        assert!(check_dimensions(4001, 3000, 4000, 4000).is_err());
+        assert!(check_dimensions(100, 5000, 4000, 4000).is_err());
+    }
+
+    #[test]
+    fn test_unset_max_resolution_has_no_budget() {
+        assert_eq!(DecodeBudget::from_max_resolution(0), None);
+    }
+
+    #[test]
+    fn test_decode_budget() {
+        let budget = DecodeBudget::from_max_resolution(1000 * 1000).unwrap();
+
+        assert!(budget.check(&size(1000, 1000, 1)).is_ok());
+        assert!(budget.check(&size(1000, 1000, 2)).is_ok());
+        // a single frame over the resolution limit
+        assert!(budget.check(&size(1001, 1000, 1)).is_err());
+        // small frames, but thousands of them
+        assert!(budget.check(&size(500, 500, 5000)).is_err());
+        // saturates instead of wrapping
+        assert!(budget.check(&size(u64::MAX, 1, u64::MAX)).is_err());
+    }
+}
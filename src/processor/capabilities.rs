@@ -0,0 +1,88 @@
+use libvips::bindings;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+
+/// Format name and the libvips operation prefix for its loader and saver.
+/// AVIF is encoded through the HEIF operations.
+const FORMATS: &[(&str, &str)] = &[
+    ("jpeg", "jpeg"),
+    ("png", "png"),
+    ("webp", "webp"),
+    ("gif", "gif"),
+    ("tiff", "tiff"),
+    ("heif", "heif"),
+    ("avif", "heif"),
+    ("jxl", "jxl"),
+    ("jp2k", "jp2k"),
+    ("pdf", "pdf"),
+    ("svg", "svg"),
+    ("magick", "magick"),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSupport {
+    pub load: bool,
+    pub save: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    pub max_width: i32,
+    pub max_height: i32,
+    pub max_resolution: i32,
+    pub max_animation_frames: usize,
+    pub max_filter_ops: usize,
+    pub concurrency: i32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub libvips: String,
+    pub formats: BTreeMap<&'static str, FormatSupport>,
+    /// Whether MP4 and WebM sources can be loaded, with the `video` feature
+    pub video: bool,
+    /// Whether `superres()` has a model to enlarge with
+    pub superres: bool,
+    pub limits: Limits,
+}
+
+/// Whether the linked libvips was built with operation `nickname`
+fn has_operation(nickname: &str) -> bool {
+    let (Ok(base), Ok(nickname)) = (CString::new("VipsOperation"), CString::new(nickname)) else {
+        return false;
+    };
+    unsafe { bindings::vips_type_find(base.as_ptr(), nickname.as_ptr()) != 0 }
+}
+
+fn libvips_version() -> String {
+    unsafe { CStr::from_ptr(bindings::vips_version_string()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Capabilities {
+    /// Probe the linked libvips for its loaders and savers
+    pub fn detect(limits: Limits) -> Self {
+        let formats = FORMATS
+            .iter()
+            .map(|(name, op)| {
+                let support = FormatSupport {
+                    load: has_operation(&format!("{}load_buffer", op)),
+                    save: has_operation(&format!("{}save_buffer", op)),
+                };
+                (*name, support)
+            })
+            .collect();
+
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            libvips: libvips_version(),
+            formats,
+            video: cfg!(feature = "video"),
+            superres: false,
+            limits,
+        }
+    }
+}
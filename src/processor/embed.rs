@@ -0,0 +1,100 @@
+use super::processor::process_params;
+use crate::imagorpath::{error::ParseError, params::Params, parse::parse_params};
+use crate::storage::storage::Blob;
+use color_eyre::{eyre::eyre, Result};
+use libvips::VipsApp;
+use std::sync::OnceLock;
+
+/// Started on first use and never shut down, since libvips can't be
+/// initialised again in the same process once it has been
+static VIPS: OnceLock<Result<VipsApp, String>> = OnceLock::new();
+
+fn ensure_vips() -> Result<()> {
+    VIPS.get_or_init(|| VipsApp::new("imagor_rs", false).map_err(|e| e.to_string()))
+        .as_ref()
+        .map(|_| ())
+        .map_err(|e| eyre!("Failed to initialize libvips: {}", e))
+}
+
+/// Anything [`process`] can take as the operations to run: an imagor path
+/// like `fit-in/300x200/filters:format(webp)/photo.jpg`, or parsed [`Params`]
+pub trait IntoParams {
+    fn into_params(self) -> Result<Params, ParseError>;
+}
+
+impl IntoParams for &str {
+    fn into_params(self) -> Result<Params, ParseError> {
+        parse_params(self.trim_start_matches('/'))
+    }
+}
+
+impl IntoParams for &String {
+    fn into_params(self) -> Result<Params, ParseError> {
+        self.as_str().into_params()
+    }
+}
+
+impl IntoParams for String {
+    fn into_params(self) -> Result<Params, ParseError> {
+        self.as_str().into_params()
+    }
+}
+
+impl IntoParams for Params {
+    fn into_params(self) -> Result<Params, ParseError> {
+        Ok(self)
+    }
+}
+
+impl IntoParams for &Params {
+    fn into_params(self) -> Result<Params, ParseError> {
+        Ok(self.clone())
+    }
+}
+
+/// Process an encoded image in memory, the way the server would answer a
+/// request for `path_or_params`, without running it.
+///
+/// libvips is initialised on the first call. Paths are used as written, so
+/// no URL signature is checked and the image segment is never loaded; the
+/// bytes in `blob` are the source. Like [`process_params`], filters that
+/// load other images such as `watermark()` need the full
+/// [`Processor`](super::processor::Processor).
+///
+/// ```no_run
+/// let jpeg = std::fs::read("photo.jpg")?;
+/// let webp = imagor_rs::process(&jpeg, "fit-in/300x200/filters:format(webp)/photo.jpg")?;
+/// std::fs::write("photo.webp", &webp.data)?;
+/// # Ok::<(), color_eyre::Report>(())
+/// ```
+pub fn process(blob: &[u8], path_or_params: impl IntoParams) -> Result<Blob> {
+    let params = path_or_params.into_params()?;
+    ensure_vips()?;
+
+    Ok(process_params(params, Blob::new(blob.to_vec()))?.blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_into_params() {
+        let params = "/unsafe/fit-in/300x200/photo.jpg".into_params().unwrap();
+        assert_eq!((params.width, params.height), (Some(300), Some(200)));
+        assert_eq!(params, params.clone().into_params().unwrap());
+
+        assert!("300x200/filters:nope(/photo.jpg".into_params().is_err());
+    }
+
+    #[test]
+    fn test_process_initializes_vips() {
+        let img = image::RgbImage::from_pixel(40, 20, image::Rgb([200, 10, 10]));
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let blob = process(&png, "20x0/filters:format(jpeg)/img.png").unwrap();
+        assert_eq!(blob.content_type, "image/jpeg");
+    }
+}
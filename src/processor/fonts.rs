@@ -0,0 +1,505 @@
+use crate::config::{FontSettings, FontSource};
+use color_eyre::{eyre::eyre, Result};
+use libvips::ops::{self, TextOptions};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use tracing::{info, warn};
+
+/// Where fontconfig usually finds the system's fonts
+const SYSTEM_FONT_DIRS: &[&str] = &[
+    "/usr/share/fonts",
+    "/usr/local/share/fonts",
+    "/Library/Fonts",
+    "/System/Library/Fonts",
+];
+
+/// Families fontconfig always resolves to something
+const GENERIC_FAMILIES: &[&str] = &[
+    "sans",
+    "sans-serif",
+    "serif",
+    "mono",
+    "monospace",
+    "cursive",
+    "fantasy",
+    "system-ui",
+    "emoji",
+];
+
+/// Words Pango reads as the style, weight, stretch or gravity of a font
+/// rather than part of its family
+const STYLE_WORDS: &[&str] = &[
+    "normal",
+    "roman",
+    "oblique",
+    "italic",
+    "small-caps",
+    "thin",
+    "ultra-light",
+    "extra-light",
+    "light",
+    "semi-light",
+    "demi-light",
+    "book",
+    "regular",
+    "medium",
+    "semi-bold",
+    "demi-bold",
+    "bold",
+    "ultra-bold",
+    "extra-bold",
+    "heavy",
+    "black",
+    "ultra-black",
+    "extra-black",
+    "ultra-condensed",
+    "extra-condensed",
+    "condensed",
+    "semi-condensed",
+    "semi-expanded",
+    "expanded",
+    "extra-expanded",
+    "ultra-expanded",
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Family {
+    pub name: String,
+    pub files: Vec<String>,
+    /// Found in a system font directory rather than a configured one
+    pub system: bool,
+}
+
+/// What `GET /admin/fonts` shows
+#[derive(Serialize, Debug, Default)]
+pub struct FontList {
+    pub families: Vec<Family>,
+    pub aliases: BTreeMap<String, String>,
+}
+
+#[derive(Default)]
+struct Fonts {
+    /// By lowercased name
+    families: BTreeMap<String, Family>,
+    /// Lowercased alias to family
+    aliases: HashMap<String, String>,
+    /// Until fonts are loaded, any font is let through to fontconfig
+    loaded: bool,
+}
+
+/// The font families `label()` can draw with
+#[derive(Default)]
+pub struct FontRegistry {
+    fonts: RwLock<Fonts>,
+}
+
+impl FontRegistry {
+    /// The registry labels are rendered with
+    pub fn global() -> &'static FontRegistry {
+        static GLOBAL: OnceLock<FontRegistry> = OnceLock::new();
+        GLOBAL.get_or_init(FontRegistry::default)
+    }
+
+    /// Find the configured fonts, and the system's, and hand the configured
+    /// ones to fontconfig. Needs libvips to be initialized.
+    pub fn load(&self, settings: &FontSettings) {
+        let mut dirs: Vec<(PathBuf, bool)> = settings
+            .directories
+            .iter()
+            .chain([&settings.cache_dir])
+            .map(|dir| (PathBuf::from(dir), false))
+            .collect();
+        if settings.system {
+            dirs.extend(
+                SYSTEM_FONT_DIRS
+                    .iter()
+                    .map(|dir| (PathBuf::from(dir), true)),
+            );
+            if let Some(home) = std::env::var_os("HOME") {
+                let home = PathBuf::from(home);
+                dirs.push((home.join(".local/share/fonts"), true));
+                dirs.push((home.join(".fonts"), true));
+            }
+        }
+
+        let mut fonts = Fonts {
+            loaded: true,
+            ..Default::default()
+        };
+        for (dir, system) in dirs {
+            for path in font_files(&dir) {
+                let families = File::open(&path).and_then(|f| font_families(BufReader::new(f)));
+                let names = match families {
+                    Ok(names) if !names.is_empty() => names,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Skipping font {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let file = path.to_string_lossy().into_owned();
+                if !system {
+                    if let Err(e) = register(&file) {
+                        warn!("fontconfig didn't take font {}: {}", file, e);
+                        continue;
+                    }
+                }
+                fonts.add(names, &file, system);
+            }
+        }
+        fonts.aliases = settings
+            .aliases
+            .iter()
+            .map(|(alias, family)| (alias.to_lowercase(), family.clone()))
+            .collect();
+        for (alias, family) in &fonts.aliases {
+            if !fonts.has_family(family) {
+                warn!("Font alias {} is for {}, which wasn't found", alias, family);
+            }
+        }
+
+        info!("Found {} font families", fonts.families.len());
+        *self.fonts.write().unwrap_or_else(|e| e.into_inner()) = fonts;
+    }
+
+    /// `description`, a Pango font description, with an alias swapped for
+    /// its family. Fails when the family wasn't found.
+    pub fn resolve(&self, description: &str) -> Result<String> {
+        let fonts = self.fonts.read().unwrap_or_else(|e| e.into_inner());
+        let (family, style) = split_description(description);
+        let family = fonts
+            .aliases
+            .get(&family.to_lowercase())
+            .cloned()
+            .unwrap_or(family);
+
+        if fonts.loaded && !fonts.has_family(&family) {
+            return Err(eyre!(
+                "Font `{}` isn't available, GET /admin/fonts lists the families that are",
+                family
+            ));
+        }
+        Ok([family, style]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+
+    pub fn list(&self) -> FontList {
+        let fonts = self.fonts.read().unwrap_or_else(|e| e.into_inner());
+        FontList {
+            families: fonts.families.values().cloned().collect(),
+            aliases: fonts
+                .aliases
+                .iter()
+                .map(|(alias, family)| (alias.clone(), family.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Fonts {
+    fn add(&mut self, names: Vec<String>, file: &str, system: bool) {
+        for name in names {
+            let family = self
+                .families
+                .entry(name.to_lowercase())
+                .or_insert_with(|| Family {
+                    name,
+                    files: Vec::new(),
+                    system,
+                });
+            family.files.push(file.to_string());
+            // a configured copy is the one fontconfig was given
+            family.system &= system;
+        }
+    }
+
+    fn has_family(&self, family: &str) -> bool {
+        let family = family.to_lowercase();
+        GENERIC_FAMILIES.contains(&family.as_str()) || self.families.contains_key(&family)
+    }
+}
+
+/// Download the sources that aren't in the cache directory yet. A font that
+/// fails to download is left out, and labels asking for it fail.
+pub async fn download(settings: &FontSettings) {
+    if settings.sources.is_empty() {
+        return;
+    }
+    if let Err(e) = tokio::fs::create_dir_all(&settings.cache_dir).await {
+        warn!("Failed to create font cache {}: {}", settings.cache_dir, e);
+        return;
+    }
+
+    for source in &settings.sources {
+        if let Err(e) = fetch(source, Path::new(&settings.cache_dir)).await {
+            warn!("Failed to download font {}: {}", source.url, e);
+        }
+    }
+}
+
+async fn fetch(source: &FontSource, cache_dir: &Path) -> Result<()> {
+    let name = source
+        .file
+        .as_deref()
+        .or_else(|| source.url.split(['?', '#']).next()?.rsplit('/').next())
+        .filter(|name| is_font_file(Path::new(name)) && !name.contains(['/', '\\']))
+        .ok_or_else(|| eyre!("name a .ttf, .otf or .ttc file to keep it as"))?;
+    let path = cache_dir.join(name);
+    if tokio::fs::try_exists(&path).await? {
+        return Ok(());
+    }
+
+    info!("Downloading font {}", source.url);
+    let data = reqwest::get(&source.url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    font_families(io::Cursor::new(&data))?;
+
+    // written aside first, so a partial download is never taken for a font
+    let partial = path.with_extension("part");
+    tokio::fs::write(&partial, &data).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    Ok(())
+}
+
+/// Hand a font file to fontconfig through libvips, which keeps it loaded
+/// for the life of the process
+fn register(file: &str) -> Result<()> {
+    ops::text_with_opts(
+        "a",
+        &TextOptions {
+            font: "sans".to_string(),
+            fontfile: file.to_string(),
+            ..Default::default()
+        },
+    )?;
+    Ok(())
+}
+
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["ttf", "otf", "ttc"].contains(&ext.to_lowercase().as_str()))
+}
+
+/// Font files under `dir`, sorted, nothing when it doesn't exist
+fn font_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if is_font_file(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The family and the style and size after it, as Pango splits a font
+/// description like `DejaVu Sans Bold Italic 12`
+pub fn split_description(description: &str) -> (String, String) {
+    let words: Vec<&str> = description.split_whitespace().collect();
+    let is_style = |word: &str| {
+        STYLE_WORDS.contains(&word.to_lowercase().as_str())
+            || word.trim_end_matches("px").parse::<f64>().is_ok()
+    };
+    let family_len = words.len() - words.iter().rev().take_while(|w| is_style(w)).count();
+    (words[..family_len].join(" "), words[family_len..].join(" "))
+}
+
+fn read_u16(file: &mut (impl Read + Seek), at: u64) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    file.seek(SeekFrom::Start(at))?;
+    file.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(file: &mut (impl Read + Seek), at: u64) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    file.seek(SeekFrom::Start(at))?;
+    file.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Family names in a TrueType or OpenType font, or in each font of a
+/// collection: the typographic family, which groups every weight, and the
+/// legacy one, which may not
+pub fn font_families(mut file: impl Read + Seek) -> io::Result<Vec<String>> {
+    let offsets = match &read_u32(&mut file, 0)?.to_be_bytes() {
+        b"ttcf" => {
+            let count = read_u32(&mut file, 8)?.min(256);
+            (0..count as u64)
+                .map(|i| read_u32(&mut file, 12 + 4 * i).map(u64::from))
+                .collect::<io::Result<Vec<_>>>()?
+        }
+        [0, 1, 0, 0] | b"OTTO" | b"true" => vec![0],
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a TrueType or OpenType font",
+            ))
+        }
+    };
+
+    let mut families: Vec<String> = Vec::new();
+    for offset in offsets {
+        for name in family_names(&mut file, offset)? {
+            if !families.iter().any(|f| f.eq_ignore_ascii_case(&name)) {
+                families.push(name);
+            }
+        }
+    }
+    Ok(families)
+}
+
+/// Name IDs 16 and 1 from the `name` table of the font at `offset`
+fn family_names(file: &mut (impl Read + Seek), offset: u64) -> io::Result<Vec<String>> {
+    let tables = read_u16(file, offset + 4)?;
+    let mut name_table = None;
+    for i in 0..tables as u64 {
+        let record = offset + 12 + 16 * i;
+        if &read_u32(file, record)?.to_be_bytes() == b"name" {
+            name_table = Some(read_u32(file, record + 8)? as u64);
+            break;
+        }
+    }
+    let Some(table) = name_table else {
+        return Ok(Vec::new());
+    };
+
+    let count = read_u16(file, table + 2)?;
+    let strings = table + read_u16(file, table + 4)? as u64;
+    let mut names = Vec::new();
+    for i in 0..count as u64 {
+        let record = table + 6 + 12 * i;
+        let (platform, encoding) = (read_u16(file, record)?, read_u16(file, record + 2)?);
+        let name_id = read_u16(file, record + 6)?;
+        if name_id != 1 && name_id != 16 {
+            continue;
+        }
+
+        let length = read_u16(file, record + 8)? as usize;
+        let at = strings + read_u16(file, record + 10)? as u64;
+        let mut data = vec![0; length];
+        file.seek(SeekFrom::Start(at))?;
+        file.read_exact(&mut data)?;
+        let name = match (platform, encoding) {
+            // UTF-16BE
+            (0, _) | (3, 0 | 1 | 10) => {
+                let units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            // Mac Roman, only trusted as far as it's ASCII
+            (1, 0) if data.is_ascii() => String::from_utf8_lossy(&data).into_owned(),
+            _ => continue,
+        };
+        let name = name.trim().to_string();
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A font with nothing but a `name` table, holding these names as
+    /// Windows Unicode records
+    fn font(names: &[(u16, &str)]) -> Vec<u8> {
+        let strings: Vec<Vec<u8>> = names
+            .iter()
+            .map(|(_, name)| name.encode_utf16().flat_map(u16::to_be_bytes).collect())
+            .collect();
+        let table_offset = 12 + 16;
+        let strings_offset = 6 + 12 * names.len();
+
+        let mut data = Vec::new();
+        data.extend([0, 1, 0, 0]);
+        data.extend(1u16.to_be_bytes());
+        data.extend([0; 6]);
+        data.extend(b"name");
+        data.extend([0; 4]);
+        data.extend((table_offset as u32).to_be_bytes());
+        data.extend([0; 4]);
+
+        data.extend(0u16.to_be_bytes());
+        data.extend((names.len() as u16).to_be_bytes());
+        data.extend((strings_offset as u16).to_be_bytes());
+        let mut at = 0;
+        for ((id, _), string) in names.iter().zip(&strings) {
+            for field in [3, 1, 0x409, *id, string.len() as u16, at as u16] {
+                data.extend(field.to_be_bytes());
+            }
+            at += string.len();
+        }
+        data.extend(strings.concat());
+        data
+    }
+
+    #[test]
+    fn test_font_families() {
+        let data = font(&[(1, "Inter Light"), (2, "Regular"), (16, "Inter")]);
+        assert_eq!(
+            font_families(io::Cursor::new(data)).unwrap(),
+            vec!["Inter Light", "Inter"]
+        );
+
+        assert!(font_families(io::Cursor::new(b"wOFF".repeat(4))).is_err());
+    }
+
+    #[test]
+    fn test_split_description() {
+        let split = split_description;
+        assert_eq!(split("sans"), ("sans".into(), "".into()));
+        assert_eq!(
+            split("DejaVu Sans Bold Italic 12"),
+            ("DejaVu Sans".into(), "Bold Italic 12".into())
+        );
+        assert_eq!(split("Inter 24px"), ("Inter".into(), "24px".into()));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let registry = FontRegistry::default();
+        // nothing loaded, so it's all up to fontconfig
+        assert_eq!(registry.resolve("Nowhere Sans").unwrap(), "Nowhere Sans");
+
+        {
+            let mut fonts = registry.fonts.write().unwrap();
+            fonts.loaded = true;
+            fonts.add(vec!["Inter".to_string()], "/fonts/Inter.ttf", false);
+            fonts
+                .aliases
+                .insert("brand".to_string(), "Inter".to_string());
+        }
+        assert_eq!(registry.resolve("inter Bold 20").unwrap(), "inter Bold 20");
+        assert_eq!(registry.resolve("Brand Bold").unwrap(), "Inter Bold");
+        assert_eq!(registry.resolve("sans-serif 12").unwrap(), "sans-serif 12");
+        let missing = registry.resolve("Comic Sans MS").unwrap_err();
+        assert!(missing.to_string().contains("Comic Sans MS"));
+
+        assert_eq!(registry.list().families.len(), 1);
+    }
+}
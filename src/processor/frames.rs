@@ -0,0 +1,163 @@
+use crate::imagorpath::filter::ImageType;
+
+/// Evenly spaced page indices when sampling `n` of `pages`
+pub fn sample_indices(pages: usize, n: usize) -> Vec<usize> {
+    if n == 0 || n >= pages {
+        return (0..pages).collect();
+    }
+
+    (0..n).map(|i| i * pages / n).collect()
+}
+
+/// Overwrite the per-frame delay of an encoded animation.
+///
+/// libvips reads frame delays from image metadata, which the bindings can't
+/// set, so the delay is patched into the encoded GIF/WebP instead.
+pub fn set_frame_delay(data: &mut [u8], format: ImageType, delay_ms: u32) {
+    match format {
+        ImageType::GIF => set_gif_delay(data, delay_ms),
+        ImageType::WEBP => set_webp_delay(data, delay_ms),
+        _ => {}
+    }
+}
+
+/// Skip a run of GIF data sub-blocks, returning the offset after the terminator
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let size = *data.get(pos)? as usize;
+        pos += 1;
+        if size == 0 {
+            return Some(pos);
+        }
+        pos += size;
+    }
+}
+
+fn color_table_size(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 * (1 << ((flags & 0x07) + 1))
+    } else {
+        0
+    }
+}
+
+fn set_gif_delay(data: &mut [u8], delay_ms: u32) {
+    // GIF delays are in hundredths of a second
+    let delay = ((delay_ms / 10).min(u16::MAX as u32) as u16).to_le_bytes();
+
+    if data.len() < 13 || !data.starts_with(b"GIF") {
+        return;
+    }
+    let mut pos = 13 + color_table_size(data[10]);
+
+    while let Some(&block) = data.get(pos) {
+        match block {
+            // extension
+            0x21 => {
+                let Some(&label) = data.get(pos + 1) else {
+                    return;
+                };
+                // graphic control extension: size, flags, delay (u16 le), ...
+                if label == 0xF9 && data.get(pos + 2) == Some(&4) && pos + 6 <= data.len() {
+                    data[pos + 4..pos + 6].copy_from_slice(&delay);
+                }
+                match skip_sub_blocks(data, pos + 2) {
+                    Some(next) => pos = next,
+                    None => return,
+                }
+            }
+            // image descriptor, local color table, LZW code size, image data
+            0x2C => {
+                let Some(&flags) = data.get(pos + 9) else {
+                    return;
+                };
+                match skip_sub_blocks(data, pos + 10 + color_table_size(flags) + 1) {
+                    Some(next) => pos = next,
+                    None => return,
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+fn set_webp_delay(data: &mut [u8], delay_ms: u32) {
+    // frame durations are 24 bit milliseconds
+    let delay = delay_ms.min(0xFF_FFFF).to_le_bytes();
+
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return;
+    }
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let payload = pos + 8;
+
+        // ANMF payload: x, y, width, height (3 bytes each), then duration
+        if &data[pos..pos + 4] == b"ANMF" && payload + 15 <= data.len() {
+            data[payload + 12..payload + 15].copy_from_slice(&delay[..3]);
+        }
+
+        // chunks are padded to an even size
+        pos = payload + size + (size & 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_indices() {
+        assert_eq!(sample_indices(10, 4), vec![0, 2, 5, 7]);
+        assert_eq!(sample_indices(3, 5), vec![0, 1, 2]);
+        assert_eq!(sample_indices(3, 0), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_set_gif_delay() {
+        let mut gif = Vec::new();
+        gif.extend_from_slice(b"GIF89a");
+        // logical screen descriptor, 2 entry global color table
+        gif.extend_from_slice(&[1, 0, 1, 0, 0x80, 0, 0]);
+        gif.extend_from_slice(&[0; 6]);
+        for _ in 0..2 {
+            // graphic control extension with a 0 delay
+            gif.extend_from_slice(&[0x21, 0xF9, 4, 0, 0, 0, 0, 0]);
+            // image descriptor, no local color table, one data sub-block
+            gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+            gif.extend_from_slice(&[2, 2, 0x4C, 0x01, 0]);
+        }
+        gif.push(0x3B);
+
+        set_frame_delay(&mut gif, ImageType::GIF, 250);
+
+        let delays: Vec<_> = gif
+            .windows(3)
+            .enumerate()
+            .filter(|(_, w)| w == &[0x21, 0xF9, 4])
+            .map(|(i, _)| u16::from_le_bytes([gif[i + 4], gif[i + 5]]))
+            .collect();
+        assert_eq!(delays, vec![25, 25]);
+    }
+
+    #[test]
+    fn test_set_webp_delay() {
+        let mut anmf = vec![0u8; 16];
+        anmf[12] = 0x10;
+        let mut webp = Vec::new();
+        webp.extend_from_slice(b"RIFF");
+        webp.extend_from_slice(&(4 + 8 + anmf.len() as u32).to_le_bytes());
+        webp.extend_from_slice(b"WEBP");
+        webp.extend_from_slice(b"ANMF");
+        webp.extend_from_slice(&(anmf.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&anmf);
+
+        set_frame_delay(&mut webp, ImageType::WEBP, 300);
+
+        let duration = &webp[20 + 12..20 + 15];
+        assert_eq!(duration, &[0x2C, 0x01, 0]);
+    }
+}
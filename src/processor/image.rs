@@ -1,31 +1,159 @@
+use std::any::Any;
 use std::ops::Deref;
 
+use super::assets::Assets;
+use super::fonts::FontRegistry;
+use super::frames::sample_indices;
+use super::label::{font_with_size, Vars};
+use super::tone::{curves_tables, gamma_table, vibrance_table, Table};
+use super::transform::{homography, quad_size};
 use crate::imagorpath::{
-    color::Color,
-    filter::{Filter, LabelPosition},
+    color::{Color, NamedColor},
+    filter::{Filter, LabelPosition, Region, ResizeKernel, WatermarkParams, WatermarkPosition},
     params::{Fit, Params},
+    parse::query_unescape,
+    registry::FilterRegistry,
+    type_utils::F32,
 };
+use crate::storage::storage::Blob;
 use color_eyre::{
     eyre::{self, Context},
     Result,
 };
 use libvips::{
     ops::{
-        self, Composite2Options, Direction, EmbedOptions, FlattenOptions, Interesting,
-        SharpenOptions, Size, TextOptions, ThumbnailImageOptions,
+        self, AffineOptions, ArrayjoinOptions, ColourspaceOptions, Composite2Options, Direction,
+        EmbedOptions, ExtractBandOptions, FlattenOptions, IccTransformOptions, Interesting,
+        MapimOptions, ResizeOptions, SharpenOptions, ShrinkOptions, Size, SmartcropOptions,
+        TextOptions, ThumbnailBufferOptions, ThumbnailImageOptions,
     },
-    VipsImage,
+    VipsImage, VipsInterpolate,
 };
 use metrics::IntoF64;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-#[derive(Error, Debug)]
+/// Serializable so render workers can hand it back over their pipe
+#[derive(Error, Debug, Serialize, Deserialize)]
 pub enum ProcessError {
     #[error("Image processing failed: {0}")]
     ImageProcessingError(String),
     #[error("Failed to load image")]
     ImageLoadError,
+    #[error("Failed to decode image: {0}")]
+    Undecodable(String),
+    #[error("Image is too large to decode: {0}")]
+    DecodeBudgetExceeded(String),
+    #[error("Page {page} is out of range, the image has {pages} pages")]
+    PageOutOfRange { page: usize, pages: usize },
+    #[error("Video sources are not supported, build with the `video` feature")]
+    VideoNotSupported,
+    #[error("Processing was abandoned after the deadline")]
+    Cancelled,
+    #[error("Filter {filter} panicked: {message}")]
+    FilterPanicked { filter: String, message: String },
+    #[error("Processing panicked: {0}")]
+    Panicked(String),
+}
+
+/// What a caught panic was raised with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+impl From<ResizeKernel> for ops::Kernel {
+    fn from(kernel: ResizeKernel) -> Self {
+        match kernel {
+            ResizeKernel::Nearest => ops::Kernel::Nearest,
+            ResizeKernel::Linear => ops::Kernel::Linear,
+            ResizeKernel::Cubic => ops::Kernel::Cubic,
+            ResizeKernel::Mitchell => ops::Kernel::Mitchell,
+            ResizeKernel::Lanczos2 => ops::Kernel::Lanczos2,
+            ResizeKernel::Lanczos3 => ops::Kernel::Lanczos3,
+        }
+    }
+}
+
+/// Checked between processing stages, a stage that's running isn't
+/// interrupted
+pub fn checkpoint(cancel: &CancellationToken) -> Result<(), ProcessError> {
+    if cancel.is_cancelled() {
+        return Err(ProcessError::Cancelled);
+    }
+    Ok(())
+}
+
+/// What `shadow()` grows the canvas by on the left, top, right and bottom:
+/// the blur spreads about three sigmas past the offset silhouette
+pub fn shadow_margins(x: i32, y: i32, sigma: f64) -> (i64, i64, i64, i64) {
+    let spread = (sigma.max(0.0) * 3.0).ceil() as i64;
+    let (x, y) = (x as i64, y as i64);
+    (
+        spread.saturating_sub(x).max(0),
+        spread.saturating_sub(y).max(0),
+        spread.saturating_add(x).max(0),
+        spread.saturating_add(y).max(0),
+    )
+}
+
+/// The size a `width` by `height` frame comes to with `filter`, for the
+/// filters that draw around the image. `None` for the rest.
+pub fn framed_size(filter: &Filter, width: i32, height: i32) -> Option<(u64, u64)> {
+    let (left, top, right, bottom) = match filter {
+        Filter::Border(border, _) => {
+            let border = *border as i64;
+            (border, border, border, border)
+        }
+        Filter::Shadow(x, y, sigma, _) => shadow_margins(*x, *y, sigma.0 as f64),
+        _ => return None,
+    };
+    let grow = |side: i32, before: i64, after: i64| {
+        (side.max(0) as u64)
+            .saturating_add(before as u64)
+            .saturating_add(after as u64)
+    };
+    Some((grow(width, left, right), grow(height, top, bottom)))
+}
+
+/// The size an `img_w` by `img_h` image is resized to for `params`
+pub fn target_dimensions(img_w: i32, img_h: i32, params: &Params, upscale: bool) -> (i32, i32) {
+    match (params.width, params.height) {
+        (None, None) => (img_w, img_h),
+        (None, Some(h)) => {
+            let w = img_w * h / img_h;
+            (if !upscale { w.min(img_w) } else { w }, h)
+        }
+        (Some(w), None) => {
+            let h = img_h * w / img_w;
+            (w, if !upscale { h.min(img_h) } else { h })
+        }
+        (Some(w), Some(h)) if params.fit == Some(Fit::FitIn) => {
+            // adaptive: rotate the box to follow the image orientation
+            let (w, h) = if params.adaptive_fit_in && (img_w > img_h) != (w > h) {
+                (h, w)
+            } else {
+                (w, h)
+            };
+
+            // full: the smaller side fits, so the image covers the box
+            if params.full_fit_in && img_w > 0 && img_h > 0 {
+                let scale = (w as f64 / img_w as f64).max(h as f64 / img_h as f64);
+                (
+                    (img_w as f64 * scale).round() as i32,
+                    (img_h as f64 * scale).round() as i32,
+                )
+            } else {
+                (w, h)
+            }
+        }
+        (Some(w), Some(h)) => (w, h),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +183,58 @@ impl Image {
         self.0.get_height() > self.0.get_page_height()
     }
 
+    /// Keep `n` evenly spaced pages of a multi-page image as an animation
+    #[instrument(skip(self))]
+    pub fn sample_frames(&self, n: usize) -> Result<Self, ProcessError> {
+        let page_height = self.0.get_page_height();
+        let pages = (self.0.get_height() / page_height) as usize;
+        let indices = sample_indices(pages, n);
+        if indices.len() == pages {
+            return Ok(self.clone());
+        }
+
+        let mut frames = indices
+            .into_iter()
+            .map(|i| {
+                ops::extract_area(
+                    &self.0,
+                    0,
+                    i as i32 * page_height,
+                    self.0.get_width(),
+                    page_height,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ProcessError::ImageProcessingError("Failed to extract frame".into()))?;
+
+        // the joined image keeps the source page-height, so each frame stays a page
+        let joined = ops::arrayjoin_with_opts(
+            &mut frames,
+            &ArrayjoinOptions {
+                across: 1,
+                ..Default::default()
+            },
+        )
+        .map_err(|_| ProcessError::ImageProcessingError("Failed to join frames".into()))?;
+
+        Ok(Image::new(joined))
+    }
+
+    /// Rotate upright by the EXIF orientation, which also drops the tag so
+    /// viewers don't rotate it a second time
+    #[instrument(skip(self))]
+    pub fn auto_orient(self) -> Result<Self, ProcessError> {
+        if self.0.get_orientation() <= 1 {
+            return Ok(self);
+        }
+
+        let rotated = ops::autorot(&self.0).map_err(|_| {
+            ProcessError::ImageProcessingError("Failed to apply EXIF orientation".into())
+        })?;
+
+        Ok(Image::new(rotated))
+    }
+
     #[instrument(skip(self))]
     pub fn apply_orientation(&self, orient: i32) -> Result<Self, ProcessError> {
         if orient > 0 {
@@ -70,32 +250,12 @@ impl Image {
 
     #[instrument(skip(self))]
     pub fn calculate_dimensions(&self, params: &Params, upscale: bool) -> (i32, i32) {
-        match (params.width, params.height) {
-            (None, None) => (self.0.get_width(), self.0.get_page_height()),
-            (None, Some(h)) => {
-                let w = self.0.get_width() * h / self.0.get_page_height();
-                (
-                    if !upscale {
-                        w.min(self.0.get_width())
-                    } else {
-                        w
-                    },
-                    h,
-                )
-            }
-            (Some(w), None) => {
-                let h = self.0.get_page_height() * w / self.0.get_width();
-                (
-                    w,
-                    if !upscale {
-                        h.min(self.0.get_page_height())
-                    } else {
-                        h
-                    },
-                )
-            }
-            (Some(w), Some(h)) => (w, h),
-        }
+        target_dimensions(
+            self.0.get_width(),
+            self.0.get_page_height(),
+            params,
+            upscale,
+        )
     }
 
     #[instrument(skip(self))]
@@ -105,7 +265,7 @@ impl Image {
         height: i32,
         fit: Option<Fit>,
         upscale: bool,
-        _params: &Params,
+        linear: bool,
     ) -> Result<Image, ProcessError> {
         let should_resize =
             upscale || width < self.0.get_width() || height < self.0.get_page_height();
@@ -123,6 +283,9 @@ impl Image {
                     height,
                     crop: Interesting::None,
                     size,
+                    // orientation is settled when loading
+                    no_rotate: true,
+                    linear,
                     ..Default::default()
                 },
             )
@@ -134,6 +297,75 @@ impl Image {
         }
     }
 
+    /// Resize to `width` by `height` with `kernel`, the way thumbnailing
+    /// does with its own: within the box for fit-in, exactly for stretch,
+    /// otherwise covering it and cropped to it by `crop`. A side that's 0
+    /// follows the other.
+    #[instrument(skip(self))]
+    pub fn resize_with_kernel(
+        &self,
+        width: i32,
+        height: i32,
+        fit: Option<Fit>,
+        upscale: bool,
+        kernel: ResizeKernel,
+        crop: Interesting,
+    ) -> Result<Image, ProcessError> {
+        let (img_w, img_h) = (self.0.get_width() as f64, self.0.get_page_height() as f64);
+        let x_scale = (width > 0).then(|| width as f64 / img_w);
+        let y_scale = (height > 0).then(|| height as f64 / img_h);
+        let (hscale, vscale) = match (fit, x_scale, y_scale) {
+            (_, None, None) => return Ok(self.to_owned()),
+            (Some(Fit::Stretch), Some(x), Some(y)) => (x, y),
+            (Some(Fit::FitIn), Some(x), Some(y)) => (x.min(y), x.min(y)),
+            (_, Some(x), Some(y)) => (x.max(y), x.max(y)),
+            (_, Some(scale), None) | (_, None, Some(scale)) => (scale, scale),
+        };
+        let (hscale, vscale) = if upscale {
+            (hscale, vscale)
+        } else {
+            (hscale.min(1.0), vscale.min(1.0))
+        };
+
+        let resized = if (hscale, vscale) == (1.0, 1.0) {
+            self.0.clone()
+        } else {
+            ops::resize_with_opts(
+                &self.0,
+                hscale,
+                &ResizeOptions {
+                    vscale,
+                    kernel: kernel.into(),
+                    // shrinking by blocks first would average the pixels
+                    // nearest is asked to keep
+                    gap: if kernel == ResizeKernel::Nearest {
+                        0.0
+                    } else {
+                        2.0
+                    },
+                },
+            )
+            .map_err(|_| ProcessError::ImageProcessingError("Failed to resize image".into()))?
+        };
+
+        let cropped = fit.is_none() && width > 0 && height > 0;
+        let (resized_w, resized_h) = (resized.get_width(), resized.get_height());
+        if !cropped || (resized_w <= width && resized_h <= height) {
+            return Ok(Image::new(resized));
+        }
+        ops::smartcrop_with_opts(
+            &resized,
+            width.min(resized_w),
+            height.min(resized_h),
+            &SmartcropOptions {
+                interesting: crop,
+                ..Default::default()
+            },
+        )
+        .map(Image::new)
+        .map_err(|_| ProcessError::ImageProcessingError("Failed to crop image".into()))
+    }
+
     #[instrument(skip(self))]
     pub fn apply_flip(&self, h_flip: bool, v_flip: bool) -> Result<Self, ProcessError> {
         let flipped = if h_flip {
@@ -155,8 +387,73 @@ impl Image {
         }
     }
 
-    #[tracing::instrument(skip(self))]
-    pub fn apply(&self, filter: &Filter, params: &Params) -> Result<Self> {
+    /// More than 8 bits per sample
+    pub fn is_high_depth(&self) -> bool {
+        !matches!(
+            self.0.get_format(),
+            Ok(ops::BandFormat::Uchar | ops::BandFormat::Char)
+        )
+    }
+
+    /// At 16 bits per sample, or 8, converted through the colourspace so
+    /// the range is scaled instead of clipped. CMYK is left as it is.
+    #[instrument(skip(self))]
+    pub fn with_depth(self, sixteen: bool) -> Result<Self, ProcessError> {
+        let at_depth = match self.0.get_format() {
+            Ok(ops::BandFormat::Uchar) => !sixteen,
+            Ok(ops::BandFormat::Ushort) => sixteen,
+            // float HDR and the like
+            _ => false,
+        };
+        if at_depth || matches!(self.0.get_interpretation(), Ok(ops::Interpretation::Cmyk)) {
+            return Ok(self);
+        }
+
+        let grey = self.0.get_bands() < 3;
+        let target = match (sixteen, grey) {
+            (true, true) => ops::Interpretation::Grey16,
+            (true, false) => ops::Interpretation::Rgb16,
+            (false, true) => ops::Interpretation::BW,
+            (false, false) => ops::Interpretation::Srgb,
+        };
+        ops::colourspace(&self.0, target)
+            .map(Image::new)
+            .map_err(|e| {
+                ProcessError::ImageProcessingError(format!("Failed to change bit depth: {}", e))
+            })
+    }
+
+    /// Convert CMYK, and RGB that carries an ICC profile, to sRGB through
+    /// the embedded profile, libvips' built-in one when there's none. The
+    /// result carries the sRGB profile. Other images are left as they are.
+    #[instrument(skip(self))]
+    pub fn to_srgb(self, profiled: bool) -> Result<Self, ProcessError> {
+        let (input_profile, depth) = match self.0.get_interpretation() {
+            Ok(ops::Interpretation::Cmyk) => ("cmyk", 8),
+            Ok(ops::Interpretation::Srgb | ops::Interpretation::Rgb) if profiled => ("srgb", 8),
+            Ok(ops::Interpretation::Rgb16) if profiled => ("srgb", 16),
+            _ => return Ok(self),
+        };
+
+        let converted = ops::icc_transform_with_opts(
+            &self.0,
+            "srgb",
+            &IccTransformOptions {
+                embedded: true,
+                input_profile: input_profile.to_string(),
+                depth,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| {
+            ProcessError::ImageProcessingError(format!("Failed to convert to sRGB: {}", e))
+        })?;
+
+        Ok(Image::new(converted))
+    }
+
+    #[tracing::instrument(skip(self, assets))]
+    pub fn apply(&self, filter: &Filter, params: &Params, assets: &Assets) -> Result<Self> {
         // Apply the filter to the imag
         match filter {
             Filter::RoundCorner(params) => {
@@ -231,7 +528,7 @@ impl Image {
 
                 Ok(Image::new(img))
             }
-            Filter::Label(params) => {
+            Filter::Label(label) => {
                 // Ensure image is in RGB/RGBA color space
                 let img = match self.0.get_interpretation()? as i32 {
                     // Compare raw discriminant values instead of enum variants
@@ -246,76 +543,101 @@ impl Image {
 
                 // Add alpha channel if not present
                 let img = if !img.image_hasalpha() {
-                    &ops::bandjoin_const(&img, &mut [255.0])?
+                    &ops::bandjoin_const(img, &mut [255.0])?
                 } else {
                     img
                 };
-
-                // Calculate x position
                 let width = img.get_width();
-                let x = match params.x {
-                    LabelPosition::Center => width / 2,
-                    LabelPosition::Right => width,
-                    LabelPosition::Left => 0,
-                    LabelPosition::Pixels(px) => {
-                        if px < 0 {
-                            width + px
-                        } else {
-                            px
-                        }
-                    }
-                    LabelPosition::Percentage(pct) => (pct.0 * width as f32) as i32,
-                    _ => 0,
-                };
-
-                // Calculate y position
                 let height = img.get_height();
-                let y = match params.y {
-                    LabelPosition::Center => (height - params.size as i32) / 2,
-                    LabelPosition::Top => 0,
-                    LabelPosition::Bottom => height - params.size as i32,
-                    LabelPosition::Pixels(px) => {
-                        if px < 0 {
-                            height + px - params.size as i32
-                        } else {
-                            px
+
+                // `var()` values only count when the URL was signed
+                let custom: Vec<(&str, String)> = params
+                    .filters
+                    .iter()
+                    .filter_map(|f| match f {
+                        Filter::Var(name, value) if !params.unsafe_ => {
+                            Some((name.as_str(), query_unescape(value)))
                         }
-                    }
-                    LabelPosition::Percentage(pct) => (pct.0 * height as f32) as i32,
-                    _ => 0,
-                };
+                        _ => None,
+                    })
+                    .collect();
+                // a frame's height, for animated images
+                let content = Vars::new(width, self.0.get_page_height())
+                    .with_custom(custom.iter().map(|(name, value)| (*name, value.as_str())))
+                    .expand(&query_unescape(&label.text));
 
                 // Get text color
-                let (r, g, b) = params
+                let (r, g, b) = label
                     .color
-                    .to_rgb(&img)
+                    .to_rgb(img)
                     .ok_or(eyre::eyre!("Invalid color"))?;
 
                 // Calculate alpha value (default to fully opaque if not specified)
-                let alpha = params.alpha.unwrap_or(255);
+                let alpha = label.alpha.unwrap_or(255);
+
+                // Use default font if none specified, failing on one that's missing
+                // rather than letting fontconfig swap in another
+                let font =
+                    FontRegistry::global().resolve(label.font.as_deref().unwrap_or("sans"))?;
 
-                // Use default font if none specified
-                let font = params.font.as_deref().unwrap_or("sans");
+                // Lines wrap inside the padding unless told otherwise
+                let padding = label.padding.unwrap_or(0) as i32;
+                let wrap = label
+                    .wrap
+                    .map_or(width - 2 * padding, |wrap| wrap as i32)
+                    .max(1);
 
                 // Create text overlay
                 let text = ops::text_with_opts(
-                    &params.text,
+                    &content,
                     &TextOptions {
-                        font: font.to_string(),
-                        width,
-                        height: params.size as i32,
-                        align: match params.x {
+                        font: font_with_size(&font, label.size),
+                        width: wrap,
+                        align: match label.x {
                             LabelPosition::Center => ops::Align::Centre,
                             LabelPosition::Right => ops::Align::High,
                             _ => ops::Align::Low,
                         },
                         dpi: 72,
-                        justify: true,
                         rgba: true,
                         spacing: 0,
                         ..Default::default()
                     },
                 )?;
+                let text_width = text.get_width();
+                let text_height = text.get_height();
+
+                // Calculate x position
+                let x = match label.x {
+                    LabelPosition::Center => (width - text_width) / 2,
+                    LabelPosition::Right => width - text_width - padding,
+                    LabelPosition::Left => padding,
+                    LabelPosition::Pixels(px) => {
+                        if px < 0 {
+                            width + px - text_width
+                        } else {
+                            px
+                        }
+                    }
+                    LabelPosition::Percentage(pct) => (pct.0 * width as f32 / 100.0) as i32,
+                    _ => padding,
+                };
+
+                // Calculate y position
+                let y = match label.y {
+                    LabelPosition::Center => (height - text_height) / 2,
+                    LabelPosition::Top => padding,
+                    LabelPosition::Bottom => height - text_height - padding,
+                    LabelPosition::Pixels(px) => {
+                        if px < 0 {
+                            height + px - text_height
+                        } else {
+                            px
+                        }
+                    }
+                    LabelPosition::Percentage(pct) => (pct.0 * height as f32 / 100.0) as i32,
+                    _ => padding,
+                };
 
                 // Colorize the text
                 let text = ops::linear(
@@ -427,7 +749,16 @@ impl Image {
 
                 Ok(Self(img))
             }
-            Filter::Blur(blur) => {
+            Filter::Blur(blur, Some(region)) => {
+                let sigma = blur.0 as f64;
+                if sigma <= 0.0 {
+                    return Ok(self.to_owned());
+                }
+                // a redaction, so every frame of an animation is blurred too
+                self.map_region(Some(region), |area| Ok(ops::gaussblur(area, sigma)?))
+                    .map_err(|e| eyre::eyre!("Failed to apply blur filter: {}", e))
+            }
+            Filter::Blur(blur, None) => {
                 if self.is_animated() {
                     return Ok(self.to_owned());
                 }
@@ -465,9 +796,123 @@ impl Image {
                 .map_err(|e| eyre::eyre!("Failed to apply sharpen filter: {}", e))
                 .map(Self)
             }
-            Filter::StripIcc => {
-                todo!()
+            // the classic sepia matrix, which on grey comes down to scaling
+            // each channel by the sum of its row
+            Filter::Sepia => self.tone_map([0.0; 3], [1.351, 1.203, 0.937]),
+            Filter::Duotone(dark, light) => {
+                let (Some(dark), Some(light)) = (dark.to_rgb(&self.0), light.to_rgb(&self.0))
+                else {
+                    return Ok(self.to_owned());
+                };
+                let dark = [dark.0, dark.1, dark.2].map(f64::from);
+                let light = [light.0, light.1, light.2].map(f64::from);
+                let slope = [0, 1, 2].map(|i| (light[i] - dark[i]) / 255.0);
+                self.tone_map(dark, slope)
+            }
+            Filter::Gamma(gamma) => {
+                let table = gamma_table(gamma.0 as f64);
+                self.map_rgb(&[table, table, table], false)
+                    .map_err(|e| eyre::eyre!("Failed to apply gamma filter: {}", e))
             }
+            Filter::Curves(curves) => self
+                .map_rgb(&curves_tables(curves), false)
+                .map_err(|e| eyre::eyre!("Failed to apply curves filter: {}", e)),
+            Filter::Vibrance(amount) => {
+                let factors =
+                    VipsImage::image_new_matrix_from_array(256, 1, &vibrance_table(*amount))?;
+                self.map_lch_band(1, |chroma| {
+                    let levels = ops::cast(&chroma, ops::BandFormat::Uchar)?;
+                    Ok(ops::multiply(&chroma, &ops::maplut(&levels, &factors)?)?)
+                })
+                .map_err(|e| eyre::eyre!("Failed to apply vibrance filter: {}", e))
+            }
+            Filter::AutoContrast => self
+                .auto_contrast()
+                .map_err(|e| eyre::eyre!("Failed to apply autocontrast filter: {}", e)),
+            Filter::Equalize => self
+                .map_lch_band(0, |lightness| {
+                    // L* is 0 to 100, histograms want whole levels
+                    let levels = ops::cast(
+                        &ops::linear(&lightness, &mut [2.55], &mut [0.0])?,
+                        ops::BandFormat::Uchar,
+                    )?;
+                    Ok(ops::linear(
+                        &ops::hist_equal(&levels)?,
+                        &mut [1.0 / 2.55],
+                        &mut [0.0],
+                    )?)
+                })
+                .map_err(|e| eyre::eyre!("Failed to apply equalize filter: {}", e)),
+            Filter::Pixelate(size) => {
+                let size = *size as i32;
+                self.map_region(None, |area| pixelate(area, size))
+                    .map_err(|e| eyre::eyre!("Failed to apply pixelate filter: {}", e))
+            }
+            Filter::PixelateRegion(region, size) => {
+                let size = *size as i32;
+                self.map_region(Some(region), |area| pixelate(area, size))
+                    .map_err(|e| eyre::eyre!("Failed to apply pixelate filter: {}", e))
+            }
+            Filter::Tint(color, strength) => {
+                let Some((r, g, b)) = color.to_rgb(&self.0) else {
+                    return Ok(self.to_owned());
+                };
+                let strength = (strength.0 as f64 / 100.0).clamp(0.0, 1.0);
+                let img = ops::colourspace(&self.0, ops::Interpretation::Srgb)?;
+
+                // multiplied, so whites take the color and blacks stay black
+                let mut scale: Vec<f64> = [r, g, b]
+                    .iter()
+                    .map(|c| 1.0 - strength + strength * *c as f64 / 255.0)
+                    .collect();
+                if img.image_hasalpha() {
+                    scale.push(1.0);
+                }
+                let mut offset = vec![0.0; scale.len()];
+                let tinted = ops::linear(&img, &mut scale, &mut offset)
+                    .map_err(|e| eyre::eyre!("Failed to apply tint filter: {}", e))?;
+                ops::cast(&tinted, ops::BandFormat::Uchar)
+                    .map_err(|e| eyre::eyre!("Failed to apply tint filter: {}", e))
+                    .map(Self)
+            }
+            Filter::Usm(radius, amount, threshold) => {
+                if self.is_animated() || radius.0 <= 0.0 || amount.0 <= 0.0 {
+                    return Ok(self.to_owned());
+                }
+
+                // sharpen works on L*, 0 to 100, edges fainter than the
+                // threshold are left alone and the halo isn't capped
+                ops::sharpen_with_opts(
+                    &self.0,
+                    &SharpenOptions {
+                        sigma: (radius.0 as f64).min(10.0),
+                        x_1: threshold.0.clamp(0.0, 255.0) as f64 * 100.0 / 255.0,
+                        y_2: 100.0,
+                        y_3: 100.0,
+                        m_1: 0.0,
+                        m_2: amount.0 as f64,
+                    },
+                )
+                .map_err(|e| eyre::eyre!("Failed to apply usm filter: {}", e))
+                .map(Self)
+            }
+            Filter::Denoise(strength) => {
+                if self.is_animated() || strength.0 <= 0.0 {
+                    return Ok(self.to_owned());
+                }
+
+                // a median takes out speckles without softening edges, a
+                // light blur after it evens out the grain that's left
+                let strength = strength.0.min(10.0) as f64;
+                let size = if strength > 5.0 { 5 } else { 3 };
+                let median = ops::rank(&self.0, size, size, size * size / 2)
+                    .map_err(|e| eyre::eyre!("Failed to apply denoise filter: {}", e))?;
+                ops::gaussblur(&median, strength / 10.0)
+                    .map_err(|e| eyre::eyre!("Failed to apply denoise filter: {}", e))
+                    .map(Self)
+            }
+            // dropped on export, after colours are converted
+            Filter::StripIcc => Ok(self.to_owned()),
             Filter::StripExif => {
                 todo!()
             }
@@ -490,6 +935,60 @@ impl Image {
                     color,
                 )
             }
+            Filter::Border(width, color) => {
+                let width = *width as i32;
+                self.fill(
+                    self.0.get_width(),
+                    self.0.get_page_height(),
+                    width,
+                    width,
+                    width,
+                    width,
+                    color,
+                )
+                .map_err(|e| eyre::eyre!("Failed to apply border filter: {}", e))
+            }
+            Filter::Shadow(x, y, sigma, color) => {
+                if self.is_animated() {
+                    return Ok(self.to_owned());
+                }
+                self.shadow(*x, *y, sigma.0 as f64, color)
+                    .map_err(|e| eyre::eyre!("Failed to apply shadow filter: {}", e))
+            }
+            // run by the processor, which holds the model
+            Filter::Superres(_) => Ok(self.to_owned()),
+            Filter::Affine([a, b, c, d, tx, ty]) => {
+                if self.is_animated() {
+                    return Ok(self.to_owned());
+                }
+                let (width, height) = (self.0.get_width(), self.0.get_height());
+                ops::affine_with_opts(
+                    &self.0,
+                    a.0 as f64,
+                    b.0 as f64,
+                    c.0 as f64,
+                    d.0 as f64,
+                    &AffineOptions {
+                        interpolate: VipsInterpolate::new_from_name("bicubic")?,
+                        // the same canvas, so the translation shows
+                        oarea: vec![0, 0, width, height],
+                        odx: tx.0 as f64,
+                        ody: ty.0 as f64,
+                        background: vec![0.0; self.0.get_bands() as usize],
+                        extend: ops::Extend::Background,
+                        ..Default::default()
+                    },
+                )
+                .map_err(|e| eyre::eyre!("Failed to apply affine filter: {}", e))
+                .map(Self)
+            }
+            Filter::Perspective(points) => {
+                if self.is_animated() {
+                    return Ok(self.to_owned());
+                }
+                self.perspective(points)
+                    .map_err(|e| eyre::eyre!("Failed to apply perspective filter: {}", e))
+            }
             Filter::Proportion(proporation) => {
                 let mut scale = proporation.0.clamp(0.0, 100.0);
                 if scale > 1.0 {
@@ -505,6 +1004,7 @@ impl Image {
                     &ThumbnailImageOptions {
                         height,
                         crop: Interesting::None,
+                        no_rotate: true,
                         ..Default::default()
                     },
                 )
@@ -512,19 +1012,73 @@ impl Image {
 
                 Ok(Self(thumbnail))
             }
-            Filter::Fill(color) => self.fill(
-                self.0.get_width(),
-                self.0.get_height(),
-                params.padding_left.unwrap_or(0),
-                params.padding_top.unwrap_or(0),
-                params.padding_right.unwrap_or(0),
-                params.padding_bottom.unwrap_or(0),
-                color,
-            ),
+            Filter::Watermark(watermark) => {
+                let blob = assets.image(&watermark.image).ok_or_else(|| {
+                    eyre::eyre!("Watermark image not loaded: {}", watermark.image)
+                })?;
+
+                self.watermark(blob, watermark)
+            }
+            Filter::Fill(color) => {
+                let (width, height) = self.letterbox_size(params);
+                self.fill(
+                    width,
+                    height,
+                    params.padding_left.unwrap_or(0),
+                    params.padding_top.unwrap_or(0),
+                    params.padding_right.unwrap_or(0),
+                    params.padding_bottom.unwrap_or(0),
+                    color,
+                )
+            }
+            Filter::Custom(custom) => FilterRegistry::global().apply(self, custom),
             _ => Ok(self.to_owned()),
         }
     }
 
+    /// The size `fill()` pads to: the whole box for `fit-in` with both
+    /// dimensions, so the image is letterboxed, otherwise the image itself
+    fn letterbox_size(&self, params: &Params) -> (i32, i32) {
+        let (width, height) = (self.0.get_width(), self.0.get_page_height());
+        match (params.fit, params.width, params.height) {
+            (Some(Fit::FitIn), Some(w), Some(h)) if w > 0 && h > 0 && !params.full_fit_in => {
+                let (w, h) = self.calculate_dimensions(params, true);
+                (w.max(width), h.max(height))
+            }
+            _ => (width, height),
+        }
+    }
+
+    /// Pad the image when the path has padding but no `fill()` filter
+    /// already consumed it
+    #[tracing::instrument(skip(self))]
+    pub fn apply_padding(&self, params: &Params) -> Result<Self> {
+        let has_padding = [
+            params.padding_left,
+            params.padding_top,
+            params.padding_right,
+            params.padding_bottom,
+        ]
+        .iter()
+        .any(|p| p.unwrap_or(0) > 0);
+        let has_fill = params.filters.iter().any(|f| matches!(f, Filter::Fill(_)));
+
+        if !has_padding || has_fill {
+            return Ok(self.to_owned());
+        }
+
+        self.fill(
+            self.0.get_width(),
+            self.0.get_page_height(),
+            params.padding_left.unwrap_or(0),
+            params.padding_top.unwrap_or(0),
+            params.padding_right.unwrap_or(0),
+            params.padding_bottom.unwrap_or(0),
+            &Color::Named(NamedColor::White),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(skip(self))]
     fn fill(
         &self,
@@ -568,7 +1122,6 @@ impl Image {
                     &ops::EmbedOptions {
                         extend: ops::Extend::Background,
                         background: vec![0.0, 0.0, 0.0, 0.0],
-                        ..Default::default()
                     },
                 )?;
 
@@ -585,6 +1138,7 @@ impl Image {
                     &ThumbnailImageOptions {
                         height: total_height,
                         size: Size::Force,
+                        no_rotate: true,
                         ..Default::default()
                     },
                 )?;
@@ -633,7 +1187,6 @@ impl Image {
                     &EmbedOptions {
                         extend: ops::Extend::Background,
                         background: vec![r.into(), g.into(), b.into()],
-                        ..Default::default()
                     },
                 )?;
 
@@ -642,6 +1195,324 @@ impl Image {
         }
     }
 
+    /// Straighten the quad with these corners, clockwise from the top left,
+    /// out into a rectangle the size of its longer edges. Corners are in
+    /// pixels, or fractions of the image's size when none is above 1.
+    #[tracing::instrument(skip(self))]
+    fn perspective(&self, points: &[(F32, F32); 4]) -> Result<Self> {
+        let (width, height) = (self.0.get_width() as f64, self.0.get_height() as f64);
+        let fractions = points.iter().all(|(x, y)| x.0 <= 1.0 && y.0 <= 1.0);
+        let corners = points.map(|(x, y)| match fractions {
+            true => (x.0 as f64 * width, y.0 as f64 * height),
+            false => (x.0 as f64, y.0 as f64),
+        });
+
+        let (out_width, out_height) = quad_size(corners);
+        if out_width < 1 || out_height < 1 {
+            return Err(eyre::eyre!("the corners don't enclose anything"));
+        }
+        let (right, bottom) = (out_width as f64, out_height as f64);
+        let rect = [(0.0, 0.0), (right, 0.0), (right, bottom), (0.0, bottom)];
+        let h = homography(rect, corners)
+            .ok_or_else(|| eyre::eyre!("the corners don't make a quad"))?;
+
+        // for every output pixel, where to sample the source:
+        // (h0 x + h1 y + h2, h3 x + h4 y + h5) / (h6 x + h7 y + 1)
+        let xy = ops::xyz(out_width, out_height)?;
+        let matrix =
+            VipsImage::image_new_matrix_from_array(2, 3, &[h[0], h[1], h[3], h[4], h[6], h[7]])?;
+        let projected = ops::linear(
+            &ops::recomb(&xy, &matrix)?,
+            &mut [1.0, 1.0, 1.0],
+            &mut [h[2], h[5], 1.0],
+        )?;
+        let index = ops::divide(
+            &ops::extract_band_with_opts(&projected, 0, &ExtractBandOptions { n: 2 })?,
+            &ops::extract_band(&projected, 2)?,
+        )?;
+
+        let img = ops::mapim_with_opts(
+            &self.0,
+            &index,
+            &MapimOptions {
+                interpolate: VipsInterpolate::new_from_name("bicubic")?,
+                background: vec![0.0; self.0.get_bands() as usize],
+                extend: ops::Extend::Background,
+                ..Default::default()
+            },
+        )?;
+        Ok(Self(img))
+    }
+
+    /// Put the image over a blurred, offset copy of its silhouette in
+    /// `color`, on a transparent canvas grown to fit the shadow
+    #[tracing::instrument(skip(self))]
+    fn shadow(&self, x: i32, y: i32, sigma: f64, color: &Color) -> Result<Self> {
+        let (r, g, b) = color
+            .to_rgb(self.as_inner())
+            .ok_or_else(|| eyre::eyre!("Invalid color"))?;
+        let img = ops::colourspace(&self.0, ops::Interpretation::Srgb)?;
+        let img = if img.image_hasalpha() {
+            img
+        } else {
+            ops::bandjoin_const(&img, &mut [255.0])?
+        };
+        let (width, height) = (img.get_width(), img.get_height());
+
+        // within the size limits, the processor having checked
+        let (left, top, right, bottom) = shadow_margins(x, y, sigma);
+        let (left, top) = (left as i32, top as i32);
+        let canvas_width = left + width + right as i32;
+        let canvas_height = top + height + bottom as i32;
+
+        let silhouette = ops::embed_with_opts(
+            &ops::extract_band(&img, 3)?,
+            left + x,
+            top + y,
+            canvas_width,
+            canvas_height,
+            &EmbedOptions {
+                extend: ops::Extend::Black,
+                ..Default::default()
+            },
+        )?;
+        let silhouette = if sigma > 0.0 {
+            ops::cast(&ops::gaussblur(&silhouette, sigma)?, ops::BandFormat::Uchar)?
+        } else {
+            silhouette
+        };
+        // one band times nothing plus the color is that color everywhere
+        let fill = ops::cast(
+            &ops::linear(
+                &silhouette,
+                &mut [0.0, 0.0, 0.0],
+                &mut [r.into(), g.into(), b.into()],
+            )?,
+            ops::BandFormat::Uchar,
+        )?;
+        // still sRGB, the header having come all the way from the alpha band
+        let shadow = ops::bandjoin(&mut [fill, silhouette])?;
+
+        let result = ops::composite_2_with_opts(
+            &shadow,
+            &img,
+            ops::BlendMode::Over,
+            &Composite2Options {
+                x: left,
+                y: top,
+                ..Default::default()
+            },
+        )?;
+        Ok(Self(result))
+    }
+
+    #[tracing::instrument(skip(self, blob))]
+    fn watermark(&self, blob: &Blob, params: &WatermarkParams) -> Result<Self> {
+        let width = self.0.get_width();
+        let height = self.0.get_page_height();
+
+        // Fit the overlay in the requested ratio of the image, if any
+        let overlay = match params.w_ratio {
+            Some(w_ratio) => {
+                let w = (width as f32 * w_ratio.0 / 100.0) as i32;
+                let h = params
+                    .h_ratio
+                    .map(|h_ratio| (height as f32 * h_ratio.0 / 100.0) as i32)
+                    .unwrap_or(height);
+                ops::thumbnail_buffer_with_opts(
+                    blob.as_ref(),
+                    w.max(1),
+                    &ThumbnailBufferOptions {
+                        height: h.max(1),
+                        crop: Interesting::None,
+                        ..Default::default()
+                    },
+                )?
+            }
+            None => VipsImage::new_from_buffer(blob.as_ref(), "")?,
+        };
+
+        let overlay = ops::colourspace(&overlay, ops::Interpretation::Srgb)?;
+        let overlay = if !overlay.image_hasalpha() {
+            ops::bandjoin_const(&overlay, &mut [255.0])?
+        } else {
+            overlay
+        };
+
+        // alpha is 0 (opaque) to 100 (fully transparent)
+        let overlay = if params.alpha > 0 {
+            let opacity = 1.0 - (params.alpha.min(100) as f64 / 100.0);
+            ops::linear(
+                &overlay,
+                &mut [1.0, 1.0, 1.0, opacity],
+                &mut [0.0, 0.0, 0.0, 0.0],
+            )?
+        } else {
+            overlay
+        };
+
+        let overlay_width = overlay.get_width();
+        let overlay_height = overlay.get_height();
+        let across = if params.x == WatermarkPosition::Repeat {
+            width / overlay_width.max(1) + 1
+        } else {
+            1
+        };
+        let down = if params.y == WatermarkPosition::Repeat {
+            height / overlay_height.max(1) + 1
+        } else {
+            1
+        };
+        let overlay = if across > 1 || down > 1 {
+            ops::replicate(&overlay, across, down)?
+        } else {
+            overlay
+        };
+
+        let x = watermark_offset(&params.x, width, overlay_width);
+        let y = watermark_offset(&params.y, height, overlay_height);
+
+        let img = ops::composite_2_with_opts(
+            &self.0,
+            &overlay,
+            ops::BlendMode::Over,
+            &Composite2Options {
+                x,
+                y,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| eyre::eyre!("Failed to apply watermark: {}", e))?;
+
+        Ok(Self(img))
+    }
+
+    /// Replace R, G and B by `offset + slope × luminance` each, through a
+    /// lookup table, keeping alpha
+    #[tracing::instrument(skip(self))]
+    fn tone_map(&self, offset: [f64; 3], slope: [f64; 3]) -> Result<Self> {
+        let tables = [0, 1, 2].map(|band| -> Table {
+            std::array::from_fn(|i| offset[band] + slope[band] * i as f64)
+        });
+        self.map_rgb(&tables, true)
+    }
+
+    /// Run the RGB bands, made grey first if asked, through a table each,
+    /// leaving alpha as it was
+    fn map_rgb(&self, tables: &[Table; 3], grey: bool) -> Result<Self> {
+        let img = ops::colourspace(&self.0, ops::Interpretation::Srgb)?;
+        let (rgb, alpha) = if img.image_hasalpha() {
+            (
+                ops::extract_band_with_opts(&img, 0, &ExtractBandOptions { n: 3 })?,
+                Some(ops::extract_band(&img, 3)?),
+            )
+        } else {
+            (img, None)
+        };
+        let rgb = if grey {
+            // grey, but still three sRGB bands for the table to map one each
+            ops::colourspace(
+                &ops::colourspace(&rgb, ops::Interpretation::BW)?,
+                ops::Interpretation::Srgb,
+            )?
+        } else {
+            rgb
+        };
+
+        let mut bands = tables
+            .iter()
+            .map(|table| VipsImage::image_new_matrix_from_array(256, 1, &table.map(f64::round)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let lut = ops::cast(&ops::bandjoin(&mut bands)?, ops::BandFormat::Uchar)?;
+        let mapped = ops::maplut(&rgb, &lut)?;
+
+        let img = match alpha {
+            Some(alpha) => ops::bandjoin(&mut [mapped, alpha])?,
+            None => mapped,
+        };
+        Ok(Self(img))
+    }
+
+    /// Run `f` over a region of every page, or over each whole page without
+    /// one, leaving the rest as it was
+    fn map_region(
+        &self,
+        region: Option<&Region>,
+        f: impl Fn(&VipsImage) -> Result<VipsImage>,
+    ) -> Result<Self> {
+        let (width, page_height) = (self.0.get_width(), self.0.get_page_height());
+        let area = match region {
+            Some(region) => region.area(width, page_height),
+            None => Some((0, 0, width, page_height)),
+        };
+        let Some((left, top, w, h)) = area else {
+            return Ok(self.to_owned());
+        };
+
+        let mut img = ops::copy(&self.0)?;
+        for page in 0..self.0.get_height() / page_height {
+            let y = page * page_height + top;
+            let mapped = f(&ops::extract_area(&self.0, left, y, w, h)?)?;
+            img = ops::insert(&img, &mapped, left, y)?;
+        }
+        Ok(Self(img))
+    }
+
+    /// Replace one band of the image in LCh, 0 for lightness and 1 for
+    /// chroma, and go back to the colorspace it came in
+    fn map_lch_band(
+        &self,
+        band: i32,
+        f: impl FnOnce(VipsImage) -> Result<VipsImage>,
+    ) -> Result<Self> {
+        let colorspace = match self.0.get_interpretation()? {
+            ops::Interpretation::Rgb => ops::Interpretation::Srgb,
+            cs => cs,
+        };
+        let lch = ops::colourspace(&self.0, ops::Interpretation::Lch)?;
+        let mut bands = (0..lch.get_bands())
+            .map(|i| ops::extract_band(&lch, i))
+            .collect::<Result<Vec<_>, _>>()?;
+        let replaced = f(bands.remove(band as usize))?;
+        bands.insert(band as usize, replaced);
+        let lch = ops::bandjoin(&mut bands)?;
+        // the joined header comes from whatever f returned, so say it's LCh
+        let img = ops::colourspace_with_opts(
+            &lch,
+            colorspace,
+            &ColourspaceOptions {
+                source_space: ops::Interpretation::Lch,
+            },
+        )?;
+        Ok(Self(img))
+    }
+
+    /// Stretch the levels so the darkest and lightest half percent of the
+    /// lightness become black and white, the same for every band so colors
+    /// keep their balance
+    fn auto_contrast(&self) -> Result<Self> {
+        let img = ops::colourspace(&self.0, ops::Interpretation::Srgb)?;
+        let grey = ops::cast(
+            &ops::colourspace(&img, ops::Interpretation::BW)?,
+            ops::BandFormat::Uchar,
+        )?;
+        let low = ops::percent(&grey, 0.5)? as f64;
+        let high = ops::percent(&grey, 99.5)? as f64;
+        if high <= low {
+            return Ok(Self(img));
+        }
+
+        let scale = 255.0 / (high - low);
+        let mut multiplications = vec![scale; 3];
+        let mut additions = vec![-low * scale; 3];
+        if img.image_hasalpha() {
+            multiplications.push(1.0);
+            additions.push(0.0);
+        }
+        let stretched = ops::linear(&img, &mut multiplications, &mut additions)?;
+        Ok(Self(ops::cast(&stretched, ops::BandFormat::Uchar)?))
+    }
+
     #[tracing::instrument(skip(self))]
     fn modulate(&self, b: f64, s: f64, h: f64) -> Result<Self> {
         let colorspace = match self.0.get_interpretation()? {
@@ -672,6 +1543,35 @@ impl Image {
     }
 }
 
+/// Average the image in `size` pixel blocks from its top left corner and
+/// blow them back up, ragged blocks at the edges included
+fn pixelate(img: &VipsImage, size: i32) -> Result<VipsImage> {
+    if size <= 1 {
+        return Ok(ops::copy(img)?);
+    }
+    let blocks =
+        ops::shrink_with_opts(img, size as f64, size as f64, &ShrinkOptions { ceil: true })?;
+    let zoomed = ops::zoom(&blocks, size, size)?;
+    Ok(ops::extract_area(
+        &zoomed,
+        0,
+        0,
+        img.get_width(),
+        img.get_height(),
+    )?)
+}
+
+fn watermark_offset(position: &WatermarkPosition, size: i32, overlay_size: i32) -> i32 {
+    match position {
+        WatermarkPosition::Right | WatermarkPosition::Bottom => size - overlay_size,
+        WatermarkPosition::Center => (size - overlay_size) / 2,
+        WatermarkPosition::Pixels(px) if *px < 0 => size - overlay_size + px,
+        WatermarkPosition::Pixels(px) => *px,
+        WatermarkPosition::Percentage(pct) => ((size - overlay_size) as f32 * pct.0 / 100.0) as i32,
+        WatermarkPosition::Left | WatermarkPosition::Top | WatermarkPosition::Repeat => 0,
+    }
+}
+
 impl Deref for Image {
     type Target = VipsImage;
 
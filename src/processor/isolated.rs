@@ -0,0 +1,451 @@
+use super::assets::Assets;
+use super::capabilities::Capabilities;
+use super::fonts::FontRegistry;
+use super::image::{checkpoint, ProcessError};
+use super::processor::{configure_spill, ImageProcessor, Processor};
+use crate::config::ProcessorSettings;
+use crate::imagorpath::params::Params;
+use crate::reload::vips_concurrency;
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::Blob;
+use color_eyre::{eyre::eyre, Result};
+use libvips::VipsApp;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// The flag a helper process is started with
+pub const WORKER_FLAG: &str = "--render-worker";
+
+/// What a render worker is asked to do. Followed on the pipe by a frame
+/// with the source and one with each asset, in order.
+#[derive(Serialize, Deserialize)]
+struct Job {
+    params: Params,
+    content_type: ContentType,
+    /// Image URIs and their content types
+    assets: Vec<(String, ContentType)>,
+}
+
+/// How a job went. A rendered result is followed by a frame with its data.
+#[derive(Serialize, Deserialize)]
+enum Outcome {
+    Rendered { content_type: ContentType },
+    Failed(ProcessError),
+    Error(String),
+}
+
+/// A length-prefixed message
+fn write_frame(output: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+    output.write_all(&len.to_le_bytes())?;
+    output.write_all(data)
+}
+
+fn read_frame(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let mut data = vec![0; u32::from_le_bytes(len) as usize];
+    input.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn write_json(output: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    write_frame(output, &serde_json::to_vec(value)?)
+}
+
+fn read_json<T: DeserializeOwned>(input: &mut impl Read) -> io::Result<T> {
+    Ok(serde_json::from_slice(&read_frame(input)?)?)
+}
+
+/// Entry point of a helper process: reads the processor settings, then
+/// renders jobs from stdin to stdout until stdin is closed
+pub fn run_worker() -> Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    let output = BufWriter::new(io::stdout().lock());
+
+    let settings: ProcessorSettings = read_json(&mut input)?;
+    configure_spill(&settings);
+    let vips_app = VipsApp::new("imagor_rs worker", false)
+        .map_err(|e| eyre!("Failed to initialize libvips: {}", e))?;
+    vips_app.concurrency_set(vips_concurrency(settings.concurrency));
+    FontRegistry::global().load(&settings.fonts);
+
+    serve(&Processor::from_settings(&settings), input, output)
+}
+
+/// Renders jobs from `input` with `processor` until `input` ends
+fn serve(
+    processor: &dyn ImageProcessor,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    loop {
+        let job: Job = match read_json(&mut input) {
+            Ok(job) => job,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let blob = Blob {
+            data: read_frame(&mut input)?,
+            content_type: job.content_type,
+            ..Default::default()
+        };
+        let mut assets = Assets::default();
+        for (uri, content_type) in job.assets {
+            let data = read_frame(&mut input)?;
+            assets.insert_image(
+                uri,
+                Blob {
+                    data,
+                    content_type,
+                    ..Default::default()
+                },
+            );
+        }
+
+        match processor.process(&blob, &job.params, &assets) {
+            Ok(rendered) => {
+                let content_type = rendered.content_type;
+                write_json(&mut output, &Outcome::Rendered { content_type })?;
+                write_frame(&mut output, &rendered.data)?;
+            }
+            Err(e) => {
+                let outcome = match e.downcast::<ProcessError>() {
+                    Ok(e) => Outcome::Failed(e),
+                    Err(e) => Outcome::Error(e.to_string()),
+                };
+                write_json(&mut output, &outcome)?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+/// A running helper process
+struct Worker {
+    child: Child,
+    input: BufWriter<ChildStdin>,
+    output: BufReader<ChildStdout>,
+    /// The settings it was started with, see [`IsolatedProcessor::reload`]
+    generation: u64,
+}
+
+impl Worker {
+    fn spawn(command: &[String], settings: &[u8], generation: u64) -> Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| eyre!("No worker command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| eyre!("Failed to start render worker {}: {}", program, e))?;
+        let (Some(input), Some(output)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(eyre!("Render worker has no pipes"));
+        };
+
+        let mut worker = Worker {
+            child,
+            input: BufWriter::new(input),
+            output: BufReader::new(output),
+            generation,
+        };
+        write_frame(&mut worker.input, settings)?;
+        worker.input.flush()?;
+        metrics::counter!("render_workers_spawned_total").increment(1);
+        Ok(worker)
+    }
+
+    /// Fails only when the worker is gone, a failed render is `Ok(Err(_))`
+    fn render(
+        &mut self,
+        blob: &Blob,
+        params: &Params,
+        assets: &Assets,
+    ) -> io::Result<Result<Blob>> {
+        let assets: Vec<_> = assets.images().collect();
+        let job = Job {
+            params: params.clone(),
+            content_type: blob.content_type.clone(),
+            assets: assets
+                .iter()
+                .map(|(uri, blob)| (uri.to_string(), blob.content_type.clone()))
+                .collect(),
+        };
+        write_json(&mut self.input, &job)?;
+        write_frame(&mut self.input, &blob.data)?;
+        for (_, blob) in &assets {
+            write_frame(&mut self.input, &blob.data)?;
+        }
+        self.input.flush()?;
+
+        Ok(match read_json(&mut self.output)? {
+            Outcome::Rendered { content_type } => Ok(Blob {
+                data: read_frame(&mut self.output)?,
+                content_type,
+                metadata: blob.metadata.clone(),
+            }),
+            Outcome::Failed(e) => Err(e.into()),
+            Outcome::Error(e) => Err(eyre!(e)),
+        })
+    }
+
+    /// How the process ended, after it stopped answering
+    fn exit_status(&mut self) -> String {
+        let _ = self.child.kill();
+        match self.child.wait() {
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Renders in a pool of helper processes, each running its own libvips,
+/// so a crashing decoder only takes down one of them. Workers are started
+/// on first use and replaced after they crash. Header reads, like
+/// `check_raw`, still happen in this process.
+pub struct IsolatedProcessor {
+    processor: Processor,
+    /// The program and its arguments
+    command: Vec<String>,
+    /// The JSON settings workers are started with
+    settings: RwLock<Vec<u8>>,
+    generation: AtomicU64,
+    /// Idle workers, `None` for one to be started
+    slots: Mutex<Vec<Option<Worker>>>,
+    returned: Condvar,
+}
+
+impl IsolatedProcessor {
+    pub fn from_settings(settings: &ProcessorSettings) -> Result<Self> {
+        let program = match &settings.isolation.command {
+            Some(command) => PathBuf::from(command),
+            None => std::env::current_exe()?,
+        };
+        let command = vec![
+            program.to_string_lossy().into_owned(),
+            WORKER_FLAG.to_string(),
+        ];
+        let workers = match settings.isolation.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            workers => workers,
+        };
+        info!("Rendering in {} worker processes", workers);
+
+        Ok(Self::new(settings, command, workers))
+    }
+
+    fn new(settings: &ProcessorSettings, command: Vec<String>, workers: usize) -> Self {
+        IsolatedProcessor {
+            processor: Processor::from_settings(settings),
+            command,
+            settings: RwLock::new(serde_json::to_vec(settings).expect("settings are serializable")),
+            generation: AtomicU64::new(0),
+            slots: Mutex::new((0..workers.max(1)).map(|_| None).collect()),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// An idle worker, waiting for one when they're all busy
+    fn checkout(&self) -> Result<Worker> {
+        let slot = {
+            let mut slots = self.slots.lock().expect("worker pool lock poisoned");
+            loop {
+                match slots.pop() {
+                    Some(slot) => break slot,
+                    None => {
+                        slots = self
+                            .returned
+                            .wait(slots)
+                            .expect("worker pool lock poisoned")
+                    }
+                }
+            }
+        };
+
+        let generation = self.generation.load(Ordering::Acquire);
+        match slot {
+            Some(worker) if worker.generation == generation => Ok(worker),
+            _ => {
+                let settings = self.settings.read().expect("settings lock poisoned");
+                Worker::spawn(&self.command, &settings, generation).inspect_err(|_| {
+                    self.checkin(None);
+                })
+            }
+        }
+    }
+
+    fn checkin(&self, worker: Option<Worker>) {
+        self.slots
+            .lock()
+            .expect("worker pool lock poisoned")
+            .push(worker);
+        self.returned.notify_one();
+    }
+}
+
+impl ImageProcessor for IsolatedProcessor {
+    fn startup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn process(&self, blob: &Blob, params: &Params, assets: &Assets) -> Result<Blob> {
+        self.process_cancellable(blob, params, assets, &CancellationToken::new())
+    }
+
+    /// Only checked before the job is sent, the worker finishes it
+    fn process_cancellable(
+        &self,
+        blob: &Blob,
+        params: &Params,
+        assets: &Assets,
+        cancel: &CancellationToken,
+    ) -> Result<Blob> {
+        let mut worker = self.checkout()?;
+        if let Err(e) = checkpoint(cancel) {
+            self.checkin(Some(worker));
+            return Err(e.into());
+        }
+
+        match worker.render(blob, params, assets) {
+            Ok(result) => {
+                self.checkin(Some(worker));
+                result
+            }
+            Err(e) => {
+                let status = worker.exit_status();
+                warn!("Render worker died ({}): {}", status, e);
+                metrics::counter!("render_worker_crashes_total").increment(1);
+                // started again for the next job
+                self.checkin(None);
+                Err(eyre!("Render worker died while processing: {}", status))
+            }
+        }
+    }
+
+    /// Drops the workers, which are killed with them
+    fn shutdown(&self) -> Result<()> {
+        let mut slots = self.slots.lock().expect("worker pool lock poisoned");
+        slots.iter_mut().for_each(|slot| *slot = None);
+        Ok(())
+    }
+
+    /// Workers started with the old settings are replaced as they come back
+    fn reload(&self, settings: &ProcessorSettings) -> Result<()> {
+        self.processor.reload(settings)?;
+        *self.settings.write().expect("settings lock poisoned") = serde_json::to_vec(settings)?;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn health(&self) -> Result<()> {
+        self.processor.health()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.processor.capabilities()
+    }
+
+    fn check_raw(&self, blob: &Blob) -> Result<(), ProcessError> {
+        self.processor.check_raw(blob)
+    }
+
+    fn is_noop(&self, blob: &Blob, params: &Params) -> bool {
+        self.processor.is_noop(blob, params)
+    }
+
+    fn strips_metadata(&self) -> bool {
+        self.processor.strips_metadata()
+    }
+
+    fn decoded_size(&self, blob: &Blob, params: &Params) -> Option<u64> {
+        self.processor.decoded_size(blob, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_params;
+    use image::{ImageBuffer, Rgb};
+    use std::io::Cursor;
+
+    fn png() -> Blob {
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(80, 60, |x, y| Rgb([x as u8, y as u8, 128]));
+        let mut data = Vec::new();
+        img_buf
+            .write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)
+            .unwrap();
+        Blob::new(data)
+    }
+
+    #[test]
+    fn test_serve_renders_jobs() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+        let source = png();
+        let mut input = Vec::new();
+        for path in [
+            "unsafe/40x30/filters:format(jpeg)/img.png",
+            "unsafe/filters:page(9)/img.png",
+        ] {
+            let job = Job {
+                params: parse_params(path).unwrap(),
+                content_type: source.content_type.clone(),
+                assets: Vec::new(),
+            };
+            write_json(&mut input, &job).unwrap();
+            write_frame(&mut input, &source.data).unwrap();
+        }
+
+        let mut output = Vec::new();
+        serve(&Processor::default(), Cursor::new(input), &mut output).unwrap();
+
+        let mut output = Cursor::new(output);
+        match read_json(&mut output).unwrap() {
+            Outcome::Rendered { content_type } => assert_eq!(content_type, "image/jpeg"),
+            _ => panic!("expected a rendered image"),
+        }
+        let rendered = read_frame(&mut output).unwrap();
+        assert_eq!(infer::get(&rendered).unwrap().mime_type(), "image/jpeg");
+        assert!(matches!(
+            read_json(&mut output).unwrap(),
+            Outcome::Failed(ProcessError::PageOutOfRange { page: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn test_crashed_workers_are_replaced() {
+        // reads the settings, then dies
+        let command = ["sh", "-c", "head -c 1 >/dev/null; exit 3"]
+            .map(String::from)
+            .to_vec();
+        let processor = IsolatedProcessor::new(&ProcessorSettings::default(), command, 1);
+        let params = parse_params("unsafe/40x30/img.png").unwrap();
+
+        for _ in 0..2 {
+            let e = processor
+                .process(&png(), &params, &Assets::default())
+                .unwrap_err();
+            assert!(e.to_string().contains("Render worker died"), "{}", e);
+        }
+        // the slot went back to the pool both times
+        assert_eq!(processor.slots.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a label's `{name}` placeholders are filled in with
+#[derive(Debug, Clone)]
+pub struct Vars(HashMap<String, String>);
+
+impl Vars {
+    /// `{width}` and `{height}` of the image as the label sees it, and
+    /// today's `{date}` and `{year}` in UTC
+    pub fn new(width: i32, height: i32) -> Self {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / 86_400);
+        let (year, month, day) = civil_date(days as i64);
+
+        let mut vars = HashMap::new();
+        vars.insert("width".to_string(), width.to_string());
+        vars.insert("height".to_string(), height.to_string());
+        vars.insert("date".to_string(), format!("{year}-{month:02}-{day:02}"));
+        vars.insert("year".to_string(), year.to_string());
+        Vars(vars)
+    }
+
+    /// Add `var()` values, the last one winning, without replacing any of
+    /// the built in vars
+    pub fn with_custom<'a>(mut self, custom: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let builtin = ["width", "height", "date", "year"];
+        for (name, value) in custom {
+            if !builtin.contains(&name) {
+                self.0.insert(name.to_string(), value.to_string());
+            }
+        }
+        self
+    }
+
+    /// `template` with each known `{name}` replaced by its value, escaped for
+    /// Pango markup. `{{` and `}}` are literal braces, and unknown names are
+    /// left as they are.
+    pub fn expand(&self, template: &str) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(i) = rest.find(['{', '}']) {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                out.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+            let value = rest
+                .strip_prefix('{')
+                .and_then(|r| r.split_once('}'))
+                .and_then(|(name, r)| Some((self.0.get(name)?, r)));
+            match value {
+                Some((value, r)) => {
+                    out.push_str(&escape_markup(value));
+                    rest = r;
+                }
+                None => {
+                    out.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Text that Pango shows as it is, rather than reading as markup
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// A Pango font description at `size` pixels, unless it already ends in a
+/// size of its own
+pub fn font_with_size(font: &str, size: u32) -> String {
+    let sized = font
+        .rsplit_once(' ')
+        .is_some_and(|(_, last)| last.trim_end_matches("px").parse::<f64>().is_ok());
+    if sized {
+        font.to_string()
+    } else {
+        format!("{} {}px", font, size)
+    }
+}
+
+/// Year, month and day of a count of days since 1970-01-01, in the
+/// proleptic Gregorian calendar
+pub fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's civil_from_days, with eras of 400 years
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = Vars::new(1200, 800).with_custom([("owner", "Jo & Co"), ("width", "1")]);
+
+        assert_eq!(vars.expand("{width}×{height}"), "1200×800");
+        assert_eq!(vars.expand("© {owner}"), "© Jo &amp; Co");
+        assert_eq!(vars.expand("{{width}} {missing} {"), "{width} {missing} {");
+        assert_eq!(vars.expand("<b>{height}</b>"), "<b>800</b>");
+        assert_eq!(vars.expand("{year}").len(), 4);
+        assert_eq!(vars.expand("{date}").len(), 10);
+    }
+
+    #[test]
+    fn test_font_with_size() {
+        assert_eq!(font_with_size("sans", 24), "sans 24px");
+        assert_eq!(
+            font_with_size("DejaVu Serif Bold", 12),
+            "DejaVu Serif Bold 12px"
+        );
+        assert_eq!(font_with_size("sans 18", 24), "sans 18");
+        assert_eq!(font_with_size("sans 18px", 24), "sans 18px");
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(20_089), (2025, 1, 1));
+    }
+}
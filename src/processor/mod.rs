@@ -1,2 +1,18 @@
+pub mod assets;
+pub mod budget;
+pub mod capabilities;
+pub mod embed;
+pub mod fonts;
+pub mod frames;
 pub mod image;
+pub mod isolated;
+pub mod label;
+pub mod palette;
+pub mod placeholder;
 pub mod processor;
+#[cfg(feature = "pure")]
+pub mod pure;
+pub mod superres;
+pub mod tone;
+pub mod transform;
+pub mod video;
@@ -0,0 +1,171 @@
+use super::image::Image;
+use super::placeholder::rgba_pixels;
+use crate::imagorpath::filter::HashOutput;
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::Blob;
+use color_eyre::Result;
+use serde::Serialize;
+
+/// Pixels more transparent than this don't count towards any color
+const MIN_ALPHA: u8 = 128;
+const MAX_ITERATIONS: usize = 16;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Palette {
+    /// The color covering the most of the image
+    pub dominant: Option<String>,
+    /// Up to n colors as `#rrggbb`, most common first
+    pub palette: Vec<String>,
+}
+
+fn hex(color: [f64; 3]) -> String {
+    let [r, g, b] = color.map(|c| c.round().clamp(0.0, 255.0) as u8);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn nearest(centroids: &[[f64; 3]], px: &[f64; 3]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a, px).total_cmp(&distance(b, px)))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Cluster RGBA pixels into at most `n` colors with k-means. Clusters start
+/// from the median pixel by luminance, then each pixel furthest from the
+/// ones picked so far, so the same image always gives the same palette.
+pub fn extract(rgba: &[u8], n: usize) -> Palette {
+    let mut pixels: Vec<[f64; 3]> = rgba
+        .chunks_exact(4)
+        .filter(|px| px[3] >= MIN_ALPHA)
+        .map(|px| [px[0] as f64, px[1] as f64, px[2] as f64])
+        .collect();
+    if pixels.is_empty() || n == 0 {
+        return Palette {
+            dominant: None,
+            palette: Vec::new(),
+        };
+    }
+
+    let luma = |px: &[f64; 3]| 0.299 * px[0] + 0.587 * px[1] + 0.114 * px[2];
+    pixels.sort_by(|a, b| luma(a).total_cmp(&luma(b)));
+    let mut centroids = vec![pixels[pixels.len() / 2]];
+    while centroids.len() < n {
+        let furthest = pixels
+            .iter()
+            .map(|px| (px, distance(&centroids[nearest(&centroids, px)], px)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match furthest {
+            Some((px, d)) if d > 0.0 => centroids.push(*px),
+            // fewer distinct colors than asked for
+            _ => break,
+        }
+    }
+
+    let mut assignments = vec![usize::MAX; pixels.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (px, assigned) in pixels.iter().zip(assignments.iter_mut()) {
+            let cluster = nearest(&centroids, px);
+            changed |= cluster != *assigned;
+            *assigned = cluster;
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![([0.0; 3], 0usize); centroids.len()];
+        for (px, cluster) in pixels.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[*cluster];
+            sum.iter_mut().zip(px).for_each(|(s, c)| *s += c);
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|s| s / count as f64);
+            }
+        }
+    }
+
+    let mut counts = vec![0usize; centroids.len()];
+    for cluster in &assignments {
+        counts[*cluster] += 1;
+    }
+    let mut clusters: Vec<(String, usize)> = Vec::new();
+    for (centroid, count) in centroids.iter().zip(counts) {
+        let color = hex(*centroid);
+        match clusters.iter_mut().find(|(c, _)| *c == color) {
+            Some((_, total)) => *total += count,
+            None if count > 0 => clusters.push((color, count)),
+            None => {}
+        }
+    }
+    clusters.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    let palette: Vec<String> = clusters.into_iter().map(|(color, _)| color).collect();
+    Palette {
+        dominant: palette.first().cloned(),
+        palette,
+    }
+}
+
+/// The palette of the processed `image`, returned instead of the image
+pub fn render(image: &Image, n: usize) -> Result<Blob> {
+    let (_, _, rgba) = rgba_pixels(image)?;
+    render_pixels(&rgba, n)
+}
+
+/// Like `render`, from RGBA pixels
+pub fn render_pixels(rgba: &[u8], n: usize) -> Result<Blob> {
+    Ok(Blob {
+        data: serde_json::to_vec(&extract(rgba, n))?,
+        content_type: ContentType::from_static(HashOutput::Json.content_type()),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(colors: &[([u8; 4], usize)]) -> Vec<u8> {
+        colors
+            .iter()
+            .flat_map(|(px, count)| px.repeat(*count))
+            .collect()
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        let rgba = pixels(&[([255, 0, 0, 255], 30), ([0, 0, 255, 255], 10)]);
+
+        let palette = extract(&rgba, 2);
+        assert_eq!(palette.dominant.as_deref(), Some("#ff0000"));
+        assert_eq!(palette.palette, vec!["#ff0000", "#0000ff"]);
+    }
+
+    #[test]
+    fn test_palette_is_no_larger_than_the_colors() {
+        let rgba = pixels(&[([10, 200, 10, 255], 8)]);
+        assert_eq!(extract(&rgba, 5).palette, vec!["#0ac80a"]);
+    }
+
+    #[test]
+    fn test_transparent_pixels_are_ignored() {
+        let rgba = pixels(&[([255, 255, 255, 0], 100), ([0, 0, 0, 255], 1)]);
+        assert_eq!(extract(&rgba, 3).dominant.as_deref(), Some("#000000"));
+
+        let rgba = pixels(&[([255, 255, 255, 0], 4)]);
+        assert_eq!(
+            extract(&rgba, 3),
+            Palette {
+                dominant: None,
+                palette: vec![],
+            }
+        );
+    }
+}
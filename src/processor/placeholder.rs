@@ -0,0 +1,270 @@
+use super::image::Image;
+use crate::imagorpath::filter::{Filter, HashOutput};
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::Blob;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::{eyre::eyre, Result};
+use libvips::ops::{self, BandFormat, Interpretation, Size, ThumbnailImageOptions};
+use std::f64::consts::PI;
+
+/// Longest side the image is shrunk to before hashing, which is as large as
+/// ThumbHash accepts and plenty for a BlurHash
+pub const MAX_SIZE: i32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    Blurhash,
+    Thumbhash,
+}
+
+impl Placeholder {
+    pub fn from_filter(filter: &Filter) -> Option<(Self, HashOutput)> {
+        match filter {
+            Filter::Blurhash(output) => Some((Placeholder::Blurhash, *output)),
+            Filter::Thumbhash(output) => Some((Placeholder::Thumbhash, *output)),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Placeholder::Blurhash => "blurhash",
+            Placeholder::Thumbhash => "thumbhash",
+        }
+    }
+
+    /// Hash 8-bit RGBA pixels, at most `MAX_SIZE` on each side
+    pub fn encode(&self, width: u32, height: u32, rgba: &[u8]) -> Result<String> {
+        match self {
+            Placeholder::Blurhash => {
+                let (x, y) = if width >= height { (4, 3) } else { (3, 4) };
+                blurhash::encode(x, y, width, height, rgba)
+                    .map_err(|e| eyre!("Failed to encode blurhash: {}", e))
+            }
+            Placeholder::Thumbhash => Ok(STANDARD.encode(thumbhash(width, height, rgba))),
+        }
+    }
+
+    /// The hash of the processed `image`, returned instead of the image
+    pub fn render(&self, image: &Image, output: HashOutput) -> Result<Blob> {
+        let img = image.as_inner();
+        let (width, height) = (img.get_width(), img.get_page_height());
+        let (small_width, small_height, rgba) = rgba_pixels(image)?;
+        self.render_pixels((width, height), small_width, small_height, &rgba, output)
+    }
+
+    /// Like `render`, from an image of `size` already shrunk to `rgba`
+    pub fn render_pixels(
+        &self,
+        (width, height): (i32, i32),
+        small_width: u32,
+        small_height: u32,
+        rgba: &[u8],
+        output: HashOutput,
+    ) -> Result<Blob> {
+        let hash = self.encode(small_width, small_height, rgba)?;
+
+        let data = match output {
+            HashOutput::Text => hash.into_bytes(),
+            HashOutput::Json => serde_json::to_vec(&serde_json::json!({
+                self.name(): hash,
+                "width": width,
+                "height": height,
+            }))?,
+        };
+
+        Ok(Blob {
+            data,
+            content_type: ContentType::from_static(output.content_type()),
+            ..Default::default()
+        })
+    }
+}
+
+/// The first frame of `image` shrunk to fit `MAX_SIZE`, as 8-bit sRGB with alpha
+pub(super) fn rgba_pixels(image: &Image) -> Result<(u32, u32, Vec<u8>)> {
+    let img = image.as_inner();
+    let img = if image.is_animated() {
+        ops::extract_area(img, 0, 0, img.get_width(), img.get_page_height())?
+    } else {
+        img.clone()
+    };
+
+    let img = ops::thumbnail_image_with_opts(
+        &img,
+        MAX_SIZE,
+        &ThumbnailImageOptions {
+            height: MAX_SIZE,
+            size: Size::Down,
+            ..Default::default()
+        },
+    )?;
+    let img = ops::colourspace(&img, Interpretation::Srgb)?;
+    let img = ops::cast(&img, BandFormat::Uchar)?;
+    let img = if img.image_hasalpha() {
+        img
+    } else {
+        ops::bandjoin_const(&img, &mut [255.0])?
+    };
+
+    Ok((
+        img.get_width() as u32,
+        img.get_height() as u32,
+        img.image_write_to_memory(),
+    ))
+}
+
+/// Port of the reference ThumbHash encoder, see https://evanw.github.io/thumbhash/
+fn thumbhash(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let pixels = || rgba.chunks_exact(4).take(w * h);
+
+    // average color, weighted by alpha
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0, 0.0, 0.0, 0.0);
+    for px in pixels() {
+        let alpha = px[3] as f64 / 255.0;
+        avg_r += alpha / 255.0 * px[0] as f64;
+        avg_g += alpha / 255.0 * px[1] as f64;
+        avg_b += alpha / 255.0 * px[2] as f64;
+        avg_a += alpha;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < (w * h) as f64;
+    // fewer luminance bits when alpha needs room
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / longest).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / longest).round() as usize).max(1);
+
+    // composite atop the average color and convert to LPQA
+    let (mut l, mut p, mut q, mut a) = (vec![], vec![], vec![], vec![]);
+    for px in pixels() {
+        let alpha = px[3] as f64 / 255.0;
+        let r = avg_r * (1.0 - alpha) + alpha / 255.0 * px[0] as f64;
+        let g = avg_g * (1.0 - alpha) + alpha / 255.0 * px[1] as f64;
+        let b = avg_b * (1.0 - alpha) + alpha / 255.0 * px[2] as f64;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    // DCT into a constant term and AC terms normalized to 0..1
+    let encode_channel = |channel: &[f64], nx: usize, ny: usize| {
+        let (mut dc, mut ac, mut scale) = (0.0, Vec::new(), 0.0f64);
+        for cy in 0..ny {
+            let mut cx = 0;
+            while cx * ny < nx * (ny - cy) {
+                let fx: Vec<f64> = (0..w)
+                    .map(|x| (PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos())
+                    .collect();
+                let mut f = 0.0;
+                for y in 0..h {
+                    let fy = (PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                    for x in 0..w {
+                        f += channel[x + y * w] * fx[x] * fy;
+                    }
+                }
+                f /= (w * h) as f64;
+                if cx > 0 || cy > 0 {
+                    ac.push(f);
+                    scale = scale.max(f.abs());
+                } else {
+                    dc = f;
+                }
+                cx += 1;
+            }
+        }
+        if scale > 0.0 {
+            for f in ac.iter_mut() {
+                *f = 0.5 + 0.5 / scale * *f;
+            }
+        }
+        (dc, ac, scale)
+    };
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, 3, 3);
+
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | (is_landscape as u32) << 15;
+    let mut hash = vec![
+        (header24 & 255) as u8,
+        ((header24 >> 8) & 255) as u8,
+        (header24 >> 16) as u8,
+        (header16 & 255) as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = encode_channel(&a, 5, 5);
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+        channels.push(a_ac);
+    }
+
+    // two AC terms per byte, low nibble first
+    let ac_start = hash.len();
+    for (i, f) in channels.iter().flatten().enumerate() {
+        if ac_start + i / 2 == hash.len() {
+            hash.push(0);
+        }
+        hash[ac_start + i / 2] |= ((15.0 * f).round() as u8) << ((i & 1) * 4);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, px: [u8; 4]) -> Vec<u8> {
+        px.repeat((width * height) as usize)
+    }
+
+    #[test]
+    fn test_thumbhash_solid_color() {
+        let hash = thumbhash(10, 10, &solid(10, 10, [255, 0, 0, 255]));
+
+        // 5 header bytes, then 27 luminance and 5 + 5 chroma terms
+        assert_eq!(hash.len(), 5 + 19);
+        // opaque
+        assert_eq!(hash[2] & 0x80, 0);
+        // luminance of pure red is a third
+        assert_eq!(hash[0] & 63, 21);
+    }
+
+    #[test]
+    fn test_thumbhash_alpha() {
+        let hash = thumbhash(8, 4, &solid(8, 4, [0, 0, 255, 128]));
+
+        assert_eq!(hash[2] & 0x80, 0x80);
+        // landscape
+        assert_eq!(hash[4] & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_blurhash_shape() {
+        let hash = Placeholder::Blurhash
+            .encode(8, 4, &solid(8, 4, [40, 120, 200, 255]))
+            .unwrap();
+
+        // size flag, max AC, DC, then 11 AC terms of 2 characters
+        assert_eq!(hash.len(), 1 + 1 + 4 + 11 * 2);
+    }
+}
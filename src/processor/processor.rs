@@ -1,36 +1,106 @@
-use std::{thread::available_parallelism, time::Instant};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
-use super::image::{Image, ProcessError};
+use super::assets::Assets;
+use super::budget::{check_dimensions, DecodeBudget, DecodeSize};
+use super::capabilities::{Capabilities, Limits};
+use super::frames::set_frame_delay;
+use super::image::{checkpoint, framed_size, panic_message, Image, ProcessError};
+use super::palette;
+use super::placeholder::Placeholder;
+use super::superres::Upscaler;
+use super::video::{extract_frame, FrameSelection};
 use crate::{
-    config::ProcessorSettings,
+    config::{FormatSettings, ProcessorSettings},
     imagorpath::{
         color::Color,
-        filter::{Filter, ImageType},
+        filter::{Filter, FilterMatcher, ImageType, ResizeKernel},
         params::{Fit, HAlign, Params, VAlign},
     },
+    reload::vips_concurrency,
     storage::storage::Blob,
 };
 use color_eyre::Result;
 use libvips::{
     ops::{
-        self, ForeignHeifCompression, ForeignPngFilter, HeifsaveBufferOptions, Interesting,
-        JpegsaveBufferOptions, PngsaveBufferOptions, Size, ThumbnailBufferOptions,
-        TiffsaveBufferOptions, WebpsaveBufferOptions,
+        self, ForeignHeifCompression, ForeignKeep, ForeignPngFilter, GifsaveBufferOptions,
+        HeifsaveBufferOptions, Interesting, JpegsaveBufferOptions, PngsaveBufferOptions, Size,
+        ThumbnailBufferOptions, TiffsaveBufferOptions, WebpsaveBufferOptions,
     },
     VipsImage,
 };
-use tracing::{debug, error};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
 
 pub trait ImageProcessor: Send + Sync {
     fn startup(&self) -> Result<()>;
-    fn process(&self, blob: &Blob, params: &Params) -> Result<Blob>;
+    fn process(&self, blob: &Blob, params: &Params, assets: &Assets) -> Result<Blob>;
+
+    /// `process`, giving up with [`ProcessError::Cancelled`] between stages
+    /// once `cancel` is cancelled
+    fn process_cancellable(
+        &self,
+        blob: &Blob,
+        params: &Params,
+        assets: &Assets,
+        cancel: &CancellationToken,
+    ) -> Result<Blob> {
+        checkpoint(cancel)?;
+        self.process(blob, params, assets)
+    }
+
     fn shutdown(&self) -> Result<()>;
+
+    /// Pick up settings that can change without a restart
+    fn reload(&self, _settings: &ProcessorSettings) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check the image library is usable
+    fn health(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Formats the linked image library can load and save, and the limits
+    /// applied to requests
+    fn capabilities(&self) -> Capabilities;
+
+    /// Check a source served untouched by `raw()` against the size limits,
+    /// reading only its header. Sources that can't be read as an image,
+    /// like a PDF the library wasn't built for, pass.
+    fn check_raw(&self, _blob: &Blob) -> Result<(), ProcessError> {
+        Ok(())
+    }
+
+    /// Whether processing `blob` with `params` would give back the same
+    /// size and format, so the source can be served instead. Reads only its
+    /// header.
+    fn is_noop(&self, _blob: &Blob, _params: &Params) -> bool {
+        false
+    }
+
+    /// Whether results are stripped of the metadata sources carry, so a
+    /// source can't be served in place of one
+    fn strips_metadata(&self) -> bool {
+        false
+    }
+
+    /// Bytes decoding `blob` for `params` takes at full size, width ×
+    /// height × bands of every frame loaded, read from its header alone.
+    /// `None` when it can't be told.
+    fn decoded_size(&self, _blob: &Blob, _params: &Params) -> Option<u64> {
+        None
+    }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct Processor {
     disable_blur: bool,
-    disable_filters: Vec<String>,
+    disable_filters: RwLock<Vec<FilterMatcher>>,
     max_filter_ops: usize,
     concurrency: i32,
     max_cache_files: i32,
@@ -41,15 +111,31 @@ pub struct Processor {
     max_resolution: i32,
     max_animation_frames: usize,
     strip_metadata: bool,
-    avif_speed: i32,
+    auto_rotate: bool,
+    deterministic: bool,
+    icc_transform: bool,
+    embed_srgb_profile: bool,
+    linear_resize: bool,
+    resize_kernel: Option<ResizeKernel>,
+    sequential_min_pixels: u64,
+    formats: FormatSettings,
+    upscaler: Option<Arc<Upscaler>>,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct ProcessingParams {
     thumbnail_not_supported: bool,
     upscale: bool,
     thumbnail: bool,
+    quality: Option<i32>,
+    bitdepth: Option<i32>,
+    /// Resize in linear light
+    linear: bool,
+    /// A kernel other than thumbnailing's lanczos3, which needs a full load
+    kernel: Option<ResizeKernel>,
     strip_exif: bool,
+    strip_icc: bool,
     strip_metadata: bool,
     orient: i32,
     format: Option<ImageType>,
@@ -58,6 +144,11 @@ pub struct ProcessingParams {
     page: usize,
     dpi: u32,
     focal_rects: Vec<FocalPoint>,
+    frames: Option<(usize, u32)>,
+    dpr: f32,
+    /// Load with `access=sequential`, streaming the source through the
+    /// pipeline instead of decoding it whole first
+    sequential: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -74,10 +165,81 @@ struct ExportOptions {
     compression: Option<i32>,
     palette: bool,
     bitdepth: Option<i32>,
+    strip_icc: bool,
     strip_metadata: bool,
     max_bytes: usize,
+    lossless: bool,
+    effort: Option<i32>,
+    progressive: bool,
+}
+
+impl ExportOptions {
+    /// `quality()` from the path wins over the configured format default,
+    /// which is lowered for high density output
+    fn new(format: ImageType, params: &ProcessingParams, formats: &FormatSettings) -> Self {
+        let options = ExportOptions {
+            quality: params.quality,
+            compression: None,
+            palette: false,
+            bitdepth: params.bitdepth,
+            strip_icc: params.strip_icc,
+            strip_metadata: params.strip_metadata,
+            max_bytes: params.max_bytes,
+            lossless: false,
+            effort: None,
+            progressive: false,
+        };
+
+        match format {
+            ImageType::JPEG => ExportOptions {
+                quality: options.quality.or(Some(formats.jpeg.quality)),
+                progressive: formats.jpeg.progressive,
+                ..options
+            },
+            ImageType::WEBP => ExportOptions {
+                quality: options.quality.or(Some(formats.webp.quality)),
+                lossless: formats.webp.lossless,
+                effort: Some(formats.webp.effort),
+                ..options
+            },
+            ImageType::AVIF => ExportOptions {
+                quality: options.quality.or(Some(formats.avif.quality)),
+                // libvips takes effort, the inverse of speed
+                effort: Some(9 - formats.avif.speed.clamp(0, 9)),
+                ..options
+            },
+            ImageType::PNG => ExportOptions {
+                compression: Some(formats.png.compression),
+                palette: formats.png.palette,
+                ..options
+            },
+            ImageType::HEIF => ExportOptions {
+                quality: options.quality.or(Some(formats.heif.quality)),
+                effort: Some(formats.heif.effort),
+                ..options
+            },
+            _ => options,
+        }
+        .with_dpr_quality(params)
+    }
+
+    /// Pixels are smaller at higher densities, so artifacts are less visible
+    fn with_dpr_quality(self, params: &ProcessingParams) -> Self {
+        match self.quality {
+            Some(quality) if params.quality.is_none() && params.dpr > 1.0 => ExportOptions {
+                quality: Some(
+                    ((quality as f32 / params.dpr.sqrt()).round() as i32).max(MIN_DPR_QUALITY),
+                ),
+                ..self
+            },
+            _ => self,
+        }
+    }
 }
 
+/// Floor for the quality lowered by `dpr()`
+const MIN_DPR_QUALITY: i32 = 40;
+
 impl ImageProcessor for Processor {
     #[tracing::instrument(skip(self))]
     fn startup(&self) -> Result<()> {
@@ -90,77 +252,405 @@ impl ImageProcessor for Processor {
     }
 
     #[tracing::instrument(skip(self, blob))]
-    fn process(&self, blob: &Blob, params: &Params) -> Result<Blob> {
+    fn check_raw(&self, blob: &Blob) -> Result<(), ProcessError> {
+        let Ok(header) = VipsImage::new_from_buffer(blob.as_ref(), "") else {
+            return Ok(());
+        };
+
+        let size = DecodeSize::from_header(&header, false);
+        check_dimensions(
+            size.width,
+            size.page_height,
+            self.max_width,
+            self.max_height,
+        )?;
+        match DecodeBudget::from_max_resolution(self.max_resolution) {
+            Some(budget) => budget.check(&size),
+            None => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(skip(self, blob))]
+    fn decoded_size(&self, blob: &Blob, params: &Params) -> Option<u64> {
+        // the still to be extracted isn't known yet
+        if blob.is_video() {
+            return None;
+        }
+
         let processing_params = self.preprocess(blob, params);
-        let img = self.load_image(blob, params, &processing_params)?;
-        let img = img.apply_orientation(processing_params.orient)?;
-        let (width, height) = img.calculate_dimensions(params, processing_params.upscale);
-        let img = img.resize_image(width, height, params.fit, processing_params.upscale, params)?;
-        let img = img.apply_flip(params.h_flip, params.v_flip)?;
+        let header =
+            VipsImage::new_from_buffer(blob.as_ref(), &dpi_option(blob, &processing_params))
+                .ok()?;
+        Some(DecodeSize::from_header(&header, processing_params.frames.is_some()).bytes())
+    }
 
-        let img = self.apply_filters(img, params, &processing_params)?;
+    fn strips_metadata(&self) -> bool {
+        self.strip_metadata || self.deterministic
+    }
 
-        // if p.meta {
-        //     // metadata without export
-        //     return imagor.NewBlobFromJsonMarshal(metadata(img, format, stripExif)), nil
-        // }
+    #[tracing::instrument(skip(self, blob))]
+    fn is_noop(&self, blob: &Blob, params: &Params) -> bool {
+        // processing would strip metadata the source may carry
+        if self.strips_metadata() || blob.is_video() {
+            return false;
+        }
+        let Some(mime_type) = infer::get(&blob.data).map(|t| t.mime_type()) else {
+            return false;
+        };
+        // formats that are read but saved as something else aren't kept
+        let format = source_format(mime_type);
+        if format.to_content_type() != mime_type {
+            return false;
+        }
+        let Ok(header) = VipsImage::new_from_buffer(blob.as_ref(), "") else {
+            return false;
+        };
+        // processing would turn it upright
+        if self.auto_rotate && header.get_orientation() > 1 {
+            return false;
+        }
+        // processing would convert its colours, or attach the sRGB profile
+        if self.icc_transform
+            && (self.embed_srgb_profile
+                || matches!(header.get_interpretation(), Ok(ops::Interpretation::Cmyk)))
+        {
+            return false;
+        }
 
-        let inferred_format: Option<ImageType> =
-            infer::get(&blob.data).map(|t| match t.mime_type() {
-                "image/png" => ImageType::PNG,
-                "image/jpeg" => ImageType::JPEG,
-                "image/jpg" => ImageType::JPEG,
-                "image/webp" => ImageType::WEBP,
-                "image/gif" => ImageType::GIF,
-                "image/tiff" => ImageType::TIFF,
-                "image/heic" => ImageType::HEIF,
-                "image/avif" => ImageType::AVIF,
-                "image/bmp" => ImageType::BMP,
-                "image/jp2" => ImageType::JP2K,
-                "image/svg+xml" => ImageType::SVG,
-                "image/magick" => ImageType::MAGICK,
-                "application/pdf" => ImageType::PDF,
-                _ => ImageType::JPEG,
-            });
-        let exportable_bytes = self.export(&img, &processing_params, inferred_format)?;
+        params.is_noop_for(header.get_width(), header.get_height(), format)
+    }
+
+    #[tracing::instrument(skip(self, settings))]
+    fn reload(&self, settings: &ProcessorSettings) -> Result<()> {
+        *self
+            .disable_filters
+            .write()
+            .expect("disabled filters lock poisoned") = disabled_filters(settings);
 
-        Ok(exportable_bytes)
+        Ok(())
     }
-}
 
-impl Processor {
-    pub fn new(p_options: ProcessorSettings) -> Self {
-        let mut disabled_filters = p_options.disabled_filters;
-        if p_options.disable_blur {
-            disabled_filters.push("blur".into());
+    #[tracing::instrument(skip(self))]
+    fn health(&self) -> Result<()> {
+        // fails if libvips was never initialised or has been shut down
+        ops::black(1, 1)?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            superres: self.upscaler.is_some(),
+            ..Capabilities::detect(self.limits())
+        }
+    }
+
+    fn process(&self, blob: &Blob, params: &Params, assets: &Assets) -> Result<Blob> {
+        self.process_cancellable(blob, params, assets, &CancellationToken::new())
+    }
+
+    #[tracing::instrument(skip(self, blob, assets, cancel))]
+    fn process_cancellable(
+        &self,
+        blob: &Blob,
+        params: &Params,
+        assets: &Assets,
+        cancel: &CancellationToken,
+    ) -> Result<Blob> {
+        let scaled = params.scaled_by_dpr();
+        let params = scaled.as_ref().unwrap_or(params);
+        let still = if blob.is_video() {
+            Some(extract_frame(blob, FrameSelection::from_params(params))?)
+        } else {
+            None
+        };
+        let source = still.as_ref().unwrap_or(blob);
+        let processing_params = self.preprocess(source, params);
+        let processing_params = ProcessingParams {
+            sequential: self.streams(source, params, &processing_params),
+            ..processing_params
+        };
+
+        match self.render(blob, source, params, &processing_params, assets, cancel) {
+            // something read it out of order after all, so it's loaded again
+            // for random access
+            Err(e) if processing_params.sequential && is_retryable(&e) => {
+                debug!("Sequential processing failed, retrying: {}", e);
+                metrics::counter!("sequential_load_retries_total").increment(1);
+                let processing_params = ProcessingParams {
+                    sequential: false,
+                    ..processing_params
+                };
+                self.render(blob, source, params, &processing_params, assets, cancel)
+            }
+            result => result,
         }
+    }
+}
+
+/// Whether nothing after the load reads the image out of order or more
+/// than once, which a sequential load can't serve
+fn reads_in_order(params: &Params, processing_params: &ProcessingParams) -> bool {
+    !params.trim
+        && !params.v_flip
+        && !params.smart
+        && processing_params.orient == 0
+        && processing_params.max_bytes == 0
+        && processing_params.frames.is_none()
+        && !params.filters.iter().any(|filter| {
+            matches!(
+                filter,
+                Filter::Rotate(_)
+                    | Filter::Focal(_)
+                    | Filter::PixelateRegion(_, _)
+                    | Filter::Blur(_, Some(_))
+                    | Filter::Affine(_)
+                    | Filter::Superres(_)
+                    | Filter::Perspective(_)
+                    | Filter::AutoContrast
+                    | Filter::Equalize
+                    | Filter::Palette(_)
+                    | Filter::Blurhash(_)
+                    | Filter::Thumbhash(_)
+                    | Filter::Fill(Color::Auto)
+                    | Filter::BackgroundColor(Color::Auto)
+                    | Filter::Custom(_)
+            )
+        })
+}
+
+/// Failures a random access load might not have, rather than ones it
+/// would run into again
+fn is_retryable(e: &color_eyre::Report) -> bool {
+    !matches!(
+        e.downcast_ref::<ProcessError>(),
+        Some(
+            ProcessError::Cancelled
+                | ProcessError::DecodeBudgetExceeded(_)
+                | ProcessError::PageOutOfRange { .. }
+                | ProcessError::VideoNotSupported
+                | ProcessError::FilterPanicked { .. }
+        )
+    )
+}
+
+/// The format a source is saved in when no other is asked for
+fn source_format(mime_type: &str) -> ImageType {
+    match mime_type {
+        "image/png" => ImageType::PNG,
+        "image/jpeg" => ImageType::JPEG,
+        "image/jpg" => ImageType::JPEG,
+        "image/webp" => ImageType::WEBP,
+        "image/gif" => ImageType::GIF,
+        "image/tiff" => ImageType::TIFF,
+        "image/heic" => ImageType::HEIF,
+        "image/avif" => ImageType::AVIF,
+        "image/bmp" => ImageType::BMP,
+        "image/jp2" => ImageType::JP2K,
+        "image/svg+xml" => ImageType::SVG,
+        "image/magick" => ImageType::MAGICK,
+        "application/pdf" => ImageType::PDF,
+        _ => ImageType::JPEG,
+    }
+}
+
+/// What a crop to the requested size keeps, by the alignment or `smart`
+fn crop_interest(params: &Params) -> Interesting {
+    match (params.v_align, params.h_align) {
+        _ if params.smart => Interesting::Attention,
+        (Some(VAlign::Top), None) | (None, Some(HAlign::Left)) => Interesting::Low,
+        (Some(VAlign::Bottom), None) | (None, Some(HAlign::Right)) => Interesting::High,
+        (None | Some(VAlign::Middle), None | Some(HAlign::Center)) => Interesting::Centre,
+        _ => Interesting::None,
+    }
+}
+
+/// Whether `blob` has more than 8 bits per sample, read from its header
+fn is_high_depth(blob: &Blob) -> bool {
+    VipsImage::new_from_buffer(blob.as_ref(), "")
+        .is_ok_and(|header| Image::new(header).is_high_depth())
+}
+
+/// Bits per sample to save as `format`, `requested` by `bitdepth()` or else
+/// kept from the image as near as the format goes. 16-bit PNG and TIFF,
+/// 10 and 12-bit HEIF and AVIF, the rest are 8-bit only.
+fn export_bitdepth(format: ImageType, requested: Option<i32>, high_depth: bool) -> i32 {
+    let depth = requested.unwrap_or(if high_depth { 16 } else { 8 });
+    match format {
+        ImageType::PNG | ImageType::TIFF if depth > 8 => 16,
+        ImageType::AVIF | ImageType::HEIF if depth > 8 => match requested {
+            Some(requested) => requested.min(12),
+            None => 10,
+        },
+        _ => 8,
+    }
+}
+
+/// How far into a source its colour profile is looked for. Profiles come
+/// before the pixels, after EXIF in a JPEG, which can take up to 64KB.
+const ICC_SNIFF_LEN: usize = 128 * 1024;
+
+/// Whether `data` embeds an ICC profile: a JPEG `ICC_PROFILE` segment, a PNG
+/// `iCCP` chunk, a WebP `ICCP` chunk or a HEIF/AVIF `prof` colour box. TIFF
+/// keeps it in a tag that can be anywhere, so TIFFs are taken to have one.
+fn has_icc_profile(data: &[u8]) -> bool {
+    const MARKERS: [&[u8]; 4] = [b"ICC_PROFILE\0", b"iCCP", b"ICCP", b"colrprof"];
+
+    if infer::get(data).is_some_and(|kind| kind.mime_type() == "image/tiff") {
+        return true;
+    }
+    let head = &data[..data.len().min(ICC_SNIFF_LEN)];
+    MARKERS
+        .iter()
+        .any(|marker| head.windows(marker.len()).any(|window| window == *marker))
+}
+
+pub(super) fn disabled_filters(settings: &ProcessorSettings) -> Vec<FilterMatcher> {
+    let mut disabled_filters: Vec<FilterMatcher> = settings
+        .disabled_filters
+        .iter()
+        .map(|name| {
+            let matcher = FilterMatcher::new(name);
+            if !matcher.is_known() {
+                warn!("Disabled filter `{}` doesn't match any filter", name);
+            }
+            matcher
+        })
+        .collect();
+    if settings.disable_blur {
+        disabled_filters.push(FilterMatcher::new("blur"));
+    }
+    disabled_filters
+}
+
+/// `dpi` is only understood by the PDF and SVG loaders
+fn dpi_option(blob: &Blob, processing_params: &ProcessingParams) -> String {
+    if processing_params.dpi > 0 && blob.is_vector() {
+        format!("dpi={}", processing_params.dpi)
+    } else {
+        String::new()
+    }
+}
+
+/// Loader options for a full, non-thumbnail load
+fn load_options(blob: &Blob, processing_params: &ProcessingParams) -> String {
+    let pages = if processing_params.frames.is_some() {
+        // every page, so frames can be sampled from them
+        "n=-1".to_string()
+    } else if processing_params.page > 1 {
+        // `page()` counts from 1, libvips from 0
+        format!("page={}", processing_params.page - 1)
+    } else {
+        String::new()
+    };
+
+    let access = if processing_params.sequential {
+        "access=sequential".to_string()
+    } else {
+        String::new()
+    };
+
+    [pages, dpi_option(blob, processing_params), access]
+        .into_iter()
+        .filter(|option| !option.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Used when `max_width`/`max_height` are left unset
+pub(super) const DEFAULT_MAX_DIMENSION: i32 = 100_000;
+
+/// Used when `sequential_min_pixels` is left unset, about 100MB decoded
+const DEFAULT_SEQUENTIAL_MIN_PIXELS: u64 = 25_000_000;
+
+/// Points libvips at where to spill large decoded images. Must run before
+/// libvips starts, since it only reads these once.
+pub fn configure_spill(settings: &ProcessorSettings) {
+    if let Some(threshold) = &settings.spill_threshold {
+        std::env::set_var("VIPS_DISC_THRESHOLD", threshold);
+    }
+    if let Some(dir) = &settings.spill_dir {
+        std::env::set_var("TMPDIR", dir);
+    }
+}
 
-        let concurrency = p_options.concurrency.unwrap_or_else(|| {
-            let default_parallelism_approx = available_parallelism().unwrap().get();
-            if default_parallelism_approx > 1 {
-                default_parallelism_approx as i32
+impl Processor {
+    pub fn from_settings(settings: &ProcessorSettings) -> Self {
+        let max_dimension = |value: i32| {
+            if value > 0 {
+                value
             } else {
-                1
+                DEFAULT_MAX_DIMENSION
             }
-        });
+        };
 
         Processor {
-            disable_blur: p_options.disable_blur,
-            disable_filters: disabled_filters,
-            max_width: 100_000,
-            max_height: 100_000,
-            concurrency,
-            ..Default::default()
+            disable_blur: settings.disable_blur,
+            disable_filters: RwLock::new(disabled_filters(settings)),
+            max_filter_ops: settings.max_filter_ops,
+            concurrency: vips_concurrency(settings.concurrency),
+            max_cache_files: settings.max_cache_files,
+            max_cache_mem: settings.max_cache_mem,
+            max_cache_size: settings.max_cache_size,
+            max_width: max_dimension(settings.max_width),
+            max_height: max_dimension(settings.max_height),
+            max_resolution: settings.max_resolution,
+            max_animation_frames: settings.max_animation_frames,
+            strip_metadata: settings.strip_metadata,
+            auto_rotate: !settings.disable_auto_rotate,
+            deterministic: settings.deterministic,
+            icc_transform: !settings.disable_icc_transform,
+            embed_srgb_profile: settings.embed_srgb_profile,
+            linear_resize: settings.linear_resize,
+            resize_kernel: settings.resize_kernel,
+            sequential_min_pixels: settings
+                .sequential_min_pixels
+                .unwrap_or(DEFAULT_SEQUENTIAL_MIN_PIXELS),
+            formats: settings.formats.clone(),
+            upscaler: Upscaler::load(&settings.superres)
+                .unwrap_or_else(|e| {
+                    error!("superres model not loaded: {:?}", e);
+                    None
+                })
+                .map(Arc::new),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_resolution: self.max_resolution,
+            max_animation_frames: self.max_animation_frames,
+            max_filter_ops: self.max_filter_ops,
+            concurrency: self.concurrency,
         }
     }
 
+    fn is_disabled(&self, filter: &Filter) -> bool {
+        self.disable_filters
+            .read()
+            .expect("disabled filters lock poisoned")
+            .iter()
+            .any(|matcher| matcher.matches(filter))
+    }
+
     #[tracing::instrument(skip(self, blob))]
     fn preprocess(&self, blob: &Blob, params: &Params) -> ProcessingParams {
+        let kernel = self.resize_kernel.filter(|k| *k != ResizeKernel::Lanczos3);
         let initial_params = ProcessingParams {
-            thumbnail_not_supported: params.trim,
+            // adaptive/full fit-in need the source dimensions up front
+            thumbnail_not_supported: params.trim
+                || params.adaptive_fit_in
+                || params.full_fit_in
+                || kernel.is_some(),
             upscale: params.fit != Some(Fit::FitIn),
             thumbnail: false,
+            quality: None,
+            bitdepth: None,
+            linear: self.linear_resize && is_high_depth(blob),
+            kernel,
             strip_exif: false,
+            strip_icc: false,
             strip_metadata: self.strip_metadata,
             orient: 0,
             format: None,
@@ -169,6 +659,9 @@ impl Processor {
             page: 1,
             dpi: 0,
             focal_rects: Vec::new(),
+            frames: None,
+            dpr: 1.0,
+            sequential: false,
         };
 
         let params_after_blob = if blob.supports_animation() {
@@ -180,11 +673,11 @@ impl Processor {
             }
         };
 
-        params
+        let processing_params = params
             .filters
             .iter()
             .fold(params_after_blob, |acc, filter| {
-                if self.disable_filters.contains(&filter.name()) {
+                if self.is_disabled(filter) {
                     return acc;
                 }
 
@@ -216,6 +709,14 @@ impl Processor {
                         upscale: true,
                         ..acc
                     },
+                    Filter::NoUpscale => ProcessingParams {
+                        upscale: false,
+                        ..acc
+                    },
+                    Filter::Quality(quality) => ProcessingParams {
+                        quality: Some(*quality as i32),
+                        ..acc
+                    },
                     Filter::Fill(color) | Filter::BackgroundColor(color) => match color {
                         Color::Auto => ProcessingParams {
                             thumbnail_not_supported: true,
@@ -226,13 +727,18 @@ impl Processor {
                     Filter::Page(page) => {
                         let new_page = *page.max(&1);
                         ProcessingParams {
+                            // thumbnailing always loads the first page
+                            thumbnail_not_supported: acc.thumbnail_not_supported || new_page > 1,
                             page: new_page,
                             ..acc
                         }
                     }
+                    Filter::Dpr(dpr) => ProcessingParams { dpr: dpr.0, ..acc },
                     Filter::Dpi(dpi) => {
                         let new_dpi = *dpi.max(&0);
                         ProcessingParams {
+                            // thumbnailing renders at the loader's default
+                            thumbnail_not_supported: acc.thumbnail_not_supported || new_dpi > 0,
                             dpi: new_dpi,
                             ..acc
                         }
@@ -261,13 +767,196 @@ impl Processor {
                         strip_exif: true,
                         ..acc
                     },
+                    Filter::StripIcc => ProcessingParams {
+                        strip_icc: true,
+                        ..acc
+                    },
+                    Filter::Bitdepth(depth) => ProcessingParams {
+                        bitdepth: Some(*depth as i32),
+                        ..acc
+                    },
+                    Filter::Kernel(kernel) => {
+                        let kernel = Some(*kernel).filter(|k| *k != ResizeKernel::Lanczos3);
+                        ProcessingParams {
+                            kernel,
+                            thumbnail_not_supported: acc.thumbnail_not_supported
+                                || kernel.is_some(),
+                            ..acc
+                        }
+                    }
                     Filter::StripMetadata => ProcessingParams {
                         strip_metadata: true,
                         ..acc
                     },
+                    Filter::Frames(n, delay) => ProcessingParams {
+                        frames: Some((*n, *delay)),
+                        max_n: *n,
+                        thumbnail_not_supported: true,
+                        ..acc
+                    },
                     _ => acc,
                 }
-            })
+            });
+
+        // sampled frames need an animated output, even from a PDF
+        match processing_params.frames {
+            Some((n, _)) => {
+                let format = match processing_params.format {
+                    Some(format) if format.is_animation_supported() => format,
+                    None if blob.content_type.essence() == "image/webp" => ImageType::WEBP,
+                    _ => ImageType::GIF,
+                };
+                ProcessingParams {
+                    format: Some(format),
+                    max_n: n,
+                    ..processing_params
+                }
+            }
+            None => processing_params,
+        }
+    }
+
+    /// Reject a `page()` past the last page, and images that would decode
+    /// past the budget. Only the header is read, so nothing is decoded yet.
+    fn check_source(
+        &self,
+        blob: &Blob,
+        processing_params: &ProcessingParams,
+    ) -> Result<(), ProcessError> {
+        let budget = DecodeBudget::from_max_resolution(self.max_resolution);
+        if budget.is_none() && processing_params.page <= 1 {
+            return Ok(());
+        }
+
+        // every page of a document has the same header, and `dpi` sets its size
+        let header =
+            VipsImage::new_from_buffer(blob.as_ref(), &dpi_option(blob, processing_params))
+                .map_err(|_| ProcessError::ImageLoadError)?;
+
+        let pages = header.get_n_pages().max(1) as usize;
+        if processing_params.page > pages {
+            return Err(ProcessError::PageOutOfRange {
+                page: processing_params.page,
+                pages,
+            });
+        }
+
+        match budget {
+            Some(budget) => budget.check(&DecodeSize::from_header(
+                &header,
+                processing_params.frames.is_some(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `blob` is large enough to be worth loading sequentially, and
+    /// `params` process it in a way that can. Thumbnails always are, libvips
+    /// shrinks them on load.
+    fn streams(&self, blob: &Blob, params: &Params, processing_params: &ProcessingParams) -> bool {
+        if self.sequential_min_pixels == 0 || !reads_in_order(params, processing_params) {
+            return false;
+        }
+        let Ok(header) =
+            VipsImage::new_from_buffer(blob.as_ref(), &dpi_option(blob, processing_params))
+        else {
+            return false;
+        };
+        // turning it upright reads it out of order
+        if self.auto_rotate && header.get_orientation() > 1 {
+            return false;
+        }
+
+        let pixels = header.get_width().max(0) as u64 * header.get_height().max(0) as u64;
+        pixels >= self.sequential_min_pixels
+    }
+
+    /// Loads `source`, which is `blob` or a still from it, and processes it
+    fn render(
+        &self,
+        blob: &Blob,
+        source: &Blob,
+        params: &Params,
+        processing_params: &ProcessingParams,
+        assets: &Assets,
+        cancel: &CancellationToken,
+    ) -> Result<Blob> {
+        checkpoint(cancel)?;
+        // whatever stops the source loading, short of the checks that refuse
+        // it first, is a source that can't be decoded
+        let img = self
+            .load_image(source, params, processing_params)
+            .map_err(|e| match e {
+                ProcessError::ImageProcessingError(message) => ProcessError::Undecodable(message),
+                e => e,
+            })?;
+        let img = match processing_params.frames {
+            Some((n, _)) => img.sample_frames(n)?,
+            None => img,
+        };
+        let img = img.apply_orientation(processing_params.orient)?;
+        checkpoint(cancel)?;
+        let (width, height) = img.calculate_dimensions(params, processing_params.upscale);
+        let img = match processing_params.kernel {
+            Some(kernel) => img.resize_with_kernel(
+                width,
+                height,
+                params.fit,
+                processing_params.upscale,
+                kernel,
+                crop_interest(params),
+            )?,
+            None => img.resize_image(
+                width,
+                height,
+                params.fit,
+                processing_params.upscale,
+                processing_params.linear,
+            )?,
+        };
+        let img = img.apply_flip(params.h_flip, params.v_flip)?;
+        // filters and colours picked in the path are meant as sRGB
+        let img = if self.icc_transform {
+            img.to_srgb(self.embed_srgb_profile || has_icc_profile(&source.data))?
+        } else {
+            img
+        };
+        checkpoint(cancel)?;
+
+        let img = self.apply_filters(img, params, processing_params, assets)?;
+        let img = img.apply_padding(params)?;
+        checkpoint(cancel)?;
+
+        // data about the result instead of the image, the last one wins
+        let data = params
+            .filters
+            .iter()
+            .rev()
+            .filter(|filter| !self.is_disabled(filter))
+            .find_map(|filter| match filter {
+                Filter::Palette(n) => Some(palette::render(&img, *n)),
+                _ => Placeholder::from_filter(filter)
+                    .map(|(placeholder, output)| placeholder.render(&img, output)),
+            });
+        if let Some(data) = data {
+            return Ok(data?.with_metadata(blob.metadata.clone()));
+        }
+        checkpoint(cancel)?;
+
+        // if p.meta {
+        //     // metadata without export
+        //     return imagor.NewBlobFromJsonMarshal(metadata(img, format, stripExif)), nil
+        // }
+
+        let inferred_format = infer::get(&blob.data).map(|t| source_format(t.mime_type()));
+        let mut exportable_bytes = self.export(&img, processing_params, inferred_format)?;
+        if let (Some((_, delay)), Some(format)) =
+            (processing_params.frames, processing_params.format)
+        {
+            set_frame_delay(&mut exportable_bytes.data, format, delay);
+        }
+
+        Ok(exportable_bytes.with_metadata(blob.metadata.clone()))
     }
 
     #[tracing::instrument(skip(self, blob))]
@@ -287,6 +976,8 @@ impl Processor {
             debug!("Detected image format: {}", format.mime_type());
         }
 
+        self.check_source(blob, processing_params)?;
+
         if !processing_params.thumbnail_not_supported
             && params.crop_bottom.is_none()
             && params.crop_top.is_none()
@@ -308,12 +999,14 @@ impl Processor {
                         &ThumbnailBufferOptions {
                             height: h,
                             size,
+                            no_rotate: !self.auto_rotate,
+                            linear: processing_params.linear,
                             ..Default::default()
                         },
                     )
                     .map_err(|e| {
                         ProcessError::ImageProcessingError(
-                            format!("Failed to create thumbnail for fit_in {:?}", e).into(),
+                            format!("Failed to create thumbnail for fit_in {:?}", e),
                         )
                     })
                 }
@@ -324,44 +1017,34 @@ impl Processor {
                         height,
                         crop: Interesting::None,
                         size: Size::Force,
+                        no_rotate: !self.auto_rotate,
+                        linear: processing_params.linear,
                         ..Default::default()
                     },
                 )
                 .map_err(|e| {
                     ProcessError::ImageProcessingError(
-                        format!("Failed to create thumbnail for stretch {:?}", e).into(),
+                        format!("Failed to create thumbnail for stretch {:?}", e),
                     )
                 }),
 
-                (None, Some(width), Some(height)) => {
-                    let interest = match (params.v_align, params.h_align) {
-                        _ if params.smart => Interesting::Attention,
-                        (Some(VAlign::Top), None) | (None, Some(HAlign::Left)) => Interesting::Low,
-                        (Some(VAlign::Bottom), None) | (None, Some(HAlign::Right)) => {
-                            Interesting::High
-                        }
-                        (None | Some(VAlign::Middle), None | Some(HAlign::Center)) => {
-                            Interesting::Centre
-                        }
-                        _ => Interesting::None,
-                    };
-
-                    ops::thumbnail_buffer_with_opts(
-                        blob.as_ref(),
-                        width,
-                        &ThumbnailBufferOptions {
-                            height,
-                            crop: interest,
-                            size: Size::Both,
-                            ..Default::default()
-                        },
+                (None, Some(width), Some(height)) => ops::thumbnail_buffer_with_opts(
+                    blob.as_ref(),
+                    width,
+                    &ThumbnailBufferOptions {
+                        height,
+                        crop: crop_interest(params),
+                        size: Size::Both,
+                        no_rotate: !self.auto_rotate,
+                        linear: processing_params.linear,
+                        ..Default::default()
+                    },
+                )
+                .map_err(|_| {
+                    ProcessError::ImageProcessingError(
+                        "Failed to create smart/aligned thumbnail".into(),
                     )
-                    .map_err(|_| {
-                        ProcessError::ImageProcessingError(
-                            "Failed to create smart/aligned thumbnail".into(),
-                        )
-                    })
-                }
+                }),
                 (None, Some(width), None) => ops::thumbnail_buffer_with_opts(
                     blob.as_ref(),
                     width,
@@ -369,12 +1052,14 @@ impl Processor {
                         height: self.max_height,
                         crop: Interesting::None,
                         size: Size::Both,
+                        no_rotate: !self.auto_rotate,
+                        linear: processing_params.linear,
                         ..Default::default()
                     },
                 )
                 .map_err(|e| {
                     ProcessError::ImageProcessingError(
-                        format!("Failed to create width-only thumbnail {:?}", e).into(),
+                        format!("Failed to create width-only thumbnail {:?}", e),
                     )
                 }),
 
@@ -385,65 +1070,79 @@ impl Processor {
                         height,
                         crop: Interesting::None,
                         size: Size::Both,
+                        no_rotate: !self.auto_rotate,
+                        linear: processing_params.linear,
                         ..Default::default()
                     },
                 )
                 .map_err(|e| {
                     ProcessError::ImageProcessingError(
-                        format!("Failed to create height-only thumbnail {:?}", e).into(),
+                        format!("Failed to create height-only thumbnail {:?}", e),
                     )
                 }),
 
-                _ => VipsImage::new_from_buffer(blob.as_ref(), "")
-                    .map_err(|_| ProcessError::ImageLoadError),
+                _ => VipsImage::new_from_buffer(
+                    blob.as_ref(),
+                    &load_options(blob, processing_params),
+                )
+                .map_err(|_| ProcessError::ImageLoadError),
             };
 
-            return img.map(Image::new);
+            return img.map(Image::new).and_then(|img| self.auto_orient(img));
         };
 
         // If we couldn't create a thumbnail, load the full image
         let img = if processing_params.thumbnail_not_supported {
-            VipsImage::new_from_buffer(blob.as_ref(), "").map_err(|e| {
-                debug!(
-                    "failed to create image from buffer of size {} - {}",
-                    blob.as_ref().len(),
-                    e
-                );
-                ProcessError::ImageLoadError
-            })
+            VipsImage::new_from_buffer(blob.as_ref(), &load_options(blob, processing_params))
+                .map_err(|e| {
+                    debug!(
+                        "failed to create image from buffer of size {} - {}",
+                        blob.as_ref().len(),
+                        e
+                    );
+                    ProcessError::ImageLoadError
+                })
         } else {
-            // ops::thumbnail_buffer_with_opts(
-            //     blob.as_ref(),
-            //     self.max_width,
-            //     &ThumbnailBufferOptions {
-            //         height: self.max_height,
-            //         crop: Interesting::None,
-            //         size: Size::Down,
-            //         no_rotate: true, // Add this to prevent rotation issues
-            //         ..Default::default()
-            //     },
-            // )
-            ops::thumbnail_buffer(blob.as_ref(), 100).map_err(|e| {
+            ops::thumbnail_buffer_with_opts(
+                blob.as_ref(),
+                100,
+                &ThumbnailBufferOptions {
+                    no_rotate: !self.auto_rotate,
+                    linear: processing_params.linear,
+                    ..Default::default()
+                },
+            )
+            .map_err(|e| {
                 ProcessError::ImageProcessingError(
                     format!(
                         "Failed to create default thumbnail of buffer size {} - {}",
                         blob.as_ref().len(),
                         e
-                    )
-                    .into(),
+                    ),
                 )
             })
         };
 
-        return img.map(Image::new);
+        img.map(Image::new).and_then(|img| self.auto_orient(img))
     }
 
-    #[tracing::instrument(skip(self, img))]
+    /// Thumbnailing rotates by the EXIF orientation while shrinking, a full
+    /// load has to catch up so both paths come out the same way up
+    fn auto_orient(&self, img: Image) -> Result<Image, ProcessError> {
+        if self.auto_rotate {
+            img.auto_orient()
+        } else {
+            Ok(img)
+        }
+    }
+
+    #[tracing::instrument(skip(self, img, assets))]
     fn apply_filters(
         &self,
         img: Image,
         params: &Params,
         processing_params: &ProcessingParams,
+        assets: &Assets,
     ) -> Result<Image, ProcessError> {
         let truncate_length = if self.max_filter_ops > 0 {
             self.max_filter_ops.min(params.filters.len())
@@ -456,30 +1155,81 @@ impl Processor {
         }
         let filters_slice: &[Filter] = &params.filters[..truncate_length];
 
-        let filtered = filters_slice.iter().fold(img, |img, filter| {
-            if self.disable_filters.contains(&filter.name()) {
-                return img;
+        filters_slice.iter().try_fold(img, |img, filter| {
+            if self.is_disabled(filter) {
+                return Ok(img);
             }
 
             let start = Instant::now();
-            let new_image = img.apply(filter, params);
+            // a panicking filter fails the render, and is named for it
+            let new_image = panic::catch_unwind(AssertUnwindSafe(|| match filter {
+                Filter::Superres(scale) => self.superres(&img, *scale),
+                Filter::Border(..) | Filter::Shadow(..) => self
+                    .check_frame(&img, filter)
+                    .and_then(|()| img.apply(filter, params, assets)),
+                _ => img.apply(filter, params, assets),
+            }))
+            .map_err(|payload| ProcessError::FilterPanicked {
+                filter: filter.name(),
+                message: panic_message(payload.as_ref()),
+            })?;
             let elapsed = start.elapsed().as_millis();
 
             debug!("filter |{}| took {}", filter, elapsed);
 
             match new_image {
-                Ok(new_image) => new_image,
+                Ok(new_image) => Ok(new_image),
                 Err(err) => {
                     error!("filter |{}| failed: {:?}", filter, err);
-                    img
+                    Ok(img)
                 }
             }
-        });
+        })
+    }
 
-        Ok(filtered)
+    /// Fails when `filter` would grow each frame of `img` past the size
+    /// limits, for the filters that draw around it
+    fn check_frame(&self, img: &Image, filter: &Filter) -> Result<()> {
+        let img = img.as_inner();
+        let Some((width, height)) = framed_size(filter, img.get_width(), img.get_page_height())
+        else {
+            return Ok(());
+        };
+        // unset when not built from settings
+        let max = |value: i32| {
+            if value > 0 {
+                value
+            } else {
+                DEFAULT_MAX_DIMENSION
+            }
+        };
+        check_dimensions(width, height, max(self.max_width), max(self.max_height))?;
+        let pixels = width.saturating_mul(height);
+        if self.max_resolution > 0 && pixels > self.max_resolution as u64 {
+            return Err(color_eyre::eyre::eyre!(
+                "{} output would be {} pixels, above the cap of {}",
+                filter.name(),
+                pixels,
+                self.max_resolution
+            ));
+        }
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self, img, params))]
+    /// Enlarge with the model, or leave the image be without one
+    #[tracing::instrument(skip(self, img))]
+    fn superres(&self, img: &Image, scale: u32) -> Result<Image> {
+        let Some(upscaler) = &self.upscaler else {
+            debug!("superres() without a model, skipped");
+            return Ok(img.clone());
+        };
+        if img.is_animated() {
+            return Ok(img.clone());
+        }
+        upscaler.upscale(img.as_inner(), scale).map(Image::new)
+    }
+
+    #[tracing::instrument(skip(self, img, params))]
     fn export(
         &self,
         img: &Image,
@@ -488,13 +1238,36 @@ impl Processor {
     ) -> Result<Blob> {
         let format = params.format.unwrap_or(inferred.unwrap_or(ImageType::JPEG));
 
-        let mut options = ExportOptions {
-            quality: None, // Set from params if needed
-            compression: None,
-            palette: false,
-            bitdepth: None,
-            strip_metadata: params.strip_metadata,
-            max_bytes: params.max_bytes,
+        let mut options = ExportOptions::new(format, params, &self.formats);
+        let bitdepth = export_bitdepth(format, options.bitdepth, img.is_high_depth());
+        // other formats are brought down to 8 bits by libvips as they're saved
+        let img = match format {
+            ImageType::PNG | ImageType::TIFF | ImageType::AVIF | ImageType::HEIF => {
+                img.clone().with_depth(bitdepth > 8)?
+            }
+            _ => img.clone(),
+        };
+
+        // EXIF/XMP/IPTC carry timestamps and encoder details that vary between
+        // runs, so deterministic output only keeps the colour profile. Keep
+        // isn't a set in libvips' bindings, so dropping only the profile
+        // keeps EXIF alone.
+        let keep = if options.strip_metadata {
+            if self.embed_srgb_profile && !options.strip_icc {
+                ForeignKeep::Icc
+            } else {
+                ForeignKeep::None
+            }
+        } else if options.strip_icc {
+            if self.deterministic {
+                ForeignKeep::None
+            } else {
+                ForeignKeep::Exif
+            }
+        } else if self.deterministic {
+            ForeignKeep::Icc
+        } else {
+            ForeignKeep::All
         };
 
         loop {
@@ -504,64 +1277,90 @@ impl Processor {
                     &PngsaveBufferOptions {
                         compression: options.compression.unwrap_or(6),
                         filter: ForeignPngFilter::None,
-                        palette: options.palette,
+                        // palettes are 8-bit at most
+                        palette: options.palette && bitdepth == 8,
+                        bitdepth,
                         q: options.quality.unwrap_or(75),
+                        keep,
                         ..Default::default()
                     },
                 )
                 .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
                 ImageType::WEBP => ops::webpsave_buffer_with_opts(
                     img.as_inner(),
                     &WebpsaveBufferOptions {
                         q: options.quality.unwrap_or(75),
+                        lossless: options.lossless,
+                        effort: options.effort.unwrap_or(4),
+                        keep,
                         ..Default::default()
                     },
                 )
                 .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
                 ImageType::TIFF => ops::tiffsave_buffer_with_opts(
                     img.as_inner(),
                     &TiffsaveBufferOptions {
                         q: options.quality.unwrap_or(75),
+                        keep,
                         ..Default::default()
                     },
                 )
                 .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
-                ImageType::GIF => ops::gifsave_buffer(img.as_inner()).map(|b| Blob {
+                ImageType::GIF => ops::gifsave_buffer_with_opts(
+                    img.as_inner(),
+                    &GifsaveBufferOptions {
+                        keep,
+                        ..Default::default()
+                    },
+                )
+                .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
                 ImageType::AVIF => ops::heifsave_buffer_with_opts(
                     img.as_inner(),
                     &HeifsaveBufferOptions {
                         q: options.quality.unwrap_or(75),
+                        effort: options.effort.unwrap_or(4),
+                        bitdepth,
+                        keep,
                         compression: ForeignHeifCompression::Av1,
                         ..Default::default()
                     },
                 )
                 .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
                 ImageType::HEIF => ops::heifsave_buffer_with_opts(
                     img.as_inner(),
                     &HeifsaveBufferOptions {
                         q: options.quality.unwrap_or(75),
+                        effort: options.effort.unwrap_or(4),
+                        bitdepth,
+                        keep,
                         compression: ForeignHeifCompression::Hevc,
                         ..Default::default()
                     },
                 )
                 .map(|b| Blob {
                     data: b,
-                    content_type: format.to_content_type(),
+                    content_type: format.into(),
+                    ..Default::default()
                 })?,
                 _ => {
                     // Default to JPEG
@@ -569,8 +1368,9 @@ impl Processor {
                         img.as_inner(),
                         &JpegsaveBufferOptions {
                             q: options.quality.unwrap_or(75),
+                            keep,
                             optimize_coding: true,
-                            interlace: true,
+                            interlace: options.progressive,
                             trellis_quant: true,
                             quant_table: 3,
                             ..Default::default()
@@ -578,7 +1378,8 @@ impl Processor {
                     )
                     .map(|b| Blob {
                         data: b,
-                        content_type: ImageType::JPEG.to_content_type(),
+                        content_type: ImageType::JPEG.into(),
+                        ..Default::default()
                     })?
                 }
             };
@@ -613,11 +1414,51 @@ impl Processor {
     }
 }
 
+/// The result of [`process_params`]
+#[derive(Debug)]
+pub struct Processed {
+    pub blob: Blob,
+    /// Size of the encoded image, or of one frame of an animation. `None`
+    /// when the path asked for data instead, e.g. `blurhash()` or `palette()`.
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+/// Process an in-memory image the way a request for `params` would, without
+/// any storage, cache or HTTP. Useful for batch pipelines and tests.
+///
+/// `params` are used as given, so apply `Params::with_defaults` first for the
+/// deployment defaults. The processor runs with default settings. Filters
+/// that load other images, like `watermark()`, fail without them, so use
+/// [`Processor::process`] with [`Assets`] for those. libvips must already be
+/// initialised, e.g. by holding a `libvips::VipsApp`, or use
+/// [`crate::process`] which starts it on first use.
+pub fn process_params(params: Params, blob: Blob) -> Result<Processed> {
+    let processor = Processor::from_settings(&ProcessorSettings::default());
+    let blob = processor.process(&blob, &params, &Assets::default())?;
+
+    let header = blob
+        .content_type
+        .is_image()
+        .then(|| VipsImage::new_from_buffer(&blob.data, "").ok())
+        .flatten();
+
+    Ok(Processed {
+        width: header.as_ref().map(|img| img.get_width()),
+        height: header.as_ref().map(|img| img.get_page_height()),
+        blob,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::AvifSettings;
+    use crate::imagorpath::type_utils::F32;
+    use crate::storage::content_type::ContentType;
     use image::{ImageBuffer, Rgb};
     use libvips::VipsApp;
+    use proptest::prelude::*;
     use rand::Rng;
 
     #[test]
@@ -651,14 +1492,898 @@ mod tests {
         // Create blob
         let blob = Blob {
             data: jpeg_data,
-            content_type: "image/jpeg".to_string(),
+            content_type: ContentType::from_static("image/jpeg"),
+            ..Default::default()
         };
 
         let processor = Processor::default();
 
         let params = Params::default();
-        let result = processor.process(&blob, &params);
+        let result = processor.process(&blob, &params, &Assets::default());
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_deterministic_output_is_byte_identical() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 128]));
+        let mut png_data = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor {
+            deterministic: true,
+            ..Default::default()
+        };
+        let (_, params) = crate::imagorpath::parse::parse_path(
+            "fit-in/32x32/filters:grayscale():format(webp)/img.png",
+        )
+        .unwrap();
+
+        let first = processor
+            .process(&blob, &params, &Assets::default())
+            .unwrap();
+        let second = processor
+            .process(&blob, &params, &Assets::default())
+            .unwrap();
+
+        assert_eq!(first.data, second.data);
+    }
+
+    #[test]
+    fn test_cmyk_is_converted_to_srgb() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 128]));
+        let mut png_data = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let rgb = VipsImage::new_from_buffer(&png_data, "").unwrap();
+        let cmyk = ops::colourspace(&rgb, ops::Interpretation::Cmyk).unwrap();
+        let blob = Blob {
+            data: ops::jpegsave_buffer(&cmyk).unwrap(),
+            content_type: ContentType::from_static("image/jpeg"),
+            ..Default::default()
+        };
+
+        let processor = Processor {
+            icc_transform: true,
+            ..Default::default()
+        };
+        let (_, params) =
+            crate::imagorpath::parse::parse_path("32x32/filters:format(png)/img.jpg").unwrap();
+        assert!(!processor.is_noop(&blob, &params));
+
+        let result = processor
+            .process(&blob, &params, &Assets::default())
+            .unwrap();
+        let result = VipsImage::new_from_buffer(&result.data, "").unwrap();
+        assert!(matches!(
+            result.get_interpretation(),
+            Ok(ops::Interpretation::Srgb)
+        ));
+        assert_eq!(result.get_bands(), 3);
+    }
+
+    #[test]
+    fn test_sixteen_bit_png_is_kept() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let img_buf: ImageBuffer<Rgb<u16>, Vec<u16>> =
+            ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u16 * 1024, y as u16 * 1024, 300]));
+        let mut png_data = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor {
+            linear_resize: true,
+            ..Default::default()
+        };
+        let color = |path: &str| {
+            let (_, params) = crate::imagorpath::parse::parse_path(path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            image::load_from_memory(&result.data).unwrap().color()
+        };
+        assert_eq!(
+            color("fit-in/32x32/filters:format(png)/img.png"),
+            image::ColorType::Rgb16
+        );
+        assert_eq!(
+            color("fit-in/32x32/filters:format(png):bitdepth(8)/img.png"),
+            image::ColorType::Rgb8
+        );
+    }
+
+    #[test]
+    fn test_nearest_kernel_keeps_pixels_crisp() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let checker: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(4, 4, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+        let mut png_data = Vec::new();
+        checker
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        for (path, size) in [
+            ("32x32/filters:kernel(nearest)/sprite.png", (32, 32)),
+            ("24x32/filters:kernel(nearest)/sprite.png", (24, 32)),
+            (
+                "fit-in/32x16/filters:upscale():kernel(nearest)/sprite.png",
+                (16, 16),
+            ),
+        ] {
+            let (_, params) = crate::imagorpath::parse::parse_path(path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let result = image::load_from_memory(&result.data).unwrap().to_rgb8();
+            assert_eq!(result.dimensions(), size, "{}", path);
+            assert!(
+                result
+                    .pixels()
+                    .all(|p| p.0 == [0, 0, 0] || p.0 == [255, 255, 255]),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_denoise_removes_speckles() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let speckled: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(16, 16, |x, y| {
+            if (x, y) == (8, 8) {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+        let mut png_data = Vec::new();
+        speckled
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let brightest = |path: &str| {
+            let (_, params) = crate::imagorpath::parse::parse_path(path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let result = image::load_from_memory(&result.data).unwrap().to_rgb8();
+            assert_eq!(result.dimensions(), (16, 16), "{}", path);
+            result.pixels().map(|p| p.0[0]).max().unwrap()
+        };
+        assert_eq!(brightest("filters:usm(1,1.5,0):format(png)/img.png"), 255);
+        assert!(brightest("filters:denoise(2):format(png)/img.png") < 32);
+    }
+
+    #[test]
+    fn test_toning_filters() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let black_and_white: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(2, 1, |x, _| Rgb([x as u8 * 255; 3]));
+        let mut png_data = Vec::new();
+        black_and_white
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let pixels = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let result = image::load_from_memory(&result.data).unwrap().to_rgb8();
+            result.pixels().map(|p| p.0).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            pixels("duotone(20,20,60,250,240,200)"),
+            vec![[20, 20, 60], [250, 240, 200]]
+        );
+        assert_eq!(pixels("tint(ff0000,100)"), vec![[0, 0, 0], [255, 0, 0]]);
+        let sepia = pixels("sepia()");
+        assert_eq!(sepia[0], [0, 0, 0]);
+        let [r, g, b] = sepia[1];
+        assert!(r == 255 && g == 255 && b < 240);
+
+        assert_eq!(
+            pixels("curves(r=0:64;b=255:128)"),
+            vec![[64, 0, 0], [255, 255, 128]]
+        );
+        assert_eq!(pixels("gamma(2.2)"), vec![[0, 0, 0], [255, 255, 255]]);
+    }
+
+    #[test]
+    fn test_level_filters() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        // a dull gradient, 100 to 150
+        let dull: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(51, 4, |x, _| Rgb([100 + x as u8; 3]));
+        let mut png_data = Vec::new();
+        dull.write_to(
+            &mut std::io::Cursor::new(&mut png_data),
+            image::ImageFormat::Png,
+        )
+        .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let range = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let result = image::load_from_memory(&result.data).unwrap().to_luma8();
+            let min = result.pixels().map(|p| p.0[0]).min().unwrap();
+            let max = result.pixels().map(|p| p.0[0]).max().unwrap();
+            (min, max)
+        };
+
+        let (min, max) = range("autocontrast()");
+        assert!(min < 5 && max > 250, "{} to {}", min, max);
+        let (min, max) = range("equalize()");
+        assert!(min < 20 && max > 235, "{} to {}", min, max);
+        let (min, max) = range("vibrance(50)");
+        assert!(min >= 99 && max <= 151, "{} to {}", min, max);
+    }
+
+    #[test]
+    fn test_region_filters() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        // a checkerboard of single pixels
+        let checkers: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(20, 20, |x, y| Rgb([((x + y) % 2) as u8 * 255; 3]));
+        let mut png_data = Vec::new();
+        checkers
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let render = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            image::load_from_memory(&result.data).unwrap().to_luma8()
+        };
+
+        for filter in ["pixelate_region(0,0,10,10,4)", "blur(2,0,0,10,10)"] {
+            let result = render(filter);
+            assert_eq!(result.dimensions(), (20, 20));
+            // the checkers inside the region even out to grey
+            for (x, y) in [(0, 0), (3, 3), (5, 6), (7, 4)] {
+                let level = result.get_pixel(x, y).0[0];
+                assert!(
+                    (110..=145).contains(&level),
+                    "{}: {} at {},{}",
+                    filter,
+                    level,
+                    x,
+                    y
+                );
+            }
+            // and the rest is untouched
+            assert_eq!(result.get_pixel(10, 10).0[0], 0);
+            assert_eq!(result.get_pixel(15, 4).0[0], 255);
+        }
+    }
+
+    #[test]
+    fn test_frame_filters() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let red: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_pixel(10, 10, Rgb([255, 0, 0]));
+        let mut png_data = Vec::new();
+        red.write_to(
+            &mut std::io::Cursor::new(&mut png_data),
+            image::ImageFormat::Png,
+        )
+        .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let render = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            image::load_from_memory(&result.data).unwrap().to_rgba8()
+        };
+
+        let bordered = render("border(3,0000ff)");
+        assert_eq!(bordered.dimensions(), (16, 16));
+        assert_eq!(bordered.get_pixel(0, 0).0, [0, 0, 255, 255]);
+        assert_eq!(bordered.get_pixel(15, 8).0, [0, 0, 255, 255]);
+        assert_eq!(bordered.get_pixel(8, 8).0, [255, 0, 0, 255]);
+
+        // a hard shadow down and to the right
+        let shadowed = render("shadow(4,4,0,black)");
+        assert_eq!(shadowed.dimensions(), (14, 14));
+        assert_eq!(shadowed.get_pixel(2, 2).0, [255, 0, 0, 255]);
+        assert_eq!(shadowed.get_pixel(12, 12).0, [0, 0, 0, 255]);
+        assert_eq!(shadowed.get_pixel(12, 1).0[3], 0);
+
+        // a soft one grows the canvas on every side
+        let soft = render("shadow(0,0,2,black)");
+        assert_eq!(soft.dimensions(), (22, 22));
+        assert_eq!(soft.get_pixel(0, 0).0[3], 0);
+
+        // past the limits, or past what a path could ask for, they're skipped
+        let processor = Processor {
+            max_resolution: 200,
+            ..Default::default()
+        };
+        let black = Color::Named(crate::imagorpath::color::NamedColor::Black);
+        for filter in [
+            Filter::Border(3, black.clone()),
+            Filter::Border(u32::MAX, black.clone()),
+            Filter::Shadow(i32::MIN, i32::MAX, F32(f32::MAX), black),
+        ] {
+            let params = Params {
+                filters: vec![filter.clone(), Filter::Format(ImageType::PNG)],
+                ..Default::default()
+            };
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let img = image::load_from_memory(&result.data).unwrap();
+            assert_eq!((img.width(), img.height()), (10, 10), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn test_label_layout() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let white: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(200, 100, Rgb([255, 255, 255]));
+        let mut png_data = Vec::new();
+        white
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        // left, top, right and bottom of whatever the text darkened
+        let ink = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            let img = image::load_from_memory(&result.data).unwrap().to_rgb8();
+            let dark: Vec<(u32, u32)> = img
+                .enumerate_pixels()
+                .filter(|(_, _, p)| p.0[0] < 128)
+                .map(|(x, y, _)| (x, y))
+                .collect();
+            (
+                dark.iter().map(|p| p.0).min().unwrap(),
+                dark.iter().map(|p| p.1).min().unwrap(),
+                dark.iter().map(|p| p.0).max().unwrap(),
+                dark.iter().map(|p| p.1).max().unwrap(),
+            )
+        };
+
+        let (left, top, _, bottom) = ink("label(Hi,left,top,20,black,,,10)");
+        assert!(left >= 10 && top >= 10);
+
+        // a second line goes below the first
+        let (_, _, _, two_lines) = ink("label(Hi%0AHi,left,top,20,black,,,10)");
+        assert!(two_lines > bottom + 10);
+
+        // filled in, and kept inside the padding from the far corner
+        let (_, _, right, bottom) =
+            ink("var(sign,©):label({sign} {width}x{height},right,bottom,20,black,,,10)");
+        assert!(right < 190 && bottom < 90);
+    }
+
+    #[test]
+    fn test_transform_filters() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        // red on the left, blue on the right
+        let halves: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let mut png_data = Vec::new();
+        halves
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        let blob = Blob {
+            data: png_data,
+            content_type: ContentType::from_static("image/png"),
+            ..Default::default()
+        };
+
+        let processor = Processor::default();
+        let render = |filter: &str| {
+            let path = format!("filters:{}:format(png)/img.png", filter);
+            let (_, params) = crate::imagorpath::parse::parse_path(&path).unwrap();
+            let result = processor
+                .process(&blob, &params, &Assets::default())
+                .unwrap();
+            image::load_from_memory(&result.data).unwrap().to_rgb8()
+        };
+
+        // moved right by 10, the canvas staying put
+        let moved = render("affine(1,0,0,1,10,0)");
+        assert_eq!(moved.dimensions(), (40, 20));
+        assert_eq!(moved.get_pixel(4, 10).0, [0, 0, 0]);
+        assert_eq!(moved.get_pixel(20, 10).0, [255, 0, 0]);
+        assert_eq!(moved.get_pixel(35, 10).0, [0, 0, 255]);
+
+        // the right half, straightened out on its own
+        let right = render("perspective(20,0,40,0,40,20,20,20)");
+        assert_eq!(right.dimensions(), (20, 20));
+        assert_eq!(right.get_pixel(10, 10).0, [0, 0, 255]);
+
+        // a fraction of the width and height works the same way
+        let left = render("perspective(0,0,0.5,0,0.5,1,0,1)");
+        assert_eq!(left.dimensions(), (20, 20));
+        assert_eq!(left.get_pixel(10, 10).0, [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_export_bitdepth() {
+        assert_eq!(export_bitdepth(ImageType::PNG, None, false), 8);
+        assert_eq!(export_bitdepth(ImageType::PNG, None, true), 16);
+        assert_eq!(export_bitdepth(ImageType::TIFF, Some(10), false), 16);
+        assert_eq!(export_bitdepth(ImageType::AVIF, None, true), 10);
+        assert_eq!(export_bitdepth(ImageType::AVIF, Some(16), false), 12);
+        assert_eq!(export_bitdepth(ImageType::HEIF, Some(8), true), 8);
+        assert_eq!(export_bitdepth(ImageType::JPEG, Some(16), true), 8);
+    }
+
+    #[test]
+    fn test_has_icc_profile() {
+        let mut jpeg = b"\xff\xd8\xff\xe2\x0c\x58ICC_PROFILE\0\x01\x01".to_vec();
+        assert!(has_icc_profile(&jpeg));
+        jpeg.truncate(6);
+        assert!(!has_icc_profile(&jpeg));
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0\0\0\0\0\0\0\0\x01\0iCCP";
+        assert!(has_icc_profile(png));
+        assert!(!has_icc_profile(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+    }
+
+    #[test]
+    fn test_preprocess_frames_forces_animated_format() {
+        let processor = Processor::default();
+        let blob = Blob {
+            content_type: ContentType::from_static("application/pdf"),
+            ..Default::default()
+        };
+        let params = Params {
+            filters: vec![Filter::Frames(6, 200), Filter::Format(ImageType::JPEG)],
+            ..Default::default()
+        };
+
+        let processing_params = processor.preprocess(&blob, &params);
+        assert_eq!(processing_params.frames, Some((6, 200)));
+        assert_eq!(processing_params.max_n, 6);
+        assert_eq!(processing_params.format, Some(ImageType::GIF));
+    }
+
+    #[test]
+    fn test_preprocess_page_loads_selected_page() {
+        let processor = Processor::default();
+        let params = Params {
+            width: Some(100),
+            filters: vec![Filter::Page(3)],
+            ..Default::default()
+        };
+
+        let processing_params = processor.preprocess(&Blob::default(), &params);
+        assert!(processing_params.thumbnail_not_supported);
+        assert_eq!(load_options(&Blob::default(), &processing_params), "page=2");
+
+        let processing_params = processor.preprocess(&Blob::default(), &Params::default());
+        assert_eq!(load_options(&Blob::default(), &processing_params), "");
+
+        // dpi only reaches loaders that understand it
+        let pdf = Blob {
+            content_type: ContentType::from_static("application/pdf"),
+            ..Default::default()
+        };
+        let params = Params {
+            filters: vec![Filter::Page(2), Filter::Dpi(300)],
+            ..Default::default()
+        };
+        let processing_params = processor.preprocess(&pdf, &params);
+        assert_eq!(load_options(&pdf, &processing_params), "page=1,dpi=300");
+        assert_eq!(load_options(&Blob::default(), &processing_params), "page=1");
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let processor = Processor::from_settings(&ProcessorSettings {
+            disable_blur: true,
+            disabled_filters: vec!["Max_Frames".to_string(), "watermark()".to_string()],
+            max_filter_ops: 5,
+            concurrency: Some(3),
+            max_width: 4000,
+            max_animation_frames: 10,
+            strip_metadata: true,
+            formats: FormatSettings {
+                avif: AvifSettings {
+                    speed: 6,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        assert_eq!(processor.max_filter_ops, 5);
+        assert_eq!(processor.concurrency, 3);
+        assert_eq!(processor.max_width, 4000);
+        assert_eq!(processor.max_height, DEFAULT_MAX_DIMENSION);
+        assert_eq!(processor.max_animation_frames, 10);
+        assert!(processor.strip_metadata);
+        assert_eq!(processor.formats.avif.speed, 6);
+        assert_eq!(processor.limits().max_width, 4000);
+
+        assert!(processor.is_disabled(&Filter::Blur(crate::imagorpath::type_utils::F32(2.0), None)));
+        assert!(processor.is_disabled(&Filter::MaxFrames(3)));
+        assert!(!processor.is_disabled(&Filter::Grayscale));
+    }
+
+    #[test]
+    fn test_export_options_format_defaults() {
+        let processor = Processor::default();
+        let mut formats = FormatSettings::default();
+        formats.webp.quality = 60;
+        formats.webp.lossless = true;
+
+        let params = processor.preprocess(&Blob::default(), &Params::default());
+        let webp = ExportOptions::new(ImageType::WEBP, &params, &formats);
+        assert_eq!(webp.quality, Some(60));
+        assert!(webp.lossless);
+        assert_eq!(webp.effort, Some(4));
+
+        let avif = ExportOptions::new(ImageType::AVIF, &params, &formats);
+        assert_eq!(avif.effort, Some(4));
+
+        let params = processor.preprocess(
+            &Blob::default(),
+            &Params {
+                filters: vec![Filter::Quality(90)],
+                ..Default::default()
+            },
+        );
+        let webp = ExportOptions::new(ImageType::WEBP, &params, &formats);
+        assert_eq!(webp.quality, Some(90));
+        // 2x output gets a lower default quality, but not an explicit one
+        let params = processor.preprocess(
+            &Blob::default(),
+            &Params {
+                filters: vec![Filter::Dpr(crate::imagorpath::type_utils::F32(2.0))],
+                ..Default::default()
+            },
+        );
+        let jpeg = ExportOptions::new(ImageType::JPEG, &params, &formats);
+        assert_eq!(jpeg.quality, Some(53));
+    }
+
+    fn png(width: u32, height: u32) -> Blob {
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, 128]));
+        let mut data = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageFormat::Png,
+            )
+            .expect("Failed to create PNG");
+        Blob::new(data)
+    }
+
+    #[test]
+    fn test_process_params_reports_output_size() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let params = crate::imagorpath::parse::parse_params("unsafe/40x0/img.png").unwrap();
+        let processed = process_params(params, png(80, 60)).unwrap();
+        assert_eq!(processed.blob.content_type, "image/png");
+        assert_eq!((processed.width, processed.height), (Some(40), Some(30)));
+
+        let params =
+            crate::imagorpath::parse::parse_params("unsafe/filters:palette(2)/img.png").unwrap();
+        let processed = process_params(params, png(8, 8)).unwrap();
+        assert_eq!(processed.blob.content_type, "application/json");
+        assert_eq!(processed.width, None);
+    }
+
+    #[test]
+    fn test_is_noop() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+        let processor = Processor::from_settings(&ProcessorSettings::default());
+        let source = png(80, 60);
+        let params = |path: &str| crate::imagorpath::parse::parse_params(path).unwrap();
+
+        let noop = params("unsafe/80x60/filters:format(png)/img.png");
+        assert!(processor.is_noop(&source, &noop));
+        // what rendering would have served, at the same size and format
+        let processed = process_params(noop, source.clone()).unwrap();
+        assert_eq!(processed.blob.content_type, source.content_type);
+        assert_eq!((processed.width, processed.height), (Some(80), Some(60)));
+
+        assert!(!processor.is_noop(&source, &params("unsafe/40x30/img.png")));
+        assert!(!processor.is_noop(
+            &source,
+            &params("unsafe/80x60/filters:format(webp)/img.png")
+        ));
+        assert!(!processor.is_noop(&source, &params("unsafe/80x60/filters:grayscale()/img.png")));
+        assert!(!processor.is_noop(&rotated_jpeg(), &params("unsafe/80x60/img.jpg")));
+
+        let stripping = Processor::from_settings(&ProcessorSettings {
+            strip_metadata: true,
+            ..Default::default()
+        });
+        assert!(!stripping.is_noop(&source, &params("unsafe/80x60/img.png")));
+    }
+
+    #[test]
+    fn test_sequential_load() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+        let processor = Processor::from_settings(&ProcessorSettings {
+            sequential_min_pixels: Some(4_000),
+            ..Default::default()
+        });
+        let source = png(80, 60);
+        let params = |path: &str| crate::imagorpath::parse::parse_params(path).unwrap();
+        let streams = |params: &Params| {
+            processor.streams(&source, params, &processor.preprocess(&source, params))
+        };
+
+        let flipped = params("unsafe/-40x30/filters:grayscale()/img.png");
+        assert!(streams(&flipped));
+        assert!(!streams(&params("unsafe/40x-30/img.png")));
+        assert!(!streams(&params("unsafe/40x30/filters:rotate(90)/img.png")));
+        // too small to be worth it
+        let small = png(40, 30);
+        assert!(!processor.streams(&small, &flipped, &processor.preprocess(&small, &flipped)));
+
+        let processed = processor
+            .process(&source, &flipped, &Assets::default())
+            .unwrap();
+        let img = VipsImage::new_from_buffer(processed.as_ref(), "").unwrap();
+        assert_eq!((img.get_width(), img.get_height()), (40, 30));
+    }
+
+    #[test]
+    fn test_panicking_filter_is_named() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+        crate::imagorpath::registry::FilterRegistry::global()
+            .register(
+                "test_explode",
+                |args| Ok(args.to_string()),
+                |_, _| todo!("not written yet"),
+            )
+            .unwrap();
+        let params =
+            crate::imagorpath::parse::parse_params("unsafe/filters:test_explode()/img.png")
+                .unwrap();
+
+        let e = Processor::default()
+            .process(&png(80, 60), &params, &Assets::default())
+            .unwrap_err();
+        match e.downcast_ref::<ProcessError>() {
+            Some(ProcessError::FilterPanicked { filter, message }) => {
+                assert_eq!(filter, "test_explode");
+                assert!(message.contains("not written yet"), "{}", message);
+            }
+            _ => panic!("expected a filter panic, got {}", e),
+        }
+    }
+
+    #[test]
+    fn test_fit_in_fill_letterboxes() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        for fill in ["auto", "blur", "white"] {
+            let path = format!("unsafe/fit-in/300x300/filters:fill({})/img.png", fill);
+            let params = crate::imagorpath::parse::parse_params(&path).unwrap();
+            let processed = process_params(params, png(80, 60)).unwrap();
+            assert_eq!((processed.width, processed.height), (Some(300), Some(300)));
+        }
+    }
+
+    /// An 80x60 JPEG tagged with EXIF orientation 6, shown rotated 90° clockwise
+    fn rotated_jpeg() -> Blob {
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(80, 60, |x, y| Rgb([x as u8, y as u8, 128]));
+        let mut jpeg = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut jpeg),
+                image::ImageFormat::Jpeg,
+            )
+            .expect("Failed to create JPEG");
+
+        // APP1 with a big-endian TIFF header and a single orientation entry
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0]);
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        app1.extend_from_slice(&exif);
+        jpeg.splice(2..2, app1);
+
+        Blob::new(jpeg)
+    }
+
+    #[test]
+    fn test_exif_orientation_is_applied_on_every_path() {
+        let _vips_app = VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+        let processor = Processor::default();
+        for path in [
+            "40x0/img.jpg",
+            // a full load instead of thumbnailing
+            "40x0/filters:max_bytes(100000)/img.jpg",
+        ] {
+            let (_, params) = crate::imagorpath::parse::parse_path(path).unwrap();
+            let blob = processor
+                .process(&rotated_jpeg(), &params, &Assets::default())
+                .unwrap();
+            let img = VipsImage::new_from_buffer(&blob.data, "").unwrap();
+            assert_eq!((img.get_width(), img.get_height()), (40, 53), "{}", path);
+            assert!(img.get_orientation() <= 1, "{}", path);
+        }
+
+        // orient() turns the upright image
+        let (_, params) =
+            crate::imagorpath::parse::parse_path("filters:orient(90)/img.jpg").unwrap();
+        let blob = processor
+            .process(&rotated_jpeg(), &params, &Assets::default())
+            .unwrap();
+        let img = VipsImage::new_from_buffer(&blob.data, "").unwrap();
+        assert_eq!((img.get_width(), img.get_height()), (80, 60));
+
+        let processor = Processor::from_settings(&ProcessorSettings {
+            disable_auto_rotate: true,
+            ..Default::default()
+        });
+        let (_, params) = crate::imagorpath::parse::parse_path("40x0/img.jpg").unwrap();
+        let blob = processor
+            .process(&rotated_jpeg(), &params, &Assets::default())
+            .unwrap();
+        let img = VipsImage::new_from_buffer(&blob.data, "").unwrap();
+        assert_eq!((img.get_width(), img.get_height()), (40, 30));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn test_process_params_any_filter_chain(
+            width in 0i32..120,
+            height in 0i32..120,
+            filters in prop::collection::vec(
+                prop_oneof![
+                    Just(Filter::Grayscale),
+                    (0u8..=100).prop_map(Filter::Quality),
+                    (-100i32..=100).prop_map(Filter::Brightness),
+                    (-100i32..=100).prop_map(Filter::Contrast),
+                    prop::sample::select(vec![0, 90, 180, 270]).prop_map(Filter::Rotate),
+                    prop::sample::select(vec![ImageType::JPEG, ImageType::PNG, ImageType::WEBP])
+                        .prop_map(Filter::Format),
+                    Just(Filter::StripMetadata),
+                ],
+                0..5,
+            ),
+        ) {
+            let _vips_app =
+                VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp");
+
+            let params = Params {
+                width: Some(width),
+                height: Some(height),
+                filters,
+                ..Default::default()
+            };
+            let processed = process_params(params, png(64, 48)).unwrap();
+            prop_assert!(processed.width.is_some_and(|w| w > 0));
+        }
+    }
 }
@@ -0,0 +1,698 @@
+use super::assets::Assets;
+use super::budget::{check_dimensions, DecodeBudget, DecodeSize};
+use super::capabilities::{Capabilities, FormatSupport, Limits};
+use super::image::{checkpoint, target_dimensions, ProcessError};
+use super::palette;
+use super::placeholder::{Placeholder, MAX_SIZE};
+use super::processor::{disabled_filters, ImageProcessor, DEFAULT_MAX_DIMENSION};
+use crate::config::{FormatSettings, ProcessorSettings};
+use crate::imagorpath::{
+    color::Color,
+    filter::{Filter, FilterMatcher, ImageType, ResizeKernel},
+    params::{Fit, HAlign, Params, VAlign},
+};
+use crate::storage::storage::Blob;
+use color_eyre::{eyre::eyre, Result};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageReader, Rgba, RgbaImage,
+};
+use std::io::Cursor;
+use std::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// Format name, whether it loads and whether it saves. AVIF is encode only
+/// without the native decoder, the rest need libvips.
+const FORMATS: &[(&str, bool, bool)] = &[
+    ("jpeg", true, true),
+    ("png", true, true),
+    ("webp", true, true),
+    ("gif", true, true),
+    ("tiff", true, true),
+    ("heif", false, false),
+    ("avif", false, true),
+    ("jxl", false, false),
+    ("jp2k", false, false),
+    ("pdf", false, false),
+    ("svg", false, false),
+    ("magick", false, false),
+];
+
+/// An `ImageProcessor` built on the `image` crate alone, for keeping
+/// untrusted images away from libvips' decoders. The build still links
+/// libvips, which the rest of the server needs. It covers crops,
+/// resizing, flips, format conversion and the basic color filters; filters
+/// it can't do are skipped, like a failing filter is with libvips.
+#[derive(Debug, Default)]
+pub struct PureProcessor {
+    disable_filters: RwLock<Vec<FilterMatcher>>,
+    max_filter_ops: usize,
+    max_width: i32,
+    max_height: i32,
+    max_resolution: i32,
+    auto_rotate: bool,
+    /// Results never keep metadata, but sources are only served in their
+    /// place when it isn't asked to be stripped
+    strip_metadata: bool,
+    resize_kernel: Option<ResizeKernel>,
+    formats: FormatSettings,
+}
+
+impl PureProcessor {
+    pub fn from_settings(settings: &ProcessorSettings) -> Self {
+        let max_dimension = |value: i32| {
+            if value > 0 {
+                value
+            } else {
+                DEFAULT_MAX_DIMENSION
+            }
+        };
+
+        PureProcessor {
+            disable_filters: RwLock::new(disabled_filters(settings)),
+            max_filter_ops: settings.max_filter_ops,
+            max_width: max_dimension(settings.max_width),
+            max_height: max_dimension(settings.max_height),
+            max_resolution: settings.max_resolution,
+            auto_rotate: !settings.disable_auto_rotate,
+            strip_metadata: settings.strip_metadata || settings.deterministic,
+            resize_kernel: settings.resize_kernel,
+            formats: settings.formats.clone(),
+        }
+    }
+
+    fn is_disabled(&self, filter: &Filter) -> bool {
+        self.disable_filters
+            .read()
+            .expect("disabled filters lock poisoned")
+            .iter()
+            .any(|matcher| matcher.matches(filter))
+    }
+
+    /// The filters that run, in path order
+    fn filters<'a>(&'a self, params: &'a Params) -> impl Iterator<Item = &'a Filter> {
+        let len = if self.max_filter_ops > 0 {
+            self.max_filter_ops.min(params.filters.len())
+        } else {
+            params.filters.len()
+        };
+        params.filters[..len]
+            .iter()
+            .filter(|filter| !self.is_disabled(filter))
+    }
+
+    fn load(&self, blob: &Blob) -> Result<(DynamicImage, ImageFormat), ProcessError> {
+        let format =
+            image::guess_format(blob.as_ref()).map_err(|_| ProcessError::ImageLoadError)?;
+        let mut decoder = ImageReader::with_format(Cursor::new(blob.as_ref()), format)
+            .into_decoder()
+            .map_err(|_| ProcessError::ImageLoadError)?;
+
+        if let Some(budget) = DecodeBudget::from_max_resolution(self.max_resolution) {
+            budget.check(&decode_size(&decoder))?;
+        }
+
+        let orientation = decoder.orientation().ok();
+        let mut img = DynamicImage::from_decoder(decoder).map_err(|e| {
+            debug!("failed to decode image - {}", e);
+            ProcessError::ImageLoadError
+        })?;
+        if let (true, Some(orientation)) = (self.auto_rotate, orientation) {
+            img.apply_orientation(orientation);
+        }
+
+        Ok((img, format))
+    }
+}
+
+/// Rotate clockwise by a multiple of 90°, anything else is left as is
+fn rotate(img: DynamicImage, angle: i32) -> DynamicImage {
+    match angle.rem_euclid(360) {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// `AxB:CxD` from the path, in pixels or as fractions of the image size
+fn crop(img: DynamicImage, params: &Params) -> DynamicImage {
+    let (Some(left), Some(top), Some(right), Some(bottom)) = (
+        params.crop_left,
+        params.crop_top,
+        params.crop_right,
+        params.crop_bottom,
+    ) else {
+        return img;
+    };
+
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let fractions = [left.0, top.0, right.0, bottom.0]
+        .iter()
+        .all(|v| (0.0..=1.0).contains(v));
+    let (left, top, right, bottom) = if fractions {
+        (
+            left.0 * width,
+            top.0 * height,
+            right.0 * width,
+            bottom.0 * height,
+        )
+    } else {
+        (left.0, top.0, right.0, bottom.0)
+    };
+
+    let left = left.clamp(0.0, width) as u32;
+    let top = top.clamp(0.0, height) as u32;
+    let right = right.clamp(0.0, width) as u32;
+    let bottom = bottom.clamp(0.0, height) as u32;
+    if right <= left || bottom <= top {
+        return img;
+    }
+    img.crop_imm(left, top, right - left, bottom - top)
+}
+
+/// The nearest the `image` crate has, which has no Mitchell or Lanczos2
+fn filter_type(kernel: Option<ResizeKernel>) -> FilterType {
+    match kernel {
+        Some(ResizeKernel::Nearest) => FilterType::Nearest,
+        Some(ResizeKernel::Linear) => FilterType::Triangle,
+        Some(ResizeKernel::Cubic | ResizeKernel::Mitchell) => FilterType::CatmullRom,
+        Some(ResizeKernel::Lanczos2 | ResizeKernel::Lanczos3) | None => FilterType::Lanczos3,
+    }
+}
+
+fn resize(img: DynamicImage, params: &Params, upscale: bool, kernel: FilterType) -> DynamicImage {
+    // a zero dimension follows the aspect ratio, like an unset one
+    let params = Params {
+        width: params.width.filter(|w| *w > 0),
+        height: params.height.filter(|h| *h > 0),
+        ..params.clone()
+    };
+    let (img_w, img_h) = (img.width() as i32, img.height() as i32);
+    let (width, height) = target_dimensions(img_w, img_h, &params, upscale);
+    if width <= 0 || height <= 0 || (width, height) == (img_w, img_h) {
+        return img;
+    }
+    let (width, height) = (width as u32, height as u32);
+
+    match params.fit {
+        Some(Fit::FitIn) if upscale || width < img.width() || height < img.height() => {
+            img.resize(width, height, kernel)
+        }
+        Some(Fit::FitIn) => img,
+        Some(Fit::Stretch) => img.resize_exact(width, height, kernel),
+        None if params.width.is_some() && params.height.is_some() => {
+            // cover the box, then crop the overflow by the alignment
+            let scale = (width as f64 / img_w as f64).max(height as f64 / img_h as f64);
+            let scaled_w = ((img_w as f64 * scale).ceil() as u32).max(width);
+            let scaled_h = ((img_h as f64 * scale).ceil() as u32).max(height);
+            let scaled = img.resize_exact(scaled_w, scaled_h, kernel);
+
+            let left = match params.h_align {
+                Some(HAlign::Left) if !params.smart => 0,
+                Some(HAlign::Right) if !params.smart => scaled_w - width,
+                _ => (scaled_w - width) / 2,
+            };
+            let top = match params.v_align {
+                Some(VAlign::Top) if !params.smart => 0,
+                Some(VAlign::Bottom) if !params.smart => scaled_h - height,
+                _ => (scaled_h - height) / 2,
+            };
+            scaled.crop_imm(left, top, width, height)
+        }
+        None => img.resize_exact(width, height, kernel),
+    }
+}
+
+/// The average of the corner pixels, see `Color::to_rgb`
+fn corner_color(img: &DynamicImage) -> Rgba<u8> {
+    let (w, h) = (img.width() - 1, img.height() - 1);
+    let corners = [(0, 0), (w, 0), (0, h), (w, h)].map(|(x, y)| img.get_pixel(x, y));
+
+    let mut sum = [0u32; 3];
+    for px in corners {
+        sum.iter_mut().zip(px.0).for_each(|(s, c)| *s += c as u32);
+    }
+    let [r, g, b] = sum.map(|s| (s as f64 / 4.0).round() as u8);
+    Rgba([r, g, b, 255])
+}
+
+/// Center `img` in a `width` by `height` box, moved by the padding, with the
+/// rest filled by `color`
+fn fill(
+    img: DynamicImage,
+    (width, height): (u32, u32),
+    padding: (i32, i32, i32, i32),
+    color: &Color,
+) -> DynamicImage {
+    let (p_left, p_top, p_right, p_bottom) = padding;
+    let total_width = (width as i32 + p_left + p_right).max(1) as u32;
+    let total_height = (height as i32 + p_top + p_bottom).max(1) as u32;
+    let left = (width as i64 - img.width() as i64) / 2 + p_left as i64;
+    let top = (height as i64 - img.height() as i64) / 2 + p_top as i64;
+
+    let mut canvas = match color {
+        Color::Blur => img
+            .resize_exact(total_width, total_height, FilterType::Triangle)
+            .blur(50.0)
+            .to_rgba8(),
+        Color::None => RgbaImage::new(total_width, total_height),
+        Color::Auto => RgbaImage::from_pixel(total_width, total_height, corner_color(&img)),
+        color => {
+            let (r, g, b) = color.fixed_rgb().unwrap_or((255, 255, 255));
+            RgbaImage::from_pixel(total_width, total_height, Rgba([r, g, b, 255]))
+        }
+    };
+    imageops::overlay(&mut canvas, &img.to_rgba8(), left, top);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Composite transparent areas over `color`
+fn flatten(img: DynamicImage, color: &Color) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let (r, g, b) = match color {
+        Color::Auto => {
+            let Rgba([r, g, b, _]) = corner_color(&img);
+            (r, g, b)
+        }
+        color => match color.fixed_rgb() {
+            Some(rgb) => rgb,
+            None => return img,
+        },
+    };
+
+    let mut canvas = RgbaImage::from_pixel(img.width(), img.height(), Rgba([r, g, b, 255]));
+    imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+}
+
+fn apply(img: DynamicImage, filter: &Filter, params: &Params) -> DynamicImage {
+    match filter {
+        Filter::Grayscale => img.grayscale(),
+        // -100 to 100 percent
+        Filter::Brightness(amount) => img.brighten(amount * 255 / 100),
+        Filter::Contrast(amount) => img.adjust_contrast(*amount as f32),
+        Filter::Hue(degrees) => img.huerotate(degrees.0 as i32),
+        Filter::Blur(sigma, None) if sigma.0 > 0.0 => img.blur(sigma.0),
+        Filter::Sharpen(amount) if amount.0 > 0.0 => img.unsharpen(1.0 + amount.0 * 2.0, 0),
+        Filter::Blur(_, None) | Filter::Sharpen(_) => img,
+        Filter::Rotate(angle) => rotate(img, *angle),
+        Filter::Proportion(scale) if scale.0 > 0.0 => {
+            let width = (img.width() as f32 * scale.0).round().max(1.0) as u32;
+            let height = (img.height() as f32 * scale.0).round().max(1.0) as u32;
+            img.resize_exact(width, height, FilterType::Lanczos3)
+        }
+        Filter::BackgroundColor(color) => flatten(img, color),
+        Filter::Fill(color) => {
+            let size = match (params.fit, params.width, params.height) {
+                (Some(Fit::FitIn), Some(w), Some(h)) if w > 0 && h > 0 && !params.full_fit_in => {
+                    let (w, h) =
+                        target_dimensions(img.width() as i32, img.height() as i32, params, true);
+                    (img.width().max(w as u32), img.height().max(h as u32))
+                }
+                _ => (img.width(), img.height()),
+            };
+            fill(img, size, padding(params), color)
+        }
+        // read before or after the filters run
+        Filter::Format(_)
+        | Filter::Quality(_)
+        | Filter::Upscale
+        | Filter::NoUpscale
+        | Filter::Orient(_)
+        | Filter::Kernel(_)
+        | Filter::Palette(_)
+        | Filter::Blurhash(_)
+        | Filter::Thumbhash(_)
+        | Filter::StripExif
+        | Filter::StripIcc
+        | Filter::StripMetadata
+        | Filter::Var(_, _) => img,
+        _ => {
+            debug!("filter |{}| isn't supported by the pure backend", filter);
+            img
+        }
+    }
+}
+
+fn padding(params: &Params) -> (i32, i32, i32, i32) {
+    (
+        params.padding_left.unwrap_or(0),
+        params.padding_top.unwrap_or(0),
+        params.padding_right.unwrap_or(0),
+        params.padding_bottom.unwrap_or(0),
+    )
+}
+
+fn source_type(format: ImageFormat) -> ImageType {
+    match format {
+        ImageFormat::Png => ImageType::PNG,
+        ImageFormat::Gif => ImageType::GIF,
+        ImageFormat::WebP => ImageType::WEBP,
+        ImageFormat::Tiff => ImageType::TIFF,
+        ImageFormat::Bmp => ImageType::BMP,
+        _ => ImageType::JPEG,
+    }
+}
+
+impl PureProcessor {
+    fn encode(&self, img: &DynamicImage, format: ImageType, quality: Option<u8>) -> Result<Blob> {
+        let mut data = Vec::new();
+        let mut out = Cursor::new(&mut data);
+
+        match format {
+            ImageType::JPEG => {
+                let quality = quality.unwrap_or(self.formats.jpeg.quality.clamp(1, 100) as u8);
+                // no alpha in JPEG
+                DynamicImage::ImageRgb8(img.to_rgb8())
+                    .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?;
+            }
+            ImageType::AVIF => {
+                let quality = quality.unwrap_or(self.formats.avif.quality.clamp(1, 100) as u8);
+                let speed = self.formats.avif.speed.clamp(1, 10) as u8;
+                img.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                    &mut out, speed, quality,
+                ))?;
+            }
+            // WebP is always lossless with the `image` encoder
+            ImageType::PNG => img.write_to(&mut out, ImageFormat::Png)?,
+            ImageType::WEBP => img.write_to(&mut out, ImageFormat::WebP)?,
+            ImageType::GIF => img.write_to(&mut out, ImageFormat::Gif)?,
+            ImageType::TIFF => img.write_to(&mut out, ImageFormat::Tiff)?,
+            ImageType::BMP => img.write_to(&mut out, ImageFormat::Bmp)?,
+            format => {
+                return Err(eyre!(
+                    "{} output isn't supported by the pure backend",
+                    format
+                ))
+            }
+        }
+
+        Ok(Blob {
+            data,
+            content_type: format.into(),
+            ..Default::default()
+        })
+    }
+}
+
+/// What decoding the first frame takes, from the header alone
+fn decode_size(decoder: &impl ImageDecoder) -> DecodeSize {
+    let (width, height) = decoder.dimensions();
+    let color = decoder.color_type();
+    let bands = color.channel_count() as u64;
+    DecodeSize {
+        width: width as u64,
+        page_height: height as u64,
+        pages: 1,
+        bands,
+        bytes_per_band: color.bytes_per_pixel() as u64 / bands.max(1),
+    }
+}
+
+impl ImageProcessor for PureProcessor {
+    fn startup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn check_raw(&self, blob: &Blob) -> Result<(), ProcessError> {
+        let Ok(decoder) = image::guess_format(blob.as_ref()).and_then(|format| {
+            ImageReader::with_format(Cursor::new(blob.as_ref()), format).into_decoder()
+        }) else {
+            return Ok(());
+        };
+
+        let size = decode_size(&decoder);
+        check_dimensions(
+            size.width,
+            size.page_height,
+            self.max_width,
+            self.max_height,
+        )?;
+        match DecodeBudget::from_max_resolution(self.max_resolution) {
+            Some(budget) => budget.check(&size),
+            None => Ok(()),
+        }
+    }
+
+    fn strips_metadata(&self) -> bool {
+        self.strip_metadata
+    }
+
+    fn decoded_size(&self, blob: &Blob, _params: &Params) -> Option<u64> {
+        let decoder = image::guess_format(blob.as_ref())
+            .and_then(|format| {
+                ImageReader::with_format(Cursor::new(blob.as_ref()), format).into_decoder()
+            })
+            .ok()?;
+        Some(decode_size(&decoder).bytes())
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reload(&self, settings: &ProcessorSettings) -> Result<()> {
+        *self
+            .disable_filters
+            .write()
+            .expect("disabled filters lock poisoned") = disabled_filters(settings);
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            libvips: String::from("none"),
+            formats: FORMATS
+                .iter()
+                .map(|(name, load, save)| {
+                    let support = FormatSupport {
+                        load: *load,
+                        save: *save,
+                    };
+                    (*name, support)
+                })
+                .collect(),
+            video: false,
+            superres: false,
+            limits: Limits {
+                max_width: self.max_width,
+                max_height: self.max_height,
+                max_resolution: self.max_resolution,
+                max_animation_frames: 1,
+                max_filter_ops: self.max_filter_ops,
+                concurrency: 0,
+            },
+        }
+    }
+
+    fn process(&self, blob: &Blob, params: &Params, assets: &Assets) -> Result<Blob> {
+        self.process_cancellable(blob, params, assets, &CancellationToken::new())
+    }
+
+    #[tracing::instrument(skip(self, blob, _assets, cancel))]
+    fn process_cancellable(
+        &self,
+        blob: &Blob,
+        params: &Params,
+        _assets: &Assets,
+        cancel: &CancellationToken,
+    ) -> Result<Blob> {
+        let scaled = params.scaled_by_dpr();
+        let params = scaled.as_ref().unwrap_or(params);
+        checkpoint(cancel)?;
+        let (img, source_format) = self.load(blob)?;
+        checkpoint(cancel)?;
+
+        let mut upscale = params.fit != Some(Fit::FitIn);
+        let mut format = None;
+        let mut quality = None;
+        let mut orient = 0;
+        let mut kernel = self.resize_kernel;
+        for filter in self.filters(params) {
+            match filter {
+                Filter::Upscale => upscale = true,
+                Filter::NoUpscale => upscale = false,
+                Filter::Format(f) => format = Some(*f),
+                Filter::Quality(q) => quality = Some(*q),
+                Filter::Orient(angle) => orient = *angle,
+                Filter::Kernel(k) => kernel = Some(*k),
+                _ => {}
+            }
+        }
+
+        let img = crop(img, params);
+        let img = rotate(img, orient);
+        let img = resize(img, params, upscale, filter_type(kernel));
+        let img = if params.h_flip { img.fliph() } else { img };
+        let img = if params.v_flip { img.flipv() } else { img };
+        checkpoint(cancel)?;
+
+        let img = self
+            .filters(params)
+            .fold(img, |img, filter| apply(img, filter, params));
+        checkpoint(cancel)?;
+        let has_fill = params.filters.iter().any(|f| matches!(f, Filter::Fill(_)));
+        let img = if padding(params) != (0, 0, 0, 0) && !has_fill {
+            let size = (img.width(), img.height());
+            fill(img, size, padding(params), &Color::Rgb(255, 255, 255))
+        } else {
+            img
+        };
+
+        // data about the result instead of the image, the last one wins
+        let data = self
+            .filters(params)
+            .filter(|filter| {
+                matches!(
+                    filter,
+                    Filter::Palette(_) | Filter::Blurhash(_) | Filter::Thumbhash(_)
+                )
+            })
+            .last();
+        if let Some(filter) = data {
+            let small = img.thumbnail(MAX_SIZE as u32, MAX_SIZE as u32).to_rgba8();
+            let data = match (filter, Placeholder::from_filter(filter)) {
+                (Filter::Palette(n), _) => palette::render_pixels(small.as_raw(), *n),
+                (_, Some((placeholder, output))) => placeholder.render_pixels(
+                    (img.width() as i32, img.height() as i32),
+                    small.width(),
+                    small.height(),
+                    small.as_raw(),
+                    output,
+                ),
+                _ => unreachable!("only data filters are picked"),
+            }?;
+            return Ok(data.with_metadata(blob.metadata.clone()));
+        }
+
+        let format = format.unwrap_or(source_type(source_format));
+        Ok(self
+            .encode(&img, format, quality)?
+            .with_metadata(blob.metadata.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_params;
+
+    fn png(width: u32, height: u32) -> Blob {
+        let img = RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 128, 255]));
+        let mut data = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        Blob::new(data)
+    }
+
+    fn process(path: &str, blob: &Blob) -> (Blob, Option<(u32, u32)>) {
+        let processor = PureProcessor::from_settings(&ProcessorSettings::default());
+        let params = parse_params(path).unwrap();
+        let blob = processor
+            .process(blob, &params, &Assets::default())
+            .unwrap();
+        let size = image::load_from_memory(&blob.data)
+            .ok()
+            .map(|img| img.dimensions());
+        (blob, size)
+    }
+
+    #[test]
+    fn test_resize() {
+        let source = png(80, 60);
+
+        for (path, size) in [
+            ("unsafe/40x40/img.png", (40, 40)),
+            ("unsafe/40x0/img.png", (40, 30)),
+            ("unsafe/fit-in/40x40/img.png", (40, 30)),
+            ("unsafe/fit-in/200x200/img.png", (80, 60)),
+            (
+                "unsafe/fit-in/200x200/filters:upscale()/img.png",
+                (200, 150),
+            ),
+            ("unsafe/stretch/50x50/img.png", (50, 50)),
+            ("unsafe/160x120/filters:kernel(nearest)/img.png", (160, 120)),
+            ("unsafe/0.25x0:0.75x1/img.png", (40, 60)),
+            (
+                "unsafe/fit-in/100x100/filters:fill(white)/img.png",
+                (100, 100),
+            ),
+            ("unsafe/filters:rotate(90)/img.png", (60, 80)),
+        ] {
+            let (blob, actual) = process(path, &source);
+            assert_eq!(blob.content_type, "image/png", "{}", path);
+            assert_eq!(actual, Some(size), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_format_conversion() {
+        let (blob, size) = process(
+            "unsafe/20x0/filters:grayscale():quality(60):format(jpeg)/img.png",
+            &png(40, 40),
+        );
+        assert_eq!(blob.content_type, "image/jpeg");
+        assert_eq!(size, Some((20, 20)));
+        assert_eq!(image::guess_format(&blob.data).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_data_filters() {
+        let (blob, _) = process("unsafe/filters:palette(2)/img.png", &png(8, 8));
+        assert_eq!(blob.content_type, "application/json");
+        assert!(serde_json::from_slice::<serde_json::Value>(&blob.data).is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_output_format() {
+        let processor = PureProcessor::from_settings(&ProcessorSettings::default());
+        let params = parse_params("unsafe/filters:format(heif)/img.png").unwrap();
+        assert!(processor
+            .process(&png(8, 8), &params, &Assets::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let processor = PureProcessor::from_settings(&ProcessorSettings::default());
+        let params = parse_params("unsafe/20x20/img.png").unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let err = processor
+            .process_cancellable(&png(40, 40), &params, &Assets::default(), &cancel)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProcessError>(),
+            Some(ProcessError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_check_raw() {
+        let settings = ProcessorSettings {
+            max_width: 100,
+            max_height: 100,
+            ..Default::default()
+        };
+        let processor = PureProcessor::from_settings(&settings);
+        assert!(processor.check_raw(&png(100, 50)).is_ok());
+        assert!(matches!(
+            processor.check_raw(&png(101, 50)),
+            Err(ProcessError::DecodeBudgetExceeded(_))
+        ));
+        // not an image as far as the backend can tell
+        assert!(processor
+            .check_raw(&Blob::new(b"%PDF-1.7".to_vec()))
+            .is_ok());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::config::SuperresSettings;
+use color_eyre::{eyre::eyre, Result};
+use libvips::{
+    ops::{self, BandFormat, ExtractBandOptions, ResizeOptions},
+    VipsImage,
+};
+
+/// Context each tile is cut with on every side and trimmed off again after
+/// upscaling, so tiles meet without seams
+#[cfg(feature = "superres")]
+const TILE_OVERLAP: i32 = 8;
+
+/// Where a tile sits in the output grid, in source pixels, without its
+/// overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub left: i32,
+    pub top: i32,
+}
+
+/// Tiles covering a `width` by `height` image, row by row. Edge tiles run
+/// past it and are cut from a copy padded with its edge pixels.
+pub fn tiles(width: i32, height: i32, size: i32) -> Vec<Tile> {
+    let (columns, rows) = (
+        (width + size - 1) / size.max(1),
+        (height + size - 1) / size.max(1),
+    );
+    (0..rows)
+        .flat_map(|row| {
+            (0..columns).map(move |column| Tile {
+                left: column * size,
+                top: row * size,
+            })
+        })
+        .collect()
+}
+
+/// Fails when enlarging a `width` by `height` image `scale` times would
+/// come out bigger than `max_pixels`
+pub fn check_output(width: i32, height: i32, scale: u32, max_pixels: u64) -> Result<()> {
+    let pixels = width as u64 * height as u64 * scale as u64 * scale as u64;
+    if max_pixels > 0 && pixels > max_pixels {
+        return Err(eyre!(
+            "superres output would be {} pixels, above the cap of {}",
+            pixels,
+            max_pixels
+        ));
+    }
+    Ok(())
+}
+
+/// A super-resolution model, run over the image in batches of tiles
+pub struct Upscaler {
+    #[cfg(feature = "superres")]
+    session: ort::session::Session,
+    /// How many times the model enlarges by
+    scale: u32,
+    tile_size: i32,
+    batch_size: usize,
+    max_pixels: u64,
+}
+
+impl std::fmt::Debug for Upscaler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Upscaler")
+            .field("scale", &self.scale)
+            .field("tile_size", &self.tile_size)
+            .field("batch_size", &self.batch_size)
+            .field("max_pixels", &self.max_pixels)
+            .finish()
+    }
+}
+
+impl Upscaler {
+    /// The configured model, `None` when there isn't one
+    #[cfg(feature = "superres")]
+    pub fn load(settings: &SuperresSettings) -> Result<Option<Self>> {
+        use ort::session::{builder::GraphOptimizationLevel, Session};
+
+        let Some(model_path) = &settings.model_path else {
+            return Ok(None);
+        };
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .commit_from_file(model_path)?;
+
+        Ok(Some(Upscaler {
+            session,
+            scale: settings.model_scale.max(1),
+            tile_size: settings.tile_size.max(16) as i32,
+            batch_size: settings.batch_size.max(1),
+            max_pixels: settings.max_output_pixels,
+        }))
+    }
+
+    #[cfg(not(feature = "superres"))]
+    pub fn load(settings: &SuperresSettings) -> Result<Option<Self>> {
+        match settings.model_path {
+            Some(_) => Err(eyre!(
+                "a superres model is configured, build with the `superres` feature to use it"
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Enlarge `scale` times, with the model up to its own scale and libvips
+    /// for whatever is left over either way. Alpha is resized with libvips.
+    pub fn upscale(&self, img: &VipsImage, scale: u32) -> Result<VipsImage> {
+        let (width, height) = (img.get_width(), img.get_height());
+        check_output(width, height, scale, self.max_pixels)?;
+
+        let img = ops::cast(
+            &ops::colourspace(img, ops::Interpretation::Srgb)?,
+            BandFormat::Uchar,
+        )?;
+        let (rgb, alpha) = if img.image_hasalpha() {
+            (
+                ops::extract_band_with_opts(&img, 0, &ExtractBandOptions { n: 3 })?,
+                Some(ops::extract_band(&img, 3)?),
+            )
+        } else {
+            (img, None)
+        };
+
+        let enlarged = self.run(&rgb)?;
+        let resize = |img: &VipsImage| -> Result<VipsImage> {
+            // exact pixel counts, whatever rounding the scales give
+            let (w, h) = (width * scale as i32, height * scale as i32);
+            let resized = ops::resize_with_opts(
+                img,
+                w as f64 / img.get_width() as f64,
+                &ResizeOptions {
+                    vscale: h as f64 / img.get_height() as f64,
+                    ..Default::default()
+                },
+            )?;
+            Ok(ops::extract_area(&resized, 0, 0, w, h)?)
+        };
+        let rgb = if scale == self.scale {
+            enlarged
+        } else {
+            resize(&enlarged)?
+        };
+
+        match alpha {
+            Some(alpha) => Ok(ops::bandjoin(&mut [rgb, resize(&alpha)?])?),
+            None => Ok(rgb),
+        }
+    }
+
+    /// The model's own enlargement of a 3 band uchar image
+    #[cfg(feature = "superres")]
+    fn run(&self, rgb: &VipsImage) -> Result<VipsImage> {
+        use libvips::ops::EmbedOptions;
+        use ort::value::Tensor;
+
+        let (width, height) = (rgb.get_width(), rgb.get_height());
+        let (size, scale) = (self.tile_size, self.scale as i32);
+        let window = size + TILE_OVERLAP * 2;
+        let tiles = tiles(width, height, size);
+
+        // every tile, overlap included, comes from inside this
+        let padded_width = (width + size - 1) / size * size + TILE_OVERLAP * 2;
+        let padded_height = (height + size - 1) / size * size + TILE_OVERLAP * 2;
+        let padded = ops::embed_with_opts(
+            rgb,
+            TILE_OVERLAP,
+            TILE_OVERLAP,
+            padded_width,
+            padded_height,
+            &EmbedOptions {
+                extend: ops::Extend::Copy,
+                ..Default::default()
+            },
+        )?;
+        let source = padded.image_write_to_memory();
+
+        let out_width = padded_width * scale;
+        let out_height = padded_height * scale;
+        let mut output = vec![0u8; (out_width * out_height * 3) as usize];
+        let plane = (window * window) as usize;
+        let out_window = window * scale;
+
+        for batch in tiles.chunks(self.batch_size) {
+            // NCHW, 0 to 1
+            let mut input = vec![0f32; batch.len() * 3 * plane];
+            for (n, tile) in batch.iter().enumerate() {
+                for y in 0..window {
+                    for x in 0..window {
+                        let src = (((tile.top + y) * padded_width + tile.left + x) * 3) as usize;
+                        let dst = (y * window + x) as usize;
+                        for c in 0..3 {
+                            input[(n * 3 + c) * plane + dst] = source[src + c] as f32 / 255.0;
+                        }
+                    }
+                }
+            }
+
+            let shape = [batch.len(), 3, window as usize, window as usize];
+            let outputs = self
+                .session
+                .run(ort::inputs![Tensor::from_array((shape, input))?]?)?;
+            let (out_shape, data) = outputs[0].try_extract_raw_tensor::<f32>()?;
+            if out_shape != [batch.len() as i64, 3, out_window as i64, out_window as i64] {
+                return Err(eyre!(
+                    "superres model gave {:?}, expected {}x enlargements of the tiles",
+                    out_shape,
+                    scale
+                ));
+            }
+
+            // keep the middle of each tile, its overlap trimmed off
+            let out_plane = (out_window * out_window) as usize;
+            let trim = TILE_OVERLAP * scale;
+            for (n, tile) in batch.iter().enumerate() {
+                for y in trim..trim + size * scale {
+                    for x in trim..trim + size * scale {
+                        let src = (y * out_window + x) as usize;
+                        let (ox, oy) = (tile.left * scale + x, tile.top * scale + y);
+                        let dst = ((oy * out_width + ox) * 3) as usize;
+                        for c in 0..3 {
+                            let value = data[(n * 3 + c) * out_plane + src];
+                            output[dst + c] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+                        }
+                    }
+                }
+            }
+        }
+
+        // copied, the pipeline reads it after this buffer is gone
+        let enlarged = VipsImage::image_copy_memory(VipsImage::new_from_memory(
+            &output,
+            out_width,
+            out_height,
+            3,
+            BandFormat::Uchar,
+        )?)?;
+        let trim = TILE_OVERLAP * scale;
+        Ok(ops::extract_area(
+            &enlarged,
+            trim,
+            trim,
+            width * scale,
+            height * scale,
+        )?)
+    }
+
+    #[cfg(not(feature = "superres"))]
+    fn run(&self, _rgb: &VipsImage) -> Result<VipsImage> {
+        unreachable!("an upscaler is only loaded with the superres feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles() {
+        assert_eq!(
+            tiles(300, 100, 128),
+            vec![
+                Tile { left: 0, top: 0 },
+                Tile { left: 128, top: 0 },
+                Tile { left: 256, top: 0 },
+            ]
+        );
+        assert_eq!(tiles(128, 129, 128).len(), 2);
+        assert!(tiles(0, 100, 128).is_empty());
+    }
+
+    #[test]
+    fn test_check_output() {
+        assert!(check_output(500, 400, 4, 16_000_000).is_ok());
+        assert!(check_output(1200, 1000, 4, 16_000_000).is_err());
+        // 0 is no cap
+        assert!(check_output(1200, 1000, 4, 0).is_ok());
+    }
+}
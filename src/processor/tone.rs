@@ -0,0 +1,184 @@
+use crate::imagorpath::filter::Curve;
+
+/// A 256 entry lookup table, one output level per input level
+pub type Table = [f64; 256];
+
+pub fn identity_table() -> Table {
+    std::array::from_fn(|i| i as f64)
+}
+
+/// `out = 255 * (in / 255) ^ (1 / gamma)`
+pub fn gamma_table(gamma: f64) -> Table {
+    std::array::from_fn(|i| 255.0 * (i as f64 / 255.0).powf(1.0 / gamma))
+}
+
+/// How much to scale each chroma level, 0 to 255, by. Muted colors get the
+/// full amount, tapering off to none at a chroma of 100 and above, so skin
+/// and already vivid colors aren't pushed over.
+pub fn vibrance_table(amount: i32) -> Table {
+    let amount = amount.clamp(-100, 100) as f64 / 100.0;
+    std::array::from_fn(|chroma| 1.0 + amount * (1.0 - (chroma as f64 / 100.0).min(1.0)))
+}
+
+/// A monotone cubic (Fritsch–Carlson) through the curve's points, so it
+/// never overshoots between them. The ends are pinned to 0 and 255 unless a
+/// point sets them, and when two points share an input the last one wins.
+pub fn curve_table(curve: &Curve) -> Table {
+    let mut points: Vec<(f64, f64)> = Vec::with_capacity(curve.points.len() + 2);
+    for &(x, y) in &curve.points {
+        let (x, y) = (x as f64, y as f64);
+        match points.iter_mut().find(|(px, _)| *px == x) {
+            Some(point) => point.1 = y,
+            None => points.push((x, y)),
+        }
+    }
+    if !points.iter().any(|(x, _)| *x == 0.0) {
+        points.push((0.0, 0.0));
+    }
+    if !points.iter().any(|(x, _)| *x == 255.0) {
+        points.push((255.0, 255.0));
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let n = points.len();
+    let secants: Vec<f64> = points
+        .windows(2)
+        .map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0))
+        .collect();
+    let mut tangents = vec![0.0; n];
+    tangents[0] = secants[0];
+    tangents[n - 1] = secants[n - 2];
+    for i in 1..n - 1 {
+        tangents[i] = if secants[i - 1] * secants[i] <= 0.0 {
+            0.0
+        } else {
+            (secants[i - 1] + secants[i]) / 2.0
+        };
+    }
+    for (i, secant) in secants.iter().enumerate() {
+        if *secant == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / secant;
+        let b = tangents[i + 1] / secant;
+        let h = a * a + b * b;
+        if h > 9.0 {
+            let t = 3.0 / h.sqrt();
+            tangents[i] = t * a * secant;
+            tangents[i + 1] = t * b * secant;
+        }
+    }
+
+    std::array::from_fn(|i| {
+        let x = i as f64;
+        let k = points.windows(2).position(|w| x <= w[1].0).unwrap_or(n - 2);
+        let ((x0, y0), (x1, y1)) = (points[k], points[k + 1]);
+        let dx = x1 - x0;
+        let t = (x - x0) / dx;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * dx * tangents[k]
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * dx * tangents[k + 1];
+        y.clamp(0.0, 255.0)
+    })
+}
+
+/// One table per RGB channel with the curves applied in order, those
+/// without a channel going through all three
+pub fn curves_tables(curves: &[Curve]) -> [Table; 3] {
+    let mut tables = [identity_table(); 3];
+    for curve in curves {
+        let table = curve_table(curve);
+        let bands = match curve.channel {
+            Some(channel) => channel.band()..channel.band() + 1,
+            None => 0..3,
+        };
+        for band in bands {
+            let current = tables[band];
+            tables[band] = current.map(|level| table[level.round() as usize]);
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::filter::Channel;
+
+    fn curve(channel: Option<Channel>, points: &[(u8, u8)]) -> Curve {
+        Curve {
+            channel,
+            points: points.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_gamma_table() {
+        let table = gamma_table(1.0);
+        for (i, level) in table.iter().enumerate() {
+            assert!((level - i as f64).abs() < 1e-9);
+        }
+
+        let table = gamma_table(2.2);
+        assert_eq!(table[0], 0.0);
+        assert_eq!(table[255], 255.0);
+        assert!(table[128] > 128.0);
+        assert!(gamma_table(0.5)[128] < 128.0);
+    }
+
+    #[test]
+    fn test_vibrance_table() {
+        assert_eq!(vibrance_table(0), [1.0; 256]);
+
+        let table = vibrance_table(50);
+        assert_eq!(table[0], 1.5);
+        assert!(table[20] > table[60]);
+        assert_eq!(table[100], 1.0);
+        assert_eq!(table[200], 1.0);
+
+        // all the way down is grey for muted colors
+        assert_eq!(vibrance_table(-300)[0], 0.0);
+    }
+
+    #[test]
+    fn test_curve_table() {
+        // no points besides the ends is a straight line
+        let table = curve_table(&curve(None, &[(0, 0), (255, 255)]));
+        for (i, level) in table.iter().enumerate() {
+            assert!((level - i as f64).abs() < 1e-9);
+        }
+
+        // passes through its points, and the implied ends
+        let table = curve_table(&curve(None, &[(64, 32), (192, 224)]));
+        assert_eq!(table[0], 0.0);
+        assert_eq!(table[64], 32.0);
+        assert_eq!(table[192], 224.0);
+        assert_eq!(table[255], 255.0);
+        assert!(table.windows(2).all(|w| w[0] <= w[1]));
+
+        // the last of two points on one input wins
+        let table = curve_table(&curve(None, &[(128, 10), (128, 200)]));
+        assert_eq!(table[128], 200.0);
+
+        // an inverted curve
+        let table = curve_table(&curve(None, &[(0, 255), (255, 0)]));
+        assert_eq!(table[0], 255.0);
+        assert_eq!(table[255], 0.0);
+    }
+
+    #[test]
+    fn test_curves_tables() {
+        let tables = curves_tables(&[
+            curve(None, &[(0, 20)]),
+            curve(Some(Channel::B), &[(255, 100)]),
+        ]);
+        assert_eq!(tables[0][0], 20.0);
+        assert_eq!(tables[1][0], 20.0);
+        assert_eq!(tables[2][255], 100.0);
+        assert_eq!(tables[0][255], 255.0);
+    }
+}
@@ -0,0 +1,86 @@
+/// The projective transform taking each of `from` to the matching `to`
+/// point, as the first eight entries of its 3x3 matrix, the ninth being 1.
+/// `None` when the points are in a line.
+pub fn homography(from: [(f64, f64); 4], to: [(f64, f64); 4]) -> Option<[f64; 8]> {
+    // u = (h0 x + h1 y + h2) / (h6 x + h7 y + 1), and the same for v with
+    // h3, h4 and h5, made linear by multiplying out the denominator
+    let mut rows = [[0.0; 9]; 8];
+    for (i, ((x, y), (u, v))) in from.into_iter().zip(to).enumerate() {
+        rows[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+        rows[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|a, b| rows[*a][col].abs().total_cmp(&rows[*b][col].abs()))?;
+        if rows[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        rows.swap(col, pivot);
+        for row in 0..8 {
+            if row != col {
+                let pivot_row = rows[col];
+                let factor = rows[row][col] / pivot_row[col];
+                for (k, value) in rows[row].iter_mut().enumerate().skip(col) {
+                    *value -= factor * pivot_row[k];
+                }
+            }
+        }
+    }
+    Some(std::array::from_fn(|i| rows[i][8] / rows[i][i]))
+}
+
+/// Width and height of the rectangle a quad straightens out to, its longer
+/// edge each way so nothing is squeezed. The points go clockwise from the
+/// top left.
+pub fn quad_size(points: [(f64, f64); 4]) -> (i32, i32) {
+    let length = |(x1, y1): (f64, f64), (x2, y2): (f64, f64)| (x2 - x1).hypot(y2 - y1);
+    let [top_left, top_right, bottom_right, bottom_left] = points;
+    let width = length(top_left, top_right).max(length(bottom_left, bottom_right));
+    let height = length(top_left, bottom_left).max(length(top_right, bottom_right));
+    (width.round() as i32, height.round() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(h: &[f64; 8], (x, y): (f64, f64)) -> (f64, f64) {
+        let w = h[6] * x + h[7] * y + 1.0;
+        (
+            (h[0] * x + h[1] * y + h[2]) / w,
+            (h[3] * x + h[4] * y + h[5]) / w,
+        )
+    }
+
+    #[test]
+    fn test_homography() {
+        let square = [(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)];
+
+        let h = homography(square, square).unwrap();
+        let identity = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+        assert!(h.iter().zip(identity).all(|(a, b)| (a - b).abs() < 1e-9));
+
+        let quad = [(12.0, 30.0), (180.0, 8.0), (210.0, 160.0), (4.0, 140.0)];
+        let h = homography(square, quad).unwrap();
+        for (from, to) in square.into_iter().zip(quad) {
+            let (u, v) = apply(&h, from);
+            assert!((u - to.0).abs() < 1e-6 && (v - to.1).abs() < 1e-6);
+        }
+
+        let line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)];
+        assert_eq!(homography(line, square), None);
+    }
+
+    #[test]
+    fn test_quad_size() {
+        assert_eq!(
+            quad_size([(0.0, 0.0), (40.0, 0.0), (40.0, 30.0), (0.0, 30.0)]),
+            (40, 30)
+        );
+        assert_eq!(
+            quad_size([(10.0, 0.0), (30.0, 0.0), (40.0, 50.0), (0.0, 50.0)]),
+            (40, 51)
+        );
+    }
+}
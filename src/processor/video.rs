@@ -0,0 +1,195 @@
+use crate::imagorpath::{filter::Filter, params::Params};
+use crate::storage::storage::Blob;
+use color_eyre::Result;
+
+/// Which frame of a video to extract
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameSelection {
+    /// Frame number, counting from 0
+    Index(usize),
+    /// Timestamp in seconds
+    Time(f64),
+}
+
+impl FrameSelection {
+    /// The last `frame()` or `seek()` filter, otherwise the first frame
+    pub fn from_params(params: &Params) -> Self {
+        params
+            .filters
+            .iter()
+            .rev()
+            .find_map(|filter| match filter {
+                Filter::Frame(index) => Some(FrameSelection::Index(*index)),
+                Filter::Seek(seconds) => Some(FrameSelection::Time(seconds.0 as f64)),
+                _ => None,
+            })
+            .unwrap_or(FrameSelection::Index(0))
+    }
+
+    /// Where to seek before decoding, in microseconds. Frame numbers are
+    /// counted from the start so they are decoded without seeking.
+    pub fn seek_target(&self) -> Option<i64> {
+        match self {
+            FrameSelection::Time(seconds) if *seconds > 0.0 => Some((seconds * 1e6) as i64),
+            _ => None,
+        }
+    }
+
+    /// Whether the `index`th decoded frame, shown at `seconds`, is the one to
+    /// extract. Frames without a timestamp match a seek.
+    pub fn is_target(&self, index: usize, seconds: Option<f64>) -> bool {
+        match self {
+            FrameSelection::Index(target) => index >= *target,
+            FrameSelection::Time(target) => seconds.is_none_or(|seconds| seconds >= *target),
+        }
+    }
+}
+
+/// Decode the selected frame of an MP4 or WebM source into a still PNG for
+/// the normal pipeline. A frame past the end of the video gives the last one.
+#[cfg(feature = "video")]
+pub fn extract_frame(blob: &Blob, selection: FrameSelection) -> Result<Blob> {
+    use super::image::ProcessError;
+    use color_eyre::eyre::eyre;
+    use ffmpeg_next::{
+        codec, decoder,
+        format::{self, Pixel},
+        frame::Video,
+        media::Type,
+        software::scaling::{self, Flags},
+    };
+    use libvips::{ops, ops::BandFormat, VipsImage};
+    use std::io::Write;
+
+    fn receive(
+        decoder: &mut decoder::Video,
+        selection: FrameSelection,
+        time_base: f64,
+        index: &mut usize,
+        last: &mut Option<Video>,
+    ) -> bool {
+        let mut decoded = Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let seconds = decoded.timestamp().map(|ts| ts as f64 * time_base);
+            let found = selection.is_target(*index, seconds);
+            *index += 1;
+            *last = Some(std::mem::replace(&mut decoded, Video::empty()));
+            if found {
+                return true;
+            }
+        }
+        false
+    }
+
+    ffmpeg_next::init()?;
+
+    // the demuxers need a seekable input, MP4 keeps its index at the end
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(&blob.data)?;
+    let mut input = format::input(file.path())?;
+
+    let (stream_index, time_base, mut decoder) = {
+        let stream = input
+            .streams()
+            .best(Type::Video)
+            .ok_or(ffmpeg_next::Error::StreamNotFound)?;
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        (stream.index(), f64::from(stream.time_base()), decoder)
+    };
+
+    if let Some(ts) = selection.seek_target() {
+        input.seek(ts, ..ts)?;
+    }
+
+    let mut index = 0;
+    let mut frame = None;
+    let mut found = false;
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        if receive(&mut decoder, selection, time_base, &mut index, &mut frame) {
+            found = true;
+            break;
+        }
+    }
+    if !found {
+        decoder.send_eof()?;
+        receive(&mut decoder, selection, time_base, &mut index, &mut frame);
+    }
+    let frame = frame.ok_or_else(|| eyre!("video has no frames to extract"))?;
+
+    let (width, height) = (frame.width(), frame.height());
+    let mut scaler = scaling::Context::get(
+        frame.format(),
+        width,
+        height,
+        Pixel::RGB24,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+    let mut rgb = Video::empty();
+    scaler.run(&frame, &mut rgb)?;
+
+    // rows are padded to the stride
+    let row = width as usize * 3;
+    let pixels: Vec<u8> = rgb
+        .data(0)
+        .chunks(rgb.stride(0))
+        .take(height as usize)
+        .flat_map(|line| &line[..row])
+        .copied()
+        .collect();
+
+    let image =
+        VipsImage::new_from_memory(&pixels, width as i32, height as i32, 3, BandFormat::Uchar)
+            .map_err(|_| ProcessError::ImageLoadError)?;
+    let png = ops::pngsave_buffer(&image).map_err(|_| ProcessError::ImageLoadError)?;
+
+    Ok(Blob::new(png))
+}
+
+#[cfg(not(feature = "video"))]
+pub fn extract_frame(_blob: &Blob, _selection: FrameSelection) -> Result<Blob> {
+    Err(super::image::ProcessError::VideoNotSupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_params;
+
+    #[test]
+    fn test_frame_selection() {
+        let selection = |path| FrameSelection::from_params(&parse_params(path).unwrap());
+
+        assert_eq!(selection("unsafe/clip.mp4"), FrameSelection::Index(0));
+        assert_eq!(
+            selection("unsafe/filters:frame(12)/clip.mp4"),
+            FrameSelection::Index(12)
+        );
+        // the last one wins
+        assert_eq!(
+            selection("unsafe/filters:frame(12):seek(1.5)/clip.mp4"),
+            FrameSelection::Time(1.5)
+        );
+    }
+
+    #[test]
+    fn test_frame_selection_target() {
+        let index = FrameSelection::Index(2);
+        assert_eq!(index.seek_target(), None);
+        assert!(!index.is_target(1, Some(10.0)));
+        assert!(index.is_target(2, None));
+
+        let time = FrameSelection::Time(1.5);
+        assert_eq!(time.seek_target(), Some(1_500_000));
+        assert!(!time.is_target(0, Some(1.0)));
+        assert!(time.is_target(0, Some(1.5)));
+        assert!(time.is_target(0, None));
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most requests kept at once, so a flood of distinct panicking paths
+/// can't grow it without bound
+const MAX_ENTRIES: usize = 10_000;
+
+/// Requests whose render panicked recently. Until their cooldown is over
+/// they're answered with the same error instead of being rendered again,
+/// so clients retrying them don't keep hitting the bug.
+#[derive(Clone, Debug, Default)]
+pub struct Quarantine {
+    cooldown: Duration,
+    /// When each request is let through again, and the error it gets until
+    /// then
+    entries: Arc<Mutex<HashMap<String, (Instant, String)>>>,
+}
+
+impl Quarantine {
+    /// A zero `cooldown` keeps nothing
+    pub fn new(cooldown: Duration) -> Self {
+        Quarantine {
+            cooldown,
+            entries: Arc::default(),
+        }
+    }
+
+    /// The error `key` panicked with, while it's cooling down
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().expect("quarantine lock poisoned");
+        match entries.get(key) {
+            Some((until, error)) if *until > Instant::now() => Some(error.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: String, error: String) {
+        if self.cooldown.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("quarantine lock poisoned");
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (until, _)| *until > now);
+        }
+        if entries.len() < MAX_ENTRIES {
+            entries.insert(key, (now + self.cooldown, error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown() {
+        let quarantine = Quarantine::new(Duration::from_millis(50));
+        quarantine.insert("key".to_string(), "boom".to_string());
+        assert_eq!(quarantine.get("key").as_deref(), Some("boom"));
+        assert_eq!(quarantine.get("other"), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(quarantine.get("key"), None);
+    }
+
+    #[test]
+    fn test_zero_cooldown_keeps_nothing() {
+        let quarantine = Quarantine::new(Duration::ZERO);
+        quarantine.insert("key".to_string(), "boom".to_string());
+        assert_eq!(quarantine.get("key"), None);
+    }
+}
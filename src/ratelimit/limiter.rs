@@ -0,0 +1,186 @@
+use crate::auth::ApiKey;
+use crate::config::{RateLimitBudget, RateLimitSettings};
+use crate::imagorpath::hasher::source_digest;
+use axum::async_trait;
+use axum::http::HeaderMap;
+use color_eyre::Result;
+use secrecy::ExposeSecret;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Token buckets, one per key. A bucket holds up to `burst` tokens and
+/// refills at `requests_per_second`; every request takes one.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    async fn check(&self, key: &str, budget: &RateLimitBudget) -> Result<Decision>;
+}
+
+/// Who a request is charged to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    /// Bucket key, `ip:<address>`, `key:<name of an authenticated key>` or
+    /// `key:<digest of the API key>`
+    pub key: String,
+    pub budget: RateLimitBudget,
+}
+
+impl Client {
+    /// `ip` or `api_key`, for metrics
+    pub fn kind(&self) -> &'static str {
+        if self.key.starts_with("key:") {
+            "api_key"
+        } else {
+            "ip"
+        }
+    }
+}
+
+/// Limits incoming requests per client IP, or per API key for requests
+/// with a key that has its own budget
+#[derive(Clone)]
+pub struct RateLimit {
+    limiter: Arc<dyn RateLimiter>,
+    default: RateLimitBudget,
+    api_key_header: String,
+    api_keys: HashMap<String, RateLimitBudget>,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimit {
+    pub fn new(settings: &RateLimitSettings, limiter: Arc<dyn RateLimiter>) -> Self {
+        Self {
+            limiter,
+            default: settings.default,
+            api_key_header: settings.api_key_header.clone(),
+            api_keys: settings
+                .api_keys
+                .iter()
+                .map(|api_key| (api_key.key.expose_secret().to_string(), api_key.budget))
+                .collect(),
+            trust_forwarded_for: settings.trust_forwarded_for,
+        }
+    }
+
+    /// Requests that authenticated are charged to their key. Otherwise
+    /// requests with an unknown API key are charged to their IP, so made up
+    /// keys don't get a fresh budget each.
+    pub fn client(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<IpAddr>,
+        authenticated: Option<&ApiKey>,
+    ) -> Option<Client> {
+        if let Some(api_key) = authenticated {
+            return Some(Client {
+                key: format!("key:{}", api_key.name),
+                budget: api_key.rate_limit.unwrap_or(self.default),
+            });
+        }
+
+        let api_key = headers
+            .get(&self.api_key_header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|key| self.api_keys.get_key_value(key));
+        if let Some((key, budget)) = api_key {
+            return Some(Client {
+                key: format!("key:{}", source_digest(key)),
+                budget: *budget,
+            });
+        }
+
+        // the address our proxy appended; the ones before it are whatever
+        // the client claimed
+        let forwarded = self
+            .trust_forwarded_for
+            .then(|| headers.get_all("x-forwarded-for").iter().next_back())
+            .flatten()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or(peer).map(|ip: IpAddr| Client {
+            key: format!("ip:{}", ip),
+            budget: self.default,
+        })
+    }
+
+    pub async fn check(&self, client: &Client) -> Result<Decision> {
+        self.limiter.check(&client.key, &client.budget).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ApiKeyBudget;
+    use crate::ratelimit::memory::MemoryRateLimiter;
+
+    fn rate_limit(trust_forwarded_for: bool) -> RateLimit {
+        let settings = RateLimitSettings {
+            trust_forwarded_for,
+            api_keys: vec![ApiKeyBudget {
+                key: "partner".to_string().into(),
+                budget: RateLimitBudget {
+                    requests_per_second: 100.0,
+                    burst: 500,
+                },
+            }],
+            ..Default::default()
+        };
+        RateLimit::new(&settings, Arc::new(MemoryRateLimiter::new()))
+    }
+
+    #[test]
+    fn test_client() {
+        let peer = Some("10.0.0.1".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.9, 203.0.113.7".parse().unwrap(),
+        );
+
+        let client = rate_limit(false).client(&headers, peer, None).unwrap();
+        assert_eq!(client.key, "ip:10.0.0.1");
+        assert_eq!(client.kind(), "ip");
+        let client = rate_limit(true).client(&headers, peer, None).unwrap();
+        assert_eq!(client.key, "ip:203.0.113.7");
+
+        // a spoofed header the proxy appended its own to
+        let mut spoofed = HeaderMap::new();
+        spoofed.append("x-forwarded-for", "198.51.100.9".parse().unwrap());
+        spoofed.append("x-forwarded-for", "203.0.113.8".parse().unwrap());
+        let client = rate_limit(true).client(&spoofed, peer, None).unwrap();
+        assert_eq!(client.key, "ip:203.0.113.8");
+
+        headers.insert("x-api-key", "made-up".parse().unwrap());
+        let client = rate_limit(false).client(&headers, peer, None).unwrap();
+        assert_eq!(client.key, "ip:10.0.0.1");
+
+        headers.insert("x-api-key", "partner".parse().unwrap());
+        let client = rate_limit(false).client(&headers, peer, None).unwrap();
+        assert_eq!(client.kind(), "api_key");
+        assert!(!client.key.contains("partner"));
+        assert_eq!(client.budget.burst, 500);
+
+        assert!(rate_limit(false)
+            .client(&HeaderMap::new(), None, None)
+            .is_none());
+
+        let authenticated = ApiKey {
+            name: "mobile".to_string(),
+            rate_limit: None,
+        };
+        let client = rate_limit(false)
+            .client(&headers, peer, Some(&authenticated))
+            .unwrap();
+        assert_eq!(client.key, "key:mobile");
+        assert_eq!(client.budget, RateLimitBudget::default());
+    }
+}
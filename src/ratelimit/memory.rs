@@ -0,0 +1,108 @@
+use super::limiter::{Decision, RateLimiter};
+use crate::config::RateLimitBudget;
+use axum::async_trait;
+use color_eyre::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets beyond this are pruned of the ones that have refilled
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again, and can be forgotten
+    full_at: Instant,
+}
+
+/// In-process buckets, for single instance deployments and tests
+#[derive(Debug, Default)]
+pub struct MemoryRateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn secs(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+#[async_trait]
+impl RateLimiter for MemoryRateLimiter {
+    async fn check(&self, key: &str, budget: &RateLimitBudget) -> Result<Decision> {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        let now = Instant::now();
+        let burst = f64::from(budget.burst);
+        let rate = budget.requests_per_second;
+
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        let decision = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            Decision::Limited {
+                retry_after: secs((1.0 - bucket.tokens) / rate),
+            }
+        };
+        bucket.full_at = now
+            .checked_add(secs((burst - bucket.tokens) / rate))
+            .unwrap_or(now);
+
+        Ok(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let limiter = MemoryRateLimiter::new();
+        let budget = RateLimitBudget {
+            requests_per_second: 10.0,
+            burst: 3,
+        };
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check("a", &budget).await.unwrap(),
+                Decision::Allowed
+            );
+        }
+        let Decision::Limited { retry_after } = limiter.check("a", &budget).await.unwrap() else {
+            panic!("expected the fourth request to be limited");
+        };
+        assert!(retry_after <= Duration::from_millis(100));
+
+        // buckets are per key
+        assert_eq!(
+            limiter.check("b", &budget).await.unwrap(),
+            Decision::Allowed
+        );
+
+        tokio::time::sleep(retry_after).await;
+        assert_eq!(
+            limiter.check("a", &budget).await.unwrap(),
+            Decision::Allowed
+        );
+    }
+}
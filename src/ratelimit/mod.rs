@@ -0,0 +1,3 @@
+pub mod limiter;
+pub mod memory;
+pub mod redis;
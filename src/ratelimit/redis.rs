@@ -0,0 +1,85 @@
+use super::limiter::{Decision, RateLimiter};
+use crate::config::RateLimitBudget;
+use axum::async_trait;
+use color_eyre::Result;
+use redis::{Client, Script};
+use std::time::Duration;
+
+/// Refills and takes from the bucket atomically, on the Redis clock so
+/// instances with skewed clocks share budgets fairly. Buckets expire once
+/// they'd be full again.
+const TOKEN_BUCKET: &str = r#"
+local rate = math.max(tonumber(ARGV[1]), 1e-9)
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+
+local allowed = 0
+local retry_ms = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+else
+  retry_ms = math.ceil((1 - tokens) / rate * 1000)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', string.format('%.6f', tokens), 'updated', string.format('%.6f', now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) / rate * 1000) + 1000)
+return {allowed, retry_ms}
+"#;
+
+/// Buckets shared by every instance using the same Redis
+#[derive(Debug, Clone)]
+pub struct RedisRateLimiter {
+    client: Client,
+    script: Script,
+}
+
+impl RedisRateLimiter {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        let client = Client::open(redis_url)?;
+        Ok(RedisRateLimiter {
+            client,
+            script: Script::new(TOKEN_BUCKET),
+        })
+    }
+
+    async fn get_connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(Into::into)
+    }
+}
+
+fn bucket_key(key: &str) -> String {
+    format!("ratelimit:{}", key)
+}
+
+#[async_trait]
+impl RateLimiter for RedisRateLimiter {
+    #[tracing::instrument(skip(self))]
+    async fn check(&self, key: &str, budget: &RateLimitBudget) -> Result<Decision> {
+        let mut conn = self.get_connection().await?;
+        let (allowed, retry_ms): (u8, u64) = self
+            .script
+            .key(bucket_key(key))
+            .arg(budget.requests_per_second)
+            .arg(budget.burst)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(if allowed == 1 {
+            Decision::Allowed
+        } else {
+            Decision::Limited {
+                retry_after: Duration::from_millis(retry_ms),
+            }
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use crate::allowlist::{AllowList, SourceNotAllowed};
+use crate::config::{get_configuration, Settings};
+use crate::processor::processor::ImageProcessor;
+use crate::telemetry::set_log_level;
+use color_eyre::Result;
+use libvips::VipsApp;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread::available_parallelism;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Settings that can change while the server is running. Storage, the cache
+/// backend and the bind address are only read at startup.
+#[derive(Debug, Clone)]
+pub struct LiveSettings {
+    pub allowed_sources: AllowList,
+    pub cache_ttl: Duration,
+}
+
+impl LiveSettings {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        Ok(Self {
+            allowed_sources: AllowList::new(&settings.loader.allowed_sources)?,
+            cache_ttl: Duration::from_secs(settings.application.cache_ttl_secs),
+        })
+    }
+
+    /// Checked before anything is fetched from `url`
+    pub fn check_source(&self, url: &str) -> Result<(), SourceNotAllowed> {
+        self.allowed_sources.check(url)
+    }
+
+    pub fn is_allowed_source(&self, url: &str) -> bool {
+        self.check_source(url).is_ok()
+    }
+}
+
+/// Re-applies the reloadable parts of the configuration to a running server
+#[derive(Clone)]
+pub struct Reloader {
+    live: Arc<RwLock<LiveSettings>>,
+    processor: Arc<dyn ImageProcessor>,
+    vips_app: Arc<VipsApp>,
+}
+
+impl Reloader {
+    pub fn new(
+        settings: &Settings,
+        processor: Arc<dyn ImageProcessor>,
+        vips_app: Arc<VipsApp>,
+    ) -> Result<Self> {
+        Ok(Self {
+            live: Arc::new(RwLock::new(LiveSettings::from_settings(settings)?)),
+            processor,
+            vips_app,
+        })
+    }
+
+    pub fn live(&self) -> LiveSettings {
+        self.live
+            .read()
+            .expect("live settings lock poisoned")
+            .clone()
+    }
+
+    pub fn apply(&self, settings: &Settings) -> Result<()> {
+        *self.live.write().expect("live settings lock poisoned") =
+            LiveSettings::from_settings(settings)?;
+
+        self.processor.reload(&settings.processor)?;
+        self.vips_app
+            .concurrency_set(vips_concurrency(settings.processor.concurrency));
+
+        if let Some(log_level) = &settings.application.log_level {
+            set_log_level(log_level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-read the configuration files and environment
+    #[tracing::instrument(skip(self))]
+    pub fn reload(&self) -> Result<()> {
+        let settings = get_configuration()?;
+        self.apply(&settings)?;
+        info!("configuration reloaded");
+
+        Ok(())
+    }
+
+    /// Reload whenever something in `dir` changes. The returned watcher must
+    /// be kept alive for as long as reloading should happen.
+    pub fn watch(&self, dir: &Path) -> Result<RecommendedWatcher> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let Err(e) = event {
+                    warn!("config watcher error: {}", e);
+                    continue;
+                }
+
+                // editors tend to write a file in several steps
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                while rx.try_recv().is_ok() {}
+
+                if let Err(e) = reloader.reload() {
+                    warn!("Failed to reload configuration: {}", e);
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+}
+
+pub fn vips_concurrency(configured: Option<i32>) -> i32 {
+    configured
+        .filter(|c| *c > 0)
+        .unwrap_or_else(|| match available_parallelism() {
+            Ok(parallelism) => parallelism.get() as i32,
+            Err(_) => 1,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(sources: &[&str]) -> LiveSettings {
+        LiveSettings {
+            allowed_sources: AllowList::new(
+                &sources.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            )
+            .unwrap(),
+            cache_ttl: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_empty_allowed_sources_allows_everything() {
+        assert!(live(&[]).is_allowed_source("https://anything.example/img.png"));
+    }
+
+    #[test]
+    fn test_allowed_sources() {
+        let live = live(&["example.com", "*.githubusercontent.com"]);
+
+        assert!(live.is_allowed_source("https://example.com/a.png"));
+        assert!(live.is_allowed_source("http://EXAMPLE.com/a.png"));
+        assert!(live.is_allowed_source("https://raw.githubusercontent.com/a.png"));
+        assert!(!live.is_allowed_source("https://githubusercontent.com/a.png"));
+        assert!(!live.is_allowed_source("https://evil-example.com/a.png"));
+        assert!(!live.is_allowed_source("not a url"));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::imagorpath::generate::generate_path;
+use crate::imagorpath::params::Params;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Custom metadata is sent as headers by S3, which only carry ASCII
+const ENCODE: &AsciiSet = &CONTROLS.add(b'%');
+
+/// S3 keeps at most 2KB of custom metadata per object, so longer values are
+/// cut off here
+const MAX_VALUE_LEN: usize = 480;
+
+const PATH: &str = "imagor-path";
+const SOURCE: &str = "imagor-source";
+const PARAMS: &str = "imagor-params";
+const DURATION: &str = "imagor-duration-ms";
+
+/// How a result was rendered, saved with it as custom metadata so a stale
+/// rendition can be traced back to the request and settings it came from
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ResultMeta {
+    /// The imagor path it was first requested with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The parameters it was rendered with, defaults and negotiated format
+    /// included, as a path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+impl ResultMeta {
+    pub fn new(params: &Params) -> Self {
+        ResultMeta {
+            path: params.path.clone(),
+            source: params.image.clone(),
+            params: Some(generate_path(params)),
+            duration_ms: None,
+        }
+    }
+
+    /// How long it took to render
+    pub fn with_duration(self, elapsed: Duration) -> Self {
+        ResultMeta {
+            duration_ms: Some(elapsed.as_millis() as u64),
+            ..self
+        }
+    }
+
+    /// As custom object metadata, values percent-encoded
+    pub fn to_custom(&self) -> BTreeMap<String, String> {
+        let duration = self.duration_ms.map(|ms| ms.to_string());
+        [
+            (PATH, self.path.as_ref()),
+            (SOURCE, self.source.as_ref()),
+            (PARAMS, self.params.as_ref()),
+            (DURATION, duration.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), encode(value?))))
+        .collect()
+    }
+
+    /// `None` for objects saved without any, like results from before
+    /// metadata was kept
+    pub fn from_custom(custom: &BTreeMap<String, String>) -> Option<Self> {
+        let value = |key: &str| custom.get(key).map(|value| decode(value));
+        let meta = ResultMeta {
+            path: value(PATH),
+            source: value(SOURCE),
+            params: value(PARAMS),
+            duration_ms: value(DURATION).and_then(|ms| ms.parse().ok()),
+        };
+        (meta != ResultMeta::default()).then_some(meta)
+    }
+}
+
+fn encode(value: &str) -> String {
+    let mut encoded = utf8_percent_encode(value, ENCODE).to_string();
+    if encoded.len() > MAX_VALUE_LEN {
+        encoded.truncate(MAX_VALUE_LEN);
+        // don't leave half an escape behind
+        if let Some(escape) = encoded[MAX_VALUE_LEN - 2..].find('%') {
+            encoded.truncate(MAX_VALUE_LEN - 2 + escape);
+        }
+    }
+    encoded
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagorpath::parse::parse_params;
+
+    #[test]
+    fn test_round_trip() {
+        let params = parse_params("unsafe/300x200/filters:quality(80)/café.jpg").unwrap();
+        let meta = ResultMeta::new(&params).with_duration(Duration::from_millis(42));
+        let custom = meta.to_custom();
+        assert_eq!(custom["imagor-duration-ms"], "42");
+        assert_eq!(custom["imagor-source"], "caf%C3%A9.jpg");
+        assert!(custom.values().all(|value| value.is_ascii()));
+
+        assert_eq!(ResultMeta::from_custom(&custom), Some(meta));
+        assert_eq!(ResultMeta::from_custom(&BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn test_long_values_are_cut() {
+        let meta = ResultMeta {
+            source: Some("é".repeat(200)),
+            ..Default::default()
+        };
+        let source = &meta.to_custom()["imagor-source"];
+        assert!(source.len() <= MAX_VALUE_LEN);
+        assert!(source.ends_with("%A9"));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::ScriptSettings;
+use crate::imagorpath::params::Params;
+use axum::http::HeaderMap;
+use color_eyre::Result;
+#[cfg(feature = "script")]
+use std::sync::{Arc, Mutex};
+
+/// A Lua script run for every image request, for rules that would
+/// otherwise need a rebuild, e.g. forcing a watermark or capping sizes for
+/// some clients. It can define either hook:
+///
+/// - `on_params(params, request)` returns the params to process, after the
+///   signature has been checked
+/// - `on_response(headers, request)` returns the response headers
+///
+/// `request` has the request's `headers`, with lowercase names.
+#[derive(Clone)]
+pub struct Script {
+    #[cfg(feature = "script")]
+    lua: Arc<Mutex<mlua::Lua>>,
+}
+
+#[cfg(feature = "script")]
+impl Script {
+    pub fn load(settings: &ScriptSettings) -> Result<Self> {
+        use color_eyre::eyre::{eyre, WrapErr};
+
+        let source = std::fs::read_to_string(&settings.path)
+            .wrap_err_with(|| format!("Failed to read script {}", settings.path))?;
+        let lua = mlua::Lua::new();
+        lua.load(&source)
+            .set_name(&settings.path)
+            .exec()
+            .map_err(|e| eyre!("Failed to load script {}: {}", settings.path, e))?;
+
+        Ok(Script {
+            lua: Arc::new(Mutex::new(lua)),
+        })
+    }
+
+    pub fn on_params(&self, params: Params, request: &HeaderMap) -> Result<Params> {
+        use color_eyre::eyre::eyre;
+        use mlua::LuaSerdeExt;
+
+        let lua = self.lua.lock().unwrap_or_else(|e| e.into_inner());
+        let Ok(hook) = lua.globals().get::<mlua::Function>("on_params") else {
+            return Ok(params);
+        };
+
+        let result = (|| {
+            let value = hook.call::<mlua::Value>((
+                lua.to_value(&params)?,
+                request_table(&lua, request)?,
+            ))?;
+            lua.from_value::<Params>(value)
+        })();
+        let mut modified = result.map_err(|e| eyre!("Script on_params failed: {}", e))?;
+
+        // the signature was checked against these, a script can't change them
+        modified.path = params.path.clone();
+        modified.hash = params.hash.clone();
+        modified.unsafe_ = params.unsafe_;
+        // the request's path no longer describes the params, so they're
+        // keyed by their own in result storage
+        if modified != params {
+            modified.path = None;
+        }
+        Ok(modified)
+    }
+
+    pub fn on_response(&self, headers: &mut HeaderMap, request: &HeaderMap) -> Result<()> {
+        use axum::http::{HeaderName, HeaderValue};
+        use color_eyre::eyre::eyre;
+
+        let lua = self.lua.lock().unwrap_or_else(|e| e.into_inner());
+        let Ok(hook) = lua.globals().get::<mlua::Function>("on_response") else {
+            return Ok(());
+        };
+
+        let result = (|| {
+            let table: mlua::Table =
+                hook.call((headers_table(&lua, headers)?, request_table(&lua, request)?))?;
+            table.pairs::<String, String>().collect::<mlua::Result<Vec<_>>>()
+        })();
+        let pairs = result.map_err(|e| eyre!("Script on_response failed: {}", e))?;
+
+        let mut modified = HeaderMap::new();
+        for (name, value) in pairs {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|e| eyre!("Script set an invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|e| eyre!("Script set an invalid value for {}: {}", name, e))?;
+            modified.insert(name, value);
+        }
+        *headers = modified;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "script")]
+fn headers_table(lua: &mlua::Lua, headers: &HeaderMap) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+    for (name, value) in headers {
+        if let Ok(value) = value.to_str() {
+            table.set(name.as_str(), value)?;
+        }
+    }
+    Ok(table)
+}
+
+#[cfg(feature = "script")]
+fn request_table(lua: &mlua::Lua, headers: &HeaderMap) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+    table.set("headers", headers_table(lua, headers)?)?;
+    Ok(table)
+}
+
+#[cfg(not(feature = "script"))]
+impl Script {
+    pub fn load(_settings: &ScriptSettings) -> Result<Self> {
+        Err(color_eyre::eyre::eyre!(
+            "Scripts need a build with the `script` feature"
+        ))
+    }
+
+    pub fn on_params(&self, params: Params, _request: &HeaderMap) -> Result<Params> {
+        Ok(params)
+    }
+
+    pub fn on_response(&self, _headers: &mut HeaderMap, _request: &HeaderMap) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "script"))]
+mod tests {
+    use super::*;
+    use crate::imagorpath::filter::Filter;
+    use crate::imagorpath::parse::parse_params;
+    use axum::http::header;
+    use std::io::Write;
+
+    fn script(source: &str) -> Script {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        Script::load(&ScriptSettings {
+            enabled: true,
+            path: file.path().to_string_lossy().to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_on_params() {
+        let script = script(
+            r#"
+            function on_params(params, request)
+                if request.headers["x-tenant"] == "free" then
+                    params.width = math.min(params.width or 0, 100)
+                    table.insert(params.filters, "Grayscale")
+                end
+                params.unsafe_ = false
+                return params
+            end
+            "#,
+        );
+        let params = parse_params("unsafe/500x0/img.jpg").unwrap();
+
+        let mut request = HeaderMap::new();
+        let unchanged = script.on_params(params.clone(), &request).unwrap();
+        assert_eq!(unchanged, params);
+
+        request.insert("x-tenant", "free".parse().unwrap());
+        let modified = script.on_params(params, &request).unwrap();
+        assert_eq!(modified.width, Some(100));
+        assert_eq!(modified.filters, vec![Filter::Grayscale]);
+        assert!(modified.unsafe_);
+        assert_eq!(modified.path, None);
+    }
+
+    #[test]
+    fn test_on_response() {
+        let script = script(
+            r#"
+            function on_response(headers, request)
+                headers["x-served-by"] = "imagor"
+                headers["cache-control"] = nil
+                return headers
+            end
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
+        headers.insert(header::CACHE_CONTROL, "no-store".parse().unwrap());
+
+        script.on_response(&mut headers, &HeaderMap::new()).unwrap();
+        assert_eq!(headers.get("x-served-by").unwrap(), "imagor");
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "image/png");
+        assert!(headers.get(header::CACHE_CONTROL).is_none());
+    }
+
+    #[test]
+    fn test_without_hooks() {
+        let script = script("-- nothing to do");
+        let params = parse_params("unsafe/500x0/img.jpg").unwrap();
+        let unchanged = script.on_params(params.clone(), &HeaderMap::new()).unwrap();
+        assert_eq!(unchanged, params);
+    }
+}
@@ -1,43 +1,112 @@
+use crate::admin::{evict_cache, evict_source, inspect_cache, list_fonts, meta_of_result};
+use crate::auth::{ApiKey, Auth};
 use crate::cache::cache::ImageCache;
 use crate::cache::redis::RedisCache;
-use crate::config::{Settings, StorageClient};
-use crate::imagorpath::hasher::{suffix_result_storage_hasher, verify_hash};
+use crate::cache::s3::S3Cache;
+use crate::config::{
+    configuration_directory, CacheSettings, CompressionSettings, ProcessorBackend, RoutesSettings,
+    Settings, StorageClient,
+};
+#[cfg(feature = "worker")]
+use crate::config::WorkerQueue;
+use crate::dimensions::AllowedDimensions;
+use crate::error::{ApiError, REQUEST_ID};
+use crate::failure::{is_image, SourceFailure};
+use crate::gc;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::health;
+use crate::imagorpath::error::ParseError;
+use crate::imagorpath::filter::Filter;
+use crate::imagorpath::generate::to_signed_string;
+use crate::imagorpath::hasher::suffix_result_storage_hasher;
 use crate::imagorpath::params::Params;
+use crate::imagorpath::parse::parse_params;
+use crate::imagorpath::signer::HmacSigner;
+use crate::inspect::{describe, inspect_params, Described};
+use crate::memory::{MemoryBudget, MemoryError, Reservation};
 use crate::metrics::{setup_metrics_recorder, track_metrics};
-use crate::middleware::cache_middleware;
-use crate::processor::processor::{ImageProcessor, Processor};
+use crate::middleware::{
+    auth_middleware, cache_middleware, compression_layer, no_script, range_middleware,
+    rate_limit_middleware, request_id, require_admin_key, require_api_key, tenant_middleware,
+};
+use crate::mirror::Mirrors;
+use crate::pathutil::normalize::SafeCharsType;
+use crate::prefetch::Prefetcher;
+use crate::presets::Presets;
+use crate::processor::assets::Assets;
+use crate::processor::capabilities::Capabilities;
+use crate::processor::fonts::{self, FontRegistry};
+use crate::processor::image::{panic_message, ProcessError};
+use crate::processor::isolated::IsolatedProcessor;
+use crate::processor::processor::{configure_spill, ImageProcessor, Processor};
+#[cfg(feature = "pure")]
+use crate::processor::pure::PureProcessor;
+use crate::quarantine::Quarantine;
+use crate::ratelimit::limiter::{RateLimit, RateLimiter};
+use crate::ratelimit::memory::MemoryRateLimiter;
+use crate::ratelimit::redis::RedisRateLimiter;
+use crate::reload::{vips_concurrency, Reloader};
+use crate::result_meta::ResultMeta;
+use crate::script::Script;
 use crate::state::AppStateDyn;
+use crate::stats::redis::RedisStats;
+use crate::stats::stats::{ImageStats, Rollup};
+use crate::storage::content_type::ContentType;
 use crate::storage::file::FileStorage;
 use crate::storage::gcs::GCloudStorage;
+use crate::storage::http::HttpStorage;
+use crate::storage::retry::{RetryPolicy, RetryingStorage, StatusError};
+use crate::storage::routed::RoutedStorage;
 use crate::storage::s3::S3Storage;
-use crate::storage::storage::{Blob, ImageStorage};
+use crate::storage::storage::{
+    check_source_size, Blob, BlobMetadata, ImageStorage, SourceTooLarge,
+};
+use crate::storage::trash::{self, RestoreStatus};
+use crate::tenant::{Tenant, TenantState, Tenants};
+use crate::tls::{self, CertResolver};
+#[cfg(feature = "worker")]
+use crate::worker::{jobs, nats::NatsQueue, queue::JobQueue, sqs::SqsQueue};
+use crate::writebehind::WriteBehind;
 use axum::body::Body;
-use axum::extract::{MatchedPath, Request, State};
-use axum::http::{header, Response, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::{middleware, Json};
-use axum::{serve::Serve, Router};
+use axum::extract::{DefaultBodyLimit, FromRequest, Host, MatchedPath, Multipart, Request, State};
+use axum::http::{header, HeaderMap, Response, StatusCode, Uri};
+use axum::routing::{delete, get, post};
+use axum::Router;
+use axum::{middleware, Extension, Json};
 use color_eyre::eyre::WrapErr;
-use color_eyre::Result;
+use color_eyre::{Report, Result};
 use libvips::VipsApp;
-use reqwest;
-use secrecy::ExposeSecret;
-use std::future::ready;
+use notify::RecommendedWatcher;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::future::{ready, Future, IntoFuture};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::thread::available_parallelism;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::TcpListener;
-use tokio::task;
+use tokio::task::{self, JoinSet};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_util::sync::CancellationToken;
+use tower::Layer;
 use tower_http::trace::TraceLayer;
 use tracing::{info, info_span, warn};
 
+type Server = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+const ORIGINAL_CACHE_CONTROL: &str = "public, max-age=86400";
+
 pub struct Application {
     pub port: u16,
-    server: Serve<Router, Router>,
+    server: Server,
 
     // This is a hack to keep the VipsApp alive for the lifetime of the application
-    _vips_app: VipsApp,
+    _vips_app: Arc<VipsApp>,
+    _config_watcher: Option<RecommendedWatcher>,
+    _cert_watcher: Option<RecommendedWatcher>,
 }
 
 impl Application {
@@ -49,63 +118,199 @@ impl Application {
         )?;
         let port = listener.local_addr()?.port();
 
-        let _vips_app = VipsApp::new("imagor_rs", true).wrap_err("Failed to initialize VipsApp")?;
-        let concurrency = match available_parallelism() {
-            Ok(parallelism) => parallelism.get() as i32,
-            Err(_) => 1,
+        configure_spill(&config.processor);
+        let _vips_app =
+            Arc::new(VipsApp::new("imagor_rs", true).wrap_err("Failed to initialize VipsApp")?);
+        _vips_app.concurrency_set(vips_concurrency(config.processor.concurrency));
+        fonts::download(&config.processor.fonts).await;
+        FontRegistry::global().load(&config.processor.fonts);
+
+        let processor: Arc<dyn ImageProcessor> = match config.processor.backend {
+            ProcessorBackend::Vips if config.processor.isolation.enabled => {
+                Arc::new(IsolatedProcessor::from_settings(&config.processor)?)
+            }
+            ProcessorBackend::Vips => Arc::new(Processor::from_settings(&config.processor)),
+            #[cfg(feature = "pure")]
+            ProcessorBackend::Pure => {
+                info!("Using the pure Rust processor");
+                Arc::new(PureProcessor::from_settings(&config.processor))
+            }
+            #[cfg(not(feature = "pure"))]
+            ProcessorBackend::Pure => {
+                return Err(color_eyre::eyre::eyre!(
+                    "The pure processor backend needs a build with the `pure` feature"
+                ))
+            }
         };
-        _vips_app.concurrency_set(concurrency);
-
-        let processor = Processor::new(config.processor);
-        let cache = RedisCache::new("redis://redis:6379")?;
-        let server = match config.storage.client {
-            StorageClient::S3(s3_settings) => {
-                info!("Using S3 storage");
-                let storage = S3Storage::new(
-                    config.storage.base_dir,
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                    s3_settings.endpoint,
-                    s3_settings.region,
-                    s3_settings.bucket,
-                    s3_settings.access_key.expose_secret(),
-                    s3_settings.secret_key.expose_secret(),
+        let reloader = Reloader::new(&config, processor.clone(), _vips_app.clone())?;
+        let _config_watcher = reloader
+            .watch(&configuration_directory())
+            .inspect_err(|e| warn!("Config hot-reload disabled: {}", e))
+            .ok();
+        let cache: Arc<dyn ImageCache> = match &config.cache {
+            CacheSettings::Redis(redis) => Arc::new(RedisCache::new(redis)?),
+            CacheSettings::S3(s3) => {
+                info!("Using the S3 response cache");
+                let cache = S3Cache::new(s3).await;
+                cache.start(s3).await?;
+                Arc::new(cache)
+            }
+            CacheSettings::Filesystem(_) => {
+                return Err(color_eyre::eyre::eyre!(
+                    "The filesystem response cache isn't implemented, use Redis"
+                ))
+            }
+        };
+        let stats = if config.stats.enabled {
+            let stats: Arc<dyn ImageStats> = Arc::new(RedisStats::new(
+                &config.stats.redis_uri,
+                config.stats.rollup_size,
+            )?);
+            spawn_stats_rollup(
+                stats.clone(),
+                Duration::from_secs(config.stats.rollup_interval_secs),
+            );
+            Some(stats)
+        } else {
+            None
+        };
+        let retry = RetryPolicy::new(&config.storage.retry);
+        let mut storage = storage_backend(
+            config.storage.client,
+            config.storage.base_dir.clone(),
+            config.storage.path_prefix,
+            config.storage.safe_chars.clone(),
+        )
+        .await?;
+        if !config.storage.routes.is_empty() {
+            let mut routed = RoutedStorage::new(storage);
+            for route in config.storage.routes {
+                info!("Routing storage under /{}", route.prefix.trim_matches('/'));
+                let backend = storage_backend(
+                    route.client,
+                    config.storage.base_dir.clone(),
+                    route.path_prefix,
+                    config.storage.safe_chars.clone(),
                 )
                 .await?;
-
-                // Ensure bucket exists
-                storage.ensure_bucket_exists().await?;
-
-                run(listener, storage, processor, cache).await?
+                routed = routed.with_route(&route.prefix, &route.name, backend);
             }
-            StorageClient::GCS(gcs_settings) => {
-                info!("using GCS storage");
-                let storage = GCloudStorage::new(
-                    config.storage.base_dir,
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                    gcs_settings.bucket,
-                )
-                .await;
+            storage = Arc::new(routed);
+        }
 
-                run(listener, storage, processor, cache).await?
-            }
-            StorageClient::Filesystem(filesystem_settings) => {
-                info!("using filesystem storage");
-                let storage = FileStorage::new(
-                    PathBuf::from(filesystem_settings.base_dir),
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                );
-
-                run(listener, storage, processor, cache).await?
+        // tenants' budgets are kept alongside the clients'
+        let tenant_limits = config.tenants.iter().any(|t| t.rate_limit.is_some());
+        let limiter = if config.rate_limit.enabled || tenant_limits {
+            let limiter: Arc<dyn RateLimiter> = match &config.rate_limit.redis_uri {
+                Some(redis_uri) => {
+                    info!("Using Redis for rate limits");
+                    Arc::new(RedisRateLimiter::new(redis_uri)?)
+                }
+                None => Arc::new(MemoryRateLimiter::new()),
+            };
+            Some(limiter)
+        } else {
+            None
+        };
+        let rate_limit = limiter
+            .clone()
+            .filter(|_| config.rate_limit.enabled)
+            .map(|limiter| RateLimit::new(&config.rate_limit, limiter));
+
+        // write-behind has its own retries, so it gets the storage unwrapped
+        let write_behind = WriteBehind::new(&config.storage.write_behind, storage.clone());
+        gc::spawn(
+            &config.gc,
+            storage.clone(),
+            config.purge.trash_prefix.clone(),
+        );
+        let storage: Arc<dyn ImageStorage> = Arc::new(RetryingStorage::new(storage, retry));
+        let state = AppStateDyn {
+            storage: storage.clone(),
+            processor,
+            cache,
+            signer: HmacSigner::new(config.application.hmac_secret),
+            allow_unsafe: config.application.allow_unsafe,
+            defaults: config.defaults,
+            purge: config.purge,
+            reloader,
+            mirrors: Mirrors::new(&config.loader)?,
+            stats,
+            prefetch: Prefetcher::new(&config.prefetch),
+            write_behind,
+            rate_limit,
+            auth: if config.auth.enabled {
+                Some(Auth::from_settings(&config.auth)?)
+            } else {
+                None
+            },
+            fallback_image: config.fallback_image,
+            error_statuses: config.error_statuses,
+            memory: MemoryBudget::new(&config.limits),
+            quarantine: Quarantine::new(Duration::from_secs(config.limits.panic_cooldown_secs)),
+            limits: config.limits,
+            script: if config.script.enabled {
+                info!("Running script {}", config.script.path);
+                Some(Script::load(&config.script)?)
+            } else {
+                None
+            },
+            presets: Presets::new(&config.presets)?,
+            dimensions: AllowedDimensions::new(&config.dimensions)?,
+            tenants: Tenants::new(&config.tenants, storage, limiter)?,
+            tenant: None,
+        };
+        if config.application.grpc.enabled {
+            #[cfg(feature = "grpc")]
+            grpc::spawn(
+                &config.application.host,
+                config.application.grpc.port,
+                state.clone(),
+            )
+            .await?;
+            #[cfg(not(feature = "grpc"))]
+            return Err(color_eyre::eyre::eyre!(
+                "The gRPC server needs a build with the `grpc` feature"
+            ));
+        }
+        if config.worker.enabled {
+            #[cfg(feature = "worker")]
+            {
+                let queue: Arc<dyn JobQueue> = match &config.worker.queue {
+                    WorkerQueue::Nats(nats) => {
+                        info!("Processing jobs from NATS subject {}", nats.subject);
+                        Arc::new(NatsQueue::new(nats).await?)
+                    }
+                    WorkerQueue::Sqs(sqs) => {
+                        info!("Processing jobs from SQS queue {}", sqs.queue_url);
+                        Arc::new(SqsQueue::new(sqs).await)
+                    }
+                };
+                jobs::spawn(&config.worker, queue, state.clone());
             }
+            #[cfg(not(feature = "worker"))]
+            return Err(color_eyre::eyre::eyre!(
+                "The job worker needs a build with the `worker` feature"
+            ));
+        }
+        let (tls, _cert_watcher) = if config.application.tls.enabled {
+            let resolver = Arc::new(CertResolver::new(&config.application.tls)?);
+            let watcher = resolver
+                .watch()
+                .inspect_err(|e| warn!("Certificate hot-reload disabled: {}", e))
+                .ok();
+            (Some(tls::server_config(resolver)?), watcher)
+        } else {
+            (None, None)
         };
+        let server = run(listener, state, config.routes, config.compression, tls).await?;
 
         Ok(Self {
             port,
             server,
             _vips_app,
+            _config_watcher,
+            _cert_watcher,
         })
     }
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
@@ -122,39 +327,126 @@ impl Application {
     }
 }
 
-async fn run<S, P, C>(
+/// A backend for one storage client
+async fn storage_backend(
+    client: StorageClient,
+    base_dir: String,
+    path_prefix: String,
+    safe_chars: SafeCharsType,
+) -> Result<Arc<dyn ImageStorage>> {
+    let storage: Arc<dyn ImageStorage> = match client {
+        StorageClient::S3(s3_settings) => {
+            info!("Using S3 storage");
+            let storage = S3Storage::new(base_dir, path_prefix, safe_chars, &s3_settings).await?;
+
+            // Ensure bucket exists
+            storage.ensure_bucket_exists().await?;
+
+            Arc::new(storage)
+        }
+        StorageClient::GCS(gcs_settings) => {
+            info!("using GCS storage");
+            let storage =
+                GCloudStorage::new(base_dir, path_prefix, safe_chars, &gcs_settings).await?;
+
+            Arc::new(storage)
+        }
+        StorageClient::Filesystem(filesystem_settings) => {
+            info!("using filesystem storage");
+            let storage = FileStorage::new(
+                PathBuf::from(filesystem_settings.base_dir),
+                path_prefix,
+                safe_chars,
+            )
+            .with_sharding(filesystem_settings.shard)
+            .with_fsync(filesystem_settings.fsync);
+
+            Arc::new(storage)
+        }
+        StorageClient::Http(http_settings) => {
+            info!("using HTTP storage at {}", http_settings.base_url);
+            let storage = HttpStorage::new(path_prefix, safe_chars, &http_settings)?;
+
+            Arc::new(storage)
+        }
+    };
+
+    Ok(storage)
+}
+
+async fn run(
     listener: TcpListener,
-    storage: S,
-    processor: P,
-    cache: C,
-) -> Result<Serve<Router, Router>>
-where
-    S: ImageStorage + Clone + Send + Sync + 'static,
-    P: ImageProcessor + Send + Sync + 'static,
-    C: ImageCache + Clone + Send + Sync + 'static,
-{
+    state: AppStateDyn,
+    routes: RoutesSettings,
+    compression: CompressionSettings,
+    tls: Option<ServerConfig>,
+) -> Result<Server> {
     let recorder_handle = setup_metrics_recorder();
 
-    let state = AppStateDyn {
-        storage: Arc::new(storage.clone()),
-        processor: Arc::new(processor),
-        cache: Arc::new(cache.clone()),
-    };
+    let favicon = routes
+        .favicon
+        .as_ref()
+        .map(std::fs::read)
+        .transpose()
+        .wrap_err("Failed to read favicon")?;
+
+    // signing and managing what's stored needs a key whenever keys are
+    // configured
+    let admin = Router::new()
+        .route("/sign", post(sign))
+        .route("/purge", post(purge))
+        .route("/restore", post(restore))
+        .route("/admin/reload", post(reload_config))
+        .route("/admin/stats", get(processing_stats))
+        .route("/admin/cache/*key", get(inspect_cache).delete(evict_cache))
+        .route("/admin/source-cache/*source", delete(evict_source))
+        .route("/purge/prefix", post(purge_prefix))
+        .route("/meta-of-result/*key", get(meta_of_result))
+        .route("/admin/fonts", get(list_fonts))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_admin_key,
+        ));
 
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/live", get(health::live))
+        .route("/ready", get(health::ready))
         .route("/metrics", get(move || ready(recorder_handle.render())))
-        .route("/", get(root))
+        .route("/", get(move || ready(routes.root)))
+        .route("/robots.txt", get(move || ready(routes.robots_txt)))
+        .route(
+            "/favicon.ico",
+            get(move || ready(favicon_response(favicon))),
+        )
+        .route("/params", post(inspect_params))
         .route("/params/*imagorpath", get(params))
+        .merge(admin)
+        .route(
+            "/process",
+            post(process)
+                .layer(process_body_limit(state.limits.max_source_bytes))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_api_key,
+                )),
+        )
+        .route("/capabilities", get(capabilities))
         .route_layer(middleware::from_fn(track_metrics))
         .nest(
             "/",
             Router::new()
                 .route("/*imagorpath", get(handler))
+                .route("/preset/*preset", get(preset))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     cache_middleware,
-                )),
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_api_key,
+                ))
+                .route_layer(middleware::from_fn(range_middleware)),
         )
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
@@ -164,158 +456,931 @@ where
                     .extensions()
                     .get::<MatchedPath>()
                     .map(MatchedPath::as_str);
+                let tenant = request.extensions().get::<Tenant>().map(Tenant::name);
+                let request_id = REQUEST_ID.try_with(String::clone).ok();
 
                 info_span!(
                     "http_request",
                     method = ?request.method(),
                     matched_path,
+                    tenant,
+                    request_id,
                     some_other_field = tracing::field::Empty,
                 )
             }),
         )
-        // .layer(
-        //     ServiceBuilder::new()
-        //         .layer(HandleErrorLayer::new(|err: BoxError| async move {
-        //             (
-        //                 StatusCode::INTERNAL_SERVER_ERROR,
-        //                 format!("Unhandled error: {}", err),
-        //             )
-        //         }))
-        //         .layer(BufferLayer::new(1024))
-        //         .layer(RateLimitLayer::new(50, Duration::from_secs(1))),
-        // )
-        .with_state(state);
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit_middleware,
+        ))
+        // authenticate first, so keys get their own rate limits
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
+        .with_state(state.clone());
+    // outside the response cache, which keeps bodies as they are
+    let app = if compression.enabled {
+        app.layer(compression_layer(&compression))
+    } else {
+        app
+    };
+    // tenants are found before routing, so their path prefixes can be
+    // stripped
+    let app = Router::new()
+        .fallback_service(middleware::from_fn_with_state(state, tenant_middleware).layer(app))
+        .layer(middleware::from_fn(request_id));
 
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    let server = axum::serve(listener, app);
+    if let Some(tls) = tls {
+        return Ok(Box::pin(tls::serve(listener, app, tls)));
+    }
 
-    Ok(server)
+    // client addresses are needed to rate limit by IP
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    );
+
+    Ok(Box::pin(server.into_future()))
+}
+
+/// Uploads can be as large as sources may be, `0` being no limit
+fn process_body_limit(max_source_bytes: u64) -> DefaultBodyLimit {
+    match usize::try_from(max_source_bytes) {
+        Ok(0) | Err(_) => DefaultBodyLimit::disable(),
+        // room for the params and the form around the image
+        Ok(max) => DefaultBodyLimit::max(max.saturating_add(64 * 1024)),
+    }
 }
 
 #[tracing::instrument(skip(state))]
 async fn handler(
-    State(state): State<AppStateDyn>,
+    TenantState(state): TenantState,
+    headers: HeaderMap,
+    params: Params,
+) -> Result<Response<Body>, ApiError> {
+    serve(&state, &headers, params).await
+}
+
+/// `GET /preset/<name>/<image>`: the image with a configured preset's
+/// params
+#[tracing::instrument(skip(state))]
+async fn preset(
+    TenantState(state): TenantState,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response<Body>, ApiError> {
+    // keep the query string, it belongs to the source image URL
+    let path = uri.path_and_query().map_or(uri.path(), |pq| pq.as_str());
+    let path = path.strip_prefix("/preset/").unwrap_or(path);
+    let params = state.presets.params(path)?;
+
+    serve(&state, &headers, params).await
+}
+
+/// [`render`], with the script's response hook and the fallback image
+async fn serve(
+    state: &AppStateDyn,
+    headers: &HeaderMap,
+    params: Params,
+) -> Result<Response<Body>, ApiError> {
+    let error = match render(state, headers, params.clone()).await {
+        Ok(mut response) => {
+            if let Some(script) = &state.script {
+                if let Err(e) = script.on_response(response.headers_mut(), headers) {
+                    warn!("{}", e);
+                }
+            }
+            return Ok(response);
+        }
+        Err(e) => e,
+    };
+
+    let fallback = state.fallback_image.source.as_deref();
+    let Some(fallback) = fallback.filter(|_| serves_fallback(error.status())) else {
+        return Err(error);
+    };
+    warn!(
+        "Serving the fallback image in place of {:?}: {}",
+        params.image, error
+    );
+    fallback_response(state, fallback, &params, error.status())
+        .await
+        .inspect_err(|e| warn!("Failed to serve the fallback image: {}", e))
+        .or(Err(error))
+}
+
+/// `POST /process`: params as JSON, or a multipart form with the params as
+/// JSON in a `params` field and the source image in an `image` field. The
+/// params aren't signed, so they need an API key or unsafe URLs enabled.
+#[tracing::instrument(skip(state, api_key, request))]
+async fn process(
+    TenantState(state): TenantState,
+    api_key: Option<Extension<ApiKey>>,
+    request: Request,
+) -> Result<Response<Body>, ApiError> {
+    if api_key.is_none() && !state.allow_unsafe {
+        return Err(ApiError::forbidden(
+            "Processing params needs an API key or unsafe URLs enabled",
+        ));
+    }
+
+    let headers = request.headers().clone();
+    let multipart = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+    let (mut params, upload) = if multipart {
+        let multipart = Multipart::from_request(request, &state).await?;
+        process_form(multipart).await?
+    } else {
+        let Json(params) = Json::<Params>::from_request(request, &state).await?;
+        (params, None)
+    };
+    // already authorized, params copied from `/params` may still say how
+    // their URL was
+    params.unsafe_ = false;
+    params.hash = None;
+    params.path = None;
+
+    match upload {
+        Some(upload) => render_upload(&state, &headers, params, upload).await,
+        None => render(&state, &headers, params).await,
+    }
+}
+
+/// The params and uploaded image of a `POST /process` form
+async fn process_form(mut multipart: Multipart) -> Result<(Params, Option<Blob>), ApiError> {
+    let mut params = Params::default();
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("params") => {
+                let json = field.text().await?;
+                params = serde_json::from_str(&json)
+                    .map_err(|e| ApiError::bad_request(format!("Invalid params: {}", e)))?;
+            }
+            Some("image") => {
+                let declared = field.content_type().map(str::to_string);
+                let data = field.bytes().await?;
+                upload = Some(Blob::typed(data.to_vec(), declared.as_deref()));
+            }
+            _ => {}
+        }
+    }
+
+    Ok((params, upload))
+}
+
+/// Like [`render`] for an uploaded source. There's no key to find the
+/// result by again, so result storage is left alone and nothing is cached.
+async fn render_upload(
+    state: &AppStateDyn,
+    headers: &HeaderMap,
+    params: Params,
+    upload: Blob,
+) -> Result<Response<Body>, ApiError> {
+    check_source_size(upload.data.len() as u64, state.limits.max_source_bytes).map_err(|e| {
+        ApiError::Request {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            detail: format!("Failed to read image: {}", e),
+        }
+    })?;
+    let params = state
+        .dimensions
+        .apply(params.with_defaults(&state.defaults))
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let params = params
+        .with_negotiated_format(accept)
+        .canonicalized(&state.defaults);
+
+    let blob = if params.filters.contains(&Filter::Raw) || serves_source(state, &params) {
+        state
+            .processor
+            .check_raw(&upload)
+            .map_err(|e| ApiError::Processing {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                detail: format!("Failed to serve image: {}", e),
+            })?;
+        upload
+    } else {
+        let assets = fetch_assets(state, &params).await;
+        let (blob, _) =
+            process_blob(state, Arc::new(upload), params, Arc::new(assets), None).await?;
+        blob
+    };
+
+    // the upload may have come back as it was sent
+    no_script(Response::builder())
+        .header(header::CONTENT_TYPE, blob.content_type.as_str())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(blob.data))
+        .map_err(ApiError::from)
+}
+
+/// Missing sources and failed processing, not requests that are refused
+fn serves_fallback(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::NOT_FOUND
+            | StatusCode::UNSUPPORTED_MEDIA_TYPE
+            | StatusCode::UNPROCESSABLE_ENTITY
+    ) || status.is_server_error()
+}
+
+/// The fallback image, resized like the image that failed. It's never
+/// cached, so the real image is served once it can be.
+async fn fallback_response(
+    state: &AppStateDyn,
+    source: &str,
+    params: &Params,
+    status: StatusCode,
+) -> Result<Response<Body>, ApiError> {
+    let mut blob = fetch_blob(state, source).await?;
+
+    let resized = params.width.unwrap_or(0) > 0 || params.height.unwrap_or(0) > 0;
+    if state.fallback_image.resize && resized {
+        let resize = Params {
+            width: params.width,
+            height: params.height,
+            fit: params.fit,
+            adaptive_fit_in: params.adaptive_fit_in,
+            full_fit_in: params.full_fit_in,
+            ..Default::default()
+        };
+        let processor = state.processor.clone();
+        blob = task::spawn_blocking(move || processor.process(&blob, &resize, &Assets::default()))
+            .await
+            .map_err(|e| ApiError::internal(format!("joining spawned task failed: {}", e)))?
+            .map_err(|e| ApiError::Processing {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                detail: format!("Failed to process image: {}", e),
+            })?;
+    }
+
+    let status = state
+        .fallback_image
+        .status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(status);
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, blob.content_type.as_str())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(blob.data))
+        .map_err(ApiError::from)
+}
+
+/// Whether the source can be served as-is for `params`: they ask for the
+/// image and nothing else, and results don't drop metadata it may carry
+pub(crate) fn serves_source(state: &AppStateDyn, params: &Params) -> bool {
+    params.is_identity() && !state.processor.strips_metadata()
+}
+
+/// Paths need a valid signature unless they're `unsafe/` ones, or unsafe
+/// URLs are enabled. Params without a path were authorized where they came
+/// from.
+fn check_signature(state: &AppStateDyn, params: &Params) -> Result<(), ApiError> {
+    let Some(path) = &params.path else {
+        return Ok(());
+    };
+    let hash = match &params.hash {
+        Some(hash) => hash,
+        None if params.unsafe_ || state.allow_unsafe => return Ok(()),
+        None => return Err(ApiError::forbidden("URL needs a signature")),
+    };
+
+    // the signature covers everything after it
+    let signed = path
+        .trim_start_matches('/')
+        .strip_prefix(hash.as_str())
+        .unwrap_or_default()
+        .trim_start_matches('/');
+    if state.signer.verify(signed, hash) {
+        Ok(())
+    } else {
+        Err(ApiError::forbidden("Invalid signature"))
+    }
+}
+
+pub(crate) async fn render(
+    state: &AppStateDyn,
+    headers: &HeaderMap,
     params: Params,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Result<Response<Body>, ApiError> {
+    let params = params.with_defaults(&state.defaults);
     info!("params: {:?}", params);
 
-    if let (Some(hash), Some(path)) = (&params.hash, &params.path) {
-        verify_hash(hash.to_owned().into(), path.to_owned().into()).map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("Failed to verify hash: {}", e),
-            )
-        })?;
+    if params.unsafe_ && !state.allow_unsafe {
+        return Err(ApiError::forbidden("Unsafe URLs are disabled"));
+    }
+    check_signature(state, &params)?;
+
+    let params = match &state.script {
+        Some(script) => script.on_params(params, headers).map_err(|e| {
+            warn!("{}", e);
+            ApiError::internal(e.to_string())
+        })?,
+        None => params,
+    };
+    let params = state
+        .dimensions
+        .apply(params)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    // the source as it is, only checked against the size limits
+    if params.filters.contains(&Filter::Raw) {
+        let img = params
+            .image
+            .as_ref()
+            .ok_or_else(|| ApiError::bad_request("Image parameter is missing"))?;
+        let blob = fetch_blob(state, img).await?;
+        state
+            .processor
+            .check_raw(&blob)
+            .map_err(|e| ApiError::Processing {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                detail: format!("Failed to serve image: {}", e),
+            })?;
+        return original_response(blob);
     }
 
+    let auto_format = params.has_auto_format();
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let params = params
+        .with_negotiated_format(accept)
+        .canonicalized(&state.defaults);
+    let vary = |response: axum::http::response::Builder| {
+        if auto_format {
+            response.header(header::VARY, "Accept")
+        } else {
+            response
+        }
+    };
+
     // TODO: check result bucket for image and serve if found
     let params_hash = suffix_result_storage_hasher(&params);
+    match state.storage.redirect_url(&params_hash).await {
+        Ok(Some(url)) => {
+            // the signed URL expires, so the redirect mustn't outlive it
+            return vary(Response::builder())
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, url)
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::empty())
+                .map_err(ApiError::from);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to sign a URL for [{}]: {}", params_hash, e),
+    }
     let result = state.storage.get(&params_hash).await.inspect_err(|_| {
         tracing::info!("no image in results storage: {}", &params);
     });
     if let Ok(blob) = result {
-        return Response::builder()
-            .header(header::CONTENT_TYPE, blob.content_type)
+        // file storage only keeps the bytes, and text can't be sniffed back
+        let content_type = match params.data_output() {
+            Some(output) if ContentType::sniff(&blob.data).is_none() => {
+                ContentType::from_static(output.content_type())
+            }
+            _ => blob.content_type,
+        };
+        return vary(Response::builder())
+            .header(header::CONTENT_TYPE, content_type.as_str())
             .body(Body::from(blob.data))
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to build response: {}", e),
-                )
-            });
+            .map_err(ApiError::from);
     }
 
     // if image is not in cache, fetch image
-    let img = params.image.as_ref().ok_or((
-        StatusCode::BAD_REQUEST,
-        "Image parameter is missing".to_string(),
-    ))?;
-
-    // TODO: add config in the config to allow/disallow fetching images from the internet
-    let blob = if img.starts_with("https://") || img.starts_with("http://") {
-        let raw_bytes = reqwest::get(img)
+    let img = params
+        .image
+        .as_ref()
+        .ok_or_else(|| ApiError::bad_request("Image parameter is missing"))?;
+
+    // nothing to do, serve the source as-is rather than re-encoding it
+    if serves_source(state, &params) {
+        let blob = fetch_blob(state, img).await?;
+        return original_response(blob);
+    }
+
+    // it panicked moments ago, rendering it again would too
+    let quarantine_key = match &state.tenant {
+        Some(tenant) => format!("{}:{}", tenant.name(), params_hash),
+        None => params_hash.clone(),
+    };
+    if let Some(error) = state.quarantine.get(&quarantine_key) {
+        metrics::counter!("quarantined_requests_total").increment(1);
+        return Err(ApiError::Processing {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            detail: error,
+        });
+    }
+
+    // fetch the source image and any overlay assets concurrently
+    let (blob, assets) = tokio::join!(fetch_blob(state, img), fetch_assets(state, &params));
+    let blob = blob?;
+
+    // it would come out the same size and format, not worth re-encoding
+    if state.processor.is_noop(&blob, &params) {
+        metrics::counter!("renders_skipped_total").increment(1);
+        return original_response(blob);
+    }
+
+    let source = img.clone();
+    let (blob, assets) = (Arc::new(blob), Arc::new(assets));
+    // rendered from the same source once this request is done
+    let prefetch = state
+        .prefetch
+        .clone()
+        .map(|prefetch| (prefetch, params.clone(), blob.clone(), assets.clone()));
+
+    let meta = ResultMeta::new(&params);
+    let (mut blob, elapsed) =
+        process_blob(state, blob, params, assets, Some(quarantine_key)).await?;
+    // replaces anything carried over from the source
+    blob.metadata.custom = meta.with_duration(elapsed).to_custom();
+
+    if let Some(stats) = &state.stats {
+        let _ = stats
+            .record(&source, &params_hash, elapsed)
             .await
-            .map_err(|e| {
-                (
-                    StatusCode::NOT_FOUND,
-                    format!("Failed to fetch image: {}", e),
-                )
-            })?
-            .bytes()
+            .inspect_err(|e| warn!("Failed to record processing stats: {}", e));
+    }
+
+    // saved after responding when there's room in the queue
+    let queued = state
+        .write_behind
+        .as_ref()
+        .is_some_and(|write_behind| write_behind.enqueue(params_hash.clone(), blob.clone()));
+    if !queued {
+        state.storage.put(&params_hash, &blob).await.map_err(|e| {
+            warn!("Failed to save result image [{}]: {}", &params_hash, e);
+            ApiError::internal(format!("Failed to save result image: {}", e))
+        })?;
+    }
+
+    if let Some((prefetch, params, source, assets)) = prefetch {
+        prefetch.prefetch(
+            state.storage.clone(),
+            state.processor.clone(),
+            &params,
+            source,
+            assets,
+        );
+    }
+
+    vary(Response::builder())
+        .header(header::CONTENT_TYPE, blob.content_type.as_str())
+        .body(Body::from(blob.data))
+        .map_err(ApiError::from)
+}
+
+/// Process `blob` on a blocking thread, within the processing deadline.
+/// Returns the result and how long processing took. A render that panics
+/// is quarantined under `quarantine_key`.
+async fn process_blob(
+    state: &AppStateDyn,
+    blob: Arc<Blob>,
+    params: Params,
+    assets: Arc<Assets>,
+    quarantine_key: Option<String>,
+) -> Result<(Blob, Duration), ApiError> {
+    let processor = state.processor.clone();
+    // held until the blocking task is done, even if the request isn't
+    let reservation = if state.memory.is_limited() {
+        reserve_memory(&state.memory, processor.decoded_size(&blob, &params)).await?
+    } else {
+        None
+    };
+    // the blocking task stops at its next stage once the deadline passes or
+    // the client goes away
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    let task = task::spawn_blocking({
+        let cancel = cancel.clone();
+        move || {
+            let _reservation = reservation;
+            // Perform CPU-intensive operation
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                processor.process_cancellable(&blob, &params, &assets, &cancel)
+            }))
+            .unwrap_or_else(|payload| {
+                Err(ProcessError::Panicked(panic_message(payload.as_ref())).into())
+            });
+            (result, start.elapsed())
+        }
+    });
+    let joined = match state.limits.processing_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, task).await.map_err(|_| {
+            cancel.cancel();
+            metrics::counter!("processing_timeouts_total").increment(1);
+            ApiError::Processing {
+                status: StatusCode::REQUEST_TIMEOUT,
+                detail: format!("Processing took longer than {:?}", timeout),
+            }
+        })?,
+        None => task.await,
+    };
+    joined
+        .map(|(result, elapsed)| result.map(|blob| (blob, elapsed)))
+        .map_err(|e| ApiError::internal(format!("joining spawned task failed: {}", e)))?
+        .map_err(|e| {
+            let status = match e.downcast_ref::<ProcessError>() {
+                Some(ProcessError::DecodeBudgetExceeded(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+                Some(ProcessError::PageOutOfRange { .. }) => StatusCode::BAD_REQUEST,
+                Some(ProcessError::Cancelled) => StatusCode::REQUEST_TIMEOUT,
+                Some(process_error) => SourceFailure::of_process(process_error)
+                    .map_or(StatusCode::INTERNAL_SERVER_ERROR, |failure| {
+                        failure.status(&state.error_statuses)
+                    }),
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let message = format!("Failed to process image: {}", e);
+            if let (
+                Some(ProcessError::FilterPanicked { .. } | ProcessError::Panicked(_)),
+                Some(key),
+            ) = (e.downcast_ref::<ProcessError>(), quarantine_key)
+            {
+                metrics::counter!("render_panics_total").increment(1);
+                state.quarantine.insert(key, message.clone());
+            }
+            ApiError::Processing {
+                status,
+                detail: message,
+            }
+        })
+}
+
+/// Holds what decoding the source takes from the memory budget. Sources
+/// whose size can't be told from their header go ahead without it.
+async fn reserve_memory(
+    memory: &MemoryBudget,
+    bytes: Option<u64>,
+) -> Result<Option<Reservation>, ApiError> {
+    let Some(bytes) = bytes else {
+        return Ok(None);
+    };
+
+    memory.reserve(bytes).await.map(Some).map_err(|e| {
+        let status = match e {
+            MemoryError::OverBudget { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            MemoryError::Busy { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        ApiError::Processing {
+            status,
+            detail: format!("Failed to process image: {}", e),
+        }
+    })
+}
+
+fn original_response(blob: Blob) -> Result<Response<Body>, ApiError> {
+    let mut response = no_script(Response::builder())
+        .header(header::CONTENT_TYPE, blob.content_type.as_str())
+        .header(header::CACHE_CONTROL, ORIGINAL_CACHE_CONTROL);
+    if let Some(etag) = blob.metadata.etag {
+        response = response.header(header::ETAG, etag);
+    }
+
+    response.body(Body::from(blob.data)).map_err(ApiError::from)
+}
+
+/// A source that couldn't be fetched, answered with the status configured
+/// for `failure`
+fn fetch_error(state: &AppStateDyn, failure: SourceFailure, e: impl Display) -> ApiError {
+    ApiError::Upstream {
+        status: failure.status(&state.error_statuses),
+        detail: format!("Failed to fetch image: {}", e),
+    }
+}
+
+fn not_an_image(state: &AppStateDyn, content_type: impl Display) -> ApiError {
+    ApiError::Upstream {
+        status: SourceFailure::UnsupportedMedia.status(&state.error_statuses),
+        detail: format!("Source is not an image: {}", content_type),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn fetch_blob(state: &AppStateDyn, img: &str) -> Result<Blob, ApiError> {
+    if !(img.starts_with("https://") || img.starts_with("http://")) {
+        let blob = state
+            .storage
+            .get_limited(img, state.limits.max_source_bytes)
             .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to fetch image: {}", e),
-                )
-            })?
-            .to_vec();
+            .map_err(|e| fetch_error(state, SourceFailure::of_fetch(&e), e))?;
+        if !is_image(&blob.data, Some(blob.content_type.as_str())) {
+            return Err(not_an_image(state, &blob.content_type));
+        }
+        return Ok(blob);
+    }
+
+    let allowed = match state.tenant.as_ref().and_then(Tenant::allowed_sources) {
+        Some(allowed_sources) => allowed_sources.check(img),
+        None => state.reloader.live().check_source(img),
+    };
+    if let Err(e) = allowed {
+        warn!("{}", e);
+        return Err(e.into());
+    }
+
+    let mut response = state
+        .mirrors
+        .fetch(img)
+        .await
+        .map_err(|e| fetch_error(state, SourceFailure::of_fetch(&e), e))?;
+    // mirrors only stand in for server errors, a missing source is returned
+    // as it is
+    let status = response.status();
+    if !status.is_success() {
+        let e = StatusError {
+            url: img.to_string(),
+            status: status.as_u16(),
+        };
+        return Err(fetch_error(state, SourceFailure::of_status(e.status), e));
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(header::ETAG);
+    let declared_type = header(header::CONTENT_TYPE);
+    let size = response.content_length();
+
+    // streamed so an origin that lies about its size or doesn't say is cut
+    // off at the limit
+    let max_bytes = state.limits.max_source_bytes;
+    let too_large = |e: SourceTooLarge| fetch_error(state, SourceFailure::TooLarge, e);
+    check_source_size(size.unwrap_or(0), max_bytes).map_err(too_large)?;
+    let mut raw_bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| {
+        let e = Report::new(e);
+        fetch_error(state, SourceFailure::of_fetch(&e), e)
+    })? {
+        raw_bytes.extend_from_slice(&chunk);
+        check_source_size(raw_bytes.len() as u64, max_bytes).map_err(too_large)?;
+    }
+    if !is_image(&raw_bytes, declared_type.as_deref()) {
+        return Err(not_an_image(
+            state,
+            declared_type.as_deref().unwrap_or("unknown"),
+        ));
+    }
+
+    // sources of no known type are taken for JPEGs
+    let mut content_type = ContentType::resolve(&raw_bytes, declared_type.as_deref());
+    if content_type.is_octet_stream() {
+        content_type = ContentType::from_static("image/jpeg");
+    }
 
-        let content_type = infer::get(&raw_bytes)
-            .map(|mime| mime.to_string())
-            .unwrap_or("image/jpeg".to_string());
+    Ok(Blob {
+        data: raw_bytes,
+        content_type,
+        metadata: BlobMetadata {
+            origin: Some(img.to_string()),
+            fetched_at: Some(SystemTime::now()),
+            etag,
+            size,
+            ..Default::default()
+        },
+    })
+}
 
-        Blob {
-            data: raw_bytes,
-            content_type,
+#[tracing::instrument(skip(state))]
+async fn fetch_assets(state: &AppStateDyn, params: &Params) -> Assets {
+    let mut set = JoinSet::new();
+    for uri in Assets::required_images(params) {
+        let state = state.clone();
+        set.spawn(async move {
+            let blob = fetch_blob(&state, &uri).await;
+            (uri, blob)
+        });
+    }
+
+    let mut assets = Assets::default();
+    while let Some(joined) = set.join_next().await {
+        match joined {
+            Ok((uri, Ok(blob))) => assets.insert_image(uri, blob),
+            Ok((uri, Err(e))) => warn!("Failed to fetch asset [{}]: {}", uri, e),
+            Err(e) => warn!("Asset fetch task failed: {}", e),
         }
+    }
+
+    assets
+}
+
+#[tracing::instrument(skip(state))]
+async fn params(
+    TenantState(state): TenantState,
+    Host(host): Host,
+    api_key: Option<Extension<ApiKey>>,
+    params: Params,
+) -> Result<Json<Described>, ApiError> {
+    info!("params: {:?}", params);
+
+    // signed like `/sign` would, so only for requests with an API key
+    let authenticated = state.auth.is_some() && api_key.is_some();
+    let signer = (authenticated && state.signer.has_secret()).then_some(&state.signer);
+    Ok(Json(describe(params, &state.defaults, signer, &host)))
+}
+
+#[derive(Serialize)]
+struct SignedUrl {
+    path: String,
+    url: String,
+}
+
+#[tracing::instrument(skip(state))]
+async fn sign(
+    TenantState(state): TenantState,
+    Host(host): Host,
+    Json(params): Json<Params>,
+) -> Result<Json<SignedUrl>, ApiError> {
+    if params.image.is_none() {
+        return Err(ApiError::bad_request("Image parameter is missing"));
+    }
+
+    let path = to_signed_string(&params, state.signer.clone());
+    let url = format!("http://{}/{}", host, path);
+
+    Ok(Json(SignedUrl { path, url }))
+}
+
+#[derive(Deserialize, Debug)]
+struct PurgeRequest {
+    path: String,
+}
+
+/// Result storage key and response cache key for an imagor path
+fn purge_keys(state: &AppStateDyn, path: &str) -> Result<(String, String), ParseError> {
+    let params = parse_params(path)?
+        .with_defaults(&state.defaults)
+        .canonicalized(&state.defaults);
+    let cache_key = format!("GET:/{}", path.trim_start_matches('/'));
+    // as `cache_middleware` keys a tenant's responses
+    let cache_key = match &state.tenant {
+        Some(tenant) => format!("tenant:{}:{}", tenant.name(), cache_key),
+        None => cache_key,
+    };
+
+    Ok((suffix_result_storage_hasher(&params), cache_key))
+}
+
+#[tracing::instrument(skip(state))]
+async fn purge(
+    TenantState(state): TenantState,
+    Json(req): Json<PurgeRequest>,
+) -> Result<StatusCode, ApiError> {
+    let (key, cache_key) = purge_keys(&state, &req.path)?;
+
+    let result = if state.purge.soft_delete {
+        trash::trash(state.storage.as_ref(), &state.purge, &key).await
     } else {
-        state.storage.get(img).await.map_err(|e| {
-            (
-                StatusCode::NOT_FOUND,
-                format!("Failed to fetch image: {}", e),
-            )
-        })?
+        state.storage.delete(&key).await
     };
+    result.map_err(|e| ApiError::not_found(format!("Failed to purge result image: {}", e)))?;
 
-    let blob = task::spawn_blocking(move || {
-        // Perform CPU-intensive operation
-        state.processor.process(&blob, &params)
-    })
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("joining spawned task failed: {}", e),
-        )
-    })?
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to process image: {}", e),
-        )
-    })?;
+    if let Err(e) = state.cache.delete(&cache_key).await {
+        warn!("Failed to purge cached response [{}]: {}", cache_key, e);
+    }
 
-    // TODO: save image to result bucket
-    state.storage.put(&params_hash, &blob).await.map_err(|e| {
-        warn!("Failed to save result image [{}]: {}", &params_hash, e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to save result image: {}", e),
-        )
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+struct PurgePrefixRequest {
+    prefix: String,
+}
+
+#[derive(Serialize)]
+struct PurgedPrefix {
+    purged: usize,
+}
+
+/// Purges every result image stored under a result storage key prefix.
+/// Source images under it are kept, and cached responses for the results
+/// are left to expire.
+#[tracing::instrument(skip(state))]
+async fn purge_prefix(
+    TenantState(state): TenantState,
+    Json(req): Json<PurgePrefixRequest>,
+) -> Result<Json<PurgedPrefix>, ApiError> {
+    // would purge every result there is
+    if req.prefix.is_empty() {
+        return Err(ApiError::bad_request("A prefix is required"));
+    }
+
+    let result = if state.purge.soft_delete {
+        trash::trash_prefix(state.storage.as_ref(), &state.purge, &req.prefix).await
+    } else {
+        trash::delete_prefix(state.storage.as_ref(), &state.purge, &req.prefix).await
+    };
+    let purged = result.map_err(|e| {
+        ApiError::internal(format!(
+            "Failed to purge result images under {}: {}",
+            req.prefix, e
+        ))
     })?;
 
-    Response::builder()
-        .header(header::CONTENT_TYPE, blob.content_type)
-        .body(Body::from(blob.data))
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to build response: {}", e),
-            )
-        })
+    Ok(Json(PurgedPrefix { purged }))
 }
 
-#[tracing::instrument]
-async fn params(params: Params) -> Result<Json<Params>, (StatusCode, String)> {
-    info!("params: {:?}", params);
+#[tracing::instrument(skip(state))]
+async fn restore(
+    TenantState(state): TenantState,
+    Json(req): Json<PurgeRequest>,
+) -> Result<StatusCode, ApiError> {
+    let (key, _) = purge_keys(&state, &req.path)?;
+
+    let status = trash::restore(state.storage.as_ref(), &state.purge, &key)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to restore result image: {}", e)))?;
 
-    Ok(Json(params))
+    match status {
+        RestoreStatus::Restored => Ok(StatusCode::NO_CONTENT),
+        RestoreStatus::NotFound => Err(ApiError::not_found(format!(
+            "No purged result image for {}",
+            req.path
+        ))),
+        RestoreStatus::Expired => Err(ApiError::Request {
+            status: StatusCode::GONE,
+            detail: format!("Retention window for {} has passed", req.path),
+        }),
+    }
 }
 
-#[tracing::instrument]
-async fn root() -> &'static str {
-    "Hello, World"
+#[tracing::instrument(skip(state))]
+async fn reload_config(State(state): State<AppStateDyn>) -> Result<StatusCode, ApiError> {
+    state
+        .reloader
+        .reload()
+        .map_err(|e| ApiError::internal(format!("Failed to reload configuration: {}", e)))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(skip(state))]
+async fn capabilities(State(state): State<AppStateDyn>) -> Json<Capabilities> {
+    Json(state.processor.capabilities())
+}
+
+#[tracing::instrument(skip(state))]
+async fn processing_stats(State(state): State<AppStateDyn>) -> Result<Json<Rollup>, ApiError> {
+    let stats = state
+        .stats
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("Processing stats are disabled"))?;
+
+    let latest = stats
+        .latest_rollup()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read stats rollup: {}", e)))?;
+
+    // before the first periodic rollup has run
+    let rollup = match latest {
+        Some(rollup) => rollup,
+        None => stats
+            .rollup()
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to compute stats rollup: {}", e)))?,
+    };
+
+    Ok(Json(rollup))
+}
+
+fn spawn_stats_rollup(stats: Arc<dyn ImageStats>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = stats.rollup().await {
+                warn!("Failed to roll up processing stats: {}", e);
+            }
+        }
+    });
+}
+
+fn favicon_response(favicon: Option<Vec<u8>>) -> Response<Body> {
+    match favicon {
+        Some(data) => Response::builder()
+            .header(header::CONTENT_TYPE, "image/x-icon")
+            .body(Body::from(data)),
+        None => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty()),
+    }
+    .expect("static response is valid")
 }
 
 #[tracing::instrument]
@@ -323,3 +1388,335 @@ async fn health_check() -> &'static str {
     tracing::info!("Health check called");
     "OK"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::config::{ApiKeySettings, AuthSettings};
+    use crate::imagorpath::generate::Signer;
+    use image::{ImageBuffer, Rgb};
+    use secrecy::SecretString;
+    use tempfile::TempDir;
+
+    /// The API key [`spawn_app`]'s client sends
+    const API_KEY: &str = "testkey";
+
+    /// A server on a random port with a PNG at `img.png`, and unsafe URLs
+    /// disabled
+    struct TestApp {
+        url: String,
+        client: reqwest::Client,
+        signer: HmacSigner,
+        _dir: TempDir,
+        _vips_app: Arc<VipsApp>,
+    }
+
+    impl TestApp {
+        async fn get(&self, path: &str) -> reqwest::Response {
+            self.client
+                .get(format!("{}/{}", self.url, path))
+                .send()
+                .await
+                .unwrap()
+        }
+
+        /// `path` with its signature
+        fn signed(&self, path: &str) -> String {
+            format!("{}/{}", self.signer.sign(path), path)
+        }
+
+        async fn sign(&self, params: &Params) -> String {
+            let response = self
+                .client
+                .post(format!("{}/sign", self.url))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(params).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let signed: serde_json::Value =
+                serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+            signed["path"].as_str().unwrap().to_string()
+        }
+    }
+
+    /// With API keys required, and a client that sends one
+    async fn spawn_app() -> TestApp {
+        spawn_app_with(Settings {
+            auth: AuthSettings {
+                enabled: true,
+                keys: vec![ApiKeySettings {
+                    name: "test".to_string(),
+                    key: SecretString::from(API_KEY.to_string()),
+                    rate_limit: None,
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Like [`spawn_app`] with `config`'s auth and tenants, each tenant with
+    /// its own copy of the PNG
+    async fn spawn_app_with(config: Settings) -> TestApp {
+        let vips_app =
+            Arc::new(VipsApp::new("imagor_rs test", true).expect("Failed to initialize VipsApp"));
+        let dir = TempDir::new().unwrap();
+
+        let storage: Arc<dyn ImageStorage> = Arc::new(FileStorage::new(
+            dir.path().to_path_buf(),
+            String::new(),
+            SafeCharsType::default(),
+        ));
+        let img_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 48, |x, y| Rgb([x as u8 * 4, y as u8 * 5, 128]));
+        let mut png_data = Vec::new();
+        img_buf
+            .write_to(
+                &mut std::io::Cursor::new(&mut png_data),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        let png = Blob::typed(png_data, Some("image/png"));
+        storage.put("img.png", &png).await.unwrap();
+        for tenant in &config.tenants {
+            storage
+                .put(&format!("{}/img.png", tenant.name), &png)
+                .await
+                .unwrap();
+        }
+
+        let processor: Arc<dyn ImageProcessor> =
+            Arc::new(Processor::from_settings(&config.processor));
+        let signer = HmacSigner::new(SecretString::from("mysecret".to_string()));
+        let state = AppStateDyn {
+            storage: storage.clone(),
+            processor: processor.clone(),
+            cache: Arc::new(MemoryCache::new()),
+            signer: signer.clone(),
+            allow_unsafe: false,
+            defaults: config.defaults.clone(),
+            purge: config.purge.clone(),
+            reloader: Reloader::new(&config, processor, vips_app.clone()).unwrap(),
+            mirrors: Mirrors::new(&config.loader).unwrap(),
+            stats: None,
+            prefetch: None,
+            write_behind: None,
+            rate_limit: None,
+            auth: config
+                .auth
+                .enabled
+                .then(|| Auth::from_settings(&config.auth).unwrap()),
+            fallback_image: config.fallback_image.clone(),
+            error_statuses: config.error_statuses,
+            limits: config.limits.clone(),
+            memory: MemoryBudget::new(&config.limits),
+            quarantine: Quarantine::new(Duration::from_secs(config.limits.panic_cooldown_secs)),
+            script: None,
+            presets: Presets::new(&config.presets).unwrap(),
+            dimensions: AllowedDimensions::new(&config.dimensions).unwrap(),
+            tenants: Tenants::new(&config.tenants, storage, None).unwrap(),
+            tenant: None,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = run(listener, state, config.routes, config.compression, None)
+            .await
+            .unwrap();
+        tokio::spawn(server);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "x-api-key",
+            reqwest::header::HeaderValue::from_static(API_KEY),
+        );
+        TestApp {
+            url,
+            client: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()
+                .unwrap(),
+            signer,
+            _dir: dir,
+            _vips_app: vips_app,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_url_round_trip() {
+        let app = spawn_app().await;
+        let params = Params {
+            image: Some("img.png".to_string()),
+            width: Some(32),
+            ..Default::default()
+        };
+
+        let path = app.sign(&params).await;
+        let response = app.get(&path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+
+        // the signature doesn't carry over to other params
+        let (hash, _) = path.split_once('/').unwrap();
+        let response = app.get(&format!("{}/16x0/img.png", hash)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_paths_are_forbidden() {
+        let app = spawn_app().await;
+
+        for path in ["32x0/img.png", "img.png", "unsafe/32x0/img.png"] {
+            let response = app.get(path).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sources_served_as_is_cant_run_script() {
+        let app = spawn_app().await;
+
+        for path in ["img.png", "filters:raw()/img.png"] {
+            let response = app.get(&app.signed(path)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            let headers = response.headers();
+            assert_eq!(
+                headers[header::CONTENT_SECURITY_POLICY],
+                "script-src 'none'",
+                "{}",
+                path
+            );
+            assert_eq!(
+                headers[header::X_CONTENT_TYPE_OPTIONS],
+                "nosniff",
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sources_are_processed_when_stripping_metadata() {
+        let app = spawn_app().await;
+        let source = app.get(&app.signed("img.png")).await.bytes().await.unwrap();
+
+        let mut config = Settings::default();
+        config.processor.strip_metadata = true;
+        let app = spawn_app_with(config).await;
+        let response = app.get(&app.signed("img.png")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.bytes().await.unwrap(), source);
+    }
+
+    #[tokio::test]
+    async fn test_cached_data_keeps_its_content_type() {
+        let app = spawn_app().await;
+
+        for (filters, content_type) in [
+            ("blurhash()", "text/plain; charset=utf-8"),
+            ("thumbhash(json)", "application/json"),
+            ("palette(4)", "application/json"),
+        ] {
+            let path = app.signed(&format!("filters:{}/img.png", filters));
+            let rendered = app.get(&path).await;
+            assert_eq!(rendered.status(), StatusCode::OK);
+            assert_eq!(rendered.headers()[header::CONTENT_TYPE], content_type);
+            let rendered = rendered.bytes().await.unwrap();
+
+            // the second one is answered from the response cache
+            let cached = app.get(&path).await;
+            assert_eq!(cached.status(), StatusCode::OK);
+            assert_eq!(
+                cached.headers()[header::CONTENT_TYPE],
+                content_type,
+                "{}",
+                filters
+            );
+            assert_eq!(cached.bytes().await.unwrap(), rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tenant_paths_are_signed_with_its_secret() {
+        let tenant = serde_json::from_value(serde_json::json!({
+            "name": "acme",
+            "path_prefix": "acme",
+            "hmac_secret": "acme-secret",
+        }))
+        .unwrap();
+        let app = spawn_app_with(Settings {
+            tenants: vec![tenant],
+            ..Default::default()
+        })
+        .await;
+        let acme = HmacSigner::new(SecretString::from("acme-secret".to_string()));
+        let path = "32x0/img.png";
+
+        let response = app.get(&format!("acme/{}/{}", acme.sign(path), path)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.get(&format!("acme/{}", app.signed(path))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_params_are_not_signed_without_an_api_key() {
+        let app = spawn_app().await;
+        let path = "params/unsafe/32x0/img.png";
+
+        let response = reqwest::get(format!("{}/{}", app.url, path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let described: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(described["image"], "img.png");
+        assert!(described["signed_url"].is_null());
+
+        let response = app.get(path).await;
+        let described: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert!(described["signed_url"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_fail_closed() {
+        let app = spawn_app().await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/sign", app.url))
+            .json(&Params::default())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // no keys to check at all
+        let app = spawn_app_with(Settings::default()).await;
+        let response = app
+            .client
+            .post(format!("{}/sign", app.url))
+            .json(&Params::default())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_purge_prefix_needs_a_prefix() {
+        let app = spawn_app().await;
+
+        let response = app
+            .client
+            .post(format!("{}/purge/prefix", app.url))
+            .json(&serde_json::json!({ "prefix": "" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app.get(&app.signed("img.png")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
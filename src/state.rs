@@ -1,5 +1,24 @@
 use crate::{
-    cache::cache::ImageCache, processor::processor::ImageProcessor, storage::storage::ImageStorage,
+    auth::Auth,
+    cache::cache::ImageCache,
+    config::{
+        DefaultsSettings, ErrorStatusSettings, FallbackImageSettings, LimitsSettings, PurgeSettings,
+    },
+    dimensions::AllowedDimensions,
+    imagorpath::signer::HmacSigner,
+    memory::MemoryBudget,
+    mirror::Mirrors,
+    prefetch::Prefetcher,
+    presets::Presets,
+    processor::processor::ImageProcessor,
+    quarantine::Quarantine,
+    ratelimit::limiter::RateLimit,
+    reload::Reloader,
+    script::Script,
+    stats::stats::ImageStats,
+    storage::storage::ImageStorage,
+    tenant::{Tenant, Tenants},
+    writebehind::WriteBehind,
 };
 use std::sync::Arc;
 
@@ -8,4 +27,30 @@ pub struct AppStateDyn {
     pub storage: Arc<dyn ImageStorage>,
     pub processor: Arc<dyn ImageProcessor>,
     pub cache: Arc<dyn ImageCache>,
+    pub signer: HmacSigner,
+    pub allow_unsafe: bool,
+    pub defaults: DefaultsSettings,
+    pub purge: PurgeSettings,
+    pub reloader: Reloader,
+    pub mirrors: Mirrors,
+    pub stats: Option<Arc<dyn ImageStats>>,
+    pub prefetch: Option<Prefetcher>,
+    /// `None` saves results before responding
+    pub write_behind: Option<WriteBehind>,
+    /// `None` when requests aren't rate limited
+    pub rate_limit: Option<RateLimit>,
+    /// `None` when the processing endpoints don't require an API key
+    pub auth: Option<Auth>,
+    pub fallback_image: FallbackImageSettings,
+    pub error_statuses: ErrorStatusSettings,
+    pub limits: LimitsSettings,
+    pub memory: MemoryBudget,
+    pub quarantine: Quarantine,
+    /// `None` when no script is configured
+    pub script: Option<Script>,
+    pub presets: Presets,
+    pub dimensions: AllowedDimensions,
+    pub tenants: Tenants,
+    /// The tenant the request belongs to, `None` for the server's own
+    pub tenant: Option<Tenant>,
 }
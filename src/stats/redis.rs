@@ -0,0 +1,109 @@
+use super::stats::{ImageStats, Rollup, SourceStats};
+use crate::imagorpath::hasher::source_digest;
+use axum::async_trait;
+use color_eyre::Result;
+use redis::AsyncCommands;
+use redis::Client;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BY_CPU_KEY: &str = "stats:by_cpu";
+const ROLLUP_KEY: &str = "stats:rollup";
+
+#[derive(Debug, Clone)]
+pub struct RedisStats {
+    client: Client,
+    rollup_size: usize,
+}
+
+impl RedisStats {
+    pub fn new(redis_url: &str, rollup_size: usize) -> Result<Self> {
+        let client = Client::open(redis_url)?;
+        Ok(RedisStats {
+            client,
+            rollup_size,
+        })
+    }
+
+    async fn get_connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(Into::into)
+    }
+}
+
+fn source_key(digest: &str) -> String {
+    format!("stats:source:{}", digest)
+}
+
+fn variants_key(digest: &str) -> String {
+    format!("stats:variants:{}", digest)
+}
+
+#[async_trait]
+impl ImageStats for RedisStats {
+    #[tracing::instrument(skip(self))]
+    async fn record(&self, source: &str, variant: &str, cpu: Duration) -> Result<()> {
+        let digest = source_digest(source);
+        let cpu_ms = cpu.as_millis() as u64;
+        let mut conn = self.get_connection().await?;
+
+        redis::pipe()
+            .hset(source_key(&digest), "source", source)
+            .ignore()
+            .hincr(source_key(&digest), "requests", 1)
+            .ignore()
+            .hincr(source_key(&digest), "cpu_ms", cpu_ms)
+            .ignore()
+            .sadd(variants_key(&digest), variant)
+            .ignore()
+            .zincr(BY_CPU_KEY, &digest, cpu_ms)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn rollup(&self) -> Result<Rollup> {
+        let mut conn = self.get_connection().await?;
+        let digests: Vec<String> = conn
+            .zrevrange(BY_CPU_KEY, 0, self.rollup_size.saturating_sub(1) as isize)
+            .await?;
+
+        let mut sources = Vec::with_capacity(digests.len());
+        for digest in digests {
+            let fields: HashMap<String, String> = conn.hgetall(source_key(&digest)).await?;
+            let variants: u64 = conn.scard(variants_key(&digest)).await?;
+            sources.push(SourceStats::from_fields(&digest, &fields, variants));
+        }
+
+        let rollup = Rollup {
+            generated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            sources,
+        };
+        conn.set::<_, _, ()>(ROLLUP_KEY, serde_json::to_string(&rollup)?)
+            .await?;
+
+        Ok(rollup)
+    }
+
+    async fn latest_rollup(&self) -> Result<Option<Rollup>> {
+        let mut conn = self.get_connection().await?;
+        let data: Option<String> = conn.get(ROLLUP_KEY).await?;
+
+        data.map(|d| serde_json::from_str(&d))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let mut conn = self.get_connection().await?;
+        redis::cmd("PING")
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,85 @@
+use axum::async_trait;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Per-source processing counters, used to find sources that are expensive
+/// to serve (e.g. a huge PNG requested in dozens of variants)
+#[async_trait]
+pub trait ImageStats: Send + Sync {
+    async fn record(&self, source: &str, variant: &str, cpu: Duration) -> Result<()>;
+
+    /// Compute the top sources by CPU time and store the snapshot
+    async fn rollup(&self) -> Result<Rollup>;
+
+    async fn latest_rollup(&self) -> Result<Option<Rollup>>;
+
+    async fn ping(&self) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SourceStats {
+    pub digest: String,
+    pub source: String,
+    pub requests: u64,
+    pub variants: u64,
+    pub cpu_ms: u64,
+}
+
+impl SourceStats {
+    pub fn from_fields(digest: &str, fields: &HashMap<String, String>, variants: u64) -> Self {
+        let number = |key: &str| {
+            fields
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default()
+        };
+
+        Self {
+            digest: digest.to_string(),
+            source: fields.get("source").cloned().unwrap_or_default(),
+            requests: number("requests"),
+            variants,
+            cpu_ms: number("cpu_ms"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rollup {
+    /// Unix timestamp in seconds
+    pub generated_at: u64,
+    pub sources: Vec<SourceStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_stats_from_fields() {
+        let fields = HashMap::from([
+            ("source".to_string(), "big.png".to_string()),
+            ("requests".to_string(), "40".to_string()),
+            ("cpu_ms".to_string(), "12000".to_string()),
+        ]);
+
+        assert_eq!(
+            SourceStats::from_fields("abc", &fields, 12),
+            SourceStats {
+                digest: "abc".to_string(),
+                source: "big.png".to_string(),
+                requests: 40,
+                variants: 12,
+                cpu_ms: 12000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_source_stats_from_missing_fields() {
+        let stats = SourceStats::from_fields("abc", &HashMap::new(), 0);
+        assert_eq!((stats.requests, stats.cpu_ms), (0, 0));
+    }
+}
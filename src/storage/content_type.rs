@@ -0,0 +1,142 @@
+use crate::imagorpath::filter::ImageType;
+use mime::Mime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// A blob's media type. Parsed, so a `charset` or other parameter, or a
+/// type in another case, doesn't stop it being told apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType(Mime);
+
+impl ContentType {
+    pub fn parse(s: &str) -> Option<Self> {
+        s.trim().parse().ok().map(ContentType)
+    }
+
+    /// Panics when `s` isn't a media type, like `HeaderValue::from_static`
+    pub fn from_static(s: &'static str) -> Self {
+        Self::parse(s).unwrap_or_else(|| panic!("invalid content type: {}", s))
+    }
+
+    /// What `data` looks like, when that can be told from its first bytes
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        infer::get(data).and_then(|kind| Self::parse(kind.mime_type()))
+    }
+
+    /// The type an origin or storage backend `declared` for `data`, sniffed
+    /// from the bytes when there's none or it only says octet-stream
+    pub fn resolve(data: &[u8], declared: Option<&str>) -> Self {
+        match declared.and_then(Self::parse) {
+            Some(declared) if !declared.is_octet_stream() => declared,
+            _ => Self::sniff(data).unwrap_or_default(),
+        }
+    }
+
+    /// Type and subtype, lowercase and without parameters
+    pub fn essence(&self) -> &str {
+        self.0.essence_str()
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.0
+            .get_param(mime::CHARSET)
+            .map(|charset| charset.as_str())
+    }
+
+    /// As declared, parameters included
+    pub fn as_str(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.0.type_() == mime::IMAGE
+    }
+
+    /// Says no more than that it's bytes
+    pub fn is_octet_stream(&self) -> bool {
+        matches!(
+            self.essence(),
+            "application/octet-stream" | "binary/octet-stream"
+        )
+    }
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        ContentType(mime::APPLICATION_OCTET_STREAM)
+    }
+}
+
+impl From<ImageType> for ContentType {
+    fn from(format: ImageType) -> Self {
+        ContentType::parse(&format.to_content_type()).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl PartialEq<str> for ContentType {
+    fn eq(&self, other: &str) -> bool {
+        self.0.as_ref() == other
+    }
+}
+
+impl PartialEq<&str> for ContentType {
+    fn eq(&self, other: &&str) -> bool {
+        self.0.as_ref() == *other
+    }
+}
+
+impl Serialize for ContentType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        ContentType::parse(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid content type: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parameters_and_case() {
+        let svg = ContentType::parse("Image/SVG+XML; charset=utf-8").unwrap();
+        assert_eq!(svg.essence(), "image/svg+xml");
+        assert_eq!(svg.charset(), Some("utf-8"));
+        assert!(svg.is_image());
+
+        assert_eq!(ContentType::parse("not a type"), None);
+        assert_eq!(ContentType::default(), "application/octet-stream");
+        assert_eq!(ContentType::from(ImageType::WEBP), "image/webp");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(ContentType::resolve(png, None), "image/png");
+        assert_eq!(
+            ContentType::resolve(png, Some("binary/octet-stream")),
+            "image/png"
+        );
+        // what was stored wins over what the bytes look like
+        assert_eq!(
+            ContentType::resolve(b"<svg/>", Some("image/svg+xml")),
+            "image/svg+xml"
+        );
+        assert_eq!(
+            ContentType::resolve(b"???", Some("nonsense")),
+            "application/octet-stream"
+        );
+    }
+}
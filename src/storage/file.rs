@@ -1,50 +1,189 @@
-use crate::imagorpath::normalize::{normalize, SafeCharsType};
-use crate::storage::storage::{Blob, ImageStorage};
+use crate::imagorpath::hasher::digest_shard;
+use crate::pathutil::normalize::{denormalize, normalize, SafeCharsType};
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::{
+    check_source_size, normalize_prefix, Blob, BlobMetadata, ImageStorage, Stat,
+};
 use axum::async_trait;
-use color_eyre::Result;
-use std::fs;
+use color_eyre::{Report, Result};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
+use std::fs::FileTimes;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::warn;
+
+/// Names of files being written, skipped when listing
+const TEMP_PREFIX: &str = ".imagor-tmp-";
+
+/// Names of the files an object's custom metadata is kept in, beside it,
+/// skipped when listing
+const META_PREFIX: &str = ".imagor-meta-";
+
+/// Enough of a file for `infer` to recognise any format it knows
+const SNIFF_LEN: usize = 8192;
 
 #[derive(Debug, Clone)]
 pub struct FileStorage {
     pub base_dir: PathBuf,
     pub path_prefix: String,
     pub safe_chars: SafeCharsType,
+    /// Objects are kept under two levels of directories named from a hash of
+    /// their key, see [`digest_shard`]
+    pub shard: bool,
+    pub fsync: bool,
 }
 
 #[async_trait]
 impl ImageStorage for FileStorage {
-    #[tracing::instrument(skip(self))]
     async fn get(&self, key: &str) -> Result<Blob> {
+        self.get_limited(key, 0).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
         let full_path = self.get_full_path(key);
-        let mut file = File::open(full_path).await?;
+        let mut file = File::open(&full_path).await?;
+        let size = file.metadata().await?.len();
+        check_source_size(size, max_bytes)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).await?;
-        Ok(Blob::new(buffer))
+        Ok(Blob::new(buffer).with_metadata(BlobMetadata {
+            origin: Some(key.to_string()),
+            fetched_at: Some(SystemTime::now()),
+            etag: None,
+            size: Some(size),
+            custom: read_custom(&full_path).await,
+        }))
+    }
+
+    /// The content type is sniffed from the start of the file
+    #[tracing::instrument(skip(self))]
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        let full_path = self.get_full_path(key);
+        let mut file = match File::open(&full_path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = file.metadata().await?;
+
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        (&mut file)
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await?;
+        // put the access time back, so looking isn't counted as a use
+        if let Ok(accessed) = metadata.accessed() {
+            let file = file.into_std().await;
+            let _ = file.set_times(FileTimes::new().set_accessed(accessed));
+        }
+
+        Ok(Some(Stat {
+            size: metadata.len(),
+            etag: None,
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+            content_type: ContentType::sniff(&head),
+            custom: read_custom(&full_path).await,
+        }))
     }
 
+    /// Written to a temporary file that's renamed into place, so a crash
+    /// never leaves a partial object behind. Custom metadata is kept in a
+    /// JSON sidecar, written first.
     #[tracing::instrument(skip(self, blob))]
     async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
         let full_path = self.get_full_path(key);
-        if let Some(parent) = full_path.parent() {
-            fs::create_dir_all(parent)?;
+        let dir = full_path.parent().unwrap_or(&self.base_dir);
+        tokio::fs::create_dir_all(dir).await?;
+
+        let sidecar = sidecar_path(&full_path);
+        if blob.metadata.custom.is_empty() {
+            remove_if_exists(&sidecar).await?;
+        } else {
+            let json = serde_json::to_vec(&blob.metadata.custom)?;
+            self.replace(dir, &sidecar, &json).await?;
+        }
+        self.replace(dir, &full_path, blob.as_ref()).await?;
+
+        if self.fsync {
+            // the rename is only durable once the directory is synced
+            File::open(dir).await?.sync_all().await?;
         }
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(full_path)
-            .await?;
-        file.write_all(blob.as_ref()).await?;
         Ok(())
     }
 
     #[tracing::instrument(skip(self))]
     async fn delete(&self, key: &str) -> Result<()> {
         let full_path = self.get_full_path(key);
-        tokio::fs::remove_file(full_path).await?;
+        tokio::fs::remove_file(&full_path).await?;
+        remove_if_exists(&sidecar_path(&full_path)).await?;
+        Ok(())
+    }
+
+    /// Walks the directories under `prefix`, one directory per page
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let root = self.base_dir.join(Path::new(&self.path_prefix));
+        let prefix = normalize_prefix(prefix, &self.safe_chars);
+        // only the directory the prefix ends in needs walking, unless keys
+        // are spread over shards
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) if !self.shard => root.join(dir),
+            _ => root.clone(),
+        };
+        let shard_depth = if self.shard { 2 } else { 0 };
+
+        stream::try_unfold(vec![start], move |mut dirs| {
+            let root = root.clone();
+            async move {
+                let Some(dir) = dirs.pop() else {
+                    return Ok(None);
+                };
+                let mut entries = match tokio::fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some((vec![], dirs))),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let mut keys = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    let path = entry.path();
+                    if entry.file_type().await?.is_dir() {
+                        dirs.push(path);
+                    } else if is_internal(&entry.file_name().to_string_lossy()) {
+                        continue;
+                    } else if let Ok(relative) = path.strip_prefix(&root) {
+                        let name = relative
+                            .components()
+                            .skip(shard_depth)
+                            .map(|c| c.as_os_str().to_string_lossy())
+                            .collect::<Vec<_>>()
+                            .join("/");
+                        // left over from before sharding was turned on
+                        if !name.is_empty() {
+                            keys.push(name);
+                        }
+                    }
+                }
+                Ok::<_, Report>(Some((keys, dirs)))
+            }
+        })
+        .map_ok(|keys| stream::iter(keys).map(Ok::<_, Report>))
+        .try_flatten()
+        .try_filter(move |name| futures::future::ready(name.starts_with(&prefix)))
+        .map_ok(|name| denormalize(&name, &self.safe_chars))
+        .boxed()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<()> {
+        // the base dir is otherwise only created on the first put
+        tokio::fs::create_dir_all(&self.base_dir).await?;
         Ok(())
     }
 }
@@ -55,13 +194,250 @@ impl FileStorage {
             base_dir,
             path_prefix,
             safe_chars,
+            shard: false,
+            fsync: false,
         }
     }
 
+    pub fn with_sharding(self, shard: bool) -> Self {
+        FileStorage { shard, ..self }
+    }
+
+    pub fn with_fsync(self, fsync: bool) -> Self {
+        FileStorage { fsync, ..self }
+    }
+
     pub fn get_full_path(&self, key: &str) -> PathBuf {
         let safe_key = normalize(key, &self.safe_chars);
-        self.base_dir
-            .join(Path::new(&self.path_prefix))
-            .join(safe_key)
+        let dir = self.base_dir.join(Path::new(&self.path_prefix));
+        if self.shard {
+            dir.join(digest_shard(&safe_key)).join(safe_key)
+        } else {
+            dir.join(safe_key)
+        }
+    }
+
+    /// Writes `path` in `dir` through a temporary file
+    async fn replace(&self, dir: &Path, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let temp_path = dir.join(format!("{}{:016x}", TEMP_PREFIX, rand::random::<u64>()));
+        let written = self.write(&temp_path, data).await;
+        let renamed = match written {
+            Ok(()) => tokio::fs::rename(&temp_path, path).await,
+            Err(e) => Err(e),
+        };
+        if renamed.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        renamed
+    }
+
+    async fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await?;
+        file.write_all(data).await?;
+        // tokio finishes writes in the background unless flushed
+        file.flush().await?;
+        if self.fsync {
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Files kept beside objects that aren't objects themselves
+fn is_internal(name: &str) -> bool {
+    name.starts_with(TEMP_PREFIX) || name.starts_with(META_PREFIX)
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}.json", META_PREFIX, name))
+}
+
+/// The custom metadata of the object at `path`, none when it has no sidecar
+/// or the sidecar can't be read
+async fn read_custom(path: &Path) -> BTreeMap<String, String> {
+    let read = tokio::fs::read(sidecar_path(path)).await;
+    let parsed = match read {
+        Ok(json) => serde_json::from_slice(&json).map_err(Report::from),
+        Err(e) if e.kind() == ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => Err(e.into()),
+    };
+    parsed.unwrap_or_else(|e| {
+        warn!("Failed to read the metadata of {}: {}", path.display(), e);
+        BTreeMap::new()
+    })
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        removed => removed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::storage::SourceTooLarge;
+
+    #[tokio::test]
+    async fn test_get_limited() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-file-{}", std::process::id()));
+        let storage = FileStorage::new(base_dir.clone(), String::new(), SafeCharsType::Default);
+        storage
+            .put("img.png", &Blob::new(vec![0; 100]))
+            .await
+            .unwrap();
+
+        assert!(storage.get_limited("img.png", 100).await.is_ok());
+        assert!(storage.get_limited("img.png", 0).await.is_ok());
+        let err = storage.get_limited("img.png", 99).await.unwrap_err();
+        assert!(err.downcast_ref::<SourceTooLarge>().is_some());
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stat() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-stat-{}", std::process::id()));
+        let storage = FileStorage::new(base_dir.clone(), String::new(), SafeCharsType::Default);
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        storage
+            .put("img.png", &Blob::new(png.clone()))
+            .await
+            .unwrap();
+
+        let stat = storage.stat("img.png").await.unwrap().unwrap();
+        assert_eq!(stat.size, png.len() as u64);
+        assert_eq!(stat.content_type.unwrap(), "image/png");
+        assert!(stat.modified.is_some());
+        // the sniffing read doesn't count as an access
+        let again = storage.stat("img.png").await.unwrap().unwrap();
+        assert_eq!(again.accessed, stat.accessed);
+        assert_eq!(storage.stat("missing.png").await.unwrap(), None);
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_and_delete_prefix() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-list-{}", std::process::id()));
+        let storage = FileStorage::new(
+            base_dir.clone(),
+            "result".to_string(),
+            SafeCharsType::Default,
+        );
+        for key in ["a/1.png", "a/b/2.png", "ab/3.png", "c d.png"] {
+            storage.put(key, &Blob::new(vec![0])).await.unwrap();
+        }
+
+        let mut keys: Vec<String> = storage.list("a/").try_collect().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a/1.png", "a/b/2.png"]);
+        let mut keys: Vec<String> = storage.list("a").try_collect().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a/1.png", "a/b/2.png", "ab/3.png"]);
+        assert_eq!(
+            storage
+                .list("")
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(storage
+            .list("missing/")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(storage.delete_prefix("a/").await.unwrap(), 2);
+        let mut keys: Vec<String> = storage.list("").try_collect().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["ab/3.png", "c d.png"]);
+        assert!(storage.get(&keys[1]).await.is_ok());
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_replaces_atomically() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-put-{}", std::process::id()));
+        let storage = FileStorage::new(base_dir.clone(), String::new(), SafeCharsType::Default)
+            .with_fsync(true);
+        storage
+            .put("img.png", &Blob::new(vec![1; 10]))
+            .await
+            .unwrap();
+        storage
+            .put("img.png", &Blob::new(vec![2; 5]))
+            .await
+            .unwrap();
+
+        assert_eq!(storage.get("img.png").await.unwrap().data, vec![2; 5]);
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_custom_metadata() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-meta-{}", std::process::id()));
+        let storage = FileStorage::new(base_dir.clone(), String::new(), SafeCharsType::Default);
+        let custom = BTreeMap::from([("imagor-path".to_string(), "unsafe/a.png".to_string())]);
+        let blob = Blob::new(vec![1]).with_metadata(BlobMetadata {
+            custom: custom.clone(),
+            ..Default::default()
+        });
+        storage.put("a.png", &blob).await.unwrap();
+
+        assert_eq!(storage.get("a.png").await.unwrap().metadata.custom, custom);
+        assert_eq!(storage.stat("a.png").await.unwrap().unwrap().custom, custom);
+        let keys: Vec<String> = storage.list("").try_collect().await.unwrap();
+        assert_eq!(keys, ["a.png"]);
+
+        // saved again without any, the old metadata goes
+        storage.put("a.png", &Blob::new(vec![2])).await.unwrap();
+        assert!(storage
+            .get("a.png")
+            .await
+            .unwrap()
+            .metadata
+            .custom
+            .is_empty());
+        storage.put("a.png", &blob).await.unwrap();
+        storage.delete("a.png").await.unwrap();
+        assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sharding() {
+        let base_dir = std::env::temp_dir().join(format!("imagor-rs-shard-{}", std::process::id()));
+        let storage = FileStorage::new(
+            base_dir.clone(),
+            "result".to_string(),
+            SafeCharsType::Default,
+        )
+        .with_sharding(true);
+        for key in ["a/1.png", "a/2.png", "b.png"] {
+            storage.put(key, &Blob::new(vec![0])).await.unwrap();
+        }
+
+        assert_eq!(
+            storage.get_full_path("b.png"),
+            base_dir
+                .join("result")
+                .join(digest_shard("b.png"))
+                .join("b.png")
+        );
+        assert!(storage.get_full_path("b.png").exists());
+        let mut keys: Vec<String> = storage.list("a/").try_collect().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a/1.png", "a/2.png"]);
+        std::fs::remove_dir_all(&base_dir).unwrap();
     }
 }
@@ -1,12 +1,23 @@
-use crate::imagorpath::normalize::{normalize, SafeCharsType};
-use crate::storage::storage::{Blob, ImageStorage};
+use crate::config::GCSSettings;
+use crate::pathutil::normalize::{denormalize, normalize, SafeCharsType};
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::{normalize_prefix, Blob, BlobMetadata, ImageStorage, Stat};
 use axum::async_trait;
-use color_eyre::Result;
+use color_eyre::{Report, Result};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use google_cloud_storage::client::google_cloud_auth::credentials::CredentialsFile;
 use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::buckets::get::GetBucketRequest;
 use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
 use google_cloud_storage::http::objects::download::Range;
 use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::list::ListObjectsRequest;
 use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::objects::Object;
+use google_cloud_storage::sign::SignedURLOptions;
+use secrecy::ExposeSecret;
+use std::time::{Duration, SystemTime};
 
 #[derive(Clone)]
 pub struct GCloudStorage {
@@ -15,34 +26,92 @@ pub struct GCloudStorage {
     pub safe_chars: SafeCharsType,
     pub client: Client,
     pub bucket: String,
-    // pub expiration: time::Duration,
+    /// Objects at least this large are redirected to, 0 never redirects
+    pub redirect_min_bytes: u64,
+    pub signed_url_ttl: Duration,
     // pub acl: String,
 }
 
 #[async_trait]
 impl ImageStorage for GCloudStorage {
+    /// The object's metadata is read first, for the content type it was
+    /// saved with
     #[tracing::instrument(skip(self))]
     async fn get(&self, key: &str) -> Result<Blob> {
-        let full_path = self.get_full_path(key);
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: self.get_full_path(key),
+            ..Default::default()
+        };
+        let object = self.client.get_object(&request).await?;
         let buffer = self
             .client
             .download_object(
                 &GetObjectRequest {
-                    bucket: self.bucket.clone(),
-                    object: full_path,
-                    ..Default::default()
+                    generation: Some(object.generation),
+                    ..request
                 },
                 &Range::default(),
             )
             .await?;
 
-        Ok(Blob::new(buffer))
+        let size = buffer.len() as u64;
+        let blob = Blob::typed(buffer, object.content_type.as_deref());
+        Ok(blob.with_metadata(BlobMetadata {
+            origin: Some(key.to_string()),
+            fetched_at: Some(SystemTime::now()),
+            etag: Some(object.etag),
+            size: Some(size),
+            custom: object.metadata.unwrap_or_default().into_iter().collect(),
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        let object = match self
+            .client
+            .get_object(&GetObjectRequest {
+                bucket: self.bucket.clone(),
+                object: self.get_full_path(key),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(object) => object,
+            Err(google_cloud_storage::http::Error::Response(e)) if e.code == 404 => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Stat {
+            size: object.size as u64,
+            etag: Some(object.etag),
+            modified: object.updated.map(SystemTime::from),
+            accessed: None,
+            content_type: object.content_type.as_deref().and_then(ContentType::parse),
+            custom: object.metadata.unwrap_or_default().into_iter().collect(),
+        }))
     }
 
     #[tracing::instrument(skip(self, blob))]
     async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
         let full_path = self.get_full_path(key);
-        let upload_type = UploadType::Simple(Media::new(full_path));
+        // served as the object's type when redirected to
+        let content_type = blob.content_type.to_string();
+        // custom metadata needs the object resource sent along
+        let upload_type = if blob.metadata.custom.is_empty() {
+            let mut media = Media::new(full_path);
+            media.content_type = content_type.into();
+            UploadType::Simple(media)
+        } else {
+            UploadType::Multipart(Box::new(Object {
+                name: full_path,
+                content_type: Some(content_type),
+                metadata: Some(blob.metadata.custom.clone().into_iter().collect()),
+                ..Default::default()
+            }))
+        };
         let blob_data = blob.data.clone();
         self.client
             .upload_object(
@@ -70,29 +139,111 @@ impl ImageStorage for GCloudStorage {
             .await?;
         Ok(())
     }
+
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let root = format!("{}/", self.path_prefix);
+        let request = ListObjectsRequest {
+            bucket: self.bucket.clone(),
+            prefix: Some(format!(
+                "{}{}",
+                root,
+                normalize_prefix(prefix, &self.safe_chars)
+            )),
+            ..Default::default()
+        };
+
+        // `None` once the last page has been read
+        stream::try_unfold(Some(request), move |request| async move {
+            let Some(mut request) = request else {
+                return Ok(None);
+            };
+            let page = self.client.list_objects(&request).await?;
+            let names: Vec<String> = page
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .map(|object| object.name)
+                .collect();
+            request.page_token = page.next_page_token;
+            Ok::<_, Report>(Some((
+                names,
+                request.page_token.is_some().then_some(request),
+            )))
+        })
+        .map_ok(|names| stream::iter(names).map(Ok::<_, Report>))
+        .try_flatten()
+        .map_ok(move |name| {
+            denormalize(name.strip_prefix(&root).unwrap_or(&name), &self.safe_chars)
+        })
+        .boxed()
+    }
+
+    /// A signed URL, valid for `signed_url_ttl`
+    #[tracing::instrument(skip(self))]
+    async fn redirect_url(&self, key: &str) -> Result<Option<String>> {
+        if self.redirect_min_bytes == 0 {
+            return Ok(None);
+        }
+        match self.stat(key).await? {
+            Some(stat) if stat.size >= self.redirect_min_bytes => {}
+            _ => return Ok(None),
+        }
+
+        let url = self
+            .client
+            .signed_url(
+                &self.bucket,
+                &self.get_full_path(key),
+                None,
+                None,
+                SignedURLOptions {
+                    expires: self.signed_url_ttl,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(Some(url))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<()> {
+        self.client
+            .get_bucket(&GetBucketRequest {
+                bucket: self.bucket.clone(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
 }
 
 impl GCloudStorage {
-    #[tracing::instrument]
+    #[tracing::instrument(skip(settings))]
     pub async fn new(
         base_dir: String,
         path_prefix: String,
         safe_chars: SafeCharsType,
-        bucket: String,
-        // expiration: time::Duration,
-        // acl: String,
-    ) -> Self {
-        let config = ClientConfig::default().with_auth().await.unwrap();
+        settings: &GCSSettings,
+    ) -> Result<Self> {
+        let config = match credentials(settings.credentials.expose_secret()).await? {
+            Some(credentials) => {
+                ClientConfig::default()
+                    .with_credentials(credentials)
+                    .await?
+            }
+            None => ClientConfig::default().with_auth().await?,
+        };
         let client = Client::new(config);
-        GCloudStorage {
+        Ok(GCloudStorage {
             base_dir,
             path_prefix,
             safe_chars,
             client,
-            bucket,
+            bucket: settings.bucket.clone(),
+            redirect_min_bytes: settings.redirect_min_bytes,
+            signed_url_ttl: Duration::from_secs(settings.signed_url_ttl_secs),
             // acl,
-            // expiration,
-        }
+        })
     }
 
     pub fn get_full_path(&self, key: &str) -> String {
@@ -100,3 +251,53 @@ impl GCloudStorage {
         format!("{}/{}", self.path_prefix, safe_key)
     }
 }
+
+/// A service account key given as JSON, or as the path of a file holding
+/// it. `None` when there's neither, for Application Default Credentials.
+async fn credentials(credentials: &str) -> Result<Option<CredentialsFile>> {
+    let credentials = credentials.trim();
+    if credentials.is_empty() {
+        Ok(None)
+    } else if credentials.starts_with('{') {
+        Ok(Some(CredentialsFile::new_from_str(credentials).await?))
+    } else {
+        Ok(Some(
+            CredentialsFile::new_from_file(credentials.to_string()).await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = r#"{"type": "service_account", "client_email": "imagor@project.iam.gserviceaccount.com", "project_id": "project"}"#;
+
+    #[tokio::test]
+    async fn test_credentials_json() {
+        let key = credentials(KEY).await.unwrap().unwrap();
+        assert_eq!(key.tp, "service_account");
+        assert_eq!(key.project_id.as_deref(), Some("project"));
+        assert!(credentials("{not json").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_credentials_file() {
+        let path = std::env::temp_dir().join(format!("imagor-rs-gcs-{}.json", std::process::id()));
+        std::fs::write(&path, KEY).unwrap();
+
+        let key = credentials(path.to_str().unwrap()).await.unwrap().unwrap();
+        assert_eq!(
+            key.client_email.as_deref(),
+            Some("imagor@project.iam.gserviceaccount.com")
+        );
+        assert!(credentials("/nonexistent/key.json").await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_application_default_credentials() {
+        assert!(credentials("").await.unwrap().is_none());
+        assert!(credentials("  ").await.unwrap().is_none());
+    }
+}
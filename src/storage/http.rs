@@ -0,0 +1,422 @@
+use crate::config::HttpStorageSettings;
+use crate::pathutil::normalize::{denormalize, normalize, SafeCharsType};
+use crate::storage::content_type::ContentType;
+use crate::storage::storage::{
+    check_source_size, normalize_prefix, Blob, BlobMetadata, ImageStorage, Stat,
+};
+use axum::async_trait;
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use color_eyre::{eyre::eyre, Report, Result};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use secrecy::{ExposeSecret, SecretString};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+use url::Url;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+#[derive(Clone)]
+enum Auth {
+    None,
+    Basic {
+        username: String,
+        password: SecretString,
+    },
+    Bearer(SecretString),
+}
+
+/// Storage on a plain HTTP file server or a WebDAV share. Objects are read
+/// with GET. When `writable`, they're saved with PUT and deleted with DELETE,
+/// creating the collections WebDAV needs with MKCOL; otherwise results
+/// aren't saved. Listing needs WebDAV.
+#[derive(Clone)]
+pub struct HttpStorage {
+    pub base_url: String,
+    pub path_prefix: String,
+    pub safe_chars: SafeCharsType,
+    pub client: reqwest::Client,
+    pub writable: bool,
+    auth: Auth,
+}
+
+#[async_trait]
+impl ImageStorage for HttpStorage {
+    async fn get(&self, key: &str) -> Result<Blob> {
+        self.get_limited(key, 0).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
+        let mut response = self
+            .request(Method::GET, &self.url(key))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let size = content_length(response.headers());
+        check_source_size(size.unwrap_or(0), max_bytes)?;
+        let etag = header_str(response.headers(), header::ETAG);
+        let content_type = header_str(response.headers(), header::CONTENT_TYPE);
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            check_source_size(data.len() as u64, max_bytes)?;
+        }
+
+        let blob = Blob::typed(data, content_type.as_deref());
+        Ok(blob.with_metadata(BlobMetadata {
+            origin: Some(key.to_string()),
+            fetched_at: Some(SystemTime::now()),
+            etag,
+            size,
+            ..Default::default()
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        let response = self.request(Method::HEAD, &self.url(key)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let headers = response.headers();
+
+        Ok(Some(Stat {
+            // a HEAD response has no body for reqwest to take the length of
+            size: content_length(headers).unwrap_or_default(),
+            etag: header_str(headers, header::ETAG),
+            modified: header_str(headers, header::LAST_MODIFIED)
+                .and_then(|date| httpdate::parse_http_date(&date).ok()),
+            accessed: None,
+            content_type: header_str(headers, header::CONTENT_TYPE)
+                .and_then(|content_type| ContentType::parse(&content_type)),
+            ..Default::default()
+        }))
+    }
+
+    #[tracing::instrument(skip(self, blob))]
+    async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
+        if !self.writable {
+            debug!("HTTP storage is read-only, not saving [{}]", key);
+            return Ok(());
+        }
+
+        let url = self.url(key);
+        let put = || {
+            self.request(Method::PUT, &url)
+                .header(header::CONTENT_TYPE, blob.content_type.as_str())
+                .body(blob.data.clone())
+                .send()
+        };
+        let mut response = put().await?;
+        // WebDAV won't create the collections a new object goes in
+        if response.status() == StatusCode::CONFLICT {
+            self.make_collections(key).await?;
+            response = put().await?;
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete(&self, key: &str) -> Result<()> {
+        if !self.writable {
+            return Err(eyre!("HTTP storage is read-only, can't delete {}", key));
+        }
+        self.request(Method::DELETE, &self.url(key))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Walks the collections under `prefix` with PROPFIND, one collection per
+    /// page
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let prefix = normalize_prefix(prefix, &self.safe_chars);
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/", dir),
+            None => String::new(),
+        };
+
+        stream::try_unfold(vec![start], move |mut collections| async move {
+            let Some(collection) = collections.pop() else {
+                return Ok(None);
+            };
+            let response = self
+                .request(propfind(), &self.collection_url(&collection))
+                .header("Depth", "1")
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY)
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(Some((vec![], collections)));
+            }
+            if response.status() == StatusCode::METHOD_NOT_ALLOWED {
+                return Err(eyre!("Listing HTTP storage needs a WebDAV server"));
+            }
+            let body = response.error_for_status()?.text().await?;
+
+            let mut names = Vec::new();
+            for (href, is_collection) in parse_multistatus(&body)? {
+                let Some(name) = self.name(&href) else {
+                    continue;
+                };
+                // the collection itself is listed too
+                if name.is_empty() || name == collection {
+                    continue;
+                }
+                if is_collection {
+                    collections.push(format!("{}/", name.trim_end_matches('/')));
+                } else {
+                    names.push(name);
+                }
+            }
+            Ok::<_, Report>(Some((names, collections)))
+        })
+        .map_ok(|names| stream::iter(names).map(Ok::<_, Report>))
+        .try_flatten()
+        .try_filter(move |name| futures::future::ready(name.starts_with(&prefix)))
+        .map_ok(|name| denormalize(&name, &self.safe_chars))
+        .boxed()
+    }
+
+    /// Any answer short of a server error will do, the base URL itself
+    /// needn't be servable
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<()> {
+        let response = self
+            .request(Method::HEAD, &self.collection_url(""))
+            .send()
+            .await?;
+        if response.status().is_server_error() {
+            response.error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpStorage {
+    pub fn new(
+        path_prefix: String,
+        safe_chars: SafeCharsType,
+        settings: &HttpStorageSettings,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()?;
+        let auth = if !settings.bearer_token.expose_secret().is_empty() {
+            Auth::Bearer(settings.bearer_token.clone())
+        } else if !settings.username.is_empty() {
+            Auth::Basic {
+                username: settings.username.clone(),
+                password: settings.password.clone(),
+            }
+        } else {
+            Auth::None
+        };
+
+        Ok(HttpStorage {
+            base_url: settings.base_url.trim_end_matches('/').to_string(),
+            path_prefix: path_prefix.trim_matches('/').to_string(),
+            safe_chars,
+            client,
+            writable: settings.writable,
+            auth,
+        })
+    }
+
+    pub fn url(&self, key: &str) -> String {
+        self.collection_url(&normalize(key, &self.safe_chars))
+    }
+
+    /// The URL of a path already normalized, under the path prefix
+    fn collection_url(&self, path: &str) -> String {
+        if self.path_prefix.is_empty() {
+            format!("{}/{}", self.base_url, path)
+        } else {
+            format!("{}/{}/{}", self.base_url, self.path_prefix, path)
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.auth {
+            Auth::None => request,
+            Auth::Basic { username, password } => {
+                request.basic_auth(username, Some(password.expose_secret()))
+            }
+            Auth::Bearer(token) => request.bearer_auth(token.expose_secret()),
+        }
+    }
+
+    /// MKCOL each collection above `key`, from the top down. Ones that
+    /// already exist answer 405.
+    async fn make_collections(&self, key: &str) -> Result<()> {
+        let path = normalize(key, &self.safe_chars);
+        let mut collection = String::new();
+        for segment in path
+            .split('/')
+            .rev()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .iter()
+            .rev()
+        {
+            collection.push_str(segment);
+            collection.push('/');
+            let response = self
+                .request(mkcol(), &self.collection_url(&collection))
+                .send()
+                .await?;
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                response.error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The name, under the path prefix, of an href in a PROPFIND response.
+    /// Hrefs may be absolute URLs or paths.
+    fn name(&self, href: &str) -> Option<String> {
+        let path = match Url::parse(href) {
+            Ok(url) => url.path().to_string(),
+            Err(_) => href.to_string(),
+        };
+        let root = Url::parse(&self.collection_url("")).ok()?;
+        path.strip_prefix(root.path()).map(str::to_string)
+    }
+}
+
+fn propfind() -> Method {
+    Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method")
+}
+
+fn mkcol() -> Method {
+    Method::from_bytes(b"MKCOL").expect("MKCOL is a valid method")
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    header_str(headers, header::CONTENT_LENGTH).and_then(|len| len.parse().ok())
+}
+
+/// The href of every response in a WebDAV multistatus, and whether it's a
+/// collection
+fn parse_multistatus(body: &str) -> Result<Vec<(String, bool)>> {
+    let document = roxmltree::Document::parse(body)?;
+    let is =
+        |node: &roxmltree::Node, name: &str| node.is_element() && node.tag_name().name() == name;
+
+    Ok(document
+        .descendants()
+        .filter(|node| is(node, "response"))
+        .filter_map(|response| {
+            let href = response
+                .children()
+                .find(|node| is(node, "href"))?
+                .text()?
+                .trim()
+                .to_string();
+            let is_collection = response.descendants().any(|node| is(&node, "collection"));
+            Some((href, is_collection))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(settings: serde_json::Value) -> HttpStorage {
+        let settings = serde_json::from_value(settings).unwrap();
+        HttpStorage::new("images/".to_string(), SafeCharsType::Default, &settings).unwrap()
+    }
+
+    #[test]
+    fn test_urls() {
+        let storage = storage(serde_json::json!({"base_url": "https://files.example.com/dav/"}));
+
+        assert_eq!(
+            storage.url("/a/caf\u{e9}.jpg"),
+            "https://files.example.com/dav/images/a/caf%C3%A9.jpg"
+        );
+        assert_eq!(
+            storage.name("/dav/images/a/caf%C3%A9.jpg").as_deref(),
+            Some("a/caf%C3%A9.jpg")
+        );
+        assert_eq!(
+            storage
+                .name("https://files.example.com/dav/images/a/")
+                .as_deref(),
+            Some("a/")
+        );
+        assert_eq!(storage.name("/elsewhere/a.jpg"), None);
+    }
+
+    #[test]
+    fn test_auth() {
+        let basic = storage(serde_json::json!({
+            "base_url": "https://files.example.com",
+            "username": "imagor",
+            "password": "secret",
+        }));
+        let request = basic
+            .request(Method::GET, &basic.url("a.jpg"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.headers()[header::AUTHORIZATION],
+            "Basic aW1hZ29yOnNlY3JldA=="
+        );
+
+        let bearer = storage(serde_json::json!({
+            "base_url": "https://files.example.com",
+            "username": "ignored",
+            "bearer_token": "token",
+        }));
+        let request = bearer
+            .request(Method::GET, &bearer.url("a.jpg"))
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()[header::AUTHORIZATION], "Bearer token");
+    }
+
+    #[test]
+    fn test_parse_multistatus() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:multistatus xmlns:D="DAV:">
+              <D:response>
+                <D:href>/dav/images/</D:href>
+                <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat>
+              </D:response>
+              <D:response>
+                <D:href>/dav/images/a.jpg</D:href>
+                <D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat>
+              </D:response>
+              <D:response>
+                <D:href>/dav/images/b/</D:href>
+                <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat>
+              </D:response>
+            </D:multistatus>"#;
+
+        assert_eq!(
+            parse_multistatus(body).unwrap(),
+            vec![
+                ("/dav/images/".to_string(), true),
+                ("/dav/images/a.jpg".to_string(), false),
+                ("/dav/images/b/".to_string(), true),
+            ]
+        );
+        assert!(parse_multistatus("<not xml").is_err());
+    }
+}
@@ -1,4 +1,10 @@
+pub mod content_type;
 pub mod file;
 pub mod gcs;
+pub mod http;
+pub mod prefixed;
+pub mod retry;
+pub mod routed;
 pub mod s3;
 pub mod storage;
+pub mod trash;
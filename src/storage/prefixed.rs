@@ -0,0 +1,147 @@
+use crate::storage::storage::{Blob, ImageStorage, Stat};
+use axum::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use futures::future::ready;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+
+/// Keeps every key under `prefix/` of another backend, so tenants sharing
+/// it can't read or overwrite each other's images
+pub struct PrefixedStorage {
+    /// Without a leading `/`, with a trailing one
+    prefix: String,
+    storage: Arc<dyn ImageStorage>,
+}
+
+impl PrefixedStorage {
+    pub fn new(prefix: &str, storage: Arc<dyn ImageStorage>) -> Self {
+        PrefixedStorage {
+            prefix: format!("{}/", prefix.trim_matches('/')),
+            storage,
+        }
+    }
+
+    fn key(&self, key: &str) -> Result<String> {
+        let key = key.trim_start_matches('/');
+        // would climb out of the prefix on a filesystem
+        if key.split('/').any(|segment| segment == "..") {
+            return Err(eyre!("Key [{}] mustn't contain `..`", key));
+        }
+        Ok(format!("{}{}", self.prefix, key))
+    }
+}
+
+#[async_trait]
+impl ImageStorage for PrefixedStorage {
+    async fn get(&self, key: &str) -> Result<Blob> {
+        self.storage.get(&self.key(key)?).await
+    }
+
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
+        self.storage.get_limited(&self.key(key)?, max_bytes).await
+    }
+
+    async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
+        self.storage.put(&self.key(key)?, blob).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.storage.delete(&self.key(key)?).await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        self.storage.stat(&self.key(key)?).await
+    }
+
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let prefix = match self.key(prefix) {
+            Ok(prefix) => prefix,
+            Err(e) => return stream::once(ready(Err(e))).boxed(),
+        };
+        self.storage
+            .list(&prefix)
+            .map_ok(move |key| {
+                let key = key.trim_start_matches('/');
+                key.strip_prefix(&self.prefix).unwrap_or(key).to_string()
+            })
+            .boxed()
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        self.storage.delete_prefix(&self.key(prefix)?).await
+    }
+
+    async fn redirect_url(&self, key: &str) -> Result<Option<String>> {
+        self.storage.redirect_url(&self.key(key)?).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.storage.ping().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathutil::normalize::SafeCharsType;
+    use crate::storage::file::FileStorage;
+
+    #[tokio::test]
+    async fn test_keys_stay_under_prefix() {
+        let dir = std::env::temp_dir().join(format!("imagor-rs-prefixed-{}", std::process::id()));
+        let shared = Arc::new(FileStorage::new(
+            dir.clone(),
+            String::new(),
+            SafeCharsType::Default,
+        ));
+        let acme = PrefixedStorage::new("/acme/", shared.clone());
+        let globex = PrefixedStorage::new("globex", shared.clone());
+
+        acme.put("/cat.jpg", &Blob::new(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        assert_eq!(acme.get("cat.jpg").await.unwrap().data, [1, 2, 3]);
+        assert_eq!(shared.get("acme/cat.jpg").await.unwrap().data, [1, 2, 3]);
+        assert!(globex.get("cat.jpg").await.is_err());
+
+        let keys: Vec<String> = acme.list("").try_collect().await.unwrap();
+        assert_eq!(keys, ["cat.jpg"]);
+        assert_eq!(globex.delete_prefix("").await.unwrap(), 0);
+        assert_eq!(acme.delete_prefix("").await.unwrap(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keys_cannot_climb_out_of_prefix() {
+        let dir = std::env::temp_dir().join(format!("imagor-rs-climb-{}", std::process::id()));
+        let shared = Arc::new(FileStorage::new(
+            dir.clone(),
+            String::new(),
+            SafeCharsType::Default,
+        ));
+        let acme = PrefixedStorage::new("acme", shared.clone());
+        shared
+            .put("globex/cat.jpg", &Blob::new(vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        assert!(acme.get("../globex/cat.jpg").await.is_err());
+        assert!(acme.get("/dogs/../../globex/cat.jpg").await.is_err());
+        assert!(acme.delete("../globex/cat.jpg").await.is_err());
+        assert!(acme
+            .put("../globex/cat.jpg", &Blob::new(vec![4]))
+            .await
+            .is_err());
+        assert!(acme.delete_prefix("../").await.is_err());
+        assert!(acme.list("../").try_next().await.is_err());
+        assert_eq!(shared.get("globex/cat.jpg").await.unwrap().data, [1, 2, 3]);
+
+        // only whole segments count
+        acme.put("cat..jpg", &Blob::new(vec![5])).await.unwrap();
+        assert_eq!(acme.get("cat..jpg").await.unwrap().data, [5]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::RetrySettings;
+use crate::storage::storage::{Blob, ImageStorage, Stat};
+use axum::async_trait;
+use color_eyre::{Report, Result};
+use futures::stream::BoxStream;
+use rand::Rng;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{warn, Instrument};
+
+/// Context on an error that's worth trying again, for failures only the
+/// backend can classify, like an S3 throttling response
+#[derive(Debug)]
+pub struct Retryable;
+
+impl std::fmt::Display for Retryable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transient failure")
+    }
+}
+
+/// An origin that answered, but with an error status
+#[derive(Debug, Error)]
+#[error("{url} responded {status}")]
+pub struct StatusError {
+    pub url: String,
+    pub status: u16,
+}
+
+/// Throttling and server errors can succeed on a later attempt
+pub fn is_retryable_status(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+/// Timeouts, dropped connections, throttling and server errors. Anything
+/// else, like a missing key or a denied request, fails the same way again.
+pub fn is_retryable(report: &Report) -> bool {
+    if report.downcast_ref::<Retryable>().is_some() {
+        return true;
+    }
+
+    report.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|s| is_retryable_status(s.as_u16()));
+        }
+        if let Some(e) = cause.downcast_ref::<StatusError>() {
+            return is_retryable_status(e.status);
+        }
+        if let Some(e) = cause.downcast_ref::<google_cloud_storage::http::Error>() {
+            return match e {
+                google_cloud_storage::http::Error::Response(response) => {
+                    is_retryable_status(response.code)
+                }
+                google_cloud_storage::http::Error::HttpClient(e) => {
+                    e.is_timeout() || e.is_connect()
+                }
+                _ => false,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            );
+        }
+        false
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(&RetrySettings::default())
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            max_attempts: settings.max_attempts.max(1),
+            base_delay: Duration::from_millis(settings.base_delay_ms),
+            max_delay: Duration::from_millis(settings.max_delay_ms),
+            jitter: settings.jitter,
+        }
+    }
+
+    /// Doubles from `base_delay` up to `max_delay`. With jitter it's
+    /// anywhere from half that to all of it, so clients that failed together
+    /// don't all retry together.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter && !delay.is_zero() {
+            let half = delay / 2;
+            half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+        } else {
+            delay
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Run `f` until it succeeds, fails with an error that isn't retryable,
+    /// or runs out of attempts. Each attempt is traced in its own span.
+    pub async fn run<T, F, Fut>(&self, operation: &str, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_if(operation, is_retryable, f).await
+    }
+
+    /// Like [`RetryPolicy::run`], retrying the errors `retryable` picks
+    pub async fn run_if<T, F, Fut>(
+        &self,
+        operation: &str,
+        retryable: impl Fn(&Report) -> bool,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let span = tracing::debug_span!("attempt", operation, attempt);
+            match f().instrument(span).await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts || !retryable(&e) => return Err(e),
+                Err(e) => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "{} failed, attempt {} of {}, retrying in {:?}: {}",
+                        operation, attempt, self.max_attempts, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a storage backend so transient failures are retried
+pub struct RetryingStorage {
+    inner: Arc<dyn ImageStorage>,
+    policy: RetryPolicy,
+}
+
+impl RetryingStorage {
+    pub fn new(inner: Arc<dyn ImageStorage>, policy: RetryPolicy) -> Self {
+        RetryingStorage { inner, policy }
+    }
+}
+
+#[async_trait]
+impl ImageStorage for RetryingStorage {
+    async fn get(&self, key: &str) -> Result<Blob> {
+        self.policy.run("storage get", || self.inner.get(key)).await
+    }
+
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
+        self.policy
+            .run("storage get", || self.inner.get_limited(key, max_bytes))
+            .await
+    }
+
+    async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
+        self.policy
+            .run("storage put", || self.inner.put(key, blob))
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.policy
+            .run("storage delete", || self.inner.delete(key))
+            .await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        self.policy
+            .run("storage stat", || self.inner.stat(key))
+            .await
+    }
+
+    /// Not retried, a page failing part way through would be listed twice
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        self.inner.list(prefix)
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        self.policy
+            .run("storage delete_prefix", || self.inner.delete_prefix(prefix))
+            .await
+    }
+
+    async fn redirect_url(&self, key: &str) -> Result<Option<String>> {
+        self.policy
+            .run("storage redirect_url", || self.inner.redirect_url(key))
+            .await
+    }
+
+    /// Not retried, health checks should see the backend as it is
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(&RetrySettings {
+            max_attempts,
+            base_delay_ms: 1,
+            max_delay_ms: 4,
+            jitter: true,
+        })
+    }
+
+    #[test]
+    fn test_classification() {
+        let timeout = std::io::Error::new(ErrorKind::TimedOut, "timed out");
+        assert!(is_retryable(&Report::new(timeout).wrap_err("get")));
+
+        let missing = std::io::Error::new(ErrorKind::NotFound, "no such file");
+        assert!(!is_retryable(&Report::new(missing)));
+
+        for (status, retryable) in [(503, true), (429, true), (404, false), (403, false)] {
+            let e = Report::new(StatusError {
+                url: "https://example.com/a.jpg".to_string(),
+                status,
+            });
+            assert_eq!(is_retryable(&e), retryable, "{}", status);
+        }
+
+        assert!(is_retryable(&eyre!("throttled").wrap_err(Retryable)));
+        assert!(!is_retryable(&eyre!("bad request")));
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy::new(&RetrySettings {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: false,
+        });
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(8), Duration::from_millis(1000));
+
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt);
+            assert!(delay <= Duration::from_millis(1000));
+            assert!(delay >= Duration::from_millis(50));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_transient_failures() {
+        let calls = AtomicU32::new(0);
+        let result = policy(3)
+            .run("test", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(eyre!("unavailable").wrap_err(Retryable)),
+                    _ => Ok(7),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // gives up after max_attempts
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy(2)
+            .run("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(eyre!("unavailable").wrap_err(Retryable))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // permanent failures aren't retried
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = policy(5)
+            .run("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(eyre!("not found"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
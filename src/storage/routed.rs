@@ -0,0 +1,275 @@
+use crate::storage::storage::{Blob, ImageStorage, Stat};
+use axum::async_trait;
+use color_eyre::{eyre::WrapErr, Result};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tracing::{Instrument, Span};
+
+/// Name the default backend is traced and counted under
+const DEFAULT_BACKEND: &str = "default";
+
+struct Route {
+    /// Without a leading `/`, with a trailing one
+    prefix: String,
+    name: String,
+    storage: Arc<dyn ImageStorage>,
+}
+
+/// Sends each key to the backend routed the longest prefix of it, with the
+/// prefix stripped, or to the default backend. Operations are traced and
+/// counted by the backend that served them.
+pub struct RoutedStorage {
+    routes: Vec<Route>,
+    default: Arc<dyn ImageStorage>,
+}
+
+impl RoutedStorage {
+    pub fn new(default: Arc<dyn ImageStorage>) -> Self {
+        RoutedStorage {
+            routes: Vec::new(),
+            default,
+        }
+    }
+
+    /// Keys under `prefix` go to `storage`. `name` labels it in traces and
+    /// metrics, the prefix does when it's empty.
+    pub fn with_route(mut self, prefix: &str, name: &str, storage: Arc<dyn ImageStorage>) -> Self {
+        let prefix = format!("{}/", prefix.trim_matches('/'));
+        let name = if name.is_empty() {
+            prefix.clone()
+        } else {
+            name.to_string()
+        };
+        self.routes.push(Route {
+            prefix,
+            name,
+            storage,
+        });
+        // longest first
+        self.routes
+            .sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        self
+    }
+
+    fn matching<'a>(&self, key: &'a str) -> Option<(&Route, &'a str)> {
+        let key = key.trim_start_matches('/');
+        self.routes
+            .iter()
+            .find_map(|route| Some((route, key.strip_prefix(&route.prefix)?)))
+    }
+
+    /// The name and backend `key` is routed to, and the key there
+    fn route<'a>(&self, key: &'a str) -> (&str, &dyn ImageStorage, &'a str) {
+        match self.matching(key) {
+            Some((route, key)) => (&route.name, route.storage.as_ref(), key),
+            None => (DEFAULT_BACKEND, self.default.as_ref(), key),
+        }
+    }
+
+    /// Routes listed whole by a listing of `prefix`, i.e. those it's a
+    /// prefix of
+    fn routes_under<'a>(&'a self, prefix: &str) -> impl Iterator<Item = &'a Route> {
+        let prefix = prefix.trim_start_matches('/').to_string();
+        self.routes
+            .iter()
+            .filter(move |route| route.prefix.starts_with(&prefix))
+    }
+}
+
+fn same_route(a: Option<&Route>, b: Option<&Route>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => std::ptr::eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn span(backend: &str, operation: &'static str) -> Span {
+    metrics::counter!(
+        "storage_operations_total",
+        "backend" => backend.to_string(),
+        "operation" => operation
+    )
+    .increment(1);
+    tracing::info_span!("storage", backend, operation)
+}
+
+#[async_trait]
+impl ImageStorage for RoutedStorage {
+    async fn get(&self, key: &str) -> Result<Blob> {
+        let (name, storage, key) = self.route(key);
+        storage.get(key).instrument(span(name, "get")).await
+    }
+
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
+        let (name, storage, key) = self.route(key);
+        storage
+            .get_limited(key, max_bytes)
+            .instrument(span(name, "get"))
+            .await
+    }
+
+    async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
+        let (name, storage, key) = self.route(key);
+        storage.put(key, blob).instrument(span(name, "put")).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let (name, storage, key) = self.route(key);
+        storage.delete(key).instrument(span(name, "delete")).await
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        let (name, storage, key) = self.route(key);
+        storage.stat(key).instrument(span(name, "stat")).await
+    }
+
+    /// The backend holding `prefix`, less keys routed elsewhere, then every
+    /// route nested under the prefix
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let base = self.matching(prefix);
+        let (storage, rest, base_prefix) = match base {
+            Some((route, rest)) => (route.storage.as_ref(), rest, route.prefix.as_str()),
+            None => (self.default.as_ref(), prefix, ""),
+        };
+        let base = base.map(|(route, _)| route);
+
+        let listed = storage
+            .list(rest)
+            .map_ok(move |key| format!("{}{}", base_prefix, key))
+            .try_filter(move |key| {
+                let route = self.matching(key).map(|(route, _)| route);
+                futures::future::ready(same_route(route, base))
+            });
+        let nested = self
+            .routes_under(prefix)
+            .filter(|route| !same_route(Some(route), base))
+            .collect::<Vec<_>>();
+        let nested = stream::iter(nested).flat_map(|route| {
+            route
+                .storage
+                .list("")
+                .map_ok(move |key| format!("{}{}", route.prefix, key))
+        });
+        listed.chain(nested).boxed()
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        let base = self.matching(prefix);
+        let mut deleted = match base {
+            Some((route, rest)) => {
+                route
+                    .storage
+                    .delete_prefix(rest)
+                    .instrument(span(&route.name, "delete_prefix"))
+                    .await?
+            }
+            None => {
+                self.default
+                    .delete_prefix(prefix)
+                    .instrument(span(DEFAULT_BACKEND, "delete_prefix"))
+                    .await?
+            }
+        };
+
+        let base = base.map(|(route, _)| route);
+        for route in self.routes_under(prefix) {
+            if same_route(Some(route), base) {
+                continue;
+            }
+            deleted += route
+                .storage
+                .delete_prefix("")
+                .instrument(span(&route.name, "delete_prefix"))
+                .await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn redirect_url(&self, key: &str) -> Result<Option<String>> {
+        let (name, storage, key) = self.route(key);
+        storage
+            .redirect_url(key)
+            .instrument(span(name, "redirect_url"))
+            .await
+    }
+
+    /// Every backend has to be reachable
+    async fn ping(&self) -> Result<()> {
+        self.default
+            .ping()
+            .await
+            .wrap_err_with(|| format!("{} storage", DEFAULT_BACKEND))?;
+        for route in &self.routes {
+            route
+                .storage
+                .ping()
+                .await
+                .wrap_err_with(|| format!("{} storage", route.name))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathutil::normalize::SafeCharsType;
+    use crate::storage::file::FileStorage;
+
+    fn file_storage(name: &str) -> Arc<FileStorage> {
+        let dir =
+            std::env::temp_dir().join(format!("imagor-rs-routed-{}-{}", name, std::process::id()));
+        Arc::new(FileStorage::new(dir, String::new(), SafeCharsType::Default))
+    }
+
+    async fn sorted(stream: BoxStream<'_, Result<String>>) -> Vec<String> {
+        let mut keys: Vec<String> = stream.try_collect().await.unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_longest_prefix() {
+        let default = file_storage("default");
+        let legacy = file_storage("legacy");
+        let archive = file_storage("archive");
+        let storage = RoutedStorage::new(default.clone())
+            .with_route("/legacy/", "gcs-legacy", legacy.clone())
+            .with_route("legacy/archive", "", archive.clone());
+
+        assert_eq!(storage.route("/legacy/a.jpg").0, "gcs-legacy");
+        assert_eq!(storage.route("legacy/archive/a.jpg").0, "legacy/archive/");
+        assert_eq!(storage.route("legacyish/a.jpg").0, DEFAULT_BACKEND);
+
+        for key in ["/legacy/a.jpg", "legacy/archive/b.jpg", "c.jpg"] {
+            storage.put(key, &Blob::new(vec![1, 2, 3])).await.unwrap();
+        }
+        assert_eq!(sorted(legacy.list("")).await, ["a.jpg"]);
+        assert_eq!(sorted(archive.list("")).await, ["b.jpg"]);
+        assert_eq!(sorted(default.list("")).await, ["c.jpg"]);
+        assert_eq!(
+            storage.get("legacy/archive/b.jpg").await.unwrap().data,
+            [1, 2, 3]
+        );
+
+        assert_eq!(
+            sorted(storage.list("")).await,
+            ["c.jpg", "legacy/a.jpg", "legacy/archive/b.jpg"]
+        );
+        assert_eq!(
+            sorted(storage.list("legacy/")).await,
+            ["legacy/a.jpg", "legacy/archive/b.jpg"]
+        );
+        assert_eq!(
+            sorted(storage.list("legacy/arch")).await,
+            ["legacy/archive/b.jpg"]
+        );
+        assert_eq!(storage.delete_prefix("").await.unwrap(), 3);
+
+        for dir in [default, legacy, archive] {
+            std::fs::remove_dir_all(&dir.base_dir).unwrap();
+        }
+    }
+}
@@ -1,12 +1,30 @@
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
 
-use crate::imagorpath::normalize::{normalize, SafeCharsType};
-use crate::storage::storage::{Blob, ImageStorage};
+use crate::config::S3Settings;
+use crate::pathutil::normalize::{denormalize, normalize, SafeCharsType};
+use crate::storage::content_type::ContentType;
+use crate::storage::retry::{is_retryable_status, Retryable};
+use crate::storage::storage::{
+    check_source_size, normalize_prefix, Blob, BlobMetadata, ImageStorage, Missing, Stat,
+    DELETE_BATCH,
+};
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
+use aws_config::sts::AssumeRoleProvider;
+use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, Delete, ObjectCannedAcl, ObjectIdentifier,
+    ServerSideEncryption, StorageClass,
+};
 use aws_sdk_s3::Client;
 use axum::async_trait;
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Report, Result};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use secrecy::ExposeSecret;
 use tracing::{debug, info, warn};
 
 #[derive(Clone)]
@@ -16,14 +34,77 @@ pub struct S3Storage {
     pub safe_chars: SafeCharsType,
     pub client: Client,
     pub bucket: String,
+    pub upload: UploadOptions,
     // pub expiration: time::Duration,
-    // pub acl: String,
+}
+
+/// S3 takes no smaller part, but for the last
+const MIN_PART_BYTES: u64 = 5 * 1024 * 1024;
+
+/// How new objects are written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UploadOptions {
+    pub storage_class: Option<StorageClass>,
+    pub acl: Option<ObjectCannedAcl>,
+    pub server_side_encryption: Option<ServerSideEncryption>,
+    pub kms_key_id: Option<String>,
+    /// Larger objects are uploaded in parts, 0 never does
+    pub multipart_threshold: u64,
+    pub part_size: u64,
+}
+
+impl UploadOptions {
+    pub fn new(settings: &S3Settings) -> Result<Self> {
+        Ok(UploadOptions {
+            storage_class: known(
+                "storage class",
+                &settings.storage_class,
+                StorageClass::values(),
+            )?,
+            acl: known("ACL", &settings.acl, ObjectCannedAcl::values())?,
+            server_side_encryption: known(
+                "server-side encryption",
+                &settings.server_side_encryption,
+                ServerSideEncryption::values(),
+            )?,
+            kms_key_id: settings.kms_key_id.clone(),
+            multipart_threshold: settings.multipart_threshold_bytes,
+            part_size: settings.multipart_part_bytes.max(MIN_PART_BYTES),
+        })
+    }
+
+    fn is_multipart(&self, size: usize) -> bool {
+        self.multipart_threshold > 0 && size as u64 > self.multipart_threshold
+    }
+}
+
+/// The SDK takes any string, so typos are caught here rather than by S3
+/// refusing every upload
+fn known<T: for<'a> From<&'a str>>(
+    option: &str,
+    value: &Option<String>,
+    values: &[&str],
+) -> Result<Option<T>> {
+    match value.as_deref() {
+        None => Ok(None),
+        Some(value) if values.contains(&value) => Ok(Some(T::from(value))),
+        Some(value) => Err(eyre!(
+            "Unknown S3 {} {:?}, expected one of {}",
+            option,
+            value,
+            values.join(", ")
+        )),
+    }
 }
 
 #[async_trait]
 impl ImageStorage for S3Storage {
-    #[tracing::instrument(skip(self))]
     async fn get(&self, key: &str) -> Result<Blob> {
+        self.get_limited(key, 0).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
         let full_path = self.get_full_path(key);
 
         let output = self
@@ -32,23 +113,72 @@ impl ImageStorage for S3Storage {
             .bucket(&self.bucket)
             .key(full_path)
             .send()
-            .await?;
+            .await
+            .map_err(sdk_error)?;
 
+        let metadata = BlobMetadata {
+            origin: Some(key.to_string()),
+            fetched_at: Some(SystemTime::now()),
+            etag: output.e_tag().map(str::to_string),
+            size: output.content_length().map(|len| len as u64),
+            custom: custom_metadata(output.metadata()),
+        };
+        if let Some(size) = metadata.size {
+            check_source_size(size, max_bytes)?;
+        }
+        let content_type = output.content_type().map(str::to_string);
         let data = output.body.collect().await?.into_bytes();
-        Ok(Blob::new(data.to_vec()))
+        Ok(Blob::typed(data.to_vec(), content_type.as_deref()).with_metadata(metadata))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        let output = match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.get_full_path(key))
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+            Err(e) => return Err(sdk_error(e)),
+        };
+
+        Ok(Some(Stat {
+            size: output.content_length().unwrap_or_default() as u64,
+            etag: output.e_tag().map(str::to_string),
+            modified: output
+                .last_modified()
+                .and_then(|modified| SystemTime::try_from(*modified).ok()),
+            accessed: None,
+            content_type: output.content_type().and_then(ContentType::parse),
+            custom: custom_metadata(output.metadata()),
+        }))
     }
 
     #[tracing::instrument(skip(self, blob))]
     async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
         let full_path = self.get_full_path(key);
+        if self.upload.is_multipart(blob.data.len()) {
+            return self.put_multipart(&full_path, blob).await;
+        }
 
         self.client
             .put_object()
             .bucket(&self.bucket)
             .key(full_path)
             .body(ByteStream::from(blob.data.clone()))
+            .content_type(blob.content_type.as_str())
+            .set_metadata(object_metadata(blob))
+            .set_storage_class(self.upload.storage_class.clone())
+            .set_acl(self.upload.acl.clone())
+            .set_server_side_encryption(self.upload.server_side_encryption.clone())
+            .set_ssekms_key_id(self.upload.kms_key_id.clone())
             .send()
-            .await?;
+            .await
+            .map_err(sdk_error)?;
 
         Ok(())
     }
@@ -62,58 +192,216 @@ impl ImageStorage for S3Storage {
             .bucket(&self.bucket)
             .key(full_path)
             .send()
-            .await?;
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let root = format!("{}/", self.path_prefix);
+        self.list_objects(prefix)
+            .map_ok(move |name| {
+                denormalize(name.strip_prefix(&root).unwrap_or(&name), &self.safe_chars)
+            })
+            .boxed()
+    }
+
+    /// Deletes a page of keys per request
+    #[tracing::instrument(skip(self))]
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut pages = self.list_objects(prefix).try_chunks(DELETE_BATCH);
+        let mut deleted = 0;
+        while let Some(keys) = pages.try_next().await.map_err(|e| e.1)? {
+            delete_objects(&self.client, &self.bucket, &keys).await?;
+            deleted += keys.len();
+        }
+        Ok(deleted)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<()> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(sdk_error)?;
 
         Ok(())
     }
 }
 
+/// Timeouts, dropped connections, throttling and 5xx responses are marked
+/// as worth retrying, and 404s as missing
+/// A blob's custom metadata, as S3 takes it
+fn object_metadata(blob: &Blob) -> Option<HashMap<String, String>> {
+    let custom = &blob.metadata.custom;
+    (!custom.is_empty()).then(|| custom.clone().into_iter().collect())
+}
+
+fn custom_metadata(metadata: Option<&HashMap<String, String>>) -> BTreeMap<String, String> {
+    metadata
+        .map(|metadata| metadata.clone().into_iter().collect())
+        .unwrap_or_default()
+}
+
+pub(crate) fn sdk_error<E>(err: SdkError<E>) -> Report
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let retryable = match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        _ => err
+            .raw_response()
+            .is_some_and(|response| is_retryable_status(response.status().as_u16())),
+    };
+
+    let missing = err
+        .raw_response()
+        .is_some_and(|response| response.status().as_u16() == 404);
+
+    let report = Report::new(err);
+    if retryable {
+        report.wrap_err(Retryable)
+    } else if missing {
+        report.wrap_err(Missing)
+    } else {
+        report
+    }
+}
+
+/// Delete `keys` with as few DeleteObjects requests as it takes
+pub(crate) async fn delete_objects(client: &Client, bucket: &str, keys: &[String]) -> Result<()> {
+    for chunk in keys.chunks(DELETE_BATCH) {
+        let objects = chunk
+            .iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<_>, _>>()?;
+        client
+            .delete_objects()
+            .bucket(bucket)
+            .delete(
+                Delete::builder()
+                    .set_objects(Some(objects))
+                    .quiet(true)
+                    .build()?,
+            )
+            .send()
+            .await
+            .map_err(sdk_error)?;
+    }
+    Ok(())
+}
+
 impl S3Storage {
-    #[tracing::instrument]
+    #[tracing::instrument(skip(settings))]
     pub async fn new(
         base_dir: String,
         path_prefix: String,
         safe_chars: SafeCharsType,
-        endpoint_url: String,
-        bucket: String,
-        region: String,
-        access_key: &str,
-        secret_key: &str,
+        settings: &S3Settings,
     ) -> Result<Self> {
-        // Create custom credentials
-        let credentials = Credentials::new(
-            access_key, secret_key, None, // session token
-            None, // expiry
-            "minio",
-        );
-
-        // Create the config
-        let config = aws_sdk_s3::Config::builder()
-            .behavior_version_latest()
-            .region(Region::new(region))
-            .endpoint_url(&endpoint_url)
-            .credentials_provider(credentials)
-            .force_path_style(true) // This is important for MinIO
-            .build();
-
-        let client = Client::from_conf(config);
+        let upload = UploadOptions::new(settings)?;
+        let client = client(settings).await;
 
         // Wait for MinIO to be ready
         debug!(
             "Waiting for MinIO to be ready... {} - {}",
-            endpoint_url, bucket
+            settings.endpoint, settings.bucket
         );
-        wait_for_minio(&client, 5, Duration::from_secs(2)).await?;
+        wait_for_minio(&client, &settings.bucket, 5, Duration::from_secs(2)).await?;
 
         Ok(S3Storage {
             base_dir,
             path_prefix,
             safe_chars,
             client,
-            bucket,
+            bucket: settings.bucket.clone(),
+            upload,
         })
     }
 
+    /// Uploads `blob` in `part_size` parts, aborting the upload if a part
+    /// fails so the parts already sent aren't kept, and billed, by S3
+    #[tracing::instrument(skip(self, blob))]
+    async fn put_multipart(&self, full_path: &str, blob: &Blob) -> Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(full_path)
+            .content_type(blob.content_type.as_str())
+            .set_metadata(object_metadata(blob))
+            .set_storage_class(self.upload.storage_class.clone())
+            .set_acl(self.upload.acl.clone())
+            .set_server_side_encryption(self.upload.server_side_encryption.clone())
+            .set_ssekms_key_id(self.upload.kms_key_id.clone())
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| eyre!("S3 started a multipart upload without an id"))?;
+
+        let result = self.upload_parts(full_path, upload_id, &blob.data).await;
+        if result.is_err() {
+            let aborted = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(full_path)
+                .upload_id(upload_id)
+                .send()
+                .await;
+            if let Err(e) = aborted {
+                warn!("Failed to abort multipart upload of {}: {}", full_path, e);
+            }
+        }
+        result
+    }
+
+    async fn upload_parts(&self, full_path: &str, upload_id: &str, data: &[u8]) -> Result<()> {
+        let mut parts = Vec::new();
+        for (i, chunk) in data.chunks(self.upload.part_size as usize).enumerate() {
+            let number = i as i32 + 1;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(full_path)
+                .upload_id(upload_id)
+                .part_number(number)
+                .body(ByteStream::from(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(sdk_error)?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(number)
+                    .set_e_tag(part.e_tag().map(str::to_string))
+                    .build(),
+            );
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(full_path)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn ensure_bucket_exists(&self) -> Result<()> {
         let exists = self
@@ -140,15 +428,105 @@ impl S3Storage {
         let safe_key = normalize(key, &self.safe_chars);
         format!("{}/{}", self.path_prefix, safe_key)
     }
+
+    /// Names of the objects under `prefix`, a page per ListObjectsV2 request
+    fn list_objects(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+        let pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(format!(
+                "{}/{}",
+                self.path_prefix,
+                normalize_prefix(prefix, &self.safe_chars)
+            ))
+            .into_paginator()
+            .send();
+
+        stream::try_unfold(pages, |mut pages| async move {
+            let Some(page) = pages.next().await else {
+                return Ok(None);
+            };
+            let names: Vec<String> = page
+                .map_err(sdk_error)?
+                .contents()
+                .iter()
+                .filter_map(|object| object.key().map(str::to_string))
+                .collect();
+            Ok::<_, Report>(Some((names, pages)))
+        })
+        .map_ok(|names| stream::iter(names).map(Ok::<_, Report>))
+        .try_flatten()
+        .boxed()
+    }
 }
 
-async fn wait_for_minio(client: &Client, max_retries: u32, delay: Duration) -> Result<()> {
+pub(crate) async fn client(settings: &S3Settings) -> Client {
+    // Create the config
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version_latest()
+        .region(Region::new(settings.region.clone()))
+        .endpoint_url(&settings.endpoint)
+        .credentials_provider(credentials(settings).await)
+        .force_path_style(settings.force_path_style)
+        .build();
+
+    Client::from_conf(config)
+}
+
+/// The configured keys, or without them the default chain: environment
+/// variables, the shared profile, web identity (IRSA) and the container or
+/// instance role. With `role_arn`, these are only used to assume the role.
+async fn credentials(settings: &S3Settings) -> SharedCredentialsProvider {
+    let region = Region::new(settings.region.clone());
+    let access_key = settings.access_key.expose_secret();
+    let source = if access_key.is_empty() {
+        SharedCredentialsProvider::new(
+            DefaultCredentialsChain::builder()
+                .region(region.clone())
+                .build()
+                .await,
+        )
+    } else {
+        SharedCredentialsProvider::new(Credentials::new(
+            access_key,
+            settings.secret_key.expose_secret(),
+            None, // session token
+            None, // expiry
+            "minio",
+        ))
+    };
+
+    match &settings.role_arn {
+        Some(role_arn) => SharedCredentialsProvider::new(
+            AssumeRoleProvider::builder(role_arn)
+                .session_name(&settings.role_session_name)
+                .region(region)
+                .build_from_provider(source)
+                .await,
+        ),
+        None => source,
+    }
+}
+
+/// Any answer about the bucket will do, so a role allowed only that bucket
+/// is enough. Whether it exists is left to `ensure_bucket_exists`.
+async fn wait_for_minio(
+    client: &Client,
+    bucket: &str,
+    max_retries: u32,
+    delay: Duration,
+) -> Result<()> {
     for i in 0..max_retries {
-        match client.list_buckets().send().await {
+        match client.head_bucket().bucket(bucket).send().await {
             Ok(_) => {
                 info!("Successfully connected to MinIO");
                 return Ok(());
             }
+            Err(e) if e.raw_response().is_some() => {
+                info!("Successfully connected to MinIO");
+                return Ok(());
+            }
             Err(e) => {
                 if i == max_retries - 1 {
                     return Err(e.into());
@@ -167,3 +545,90 @@ async fn wait_for_minio(client: &Client, max_retries: u32, delay: Duration) -> R
         max_retries
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(extra: serde_json::Value) -> S3Settings {
+        let mut settings = serde_json::json!({
+            "region": "us-east-1",
+            "bucket": "images",
+            "access_key": "key",
+            "secret_key": "secret",
+        });
+        settings
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn test_upload_defaults() {
+        let settings = settings(serde_json::json!({}));
+        assert!(settings.force_path_style);
+
+        let upload = UploadOptions::new(&settings).unwrap();
+        assert_eq!(upload.storage_class, None);
+        assert!(!upload.is_multipart(16 * 1024 * 1024));
+        assert!(upload.is_multipart(16 * 1024 * 1024 + 1));
+    }
+
+    #[test]
+    fn test_upload_options() {
+        let upload = UploadOptions::new(&settings(serde_json::json!({
+            "storage_class": "STANDARD_IA",
+            "acl": "public-read",
+            "server_side_encryption": "aws:kms",
+            "kms_key_id": "alias/images",
+            "multipart_threshold_bytes": 0,
+            "multipart_part_bytes": 1024,
+        })))
+        .unwrap();
+
+        assert_eq!(upload.storage_class, Some(StorageClass::StandardIa));
+        assert_eq!(upload.acl, Some(ObjectCannedAcl::PublicRead));
+        assert_eq!(
+            upload.server_side_encryption,
+            Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(upload.part_size, MIN_PART_BYTES);
+        assert!(!upload.is_multipart(usize::MAX));
+    }
+
+    #[test]
+    fn test_unknown_options() {
+        assert!(UploadOptions::new(&settings(serde_json::json!({
+            "storage_class": "STANDARD-IA",
+        })))
+        .is_err());
+        assert!(UploadOptions::new(&settings(serde_json::json!({
+            "server_side_encryption": "kms",
+        })))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_credentials() {
+        use aws_credential_types::provider::ProvideCredentials;
+
+        let provider = credentials(&settings(serde_json::json!({}))).await;
+        let credentials = provider.provide_credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "key");
+        assert_eq!(credentials.secret_access_key(), "secret");
+    }
+
+    #[test]
+    fn test_keys_are_optional() {
+        let settings: S3Settings = serde_json::from_value(serde_json::json!({
+            "region": "us-east-1",
+            "bucket": "images",
+            "role_arn": "arn:aws:iam::123456789012:role/imagor",
+        }))
+        .unwrap();
+
+        assert!(settings.access_key.expose_secret().is_empty());
+        assert_eq!(settings.role_session_name, "imagor-rs");
+    }
+}
@@ -1,24 +1,129 @@
+use crate::pathutil::normalize::{normalize, SafeCharsType};
+use crate::storage::content_type::ContentType;
 use axum::async_trait;
 use color_eyre::Result;
-use infer;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// A source over the configured size limit
+#[derive(Debug, Error)]
+#[error("Source is larger than the {limit} byte limit")]
+pub struct SourceTooLarge {
+    pub limit: u64,
+}
+
+/// Context on an error for a key that isn't there, for backends whose own
+/// errors only say so in their response, like an S3 `NoSuchKey`
+#[derive(Debug)]
+pub struct Missing;
+
+impl std::fmt::Display for Missing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not found")
+    }
+}
+
+/// `max_bytes` of 0 is no limit
+pub fn check_source_size(size: u64, max_bytes: u64) -> Result<(), SourceTooLarge> {
+    if max_bytes > 0 && size > max_bytes {
+        return Err(SourceTooLarge { limit: max_bytes });
+    }
+    Ok(())
+}
+
+/// Keys deleted at a time by `delete_prefix`, the most S3 takes in one request
+pub const DELETE_BATCH: usize = 1000;
+
+/// A listing prefix normalized like the keys it's matched against, keeping
+/// the trailing `/` normalization would otherwise drop
+pub fn normalize_prefix(prefix: &str, safe_chars: &SafeCharsType) -> String {
+    let normalized = normalize(prefix, safe_chars);
+    if prefix.ends_with('/') && !normalized.is_empty() {
+        format!("{}/", normalized)
+    } else {
+        normalized
+    }
+}
 
 #[async_trait]
 pub trait ImageStorage: Send + Sync {
     async fn get(&self, key: &str) -> Result<Blob>;
+
+    /// `get`, failing with [`SourceTooLarge`] for an object over `max_bytes`.
+    /// Backends that know the size up front refuse before reading the object.
+    async fn get_limited(&self, key: &str, max_bytes: u64) -> Result<Blob> {
+        let blob = self.get(key).await?;
+        check_source_size(blob.data.len() as u64, max_bytes)?;
+        Ok(blob)
+    }
+
     async fn put(&self, key: &str, blob: &Blob) -> Result<()>;
     async fn delete(&self, key: &str) -> Result<()>;
+
+    /// What's stored under `key` without reading it, `None` when nothing is
+    async fn stat(&self, key: &str) -> Result<Option<Stat>>;
+
+    /// Keys stored under `prefix`, fetched from the backend a page at a time.
+    /// A prefix ending in `/` only matches whole path segments.
+    fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>>;
+
+    /// Delete everything under `prefix`, returning how many objects went
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut pages = self.list(prefix).try_chunks(DELETE_BATCH);
+        let mut deleted = 0;
+        while let Some(keys) = pages.try_next().await.map_err(|e| e.1)? {
+            for key in &keys {
+                self.delete(key).await?;
+            }
+            deleted += keys.len();
+        }
+        Ok(deleted)
+    }
+
+    /// A URL clients can fetch `key` from themselves, for backends that
+    /// offer one and objects big enough to be worth the redirect
+    async fn redirect_url(&self, _key: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Check the backend is reachable
+    async fn ping(&self) -> Result<()>;
 }
 
-// #[derive(Debug)]
-// pub struct Stat {
-//     pub size: u64,
-//     pub modified: Option<time::SystemTime>,
-// }
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub modified: Option<SystemTime>,
+    /// Last read, where the backend keeps track
+    pub accessed: Option<SystemTime>,
+    pub content_type: Option<ContentType>,
+    /// Custom metadata saved with the object
+    pub custom: BTreeMap<String, String>,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct Blob {
     pub data: Vec<u8>,
-    pub content_type: String,
+    pub content_type: ContentType,
+    pub metadata: BlobMetadata,
+}
+
+/// Where a blob came from and what the origin told us about it.
+#[derive(Debug, Clone, Default)]
+pub struct BlobMetadata {
+    /// Source URL or storage key the blob was loaded from
+    pub origin: Option<String>,
+    pub fetched_at: Option<SystemTime>,
+    pub etag: Option<String>,
+    /// Size of the object as reported by the origin
+    pub size: Option<u64>,
+    /// Saved with the object by backends that keep custom metadata, like the
+    /// [`ResultMeta`](crate::result_meta::ResultMeta) of a result
+    pub custom: BTreeMap<String, String>,
 }
 
 impl AsRef<[u8]> for Blob {
@@ -28,16 +133,39 @@ impl AsRef<[u8]> for Blob {
 }
 
 impl Blob {
+    /// The content type is sniffed from `data`
     pub fn new(data: Vec<u8>) -> Self {
-        let content_type = match infer::get(&data) {
-            Some(kind) => kind.mime_type().to_string(),
-            None => "application/octet-stream".to_string(),
-        };
+        Blob::typed(data, None)
+    }
+
+    /// A blob of the type `declared` by the backend or origin it came from,
+    /// sniffed when there's none
+    pub fn typed(data: Vec<u8>, declared: Option<&str>) -> Self {
+        Blob {
+            content_type: ContentType::resolve(&data, declared),
+            data,
+            metadata: BlobMetadata::default(),
+        }
+    }
 
-        Blob { data, content_type }
+    pub fn with_metadata(self, metadata: BlobMetadata) -> Self {
+        Blob { metadata, ..self }
     }
 
     pub fn supports_animation(&self) -> bool {
-        self.content_type.starts_with("image/gif") || self.content_type.starts_with("image/webp")
+        matches!(self.content_type.essence(), "image/gif" | "image/webp")
+    }
+
+    /// Rasterised by the loader, at a resolution set with `dpi`
+    pub fn is_vector(&self) -> bool {
+        matches!(
+            self.content_type.essence(),
+            "application/pdf" | "image/svg+xml"
+        )
+    }
+
+    /// Loaded as a still frame with `frame` or `seek`
+    pub fn is_video(&self) -> bool {
+        matches!(self.content_type.essence(), "video/mp4" | "video/webm")
     }
 }
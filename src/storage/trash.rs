@@ -0,0 +1,224 @@
+use crate::config::PurgeSettings;
+use crate::imagorpath::hasher::is_result_key;
+use crate::storage::storage::{Blob, ImageStorage};
+use color_eyre::{eyre::eyre, Result};
+use futures::future::ready;
+use futures::TryStreamExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestoreStatus {
+    Restored,
+    NotFound,
+    Expired,
+}
+
+fn trash_key(settings: &PurgeSettings, key: &str) -> String {
+    format!("{}{}", settings.trash_prefix, key)
+}
+
+fn marker_key(settings: &PurgeSettings, key: &str) -> String {
+    format!("{}{}.deleted_at", settings.trash_prefix, key)
+}
+
+/// Move `key` into the trash prefix, recording when it was deleted
+#[tracing::instrument(skip(storage))]
+pub async fn trash(storage: &dyn ImageStorage, settings: &PurgeSettings, key: &str) -> Result<()> {
+    let blob = storage.get(key).await?;
+    let deleted_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    storage.put(&trash_key(settings, key), &blob).await?;
+    storage
+        .put(
+            &marker_key(settings, key),
+            &Blob::new(deleted_at.to_string().into_bytes()),
+        )
+        .await?;
+    storage.delete(key).await
+}
+
+/// Results under `prefix`, leaving out the source images stored alongside
+/// them and what's already in the trash
+async fn result_keys(
+    storage: &dyn ImageStorage,
+    settings: &PurgeSettings,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    storage
+        .list(prefix)
+        .try_filter(|key| ready(!key.starts_with(&settings.trash_prefix) && is_result_key(key)))
+        .try_collect()
+        .await
+}
+
+/// Move every result under `prefix` into the trash, leaving what's already
+/// there. Returns how many results were trashed.
+#[tracing::instrument(skip(storage))]
+pub async fn trash_prefix(
+    storage: &dyn ImageStorage,
+    settings: &PurgeSettings,
+    prefix: &str,
+) -> Result<usize> {
+    // listed up front, so the trashed copies aren't listed too
+    let keys = result_keys(storage, settings, prefix).await?;
+    for key in &keys {
+        trash(storage, settings, key).await?;
+    }
+    Ok(keys.len())
+}
+
+/// Delete every result under `prefix`, leaving sources and the trash be.
+/// Returns how many results were deleted.
+#[tracing::instrument(skip(storage))]
+pub async fn delete_prefix(
+    storage: &dyn ImageStorage,
+    settings: &PurgeSettings,
+    prefix: &str,
+) -> Result<usize> {
+    let keys = result_keys(storage, settings, prefix).await?;
+    for key in &keys {
+        storage.delete(key).await?;
+    }
+    Ok(keys.len())
+}
+
+/// Move a trashed `key` back into place if it is still within the retention
+/// window. Expired entries are removed.
+#[tracing::instrument(skip(storage))]
+pub async fn restore(
+    storage: &dyn ImageStorage,
+    settings: &PurgeSettings,
+    key: &str,
+) -> Result<RestoreStatus> {
+    let Ok(marker) = storage.get(&marker_key(settings, key)).await else {
+        return Ok(RestoreStatus::NotFound);
+    };
+
+    let deleted_at = String::from_utf8(marker.data)?
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| eyre!("Invalid trash marker for {}: {}", key, e))?;
+    let deleted_at = UNIX_EPOCH + Duration::from_secs(deleted_at);
+    let elapsed = SystemTime::now()
+        .duration_since(deleted_at)
+        .unwrap_or_default();
+
+    if elapsed >= Duration::from_secs(settings.retention_secs) {
+        storage.delete(&trash_key(settings, key)).await?;
+        storage.delete(&marker_key(settings, key)).await?;
+        return Ok(RestoreStatus::Expired);
+    }
+
+    let blob = storage.get(&trash_key(settings, key)).await?;
+    storage.put(key, &blob).await?;
+    storage.delete(&trash_key(settings, key)).await?;
+    storage.delete(&marker_key(settings, key)).await?;
+
+    Ok(RestoreStatus::Restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathutil::normalize::SafeCharsType;
+    use crate::storage::file::FileStorage;
+
+    fn temp_storage(name: &str) -> FileStorage {
+        let dir =
+            std::env::temp_dir().join(format!("imagor-rs-trash-{}-{}", name, std::process::id()));
+        FileStorage::new(dir, String::new(), SafeCharsType::Default)
+    }
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let storage = temp_storage("restore");
+        let settings = PurgeSettings::default();
+        storage
+            .put("result/abc", &Blob::new(b"data".to_vec()))
+            .await
+            .unwrap();
+
+        trash(&storage, &settings, "result/abc").await.unwrap();
+        assert!(storage.get("result/abc").await.is_err());
+        assert!(storage.get("trash/result/abc").await.is_ok());
+
+        let status = restore(&storage, &settings, "result/abc").await.unwrap();
+        assert_eq!(status, RestoreStatus::Restored);
+        assert_eq!(storage.get("result/abc").await.unwrap().data, b"data");
+        assert!(storage.get("trash/result/abc").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restore_after_retention_expired() {
+        let storage = temp_storage("expired");
+        let settings = PurgeSettings {
+            retention_secs: 0,
+            ..Default::default()
+        };
+        storage
+            .put("result/def", &Blob::new(b"data".to_vec()))
+            .await
+            .unwrap();
+
+        trash(&storage, &settings, "result/def").await.unwrap();
+
+        let status = restore(&storage, &settings, "result/def").await.unwrap();
+        assert_eq!(status, RestoreStatus::Expired);
+        assert!(storage.get("result/def").await.is_err());
+        assert!(storage.get("trash/result/def").await.is_err());
+    }
+
+    const A1: &str = "a/1.0123456789abcdef0123.png";
+    const A2: &str = "a/2.0123456789abcdef0123.png";
+    const B3: &str = "b/3.0123456789abcdef0123.png";
+
+    /// Three results and the source they were made from
+    async fn results(name: &str) -> FileStorage {
+        let storage = temp_storage(name);
+        for key in [A1, A2, B3, "a/1.png"] {
+            storage
+                .put(key, &Blob::new(b"data".to_vec()))
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    #[tokio::test]
+    async fn test_trash_prefix() {
+        let storage = results("prefix").await;
+        let settings = PurgeSettings::default();
+
+        assert_eq!(trash_prefix(&storage, &settings, "a/").await.unwrap(), 2);
+        assert!(storage.get(A1).await.is_err());
+        assert!(storage.get(B3).await.is_ok());
+        assert!(storage.get("a/1.png").await.is_ok());
+        let status = restore(&storage, &settings, A2).await.unwrap();
+        assert_eq!(status, RestoreStatus::Restored);
+
+        // what's already in the trash stays put
+        assert_eq!(trash_prefix(&storage, &settings, "").await.unwrap(), 2);
+        assert!(storage.get(&format!("trash/{}", A1)).await.is_ok());
+        assert!(storage.get("a/1.png").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix_keeps_sources() {
+        let storage = results("delete").await;
+        let settings = PurgeSettings::default();
+
+        assert_eq!(delete_prefix(&storage, &settings, "a/").await.unwrap(), 2);
+        assert!(storage.get(A2).await.is_err());
+        assert!(storage.get(B3).await.is_ok());
+        assert!(storage.get("a/1.png").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_restore_missing() {
+        let storage = temp_storage("missing");
+        let status = restore(&storage, &PurgeSettings::default(), "result/nope")
+            .await
+            .unwrap();
+        assert_eq!(status, RestoreStatus::NotFound);
+    }
+}
@@ -1,8 +1,12 @@
+use color_eyre::{eyre::eyre, Result};
+use std::sync::OnceLock;
 use tracing::{subscriber::set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
+
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
 pub fn get_subscriber<Sink>(
     name: String,
@@ -15,6 +19,9 @@ where
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
 
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = LOG_FILTER.set(handle);
+
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
 
     Registry::default()
@@ -28,3 +35,14 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
 
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Swap the active tracing filter, e.g. after a config reload
+pub fn set_log_level(filter: &str) -> Result<()> {
+    let handle = LOG_FILTER
+        .get()
+        .ok_or_else(|| eyre!("Tracing subscriber is not initialized"))?;
+    let env_filter = EnvFilter::try_new(filter)?;
+    handle.reload(env_filter)?;
+
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use crate::allowlist::AllowList;
+use crate::config::{RateLimitBudget, TenantSettings};
+use crate::imagorpath::signer::HmacSigner;
+use crate::ratelimit::limiter::{Decision, RateLimiter};
+use crate::state::AppStateDyn;
+use crate::storage::prefixed::PrefixedStorage;
+use crate::storage::storage::ImageStorage;
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use color_eyre::{eyre::eyre, Result};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// A customer of a shared server, with its own signing secret, storage,
+/// allowed sources and rate limit
+#[derive(Clone)]
+pub struct Tenant {
+    name: Arc<str>,
+    signer: Option<HmacSigner>,
+    allow_unsafe: Option<bool>,
+    storage: Arc<dyn ImageStorage>,
+    allowed_sources: Option<AllowList>,
+    rate_limit: Option<RateLimitBudget>,
+}
+
+impl Tenant {
+    fn new(settings: &TenantSettings, storage: Arc<dyn ImageStorage>) -> Result<Self> {
+        let storage_prefix = settings.storage_prefix.as_deref().unwrap_or(&settings.name);
+        if storage_prefix.trim_matches('/').is_empty() {
+            return Err(eyre!("Tenant `{}` needs a storage prefix", settings.name));
+        }
+
+        Ok(Tenant {
+            name: settings.name.as_str().into(),
+            signer: settings.hmac_secret.clone().map(HmacSigner::new),
+            allow_unsafe: settings.allow_unsafe,
+            storage: Arc::new(PrefixedStorage::new(storage_prefix, storage)),
+            allowed_sources: settings
+                .allowed_sources
+                .as_deref()
+                .map(AllowList::new)
+                .transpose()?,
+            rate_limit: settings.rate_limit,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hosts the tenant may fetch from, `None` for the server's
+    pub fn allowed_sources(&self) -> Option<&AllowList> {
+        self.allowed_sources.as_ref()
+    }
+
+    /// `state` with the tenant's settings in place of the server's
+    pub fn state(&self, state: AppStateDyn) -> AppStateDyn {
+        AppStateDyn {
+            storage: self.storage.clone(),
+            signer: self.signer.clone().unwrap_or(state.signer),
+            allow_unsafe: self.allow_unsafe.unwrap_or(state.allow_unsafe),
+            // write-behind saves to the shared storage, not the tenant's
+            write_behind: None,
+            tenant: Some(self.clone()),
+            ..state
+        }
+    }
+}
+
+/// The tenants of a server, matched by hostname first and then by the
+/// longest path prefix
+#[derive(Clone, Default)]
+pub struct Tenants {
+    by_host: Arc<HashMap<String, Tenant>>,
+    /// Without slashes, longest first
+    by_prefix: Arc<Vec<(String, Tenant)>>,
+    limiter: Option<Arc<dyn RateLimiter>>,
+}
+
+impl Tenants {
+    /// Each tenant's storage is kept under its prefix of `storage`, and its
+    /// rate limit is kept by `limiter`
+    pub fn new(
+        settings: &[TenantSettings],
+        storage: Arc<dyn ImageStorage>,
+        limiter: Option<Arc<dyn RateLimiter>>,
+    ) -> Result<Self> {
+        let mut names = Vec::new();
+        let mut by_host = HashMap::new();
+        let mut by_prefix = Vec::new();
+        for settings in settings {
+            let name = &settings.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(eyre!(
+                    "Invalid tenant name {:?}, use letters, digits, `-` and `_`",
+                    name
+                ));
+            }
+            if names.contains(name) {
+                return Err(eyre!("Tenant `{}` is configured twice", name));
+            }
+            names.push(name.clone());
+
+            let prefix = settings
+                .path_prefix
+                .as_deref()
+                .map(|prefix| prefix.trim_matches('/'))
+                .filter(|prefix| !prefix.is_empty());
+            if settings.hosts.is_empty() && prefix.is_none() {
+                return Err(eyre!("Tenant `{}` needs hosts or a path prefix", name));
+            }
+            if settings.rate_limit.is_some() && limiter.is_none() {
+                return Err(eyre!("Tenant `{}` has a rate limit but no limiter", name));
+            }
+
+            let tenant = Tenant::new(settings, storage.clone())?;
+            for host in &settings.hosts {
+                let host = host.to_lowercase();
+                if by_host.insert(host.clone(), tenant.clone()).is_some() {
+                    return Err(eyre!("Host {} belongs to more than one tenant", host));
+                }
+            }
+            if let Some(prefix) = prefix {
+                if by_prefix.iter().any(|(other, _)| other == prefix) {
+                    return Err(eyre!(
+                        "Path prefix /{} belongs to more than one tenant",
+                        prefix
+                    ));
+                }
+                by_prefix.push((prefix.to_string(), tenant));
+            }
+        }
+        by_prefix.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Tenants {
+            by_host: Arc::new(by_host),
+            by_prefix: Arc::new(by_prefix),
+            limiter,
+        })
+    }
+
+    /// The tenant a request for `host` and `path` belongs to, and the path
+    /// to route it by
+    pub fn resolve<'a>(&self, host: Option<&str>, path: &'a str) -> Option<(&Tenant, &'a str)> {
+        let host = host.map(|host| {
+            // without the port, which may be bracketed IPv6
+            let host = match host.rsplit_once(':') {
+                Some((name, port)) if !port.contains(']') => name,
+                _ => host,
+            };
+            host.to_lowercase()
+        });
+        if let Some(tenant) = host.and_then(|host| self.by_host.get(&host)) {
+            return Some((tenant, path));
+        }
+
+        self.by_prefix.iter().find_map(|(prefix, tenant)| {
+            let rest = path.strip_prefix('/')?.strip_prefix(prefix.as_str())?;
+            match rest {
+                "" => Some((tenant, "/")),
+                _ if rest.starts_with('/') => Some((tenant, rest)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Charges a request to the tenant's budget, when it has one
+    pub async fn check(&self, tenant: &Tenant) -> Result<Decision> {
+        match (&self.limiter, &tenant.rate_limit) {
+            (Some(limiter), Some(budget)) => {
+                limiter
+                    .check(&format!("tenant:{}", tenant.name), budget)
+                    .await
+            }
+            _ => Ok(Decision::Allowed),
+        }
+    }
+}
+
+/// The app state, with the settings of the tenant the request belongs to
+pub struct TenantState(pub AppStateDyn);
+
+#[async_trait]
+impl FromRequestParts<AppStateDyn> for TenantState {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppStateDyn,
+    ) -> Result<Self, Self::Rejection> {
+        let state = state.clone();
+        Ok(TenantState(match parts.extensions.get::<Tenant>() {
+            Some(tenant) => tenant.state(state),
+            None => state,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathutil::normalize::SafeCharsType;
+    use crate::storage::file::FileStorage;
+
+    fn tenant(name: &str, hosts: &[&str], path_prefix: Option<&str>) -> TenantSettings {
+        TenantSettings {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            path_prefix: path_prefix.map(str::to_string),
+            hmac_secret: None,
+            allow_unsafe: None,
+            storage_prefix: None,
+            allowed_sources: None,
+            rate_limit: None,
+        }
+    }
+
+    fn tenants(settings: &[TenantSettings]) -> Result<Tenants> {
+        let storage = Arc::new(FileStorage::new(
+            std::env::temp_dir().join("imagor-rs-tenants"),
+            String::new(),
+            SafeCharsType::Default,
+        ));
+        Tenants::new(settings, storage, None)
+    }
+
+    fn resolved<'a>(tenants: &'a Tenants, host: &str, path: &'a str) -> Option<(&'a str, &'a str)> {
+        tenants
+            .resolve(Some(host), path)
+            .map(|(tenant, path)| (tenant.name(), path))
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tenants = tenants(&[
+            tenant("acme", &["img.acme.com"], Some("/acme/")),
+            tenant("acme-eu", &[], Some("acme/eu")),
+        ])
+        .unwrap();
+
+        let path = "/unsafe/100x0/cat.jpg";
+        assert_eq!(
+            resolved(&tenants, "IMG.acme.com:8080", path),
+            Some(("acme", path))
+        );
+        assert_eq!(
+            resolved(&tenants, "localhost", "/acme/unsafe/100x0/cat.jpg"),
+            Some(("acme", path))
+        );
+        assert_eq!(
+            resolved(&tenants, "localhost", "/acme/eu/unsafe/100x0/cat.jpg"),
+            Some(("acme-eu", path))
+        );
+        assert_eq!(
+            resolved(&tenants, "localhost", "/acme"),
+            Some(("acme", "/"))
+        );
+        assert_eq!(resolved(&tenants, "localhost", "/acmecorp/cat.jpg"), None);
+        assert_eq!(resolved(&tenants, "[::1]:8080", path), None);
+        assert!(tenants.resolve(None, path).is_none());
+    }
+
+    #[test]
+    fn test_invalid_tenants() {
+        assert!(tenants(&[tenant("acme", &[], None)]).is_err());
+        assert!(tenants(&[tenant("ac me", &["a.com"], None)]).is_err());
+        assert!(tenants(&[
+            tenant("acme", &["a.com"], None),
+            tenant("acme", &["b.com"], None)
+        ])
+        .is_err());
+        assert!(tenants(&[tenant("a", &["a.com"], None), tenant("b", &["A.com"], None)]).is_err());
+        assert!(tenants(&[tenant("a", &[], Some("x")), tenant("b", &[], Some("/x/"))]).is_err());
+
+        let limited = TenantSettings {
+            rate_limit: Some(RateLimitBudget::default()),
+            ..tenant("acme", &["a.com"], None)
+        };
+        assert!(tenants(&[limited]).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::config::TlsSettings;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use color_eyre::{eyre::eyre, Result};
+use hyper::body::Incoming;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// Serves the certificate and key from disk, reloading them when they change
+/// so renewed certificates are picked up without a restart
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(settings: &TlsSettings) -> Result<Self> {
+        let cert_path = PathBuf::from(&settings.cert_path);
+        let key_path = PathBuf::from(&settings.key_path);
+        let current = load(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Keeps the certificate being served when the new one can't be loaded,
+    /// e.g. when only one of the two files has been replaced so far
+    pub fn reload(&self) -> Result<()> {
+        let key = load(&self.cert_path, &self.key_path)?;
+        *self.current.write().expect("certificate lock poisoned") = Arc::new(key);
+        info!("Reloaded TLS certificate from {}", self.cert_path.display());
+        Ok(())
+    }
+
+    /// Reload whenever the directories holding the certificate or key change.
+    /// The returned watcher must be kept alive for as long as reloading
+    /// should happen.
+    pub fn watch(self: &Arc<Self>) -> Result<RecommendedWatcher> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        for path in [&self.cert_path, &self.key_path] {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let Err(e) = event {
+                    warn!("certificate watcher error: {}", e);
+                    continue;
+                }
+
+                // certificate and key tend to be replaced one after the other
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                while rx.try_recv().is_ok() {}
+
+                if let Err(e) = resolver.reload() {
+                    warn!("Failed to reload TLS certificate: {}", e);
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .expect("certificate lock poisoned")
+                .clone(),
+        )
+    }
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(eyre!("No certificates in {}", cert_path.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| eyre!("No private key in {}", key_path.display()))?;
+    let key = ring::sign::any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// TLS 1.2 and 1.3, offering HTTP/2 through ALPN
+pub fn server_config(resolver: Arc<CertResolver>) -> Result<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Accept TLS connections on `listener` and serve `app` over HTTP/1.1 or
+/// HTTP/2, whichever the client negotiated
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: ServerConfig,
+) -> std::io::Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // e.g. out of file descriptors, which passes
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = app.clone().map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(addr));
+            req
+        });
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    TowerToHyperService::new(service),
+                )
+                .await
+            {
+                debug!("Connection with {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_files() {
+        let settings = TlsSettings {
+            enabled: true,
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            key_path: "/nonexistent/key.pem".to_string(),
+        };
+        assert!(CertResolver::new(&settings).is_err());
+    }
+
+    #[test]
+    fn test_no_certificate() {
+        let dir = std::env::temp_dir().join(format!("imagor-rs-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+
+        let err = load(&cert_path, &dir.join("key.pem")).unwrap_err();
+        assert!(err.to_string().contains("No certificates"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
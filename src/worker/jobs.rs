@@ -0,0 +1,242 @@
+use crate::config::WorkerSettings;
+use crate::error::ApiError;
+use crate::imagorpath::filter::Filter;
+use crate::imagorpath::hasher::suffix_result_storage_hasher;
+use crate::imagorpath::parse::parse_params;
+use crate::startup::{render, serves_source};
+use crate::state::AppStateDyn;
+use crate::storage::storage::Blob;
+use crate::worker::queue::{Completion, Delivery, Job, JobQueue};
+use axum::body::to_bytes;
+use axum::http::{header, HeaderMap, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+/// Wait before receiving again after the queue failed
+const RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where a job's result was saved
+#[derive(Debug, PartialEq)]
+struct Rendered {
+    result_key: String,
+    content_type: String,
+    bytes: usize,
+}
+
+/// Processes jobs from `queue` until the process exits. Must be called
+/// within a tokio runtime.
+pub fn spawn(settings: &WorkerSettings, queue: Arc<dyn JobQueue>, state: AppStateDyn) {
+    // completion events go out once the result is saved
+    let state = AppStateDyn {
+        write_behind: None,
+        ..state
+    };
+    let permits = Arc::new(Semaphore::new(settings.concurrency.max(1)));
+    tokio::spawn(run(queue, state, permits));
+}
+
+async fn run(queue: Arc<dyn JobQueue>, state: AppStateDyn, permits: Arc<Semaphore>) {
+    loop {
+        let deliveries = match queue.receive().await {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                warn!("Failed to receive jobs: {}", e);
+                tokio::time::sleep(RECEIVE_BACKOFF).await;
+                continue;
+            }
+        };
+
+        for delivery in deliveries {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                return;
+            };
+            let (queue, state) = (queue.clone(), state.clone());
+            tokio::spawn(async move {
+                let _permit = permit;
+                handle(queue.as_ref(), &state, delivery).await;
+            });
+        }
+    }
+}
+
+/// Runs the job in `delivery` and acknowledges it. Failed jobs aren't
+/// retried, their completion event says why.
+async fn handle(queue: &dyn JobQueue, state: &AppStateDyn, delivery: Delivery) {
+    match serde_json::from_slice::<Job>(&delivery.body) {
+        Ok(job) => {
+            let completion = run_job(state, &job).await;
+            let status = if completion.ok { "ok" } else { "failed" };
+            metrics::counter!("worker_jobs_total", "status" => status).increment(1);
+
+            if let Err(e) = queue.publish(&delivery, &completion).await {
+                warn!("Failed to publish completion of {}: {}", job.path, e);
+            }
+            if let Some(callback_url) = &job.callback_url {
+                callback(callback_url, &completion).await;
+            }
+        }
+        Err(e) => {
+            metrics::counter!("worker_jobs_total", "status" => "malformed").increment(1);
+            warn!("Dropping malformed job: {}", e);
+        }
+    }
+
+    if let Err(e) = queue.ack(&delivery).await {
+        warn!("Failed to acknowledge job: {}", e);
+    }
+}
+
+/// Renders the job's path as `GET /{path}` would and saves the result
+pub async fn run_job(state: &AppStateDyn, job: &Job) -> Completion {
+    info!("Running job: {}", job.path);
+    completion(job, render_job(state, job).await)
+}
+
+async fn render_job(state: &AppStateDyn, job: &Job) -> Result<Rendered, ApiError> {
+    let params = parse_params(job.path.trim_start_matches('/'))?;
+    // keyed as `render` keys it, with no Accept header to negotiate
+    let canonical = params
+        .clone()
+        .with_defaults(&state.defaults)
+        .with_negotiated_format("")
+        .canonicalized(&state.defaults);
+    let key = suffix_result_storage_hasher(&canonical);
+    // served from the source, so `render` doesn't save them
+    let unsaved = canonical.filters.contains(&Filter::Raw) || serves_source(state, &canonical);
+
+    let response = render(state, &HeaderMap::new(), params).await?;
+    let blob = if response.status() == StatusCode::FOUND {
+        state
+            .storage
+            .get(&key)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to read result image: {}", e)))?
+    } else {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let data = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to read result image: {}", e)))?
+            .to_vec();
+        Blob::typed(data, content_type.as_deref())
+    };
+
+    // sources that needn't be rendered aren't saved either
+    let unsaved = unsaved || matches!(state.storage.stat(&key).await, Ok(None));
+    let result_key = match &job.result_key {
+        Some(result_key) => result_key.clone(),
+        None if unsaved => key,
+        None => {
+            return Ok(Rendered {
+                result_key: key,
+                content_type: blob.content_type.to_string(),
+                bytes: blob.data.len(),
+            })
+        }
+    };
+    state
+        .storage
+        .put(&result_key, &blob)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to save result image: {}", e)))?;
+
+    Ok(Rendered {
+        result_key,
+        content_type: blob.content_type.to_string(),
+        bytes: blob.data.len(),
+    })
+}
+
+fn completion(job: &Job, result: Result<Rendered, ApiError>) -> Completion {
+    let completion = Completion {
+        id: job.id.clone(),
+        path: job.path.clone(),
+        ok: false,
+        result_key: None,
+        content_type: None,
+        bytes: None,
+        status: StatusCode::OK.as_u16(),
+        error: None,
+    };
+
+    match result {
+        Ok(rendered) => Completion {
+            ok: true,
+            result_key: Some(rendered.result_key),
+            content_type: Some(rendered.content_type),
+            bytes: Some(rendered.bytes),
+            ..completion
+        },
+        Err(e) => {
+            warn!("Job {} failed: {}", job.path, e);
+            Completion {
+                status: e.status().as_u16(),
+                error: Some(e.to_string()),
+                ..completion
+            }
+        }
+    }
+}
+
+async fn callback(url: &str, completion: &Completion) {
+    let Ok(body) = serde_json::to_vec(completion) else {
+        return;
+    };
+    let result = reqwest::Client::new()
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        warn!("Failed to call back {}: {}", url, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_from_json() {
+        let job: Job = serde_json::from_str(r#"{"path": "unsafe/300x200/gopher.png"}"#).unwrap();
+        assert_eq!(job.path, "unsafe/300x200/gopher.png");
+        assert_eq!(job.result_key, None);
+        assert_eq!(job.callback_url, None);
+    }
+
+    #[test]
+    fn test_completion() {
+        let job = Job {
+            id: Some("42".to_string()),
+            path: "unsafe/300x200/gopher.png".to_string(),
+            result_key: None,
+            callback_url: None,
+        };
+
+        let done = completion(
+            &job,
+            Ok(Rendered {
+                result_key: "gopher.png".to_string(),
+                content_type: "image/png".to_string(),
+                bytes: 10,
+            }),
+        );
+        assert!(done.ok);
+        assert_eq!(done.id.as_deref(), Some("42"));
+        assert_eq!(done.result_key.as_deref(), Some("gopher.png"));
+        assert_eq!(done.status, 200);
+
+        let failed = completion(&job, Err(ApiError::not_found("missing")));
+        assert!(!failed.ok);
+        assert_eq!(failed.status, 404);
+        assert_eq!(failed.error.as_deref(), Some("missing"));
+        assert_eq!(failed.result_key, None);
+    }
+}
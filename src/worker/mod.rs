@@ -0,0 +1,4 @@
+pub mod jobs;
+pub mod nats;
+pub mod queue;
+pub mod sqs;
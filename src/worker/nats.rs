@@ -0,0 +1,68 @@
+use crate::config::NatsQueueSettings;
+use crate::worker::queue::{Completion, Delivery, JobQueue};
+use async_nats::{Client, Subscriber};
+use axum::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use futures::StreamExt;
+use tokio::sync::Mutex;
+
+/// Jobs from a NATS subject, shared between the workers of a queue group.
+/// Core NATS doesn't redeliver, so jobs in flight when a worker stops are
+/// lost.
+pub struct NatsQueue {
+    client: Client,
+    subscriber: Mutex<Subscriber>,
+    events_subject: String,
+}
+
+impl NatsQueue {
+    pub async fn new(settings: &NatsQueueSettings) -> Result<Self> {
+        let client = async_nats::connect(&settings.url).await?;
+        let subscriber = client
+            .queue_subscribe(settings.subject.clone(), settings.queue_group.clone())
+            .await?;
+
+        Ok(NatsQueue {
+            client,
+            subscriber: Mutex::new(subscriber),
+            events_subject: settings.events_subject.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl JobQueue for NatsQueue {
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let message = self
+            .subscriber
+            .lock()
+            .await
+            .next()
+            .await
+            .ok_or_else(|| eyre!("NATS subscription closed"))?;
+
+        Ok(vec![Delivery {
+            body: message.payload.to_vec(),
+            receipt: None,
+            reply_to: message.reply.map(|reply| reply.to_string()),
+        }])
+    }
+
+    async fn ack(&self, _delivery: &Delivery) -> Result<()> {
+        Ok(())
+    }
+
+    async fn publish(&self, delivery: &Delivery, completion: &Completion) -> Result<()> {
+        let payload = serde_json::to_vec(completion)?;
+        if let Some(reply_to) = &delivery.reply_to {
+            self.client
+                .publish(reply_to.clone(), payload.clone().into())
+                .await?;
+        }
+        self.client
+            .publish(self.events_subject.clone(), payload.into())
+            .await?;
+
+        Ok(())
+    }
+}
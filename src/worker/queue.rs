@@ -0,0 +1,55 @@
+use axum::async_trait;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// A render job, sent to the queue as JSON
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    /// Echoed back in the completion event
+    #[serde(default)]
+    pub id: Option<String>,
+    /// A signed or unsafe imagor path, e.g. `unsafe/fit-in/300x200/gopher.png`
+    pub path: String,
+    /// Also save the result under this key, e.g. for a rendition a client
+    /// looks up by name
+    #[serde(default)]
+    pub result_key: Option<String>,
+    /// The completion event is POSTed here as JSON
+    #[serde(default)]
+    pub callback_url: Option<String>,
+}
+
+/// Published once a job is done, whether it succeeded or not
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub id: Option<String>,
+    pub path: String,
+    pub ok: bool,
+    /// Where the result was saved
+    pub result_key: Option<String>,
+    pub content_type: Option<String>,
+    pub bytes: Option<usize>,
+    /// The HTTP status a request for the path would have got
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+/// A message taken off the queue, not yet acknowledged
+#[derive(Debug, Clone, Default)]
+pub struct Delivery {
+    pub body: Vec<u8>,
+    /// SQS receipt handle, to delete the message once it's done
+    pub receipt: Option<String>,
+    /// NATS reply subject, for jobs sent as requests
+    pub reply_to: Option<String>,
+}
+
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    /// Waits for the next messages
+    async fn receive(&self) -> Result<Vec<Delivery>>;
+    /// Drops a message that's been dealt with, so it isn't delivered again
+    async fn ack(&self, delivery: &Delivery) -> Result<()>;
+    /// Publishes the completion event for a message
+    async fn publish(&self, delivery: &Delivery, completion: &Completion) -> Result<()>;
+}
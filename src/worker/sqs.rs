@@ -0,0 +1,95 @@
+use crate::config::SqsQueueSettings;
+use crate::worker::queue::{Completion, Delivery, JobQueue};
+use aws_config::BehaviorVersion;
+use aws_sdk_sqs::config::Region;
+use aws_sdk_sqs::error::DisplayErrorContext;
+use aws_sdk_sqs::Client;
+use axum::async_trait;
+use color_eyre::{eyre::eyre, Result};
+
+/// Messages received at once, the most SQS allows
+const BATCH_SIZE: i32 = 10;
+/// Long polling, so an idle worker makes few requests
+const WAIT_TIME_SECS: i32 = 20;
+
+/// Jobs from an SQS queue. A message is deleted once its job is done, so
+/// one a worker dies holding is delivered again after its visibility
+/// timeout.
+pub struct SqsQueue {
+    client: Client,
+    queue_url: String,
+    events_queue_url: Option<String>,
+}
+
+impl SqsQueue {
+    pub async fn new(settings: &SqsQueueSettings) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &settings.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint) = &settings.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+
+        SqsQueue {
+            client: Client::new(&loader.load().await),
+            queue_url: settings.queue_url.clone(),
+            events_queue_url: settings.events_queue_url.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl JobQueue for SqsQueue {
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let output = self
+            .client
+            .receive_message()
+            .queue_url(&self.queue_url)
+            .max_number_of_messages(BATCH_SIZE)
+            .wait_time_seconds(WAIT_TIME_SECS)
+            .send()
+            .await
+            .map_err(|e| eyre!("Failed to receive jobs: {}", DisplayErrorContext(e)))?;
+
+        Ok(output
+            .messages()
+            .iter()
+            .map(|message| Delivery {
+                body: message.body().unwrap_or_default().as_bytes().to_vec(),
+                receipt: message.receipt_handle().map(str::to_string),
+                reply_to: None,
+            })
+            .collect())
+    }
+
+    async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        let Some(receipt) = &delivery.receipt else {
+            return Ok(());
+        };
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt)
+            .send()
+            .await
+            .map_err(|e| eyre!("Failed to delete job: {}", DisplayErrorContext(e)))?;
+
+        Ok(())
+    }
+
+    async fn publish(&self, _delivery: &Delivery, completion: &Completion) -> Result<()> {
+        let Some(events_queue_url) = &self.events_queue_url else {
+            return Ok(());
+        };
+        self.client
+            .send_message()
+            .queue_url(events_queue_url)
+            .message_body(serde_json::to_string(completion)?)
+            .send()
+            .await
+            .map_err(|e| eyre!("Failed to send completion event: {}", DisplayErrorContext(e)))?;
+
+        Ok(())
+    }
+}
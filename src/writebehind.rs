@@ -0,0 +1,230 @@
+use crate::config::{RetrySettings, WriteBehindSettings};
+use crate::storage::retry::RetryPolicy;
+use crate::storage::storage::{Blob, ImageStorage};
+use color_eyre::Result;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error};
+
+/// Saves results to storage after the response has been sent, so a slow
+/// bucket doesn't add to request latency. Saves that keep failing are given
+/// up on and logged to the `imagor_rs::dead_letter` target.
+#[derive(Clone)]
+pub struct WriteBehind {
+    sender: mpsc::Sender<(String, Blob)>,
+}
+
+impl WriteBehind {
+    /// `None` when results are saved before responding. Must be called
+    /// within a tokio runtime.
+    pub fn new(settings: &WriteBehindSettings, storage: Arc<dyn ImageStorage>) -> Option<Self> {
+        if !settings.enabled || settings.queue_size == 0 {
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(settings.queue_size);
+        let retry = RetryPolicy::new(&RetrySettings {
+            max_attempts: settings.max_attempts,
+            base_delay_ms: settings.retry_backoff_ms,
+            max_delay_ms: settings.max_retry_delay_ms,
+            jitter: true,
+        });
+        let permits = Arc::new(Semaphore::new(settings.concurrency.max(1)));
+        tokio::spawn(run(receiver, storage, permits, retry));
+
+        Some(WriteBehind { sender })
+    }
+
+    /// Queue `blob` to be saved under `key`. False when the queue is full,
+    /// so the caller has to save it itself.
+    pub fn enqueue(&self, key: String, blob: Blob) -> bool {
+        match self.sender.try_send((key, blob)) {
+            Ok(()) => true,
+            Err(e) => {
+                let (key, _) = e.into_inner();
+                debug!("Write-behind queue is full, saving [{}] inline", key);
+                false
+            }
+        }
+    }
+}
+
+async fn run(
+    mut receiver: mpsc::Receiver<(String, Blob)>,
+    storage: Arc<dyn ImageStorage>,
+    permits: Arc<Semaphore>,
+    retry: RetryPolicy,
+) {
+    while let Some((key, blob)) = receiver.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let storage = storage.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = put_with_retries(storage.as_ref(), &key, &blob, retry).await {
+                metrics::counter!("result_storage_dead_letters_total").increment(1);
+                error!(
+                    target: "imagor_rs::dead_letter",
+                    key = %key,
+                    bytes = blob.data.len(),
+                    "Gave up saving result after {} attempts: {}",
+                    retry.max_attempts(),
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Backs off exponentially between attempts, retrying any failure since
+/// nobody is waiting on the save
+async fn put_with_retries(
+    storage: &dyn ImageStorage,
+    key: &str,
+    blob: &Blob,
+    retry: RetryPolicy,
+) -> Result<()> {
+    retry
+        .run_if(
+            &format!("Saving result [{}]", key),
+            |_| true,
+            || storage.put(key, blob),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::storage::Stat;
+    use axum::async_trait;
+    use color_eyre::eyre::eyre;
+    use futures::stream::{self, BoxStream, StreamExt};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Fails the first `failures` puts of every key
+    #[derive(Default)]
+    struct FlakyStorage {
+        failures: u32,
+        attempts: Mutex<HashMap<String, u32>>,
+        saved: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ImageStorage for FlakyStorage {
+        async fn get(&self, key: &str) -> Result<Blob> {
+            let saved = self.saved.lock().unwrap();
+            let data = saved.get(key).ok_or_else(|| eyre!("not found"))?;
+            Ok(Blob::new(data.clone()))
+        }
+
+        async fn put(&self, key: &str, blob: &Blob) -> Result<()> {
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(key.to_string()).or_default();
+            *attempt += 1;
+            if *attempt <= self.failures {
+                return Err(eyre!("unavailable"));
+            }
+            self.saved
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), blob.data.clone());
+            Ok(())
+        }
+
+        async fn delete(&self, _key: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+            Ok(self.saved.lock().unwrap().get(key).map(|data| Stat {
+                size: data.len() as u64,
+                ..Default::default()
+            }))
+        }
+
+        fn list(&self, prefix: &str) -> BoxStream<'_, Result<String>> {
+            let keys: Vec<_> = self
+                .saved
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            stream::iter(keys).map(Ok).boxed()
+        }
+
+        async fn ping(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    use std::time::Duration;
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy::new(&RetrySettings {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 4,
+            jitter: true,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_put_retries() {
+        let storage = FlakyStorage {
+            failures: 2,
+            ..Default::default()
+        };
+        put_with_retries(&storage, "a", &Blob::new(vec![1]), retry())
+            .await
+            .unwrap();
+        assert_eq!(storage.get("a").await.unwrap().data, vec![1]);
+
+        let storage = FlakyStorage {
+            failures: 3,
+            ..Default::default()
+        };
+        assert!(
+            put_with_retries(&storage, "b", &Blob::new(vec![1]), retry())
+                .await
+                .is_err()
+        );
+        assert_eq!(storage.attempts.lock().unwrap()["b"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_enqueued_results_are_saved() {
+        let storage = Arc::new(FlakyStorage {
+            failures: 1,
+            ..Default::default()
+        });
+        let settings = WriteBehindSettings {
+            retry_backoff_ms: 1,
+            ..Default::default()
+        };
+        let queue = WriteBehind::new(&settings, storage.clone()).unwrap();
+
+        assert!(queue.enqueue("a".to_string(), Blob::new(vec![7])));
+        for _ in 0..100 {
+            if storage.get("a").await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(storage.get("a").await.unwrap().data, vec![7]);
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let settings = WriteBehindSettings {
+            enabled: false,
+            ..Default::default()
+        };
+        assert!(WriteBehind::new(&settings, Arc::new(FlakyStorage::default())).is_none());
+    }
+}